@@ -4,9 +4,11 @@
 mod p1_switches;
 mod p2_laundry_machine;
 mod p3_atm;
-mod p4_accounted_currency;
+pub mod p4_accounted_currency;
 mod p5_digital_cash;
 mod p6_open_ended;
+pub mod p7_storage;
+pub mod p8_transaction_validity;
 
 /// A state machine - Generic over the transition type
 pub trait StateMachine {
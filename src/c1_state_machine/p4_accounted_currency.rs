@@ -8,11 +8,15 @@
 //! In this module we design a state machine that tracks the currency balances of several users.
 //! Each user is associated with an account balance and users are able to send money to other users.
 
+use super::p8_transaction_validity::{
+    InvalidTransaction, TransactionValidity, ValidTransaction, ValidateTransaction,
+};
 use super::{StateMachine, User};
 use std::collections::HashMap;
 
 /// This state machine models a multi-user currency system. It tracks the balance of each
 /// user and allows users to send funds to one another.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct AccountedCurrency;
 
 /// The main balances mapping.
@@ -21,9 +25,10 @@ pub struct AccountedCurrency;
 /// There exists an existential deposit of at least 1. That is
 /// to say that an account gets removed from the map entirely
 /// when its balance falls back to 0.
-type Balances = HashMap<User, u64>;
+pub type Balances = HashMap<User, u64>;
 
 /// The state transitions that users can make in an accounted currency system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum AccountingTransaction {
     /// Create some new money for the given minter in the given amount
     Mint { minter: User, amount: u64 },
@@ -49,6 +54,16 @@ impl StateMachine for AccountedCurrency {
     }
 }
 
+/// There are no fees in this system, so every valid transaction has the same priority.
+/// Transactions that would have no effect, such as empty ones or transfers to oneself, are bad
+/// transactions and are rejected rather than pooled. So are burns from accounts that do not exist
+/// and transfers the sender can not afford, for insufficient funds.
+impl ValidateTransaction for AccountedCurrency {
+    fn validate_transaction(state: &Balances, t: &AccountingTransaction) -> TransactionValidity {
+        todo!("Exercise 2")
+    }
+}
+
 #[test]
 fn sm_4_mint_creates_account() {
     let start = HashMap::new();
@@ -323,3 +338,51 @@ fn sm_4_transfer() {
 
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_4_validate_transactions() {
+    let state = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let validate = |t| AccountedCurrency::validate_transaction(&state, &t);
+
+    assert!(validate(AccountingTransaction::Mint {
+        minter: User::Charlie,
+        amount: 10,
+    })
+    .is_ok());
+    assert!(validate(AccountingTransaction::Transfer {
+        sender: User::Bob,
+        receiver: User::Charlie,
+        amount: 50,
+    })
+    .is_ok());
+    assert_eq!(
+        validate(AccountingTransaction::Transfer {
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 60,
+        }),
+        Err(InvalidTransaction::InsufficientFunds)
+    );
+    assert_eq!(
+        validate(AccountingTransaction::Burn {
+            burner: User::Charlie,
+            amount: 5,
+        }),
+        Err(InvalidTransaction::InsufficientFunds)
+    );
+    assert_eq!(
+        validate(AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Alice,
+            amount: 5,
+        }),
+        Err(InvalidTransaction::BadTransaction)
+    );
+    assert_eq!(
+        validate(AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 0,
+        }),
+        Err(InvalidTransaction::BadTransaction)
+    );
+}
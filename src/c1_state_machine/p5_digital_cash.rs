@@ -3,6 +3,9 @@
 //! cash bills. Each bill has an amount and an owner, and can be spent in its entirety.
 //! When a state transition spends bills, new bills are created in lesser or equal amount.
 
+use super::p8_transaction_validity::{
+    InvalidTransaction, TransactionTag, TransactionValidity, ValidTransaction, ValidateTransaction,
+};
 use super::{StateMachine, User};
 use std::collections::HashSet;
 
@@ -98,6 +101,42 @@ impl StateMachine for DigitalCashSystem {
     }
 }
 
+/// The tag provided by creating a bill and required by spending it.
+fn bill_tag(bill: &Bill) -> TransactionTag {
+    let mut tag = b"bill".to_vec();
+    tag.extend(crate::hash(bill).to_le_bytes());
+    tag
+}
+
+/// The tag provided once bills up to, but not including, the given serial number exist.
+fn serial_tag(serial: u64) -> TransactionTag {
+    let mut tag = b"serial".to_vec();
+    tag.extend(serial.to_le_bytes());
+    tag
+}
+
+/// Transfers that can never be valid are bad transactions: ones that spend nothing, spend a bill
+/// twice or also receive it, create bills worth nothing, create more money than they spend, or
+/// receive bills whose serial numbers do not follow one another. Transfers that spend a bill that
+/// is gone, or receive a serial number that has already been used, are stale.
+///
+/// Bills that do not exist yet, but have serial numbers that have not been used, may still be
+/// created by other pending transactions, so spending them is a requirement rather than an error.
+/// The same goes for receiving bills with serial numbers beyond the next one. A transfer provides
+/// the `bill_tag` of each bill it creates and the `serial_tag` of the serial number after its last
+/// one, and requires the tags of whatever does not exist yet.
+///
+/// A mint's serial number is only assigned when it is executed, so a pending mint can not promise
+/// any particular bill or serial number. Mints provide no tags, and any number of them may wait in
+/// the pool side by side.
+///
+/// The amount destroyed by a transfer acts as its fee, and so is its priority.
+impl ValidateTransaction for DigitalCashSystem {
+    fn validate_transaction(state: &State, t: &CashTransaction) -> TransactionValidity {
+        todo!("Exercise 2")
+    }
+}
+
 #[test]
 fn sm_5_mint_new_cash() {
     let start = State::new();
@@ -679,3 +718,94 @@ fn sm_5_spending_from_charlie_to_all() {
     expected.set_serial(62);
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_5_validate_transactions() {
+    let alice_bill = Bill {
+        owner: User::Alice,
+        amount: 20,
+        serial: 0,
+    };
+    let state = State::from([alice_bill.clone()]);
+    let bob_bill = Bill {
+        owner: User::Bob,
+        amount: 15,
+        serial: 1,
+    };
+    let transfer = CashTransaction::Transfer {
+        spends: vec![alice_bill.clone()],
+        receives: vec![bob_bill.clone()],
+    };
+    let valid = DigitalCashSystem::validate_transaction(&state, &transfer).unwrap();
+    assert_eq!(valid.priority, 5);
+    assert!(valid.requires.is_empty());
+    assert_eq!(valid.provides, vec![bill_tag(&bob_bill), serial_tag(2)]);
+
+    // Spending Bob's bill depends on the transfer above, which is still pending.
+    let dependent = CashTransaction::Transfer {
+        spends: vec![bob_bill.clone()],
+        receives: vec![Bill {
+            owner: User::Charlie,
+            amount: 15,
+            serial: 2,
+        }],
+    };
+    let valid = DigitalCashSystem::validate_transaction(&state, &dependent).unwrap();
+    assert_eq!(valid.requires, vec![bill_tag(&bob_bill), serial_tag(2)]);
+
+    // A bill whose serial is already used but does not exist has been spent.
+    let spent = State::from_iter([
+        Bill {
+            owner: User::Alice,
+            amount: 5,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 5,
+            serial: 1,
+        },
+    ]);
+    assert_eq!(
+        DigitalCashSystem::validate_transaction(&spent, &transfer),
+        Err(InvalidTransaction::Stale)
+    );
+
+    let overspend = CashTransaction::Transfer {
+        spends: vec![alice_bill.clone()],
+        receives: vec![Bill {
+            owner: User::Bob,
+            amount: 25,
+            serial: 1,
+        }],
+    };
+    assert_eq!(
+        DigitalCashSystem::validate_transaction(&state, &overspend),
+        Err(InvalidTransaction::BadTransaction)
+    );
+    let empty = CashTransaction::Transfer {
+        spends: vec![],
+        receives: vec![],
+    };
+    assert_eq!(
+        DigitalCashSystem::validate_transaction(&state, &empty),
+        Err(InvalidTransaction::BadTransaction)
+    );
+}
+
+#[test]
+fn sm_5_pending_mints_do_not_conflict() {
+    let state = State::new();
+    let mint = |amount| CashTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    };
+
+    let first = DigitalCashSystem::validate_transaction(&state, &mint(10)).unwrap();
+    let second = DigitalCashSystem::validate_transaction(&state, &mint(20)).unwrap();
+    assert!(first.provides.is_empty() && second.provides.is_empty());
+    assert_eq!(
+        DigitalCashSystem::validate_transaction(&state, &mint(0)),
+        Err(InvalidTransaction::BadTransaction)
+    );
+}
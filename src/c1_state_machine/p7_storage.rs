@@ -0,0 +1,566 @@
+//! So far every state machine has owned its entire state as a plain Rust struct. That is the most
+//! natural way to model a state machine, but it makes life hard for anyone who wants to treat
+//! states generically. A blockchain client would like to share states between blocks, see exactly
+//! what changed in each block, and persist states to disk. None of that is possible when each state
+//! is an opaque struct.
+//!
+//! Real-world frameworks like Substrate solve this by writing every state machine against a
+//! key-value storage interface. The state is just a mapping from byte keys to byte values, and the
+//! state machine reads and writes individual entries as it executes.
+//!
+//! In this module we introduce that storage interface along with:
+//! * A simple in-memory backend.
+//! * An overlay that buffers writes on top of another storage without modifying it. The overlay
+//!   supports nested transactions, so a state machine can try some writes and roll them back
+//!   if something goes wrong part way through.
+//! * A way to write state machines against storage, and to run them as regular state machines.
+
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use super::{StateMachine, User};
+
+/// A set of changes to a storage. Each key maps to its new value, or to None if it was removed.
+pub type ChangeSet = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// A key-value storage with byte keys and byte values.
+pub trait Storage {
+    /// Read the value stored at the given key.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Write a value at the given key, replacing any previous value.
+    fn set(&mut self, key: &[u8], value: Vec<u8>);
+
+    /// Remove the given key from storage if it exists.
+    fn remove(&mut self, key: &[u8]);
+
+    /// Return every key-value pair whose key starts with the given prefix, ordered by key.
+    fn iter_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Check whether a value exists at the given key.
+    fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Apply a complete set of changes to this storage.
+    fn apply(&mut self, changes: &ChangeSet) {
+        for (key, value) in changes {
+            match value {
+                Some(value) => self.set(key, value.clone()),
+                None => self.remove(key),
+            }
+        }
+    }
+}
+
+/// The simplest storage backend. All entries are kept in memory in a sorted map.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct InMemoryStorage(BTreeMap<Vec<u8>, Vec<u8>>);
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The total number of entries in storage.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check whether the storage has no entries at all.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<const N: usize> From<[(Vec<u8>, Vec<u8>); N]> for InMemoryStorage {
+    fn from(value: [(Vec<u8>, Vec<u8>); N]) -> Self {
+        Self(BTreeMap::from(value))
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) {
+        self.0.insert(key.to_vec(), value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.0.remove(key);
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+/// A layer of uncommitted changes on top of another storage.
+///
+/// Reads see the overlay's changes first and fall through to the backend. Writes only ever
+/// touch the overlay, so the backend is never modified. When execution is finished, the
+/// accumulated changes can be extracted as a `ChangeSet`.
+///
+/// The overlay supports nested transactions. Starting a transaction creates a checkpoint. The
+/// writes made after the checkpoint can later be either committed into the enclosing layer or
+/// rolled back as if they never happened.
+pub struct OverlayedStorage<'a, S: Storage> {
+    /// The storage underneath the overlay.
+    backend: &'a S,
+    /// The layers of changes. The first layer is never removed and holds everything that has
+    /// been committed so far. Each open transaction adds one more layer on top.
+    layers: Vec<ChangeSet>,
+}
+
+impl<'a, S: Storage> OverlayedStorage<'a, S> {
+    /// Create a new overlay with no changes on top of the given backend.
+    pub fn new(backend: &'a S) -> Self {
+        Self {
+            backend,
+            layers: vec![ChangeSet::new()],
+        }
+    }
+
+    /// The number of transactions that are currently open.
+    pub fn transaction_depth(&self) -> usize {
+        self.layers.len() - 1
+    }
+
+    /// Open a new nested transaction.
+    pub fn start_transaction(&mut self) {
+        self.layers.push(ChangeSet::new());
+    }
+
+    /// Close the innermost transaction, keeping its changes.
+    /// Returns false if there is no open transaction.
+    pub fn commit_transaction(&mut self) -> bool {
+        if self.transaction_depth() == 0 {
+            return false;
+        }
+        let top = self.layers.pop().expect("depth was checked above");
+        self.layers
+            .last_mut()
+            .expect("the base layer is never removed")
+            .extend(top);
+        true
+    }
+
+    /// Close the innermost transaction, discarding its changes.
+    /// Returns false if there is no open transaction.
+    pub fn rollback_transaction(&mut self) -> bool {
+        if self.transaction_depth() == 0 {
+            return false;
+        }
+        self.layers.pop();
+        true
+    }
+
+    /// Consume the overlay and return all of the committed changes.
+    /// The changes from any transactions that are still open are discarded.
+    pub fn into_changes(mut self) -> ChangeSet {
+        self.layers.swap_remove(0)
+    }
+
+    /// Find the most recent change to the given key, if any layer has changed it.
+    fn change(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.layers.iter().rev().find_map(|layer| layer.get(key))
+    }
+
+    fn top_layer(&mut self) -> &mut ChangeSet {
+        self.layers
+            .last_mut()
+            .expect("the base layer is never removed")
+    }
+}
+
+impl<'a, S: Storage> Storage for OverlayedStorage<'a, S> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.change(key) {
+            Some(change) => change.clone(),
+            None => self.backend.get(key),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) {
+        self.top_layer().insert(key.to_vec(), Some(value));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.top_layer().insert(key.to_vec(), None);
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries: BTreeMap<Vec<u8>, Vec<u8>> =
+            self.backend.iter_prefix(prefix).into_iter().collect();
+        for layer in &self.layers {
+            for (key, value) in layer.range(prefix.to_vec()..) {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                match value {
+                    Some(value) => entries.insert(key.clone(), value.clone()),
+                    None => entries.remove(key),
+                };
+            }
+        }
+        entries.into_iter().collect()
+    }
+}
+
+/// A state machine that is written against storage rather than an owned state struct.
+///
+/// Instead of calculating a whole new state, the machine reads and writes the individual
+/// storage entries it cares about.
+pub trait StorageStateMachine {
+    /// The transitions that can be made.
+    type Transition;
+
+    /// Execute the given transition against storage.
+    ///
+    /// Returns whether the transition was valid. When the transition is invalid, the machine
+    /// does not need to clean up any writes it already made. The caller is responsible for
+    /// rolling them back.
+    fn execute<S: Storage>(storage: &mut S, t: &Self::Transition) -> bool;
+}
+
+/// Execute each of the given transitions in order on top of the given storage, and return the
+/// combined changes. Each transition is executed in its own transaction, so invalid transitions
+/// leave no trace in the change set.
+///
+/// This is how a client can calculate exactly what changed in a block without modifying, or even
+/// cloning, the parent block's state.
+pub fn execute_all<M: StorageStateMachine, S: Storage>(
+    storage: &S,
+    transitions: &[M::Transition],
+) -> ChangeSet {
+    let mut overlay = OverlayedStorage::new(storage);
+    for t in transitions {
+        overlay.start_transaction();
+        if M::execute(&mut overlay, t) {
+            overlay.commit_transaction();
+        } else {
+            overlay.rollback_transaction();
+        }
+    }
+    overlay.into_changes()
+}
+
+/// An adapter that turns any storage-based state machine into a regular state machine whose
+/// state is an in-memory storage.
+pub struct OnStorage<M>(PhantomData<M>);
+
+impl<M: StorageStateMachine> StateMachine for OnStorage<M> {
+    type State = InMemoryStorage;
+    type Transition = M::Transition;
+
+    fn next_state(starting_state: &InMemoryStorage, t: &M::Transition) -> InMemoryStorage {
+        let changes = execute_all::<M, _>(starting_state, std::slice::from_ref(t));
+        let mut state = starting_state.clone();
+        state.apply(&changes);
+        state
+    }
+}
+
+/// An example of a state machine written against storage. It models a registry in which users can
+/// claim unique names.
+pub struct NameRegistry;
+
+/// The transitions that users can make in the name registry.
+pub enum RegistryTransaction {
+    /// Claim a name that nobody else has claimed yet.
+    Register { name: String, owner: User },
+    /// Give up a name that the owner previously claimed.
+    Release { name: String, owner: User },
+    /// Claim several names at once. If any of them is already claimed, none of them are.
+    RegisterAll { names: Vec<String>, owner: User },
+}
+
+/// The storage prefix under which all names are stored.
+const NAME_PREFIX: &[u8] = b"name:";
+
+/// The storage key for the given name.
+fn name_key(name: &str) -> Vec<u8> {
+    [NAME_PREFIX, name.as_bytes()].concat()
+}
+
+/// Read the owner of the given name from storage.
+pub fn name_owner<S: Storage>(storage: &S, name: &str) -> Option<User> {
+    storage
+        .get(&name_key(name))
+        .and_then(|bytes| decode_user(&bytes))
+}
+
+/// List every name that has been claimed, in alphabetical order.
+pub fn all_names<S: Storage>(storage: &S) -> Vec<String> {
+    storage
+        .iter_prefix(NAME_PREFIX)
+        .into_iter()
+        .map(|(key, _)| String::from_utf8_lossy(&key[NAME_PREFIX.len()..]).into_owned())
+        .collect()
+}
+
+fn encode_user(user: User) -> Vec<u8> {
+    vec![user as u8]
+}
+
+fn decode_user(bytes: &[u8]) -> Option<User> {
+    match bytes {
+        [0] => Some(User::Alice),
+        [1] => Some(User::Bob),
+        [2] => Some(User::Charlie),
+        _ => None,
+    }
+}
+
+impl StorageStateMachine for NameRegistry {
+    type Transition = RegistryTransaction;
+
+    fn execute<S: Storage>(storage: &mut S, t: &RegistryTransaction) -> bool {
+        match t {
+            RegistryTransaction::Register { name, owner } => {
+                if storage.contains(&name_key(name)) {
+                    return false;
+                }
+                storage.set(&name_key(name), encode_user(*owner));
+                true
+            }
+            RegistryTransaction::Release { name, owner } => {
+                if name_owner(storage, name) != Some(*owner) {
+                    return false;
+                }
+                storage.remove(&name_key(name));
+                true
+            }
+            RegistryTransaction::RegisterAll { names, owner } => names.iter().all(|name| {
+                Self::execute(
+                    storage,
+                    &RegistryTransaction::Register {
+                        name: name.clone(),
+                        owner: *owner,
+                    },
+                )
+            }),
+        }
+    }
+}
+
+#[test]
+fn sm_7_in_memory_get_set_remove() {
+    let mut storage = InMemoryStorage::new();
+    storage.set(b"a", vec![1]);
+
+    assert_eq!(storage.get(b"a"), Some(vec![1]));
+    assert!(storage.contains(b"a"));
+    assert_eq!(storage.len(), 1);
+
+    storage.remove(b"a");
+    assert_eq!(storage.get(b"a"), None);
+    assert!(storage.is_empty());
+}
+
+#[test]
+fn sm_7_in_memory_iter_prefix() {
+    let storage = InMemoryStorage::from([
+        (b"a:1".to_vec(), vec![1]),
+        (b"b:1".to_vec(), vec![2]),
+        (b"a:2".to_vec(), vec![3]),
+        (b"a".to_vec(), vec![4]),
+    ]);
+
+    assert_eq!(
+        storage.iter_prefix(b"a:"),
+        vec![(b"a:1".to_vec(), vec![1]), (b"a:2".to_vec(), vec![3])]
+    );
+}
+
+#[test]
+fn sm_7_overlay_does_not_modify_backend() {
+    let backend = InMemoryStorage::from([(b"a".to_vec(), vec![1])]);
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.set(b"a", vec![2]);
+    overlay.set(b"b", vec![3]);
+
+    assert_eq!(overlay.get(b"a"), Some(vec![2]));
+    assert_eq!(overlay.get(b"b"), Some(vec![3]));
+    assert_eq!(backend.get(b"a"), Some(vec![1]));
+    assert_eq!(backend.get(b"b"), None);
+}
+
+#[test]
+fn sm_7_overlay_remove_hides_backend_value() {
+    let backend = InMemoryStorage::from([(b"a".to_vec(), vec![1])]);
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.remove(b"a");
+
+    assert_eq!(overlay.get(b"a"), None);
+    assert_eq!(
+        overlay.into_changes(),
+        ChangeSet::from([(b"a".to_vec(), None)])
+    );
+}
+
+#[test]
+fn sm_7_overlay_iter_prefix_merges_layers() {
+    let backend = InMemoryStorage::from([(b"a:1".to_vec(), vec![1]), (b"a:2".to_vec(), vec![2])]);
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.remove(b"a:1");
+    overlay.start_transaction();
+    overlay.set(b"a:3", vec![3]);
+    overlay.set(b"b:1", vec![4]);
+
+    assert_eq!(
+        overlay.iter_prefix(b"a:"),
+        vec![(b"a:2".to_vec(), vec![2]), (b"a:3".to_vec(), vec![3])]
+    );
+}
+
+#[test]
+fn sm_7_rollback_discards_changes() {
+    let backend = InMemoryStorage::new();
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.set(b"a", vec![1]);
+    overlay.start_transaction();
+    overlay.set(b"a", vec![2]);
+    overlay.set(b"b", vec![2]);
+
+    assert_eq!(overlay.get(b"a"), Some(vec![2]));
+    assert!(overlay.rollback_transaction());
+    assert_eq!(overlay.get(b"a"), Some(vec![1]));
+    assert_eq!(overlay.get(b"b"), None);
+}
+
+#[test]
+fn sm_7_nested_transactions() {
+    let backend = InMemoryStorage::new();
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.start_transaction();
+    overlay.set(b"a", vec![1]);
+    overlay.start_transaction();
+    overlay.set(b"b", vec![2]);
+    assert_eq!(overlay.transaction_depth(), 2);
+
+    // Commit the inner transaction into the outer one, then roll back the outer one.
+    assert!(overlay.commit_transaction());
+    assert_eq!(overlay.get(b"b"), Some(vec![2]));
+    assert!(overlay.rollback_transaction());
+
+    assert_eq!(overlay.get(b"a"), None);
+    assert_eq!(overlay.get(b"b"), None);
+    assert!(!overlay.rollback_transaction());
+    assert!(!overlay.commit_transaction());
+}
+
+#[test]
+fn sm_7_open_transactions_are_not_in_change_set() {
+    let backend = InMemoryStorage::new();
+    let mut overlay = OverlayedStorage::new(&backend);
+    overlay.set(b"a", vec![1]);
+    overlay.start_transaction();
+    overlay.set(b"b", vec![2]);
+
+    assert_eq!(
+        overlay.into_changes(),
+        ChangeSet::from([(b"a".to_vec(), Some(vec![1]))])
+    );
+}
+
+#[test]
+fn sm_7_apply_change_set() {
+    let mut storage = InMemoryStorage::from([(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]);
+    storage.apply(&ChangeSet::from([
+        (b"a".to_vec(), None),
+        (b"c".to_vec(), Some(vec![3])),
+    ]));
+
+    assert_eq!(
+        storage,
+        InMemoryStorage::from([(b"b".to_vec(), vec![2]), (b"c".to_vec(), vec![3])])
+    );
+}
+
+#[test]
+fn sm_7_registry_register_and_release() {
+    let start = InMemoryStorage::new();
+    let registered = OnStorage::<NameRegistry>::next_state(
+        &start,
+        &RegistryTransaction::Register {
+            name: "josh".into(),
+            owner: User::Alice,
+        },
+    );
+    assert_eq!(name_owner(&registered, "josh"), Some(User::Alice));
+
+    let released = OnStorage::<NameRegistry>::next_state(
+        &registered,
+        &RegistryTransaction::Release {
+            name: "josh".into(),
+            owner: User::Alice,
+        },
+    );
+    assert_eq!(released, start);
+}
+
+#[test]
+fn sm_7_registry_cannot_release_others_name() {
+    let start = InMemoryStorage::from([(name_key("josh"), encode_user(User::Alice))]);
+    let end = OnStorage::<NameRegistry>::next_state(
+        &start,
+        &RegistryTransaction::Release {
+            name: "josh".into(),
+            owner: User::Bob,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_7_registry_register_all_is_atomic() {
+    let start = InMemoryStorage::from([(name_key("bob"), encode_user(User::Bob))]);
+    let end = OnStorage::<NameRegistry>::next_state(
+        &start,
+        &RegistryTransaction::RegisterAll {
+            names: vec!["alice".into(), "bob".into()],
+            owner: User::Alice,
+        },
+    );
+
+    assert_eq!(end, start);
+    assert_eq!(all_names(&end), vec!["bob".to_string()]);
+}
+
+#[test]
+fn sm_7_block_change_set_skips_invalid_transitions() {
+    let start = InMemoryStorage::from([(name_key("bob"), encode_user(User::Bob))]);
+    let changes = execute_all::<NameRegistry, _>(
+        &start,
+        &[
+            RegistryTransaction::Register {
+                name: "alice".into(),
+                owner: User::Alice,
+            },
+            RegistryTransaction::Register {
+                name: "bob".into(),
+                owner: User::Charlie,
+            },
+            RegistryTransaction::Release {
+                name: "bob".into(),
+                owner: User::Bob,
+            },
+        ],
+    );
+
+    assert_eq!(
+        changes,
+        ChangeSet::from([
+            (name_key("alice"), Some(encode_user(User::Alice))),
+            (name_key("bob"), None),
+        ])
+    );
+}
@@ -0,0 +1,78 @@
+//! A state machine tells us what happens when a transition is applied, but a blockchain client
+//! often needs to know something else first: whether a transition is worth keeping around at all.
+//! Clients receive transactions from users long before they are included in a block, and they
+//! must decide which ones to keep in their pool, and in what order to include them.
+//!
+//! Here we give state machines a way to answer that question. Validating a transaction against a
+//! state does not apply it. Instead it returns some metadata about the transaction:
+//! * Priority - Transactions with higher priority should be included first.
+//! * Longevity - How many blocks the transaction stays valid for.
+//! * Provides and requires tags - Opaque tags that describe dependencies between transactions.
+//!   A transaction that requires a tag can only be applied after some transaction that provides it.
+//!
+//! Tags let the pool hold on to a transaction that cannot be applied to the current state yet, but
+//! will be once some other pending transaction is applied. For example, spending a bill that is
+//! created by another transaction still waiting in the pool.
+
+use super::StateMachine;
+
+/// An opaque tag describing something a transaction provides or requires.
+pub type TransactionTag = Vec<u8>;
+
+/// The number of blocks a transaction remains valid for.
+pub type TransactionLongevity = u64;
+
+/// The longevity of transactions that never expire.
+pub const IMMORTAL: TransactionLongevity = TransactionLongevity::MAX;
+
+/// Information about a transaction that is valid, or may become valid, on top of some state.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ValidTransaction {
+    /// Transactions with higher priority are included first.
+    pub priority: u64,
+    /// The number of blocks, counted from the block it was validated against, that the
+    /// transaction remains valid for.
+    pub longevity: TransactionLongevity,
+    /// The tags this transaction provides once applied.
+    pub provides: Vec<TransactionTag>,
+    /// The tags that must be provided by the state or by earlier transactions before this one
+    /// can be applied.
+    pub requires: Vec<TransactionTag>,
+}
+
+impl Default for ValidTransaction {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            longevity: IMMORTAL,
+            provides: Vec::new(),
+            requires: Vec::new(),
+        }
+    }
+}
+
+/// The reasons a transaction may be invalid.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InvalidTransaction {
+    /// The transaction is malformed and can never be valid on any state.
+    BadTransaction,
+    /// The sender cannot afford the transaction.
+    InsufficientFunds,
+    /// The transaction depends on something that has already been used up, so it can never
+    /// become valid again.
+    Stale,
+    /// The transaction's mortal era has ended, so it may no longer be included.
+    Expired,
+    /// The block the transaction's mortal era is anchored to is not on this chain.
+    UnknownAnchor,
+}
+
+/// The result of validating a transaction.
+pub type TransactionValidity = Result<ValidTransaction, InvalidTransaction>;
+
+/// A state machine that can check transactions against a state without applying them.
+pub trait ValidateTransaction: StateMachine {
+    /// Check whether the given transaction is valid on top of the given state, or could become
+    /// valid once the transactions providing its required tags have been applied.
+    fn validate_transaction(state: &Self::State, t: &Self::Transition) -> TransactionValidity;
+}
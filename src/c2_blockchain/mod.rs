@@ -12,3 +12,5 @@ mod p3_consensus;
 pub mod p4_batched_extrinsics;
 mod p5_fork_choice;
 mod p6_rich_state;
+pub mod p7_merkle_tree;
+pub mod p8_state_trie;
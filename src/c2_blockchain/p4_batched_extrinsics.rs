@@ -5,6 +5,7 @@
 use crate::hash;
 type Hash = u64;
 use super::p3_consensus::THRESHOLD;
+use super::p7_merkle_tree::{merkle_proof, merkle_root, MerkleProof};
 
 /// The header no longer contains an extrinsic directly. Rather a vector of extrinsics will be stored in
 /// the block body. 
@@ -18,7 +19,8 @@ pub struct Header {
     height: u64,
     // We now switch from storing an extrinsic directly, to storing an extrinsic root.
     // This is basically a concise cryptographic commitment to the complete list of extrinsics.
-    // For example, a hash or a Merkle root.
+    // For example, a hash or a Merkle root. We use the Merkle root from the Merkle Tree lesson,
+    // which allows users who only have the header to check that an extrinsic was included.
     extrinsics_root: Hash,
     state: u64,
     pub consensus_digest: u64,
@@ -64,6 +66,14 @@ impl Header {
     fn verify_sub_chain(&self, chain: &[Header]) -> bool {
         todo!("Exercise 4")
     }
+
+    /// Check that the given extrinsic was included in the block with this header.
+    ///
+    /// This works because blocks commit to their extrinsics with a Merkle root rather than a
+    /// flat hash. Notice that no part of the block body is required.
+    pub fn verify_extrinsic(&self, extrinsic: u64, proof: &MerkleProof) -> bool {
+        proof.verify(self.extrinsics_root, &extrinsic)
+    }
 }
 
 /// A complete Block is a header and the extrinsics.
@@ -86,6 +96,9 @@ impl Block {
 
     /// Create and return a valid child block.
     /// The extrinsics are batched now, so we need to execute each of them.
+    ///
+    /// The header commits to the extrinsics with `merkle_root`, so that the proofs from
+    /// `extrinsic_proof` can be checked against it.
     pub fn child(&self, extrinsics: Vec<u64>) -> Self {
         todo!("Exercise 6")
     }
@@ -96,6 +109,13 @@ impl Block {
     pub fn verify_sub_chain(&self, chain: &[Block]) -> bool {
         todo!("Exercise 7")
     }
+
+    /// Create a proof that the extrinsic at the given index is included in this block.
+    /// The proof can be checked by anyone who has this block's header.
+    /// Returns None if the index is out of bounds.
+    pub fn extrinsic_proof(&self, index: usize) -> Option<MerkleProof> {
+        merkle_proof(&self.body, index)
+    }
 }
 
 /// Create an invalid child block of the given block. Although the child block is invalid,
@@ -110,6 +130,21 @@ fn build_invalid_child_block_with_valid_header(parent: &Header) -> Block {
     todo!("Exercise 8")
 }
 
+/// A block at height 1 whose header commits to the given body, for the Merkle Tree lesson's tests.
+#[cfg(test)]
+pub(super) fn merkle_committed_block(body: Vec<u64>) -> Block {
+    Block {
+        header: Header {
+            parent: 0,
+            height: 1,
+            extrinsics_root: merkle_root(&body),
+            state: body.iter().sum(),
+            consensus_digest: 0,
+        },
+        body,
+    }
+}
+
 #[test]
 fn bc_4_genesis_header() {
     let g = Header::genesis();
@@ -152,6 +187,7 @@ fn bc_4_child_block() {
 
     assert_eq!(b1.header.height, 1);
     assert_eq!(b1.header.parent, hash(&b0.header));
+    assert_eq!(b1.header.extrinsics_root, merkle_root(&[1u64, 2, 3, 4, 5]));
     assert_eq!(
         b1,
         Block {
@@ -161,6 +197,16 @@ fn bc_4_child_block() {
     );
 }
 
+#[test]
+fn bc_4_child_block_extrinsics_can_be_proven() {
+    let b0 = Block::genesis();
+    let b1 = b0.child(vec![5, 6, 7]);
+
+    let proof = b1.extrinsic_proof(2).unwrap();
+    assert!(b1.header.verify_extrinsic(7, &proof));
+    assert!(!b1.header.verify_extrinsic(6, &proof));
+}
+
 #[test]
 fn bc_4_child_header() {
     let g = Header::genesis();
@@ -14,6 +14,7 @@
 type Hash = u64;
 use crate::hash;
 use super::p3_consensus::THRESHOLD;
+use super::p7_merkle_tree::merkle_root;
 
 /// In this section we will use sum and product together to be our state. While this is only a doubling of state size
 /// remember that in real world blockchains, the state is often really really large.
@@ -92,7 +93,8 @@ impl Block {
         todo!("Exercise 5")
     }
 
-    /// Create and return a valid child block.
+    /// Create and return a valid child block. As in the Batched Extrinsics lesson, the header
+    /// commits to the extrinsics with `merkle_root`.
     pub fn child(&self, pre_state: &State, extrinsics: Vec<u64>) -> Self {
         todo!("Exercise 6")
     }
@@ -167,6 +169,7 @@ fn bc_6_child_block() {
 
     assert_eq!(b1.header.height, 1);
     assert_eq!(b1.header.parent, hash(&b0.header));
+    assert_eq!(b1.header.extrinsics_root, merkle_root(&[1u64, 2, 3, 4, 5]));
     assert_eq!(
         b1,
         Block {
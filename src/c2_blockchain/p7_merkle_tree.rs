@@ -0,0 +1,283 @@
+//! Since the Batched Extrinsics lesson, our headers have contained an extrinsics root that commits
+//! to the entire block body, and our blocks have computed it with the `merkle_root` function from
+//! this lesson. Why not simply hash the whole list? That works fine for full nodes who have the
+//! entire body anyway. But consider a user who only has the header and wants to know whether
+//! _their_ transaction made it into the block. With a flat hash, the only way to check is to
+//! download every extrinsic in the block.
+//!
+//! A binary Merkle tree solves this problem. Each extrinsic is hashed to form a leaf, and pairs of
+//! nodes are hashed together level by level until a single root remains. To prove that a
+//! particular extrinsic is included, we only need to provide the sibling hashes along the path
+//! from its leaf to the root. That is logarithmic in the number of extrinsics rather than linear.
+//!
+//! A few details are worth pointing out:
+//! * Leaves and interior nodes are hashed with different prefixes. Otherwise an attacker could
+//!   present an interior node as if it were a leaf.
+//! * When a level has an odd number of nodes, the last node is promoted to the next level as-is.
+//!   Some chains duplicate the last node instead, but that allows two different lists to share
+//!   the same root.
+//! * By convention the root of an empty list is the hash of the empty list itself. That keeps
+//!   genesis blocks identical to the ones we built in earlier lessons.
+
+use crate::hash;
+
+type Hash = u64;
+
+/// Prefix mixed into every leaf hash.
+const LEAF_PREFIX: u8 = 0;
+/// Prefix mixed into every interior node hash.
+const NODE_PREFIX: u8 = 1;
+
+/// Hash a single item into a leaf of the tree.
+fn leaf_hash<T: std::hash::Hash>(leaf: &T) -> Hash {
+    hash(&(LEAF_PREFIX, leaf))
+}
+
+/// Hash two child nodes into their parent.
+fn node_hash(left: Hash, right: Hash) -> Hash {
+    hash(&(NODE_PREFIX, left, right))
+}
+
+/// Compute the next level up the tree from the given level.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(*left, *right),
+            [lone] => *lone,
+            _ => unreachable!("chunks of two are never empty"),
+        })
+        .collect()
+}
+
+/// Calculate the Merkle root of the given list of leaves.
+pub fn merkle_root<T: std::hash::Hash>(leaves: &[T]) -> Hash {
+    if leaves.is_empty() {
+        return hash(&leaves);
+    }
+
+    let mut level: Vec<Hash> = leaves.iter().map(leaf_hash).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// A compact proof that a single leaf is included in a Merkle tree.
+///
+/// The proof does not contain the leaf itself. The verifier is expected to already know
+/// which leaf they are interested in, and to compare the proof against a root they trust,
+/// such as the extrinsics root in a header.
+///
+/// The root commits to the leaves and their order, but not to how many there are. So `index`
+/// and `leaf_count` only tell the verifier which side each sibling goes on, and a proof may
+/// still verify with other values that lead to the same path. For example, the last leaf of
+/// three is promoted once and then hashed on the right, exactly like the second leaf of two.
+/// A successful verification shows that the leaf is in the tree, not where. Verifiers that care
+/// about the position must learn the leaf count from somewhere they trust, and check it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MerkleProof {
+    /// The position of the leaf in the original list. Not committed to by the root.
+    pub index: usize,
+    /// The total number of leaves in the tree. This is necessary to know which levels
+    /// the leaf was promoted through without a sibling. Not committed to by the root.
+    pub leaf_count: usize,
+    /// The sibling hashes from the bottom of the tree to the top. Levels where the node
+    /// was promoted without a sibling do not contribute an entry.
+    pub siblings: Vec<Hash>,
+}
+
+/// Create a proof that the leaf at the given index is included in the tree.
+/// Returns None if the index is out of bounds.
+pub fn merkle_proof<T: std::hash::Hash>(leaves: &[T], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut siblings = Vec::new();
+    let mut level: Vec<Hash> = leaves.iter().map(leaf_hash).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            siblings.push(level[sibling]);
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof {
+        index,
+        leaf_count: leaves.len(),
+        siblings,
+    })
+}
+
+impl MerkleProof {
+    /// Check that the given leaf is included at this proof's index in a tree with the given root.
+    pub fn verify<T: std::hash::Hash>(&self, root: Hash, leaf: &T) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut current = leaf_hash(leaf);
+        let mut position = self.index;
+        let mut width = self.leaf_count;
+        while width > 1 {
+            let sibling = position ^ 1;
+            if sibling < width {
+                let Some(sibling_hash) = siblings.next() else {
+                    return false;
+                };
+                current = if position.is_multiple_of(2) {
+                    node_hash(current, *sibling_hash)
+                } else {
+                    node_hash(*sibling_hash, current)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+
+        // Every sibling must be used exactly once.
+        siblings.next().is_none() && current == root
+    }
+}
+
+#[test]
+fn bc_7_empty_root_matches_flat_hash() {
+    assert_eq!(merkle_root::<u64>(&[]), hash(&Vec::<u64>::new()));
+}
+
+#[test]
+fn bc_7_single_leaf_root() {
+    assert_eq!(merkle_root(&[7u64]), leaf_hash(&7u64));
+}
+
+#[test]
+fn bc_7_root_of_four_leaves() {
+    let expected = node_hash(
+        node_hash(leaf_hash(&1u64), leaf_hash(&2u64)),
+        node_hash(leaf_hash(&3u64), leaf_hash(&4u64)),
+    );
+
+    assert_eq!(merkle_root(&[1u64, 2, 3, 4]), expected);
+}
+
+#[test]
+fn bc_7_odd_leaf_is_promoted() {
+    let expected = node_hash(
+        node_hash(leaf_hash(&1u64), leaf_hash(&2u64)),
+        leaf_hash(&3u64),
+    );
+
+    assert_eq!(merkle_root(&[1u64, 2, 3]), expected);
+}
+
+#[test]
+fn bc_7_root_depends_on_order() {
+    assert_ne!(merkle_root(&[1u64, 2, 3]), merkle_root(&[3u64, 2, 1]));
+}
+
+#[test]
+fn bc_7_every_proof_verifies() {
+    for n in 1..=17u64 {
+        let leaves: Vec<u64> = (0..n).map(|i| i * 10).collect();
+        let root = merkle_root(&leaves);
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = merkle_proof(&leaves, i).unwrap();
+            assert!(proof.verify(root, leaf), "leaf {i} of {n} failed");
+        }
+    }
+}
+
+#[test]
+fn bc_7_proof_is_logarithmic() {
+    let leaves: Vec<u64> = (0..1024).collect();
+    let proof = merkle_proof(&leaves, 500).unwrap();
+
+    assert_eq!(proof.siblings.len(), 10);
+}
+
+#[test]
+fn bc_7_proof_out_of_bounds() {
+    assert_eq!(merkle_proof(&[1u64, 2, 3], 3), None);
+    assert_eq!(merkle_proof::<u64>(&[], 0), None);
+}
+
+#[test]
+fn bc_7_proof_rejects_wrong_leaf() {
+    let leaves = [1u64, 2, 3, 4, 5];
+    let root = merkle_root(&leaves);
+    let proof = merkle_proof(&leaves, 2).unwrap();
+
+    assert!(!proof.verify(root, &4u64));
+}
+
+#[test]
+fn bc_7_proof_rejects_wrong_index() {
+    let leaves = [1u64, 2, 3, 4, 5];
+    let root = merkle_root(&leaves);
+    let mut proof = merkle_proof(&leaves, 2).unwrap();
+    proof.index = 3;
+
+    assert!(!proof.verify(root, &3u64));
+}
+
+#[test]
+fn bc_7_proof_rejects_tampered_sibling() {
+    let leaves = [1u64, 2, 3, 4, 5];
+    let root = merkle_root(&leaves);
+    let mut proof = merkle_proof(&leaves, 1).unwrap();
+    proof.siblings[0] ^= 1;
+
+    assert!(!proof.verify(root, &2u64));
+}
+
+#[test]
+fn bc_7_proof_rejects_extra_siblings() {
+    let leaves = [1u64, 2, 3, 4];
+    let root = merkle_root(&leaves);
+    let mut proof = merkle_proof(&leaves, 0).unwrap();
+    proof.siblings.push(0);
+
+    assert!(!proof.verify(root, &1u64));
+}
+
+#[test]
+fn bc_7_interior_node_is_not_a_leaf() {
+    let leaves = [1u64, 2, 3, 4];
+    let root = merkle_root(&leaves);
+    let interior = node_hash(leaf_hash(&1u64), leaf_hash(&2u64));
+    let proof = MerkleProof {
+        index: 0,
+        leaf_count: 2,
+        siblings: vec![node_hash(leaf_hash(&3u64), leaf_hash(&4u64))],
+    };
+
+    assert!(!proof.verify(root, &interior));
+}
+
+#[test]
+fn bc_7_proof_does_not_bind_the_position() {
+    let leaves = [1u64, 2, 3];
+    let root = merkle_root(&leaves);
+    let mut proof = merkle_proof(&leaves, 2).unwrap();
+    assert!(proof.verify(root, &3u64));
+
+    // The same path, read as the second leaf of two.
+    proof.index = 1;
+    proof.leaf_count = 2;
+    assert!(proof.verify(root, &3u64));
+}
+
+#[test]
+fn bc_7_header_verifies_included_extrinsic() {
+    let block = super::p4_batched_extrinsics::merkle_committed_block(vec![5, 6, 7]);
+
+    let proof = block.extrinsic_proof(1).unwrap();
+    assert!(block.header.verify_extrinsic(6, &proof));
+    assert!(!block.header.verify_extrinsic(7, &proof));
+    assert!(block.extrinsic_proof(3).is_none());
+}
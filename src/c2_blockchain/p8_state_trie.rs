@@ -0,0 +1,633 @@
+//! In the Rich State lesson we learned that the header commits to the post state with a state root,
+//! so that verifiers can trust a state without storing it in the block. But our state root was just
+//! `hash(&state)`. That commits to the state, but the only way to check any part of it is to have
+//! all of it. Nobody can prove a single value, like one user's balance, to someone who only has
+//! the header.
+//!
+//! Real-world chains solve this with a Merkle trie. Ethereum and Substrate use a Merkle-Patricia
+//! trie. Here we build a close cousin that is easier to reason about: a sparse Merkle tree.
+//!
+//! Every key is hashed to a 64-bit path. Imagine a complete binary tree with 2^64 leaves, one for
+//! every possible path. Almost all of those leaves are empty, and a subtree that contains only
+//! empty leaves always has the same well-known hash. So we only ever store the nodes that are
+//! _not_ empty. Updating a key rehashes the 64 nodes along its path, which means the state root
+//! is maintained incrementally rather than recomputed from scratch.
+//!
+//! A storage proof for a key is the list of sibling hashes along its path. Since most siblings are
+//! empty subtrees, the proof only carries the non-empty ones plus a bitmap saying where they go.
+//! A proof can show that a key has a particular value, or that the key is absent entirely.
+//!
+//! Our toy 64-bit hash means that two keys could land on the same path. Rather than ignore that,
+//! each leaf holds a small bucket of every key-value pair that shares the path.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::c1_state_machine::{
+    p4_accounted_currency::{AccountedCurrency, AccountingTransaction, Balances},
+    p7_storage::Storage,
+    p8_transaction_validity::{TransactionValidity, ValidateTransaction},
+    StateMachine, User,
+};
+use crate::hash;
+
+type Hash = u64;
+
+/// The number of levels between the root and the leaves.
+const DEPTH: u8 = 64;
+/// Prefix mixed into every leaf hash.
+const LEAF_PREFIX: u8 = 0;
+/// Prefix mixed into every interior node hash.
+const NODE_PREFIX: u8 = 1;
+/// The hash of a leaf that contains no keys.
+const EMPTY_LEAF: Hash = 0;
+
+/// All the key-value pairs whose keys hash to the same path.
+type Bucket = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// The path from the root to the leaf where the given key is stored.
+fn key_path(key: &[u8]) -> u64 {
+    hash(&key)
+}
+
+/// The bits of the path that identify the node at the given depth.
+fn path_prefix(path: u64, depth: u8) -> u64 {
+    path.checked_shr((DEPTH - depth) as u32).unwrap_or(0)
+}
+
+fn leaf_hash(bucket: &Bucket) -> Hash {
+    if bucket.is_empty() {
+        EMPTY_LEAF
+    } else {
+        hash(&(LEAF_PREFIX, bucket))
+    }
+}
+
+fn node_hash(left: Hash, right: Hash) -> Hash {
+    hash(&(NODE_PREFIX, left, right))
+}
+
+/// The hash of an empty subtree at each depth, indexed by depth. The root is at depth 0
+/// and the leaves are at depth 64.
+fn empty_subtree_hashes() -> Vec<Hash> {
+    let mut hashes = vec![EMPTY_LEAF; DEPTH as usize + 1];
+    for depth in (0..DEPTH as usize).rev() {
+        hashes[depth] = node_hash(hashes[depth + 1], hashes[depth + 1]);
+    }
+    hashes
+}
+
+/// A key-value state backend whose root commits to every entry and can prove any single entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateTrie {
+    /// The non-empty leaves, keyed by their path.
+    buckets: BTreeMap<u64, Bucket>,
+    /// The hashes of all non-empty nodes keyed by (depth, path prefix).
+    /// Nodes that are absent from this map are empty subtrees.
+    nodes: HashMap<(u8, u64), Hash>,
+    /// Cached hashes of empty subtrees at each depth.
+    empty: Vec<Hash>,
+}
+
+impl Default for StateTrie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateTrie {
+    /// Create a new trie with no entries.
+    pub fn new() -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+            nodes: HashMap::new(),
+            empty: empty_subtree_hashes(),
+        }
+    }
+
+    /// Read the value stored at the given key.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.buckets
+            .get(&key_path(key))
+            .and_then(|bucket| bucket.get(key))
+            .map(Vec::as_slice)
+    }
+
+    /// Write a value at the given key, replacing any previous value.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let path = key_path(&key);
+        self.buckets.entry(path).or_default().insert(key, value);
+        self.update_path(path);
+    }
+
+    /// Remove the given key from the trie, returning its previous value if there was one.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let path = key_path(key);
+        let bucket = self.buckets.get_mut(&path)?;
+        let previous = bucket.remove(key)?;
+        if bucket.is_empty() {
+            self.buckets.remove(&path);
+        }
+        self.update_path(path);
+        Some(previous)
+    }
+
+    /// Iterate over every key-value pair in the trie. The order is determined by the key
+    /// paths, so it is deterministic but otherwise meaningless.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.buckets.values().flat_map(|bucket| bucket.iter())
+    }
+
+    /// The total number of keys in the trie.
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(Bucket::len).sum()
+    }
+
+    /// Check whether the trie has no keys at all.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// The Merkle root committing to every entry in the trie.
+    pub fn state_root(&self) -> Hash {
+        self.node(0, 0)
+    }
+
+    /// Create a proof of the given key's value, or of its absence, that can be checked against
+    /// the state root.
+    pub fn prove(&self, key: &[u8]) -> StorageProof {
+        let path = key_path(key);
+        let mut occupied = 0u64;
+        let mut siblings = Vec::new();
+        for depth in (1..=DEPTH).rev() {
+            let sibling = self.node(depth, path_prefix(path, depth) ^ 1);
+            if sibling != self.empty[depth as usize] {
+                occupied |= 1 << (DEPTH - depth);
+                siblings.push(sibling);
+            }
+        }
+
+        StorageProof {
+            bucket: self.buckets.get(&path).cloned().unwrap_or_default(),
+            occupied,
+            siblings,
+        }
+    }
+
+    /// Look up the hash of the node at the given position.
+    fn node(&self, depth: u8, prefix: u64) -> Hash {
+        self.nodes
+            .get(&(depth, prefix))
+            .copied()
+            .unwrap_or(self.empty[depth as usize])
+    }
+
+    /// Store a node hash, forgetting it instead if it is an empty subtree.
+    fn set_node(&mut self, depth: u8, prefix: u64, node: Hash) {
+        if node == self.empty[depth as usize] {
+            self.nodes.remove(&(depth, prefix));
+        } else {
+            self.nodes.insert((depth, prefix), node);
+        }
+    }
+
+    /// Rehash every node from the leaf at the given path up to the root.
+    fn update_path(&mut self, path: u64) {
+        let leaf = self.buckets.get(&path).map(leaf_hash).unwrap_or(EMPTY_LEAF);
+        self.set_node(DEPTH, path, leaf);
+
+        for depth in (0..DEPTH).rev() {
+            let prefix = path_prefix(path, depth);
+            let left = self.node(depth + 1, prefix << 1);
+            let right = self.node(depth + 1, (prefix << 1) | 1);
+            self.set_node(depth, prefix, node_hash(left, right));
+        }
+    }
+}
+
+/// The trie can be used as the storage backend for any state machine written against storage.
+/// That way every storage-based state machine gets storage proofs for free.
+impl Storage for StateTrie {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        StateTrie::get(self, key).map(<[u8]>::to_vec)
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) {
+        self.insert(key.to_vec(), value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        StateTrie::remove(self, key);
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries: Vec<_> = self
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        entries.sort();
+        entries
+    }
+}
+
+/// A proof that a single key has a particular value, or is absent, in a trie with a known root.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StorageProof {
+    /// Every key-value pair stored at the same path as the key being proven. This is
+    /// usually just the one entry, or nothing at all when the key is absent.
+    bucket: Bucket,
+    /// A bitmap marking which levels have a non-empty sibling. Bit 0 is the level just
+    /// above the leaves.
+    occupied: u64,
+    /// The non-empty sibling hashes from the bottom of the tree to the top.
+    siblings: Vec<Hash>,
+}
+
+impl StorageProof {
+    /// The value that this proof claims is stored at the given key. This claim means nothing
+    /// until the proof is verified against a trusted state root.
+    pub fn claimed_value(&self, key: &[u8]) -> Option<&[u8]> {
+        self.bucket.get(key).map(Vec::as_slice)
+    }
+
+    /// Check that the given key has exactly the given value in the trie with the given root.
+    /// Pass `None` as the value to check that the key is absent.
+    pub fn verify(&self, state_root: Hash, key: &[u8], value: Option<&[u8]>) -> bool {
+        if self.claimed_value(key) != value {
+            return false;
+        }
+        let path = key_path(key);
+        if self.bucket.keys().any(|k| key_path(k) != path) {
+            return false;
+        }
+
+        let empty = empty_subtree_hashes();
+        let mut siblings = self.siblings.iter();
+        let mut current = leaf_hash(&self.bucket);
+        for depth in (1..=DEPTH).rev() {
+            let sibling = if self.occupied & (1 << (DEPTH - depth)) != 0 {
+                match siblings.next() {
+                    Some(sibling) => *sibling,
+                    None => return false,
+                }
+            } else {
+                empty[depth as usize]
+            };
+            current = if path_prefix(path, depth) & 1 == 0 {
+                node_hash(current, sibling)
+            } else {
+                node_hash(sibling, current)
+            };
+        }
+
+        siblings.next().is_none() && current == state_root
+    }
+}
+
+/// The prefix shared by the storage keys of all balances.
+const BALANCE_PREFIX: &[u8] = b"balance:";
+
+/// The storage key under which a user's balance is stored.
+pub fn balance_key(user: User) -> Vec<u8> {
+    let mut key = BALANCE_PREFIX.to_vec();
+    key.push(user as u8);
+    key
+}
+
+/// The user whose balance is stored under the given key, if it is a balance key.
+fn balance_owner(key: &[u8]) -> Option<User> {
+    match key.strip_prefix(BALANCE_PREFIX)? {
+        [0] => Some(User::Alice),
+        [1] => Some(User::Bob),
+        [2] => Some(User::Charlie),
+        _ => None,
+    }
+}
+
+/// Decode a balance as it is stored in the trie. Trie contents may come from peers, so a value
+/// that is not a valid balance gives None instead of a panic.
+fn decode_balance(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Set a user's balance in the trie, removing the account if the balance is 0.
+pub(crate) fn set_balance(trie: &mut StateTrie, user: User, balance: u64) {
+    if balance == 0 {
+        trie.remove(&balance_key(user));
+    } else {
+        trie.insert(balance_key(user), balance.to_le_bytes().to_vec());
+    }
+}
+
+/// Read a user's balance from the trie. Users without an account, or whose stored balance is
+/// malformed, have a balance of 0.
+pub fn balance_of(trie: &StateTrie, user: User) -> u64 {
+    trie.get(&balance_key(user))
+        .and_then(decode_balance)
+        .unwrap_or(0)
+}
+
+/// Check a proof that the given user has the given balance in the state with the given root.
+///
+/// Remember that the accounted currency removes accounts whose balance falls to 0, so a
+/// balance of 0 is proven by showing that the account is absent.
+pub fn verify_balance(state_root: Hash, user: User, balance: u64, proof: &StorageProof) -> bool {
+    let bytes = balance.to_le_bytes();
+    let value = if balance == 0 { None } else { Some(&bytes[..]) };
+    proof.verify(state_root, &balance_key(user), value)
+}
+
+/// Build a trie containing exactly the given balances.
+pub fn balances_to_trie(balances: &Balances) -> StateTrie {
+    let mut trie = StateTrie::new();
+    write_balances(&mut trie, balances);
+    trie
+}
+
+/// Write the given balances into the trie, removing any accounts that are not present. Accounts
+/// whose balance is already right are left alone, so their paths are not rehashed.
+fn write_balances(trie: &mut StateTrie, balances: &Balances) {
+    for (key, _) in trie.iter_prefix(BALANCE_PREFIX) {
+        if balance_owner(&key).is_some_and(|user| !balances.contains_key(&user)) {
+            trie.remove(&key);
+        }
+    }
+    for (user, balance) in balances {
+        if balance_of(trie, *user) != *balance {
+            set_balance(trie, *user, *balance);
+        }
+    }
+}
+
+/// Read all of the balances out of the trie. Malformed balances are skipped.
+pub fn trie_to_balances(trie: &StateTrie) -> Balances {
+    trie.iter_prefix(BALANCE_PREFIX)
+        .into_iter()
+        .filter_map(|(key, value)| Some((balance_owner(&key)?, decode_balance(&value)?)))
+        .collect()
+}
+
+/// The accounted currency from the State Machine chapter, but with its balances stored in a
+/// state trie. The rules are the same, because `next_state` reads the balances out of the trie,
+/// applies your `AccountedCurrency` to them, and writes back the accounts that changed. Only the
+/// paths of those accounts are rehashed, instead of the whole state.
+/// The state root of this state machine's state can prove any individual user's balance.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TrieAccountedCurrency;
+
+impl StateMachine for TrieAccountedCurrency {
+    type State = StateTrie;
+    type Transition = AccountingTransaction;
+
+    fn next_state(starting_state: &StateTrie, t: &AccountingTransaction) -> StateTrie {
+        let balances = AccountedCurrency::next_state(&trie_to_balances(starting_state), t);
+        let mut trie = starting_state.clone();
+        write_balances(&mut trie, &balances);
+        trie
+    }
+
+    fn human_name() -> String {
+        "Accounted Currency (trie-backed)".into()
+    }
+}
+
+/// Transactions are valid under the same rules as in the original accounted currency.
+impl ValidateTransaction for TrieAccountedCurrency {
+    fn validate_transaction(state: &StateTrie, t: &AccountingTransaction) -> TransactionValidity {
+        AccountedCurrency::validate_transaction(&trie_to_balances(state), t)
+    }
+}
+
+#[test]
+fn bc_8_empty_trie_root_is_well_known() {
+    let trie = StateTrie::new();
+
+    assert!(trie.is_empty());
+    assert_eq!(trie.state_root(), empty_subtree_hashes()[0]);
+}
+
+#[test]
+fn bc_8_insert_and_get() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"foo".to_vec(), b"bar".to_vec());
+
+    assert_eq!(trie.get(b"foo"), Some(&b"bar"[..]));
+    assert_eq!(trie.get(b"baz"), None);
+    assert_eq!(trie.len(), 1);
+}
+
+#[test]
+fn bc_8_root_changes_with_contents() {
+    let mut trie = StateTrie::new();
+    let empty_root = trie.state_root();
+    trie.insert(b"foo".to_vec(), b"bar".to_vec());
+    let root_1 = trie.state_root();
+    trie.insert(b"foo".to_vec(), b"baz".to_vec());
+    let root_2 = trie.state_root();
+
+    assert_ne!(empty_root, root_1);
+    assert_ne!(root_1, root_2);
+}
+
+#[test]
+fn bc_8_remove_restores_previous_root() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"a".to_vec(), b"1".to_vec());
+    let root = trie.state_root();
+    trie.insert(b"b".to_vec(), b"2".to_vec());
+
+    assert_eq!(trie.remove(b"b"), Some(b"2".to_vec()));
+    assert_eq!(trie.state_root(), root);
+    assert_eq!(trie.remove(b"b"), None);
+}
+
+#[test]
+fn bc_8_root_is_independent_of_insertion_order() {
+    let mut trie_1 = StateTrie::new();
+    let mut trie_2 = StateTrie::new();
+    for i in 0..20u8 {
+        trie_1.insert(vec![i], vec![i, i]);
+        trie_2.insert(vec![19 - i], vec![19 - i, 19 - i]);
+    }
+
+    assert_eq!(trie_1.state_root(), trie_2.state_root());
+    assert_eq!(trie_1, trie_2);
+}
+
+#[test]
+fn bc_8_inclusion_proof_verifies() {
+    let mut trie = StateTrie::new();
+    for i in 0..20u8 {
+        trie.insert(vec![i], vec![i, i]);
+    }
+    let root = trie.state_root();
+    let proof = trie.prove(&[7]);
+
+    assert_eq!(proof.claimed_value(&[7]), Some(&[7, 7][..]));
+    assert!(proof.verify(root, &[7], Some(&[7, 7])));
+    assert!(!proof.verify(root, &[7], Some(&[7, 8])));
+    assert!(!proof.verify(root, &[7], None));
+}
+
+#[test]
+fn bc_8_absence_proof_verifies() {
+    let mut trie = StateTrie::new();
+    for i in 0..20u8 {
+        trie.insert(vec![i], vec![i, i]);
+    }
+    let root = trie.state_root();
+    let proof = trie.prove(&[100]);
+
+    assert!(proof.verify(root, &[100], None));
+    assert!(!proof.verify(root, &[100], Some(&[100, 100])));
+}
+
+#[test]
+fn bc_8_proof_rejected_against_other_root() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"a".to_vec(), b"1".to_vec());
+    let proof = trie.prove(b"a");
+    trie.insert(b"b".to_vec(), b"2".to_vec());
+
+    assert!(!proof.verify(trie.state_root(), b"a", Some(b"1")));
+}
+
+#[test]
+fn bc_8_proof_for_one_key_does_not_prove_another() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"a".to_vec(), b"1".to_vec());
+    trie.insert(b"b".to_vec(), b"1".to_vec());
+    let proof = trie.prove(b"a");
+
+    assert!(!proof.verify(trie.state_root(), b"b", Some(b"1")));
+}
+
+#[test]
+fn bc_8_proofs_are_compact() {
+    let mut trie = StateTrie::new();
+    for i in 0..64u8 {
+        trie.insert(vec![i], vec![i]);
+    }
+    let proof = trie.prove(&[3]);
+
+    // With 64 keys, only the top handful of levels have non-empty siblings.
+    assert!(proof.siblings.len() < 16);
+}
+
+#[test]
+fn bc_8_trie_as_storage_backend() {
+    use crate::c1_state_machine::p7_storage::OverlayedStorage;
+
+    let mut trie = StateTrie::new();
+    trie.insert(b"x:2".to_vec(), vec![2]);
+    let changes = {
+        let mut overlay = OverlayedStorage::new(&trie);
+        overlay.set(b"x:1", vec![1]);
+        overlay.set(b"y:1", vec![3]);
+        overlay.into_changes()
+    };
+    assert_eq!(changes.len(), 2);
+    Storage::apply(&mut trie, &changes);
+
+    assert_eq!(
+        trie.iter_prefix(b"x:"),
+        vec![(b"x:1".to_vec(), vec![1]), (b"x:2".to_vec(), vec![2])]
+    );
+    assert!(trie
+        .prove(b"y:1")
+        .verify(trie.state_root(), b"y:1", Some(&[3])));
+}
+
+#[test]
+fn bc_8_balances_round_trip() {
+    let balances = Balances::from([(User::Alice, 100), (User::Charlie, 7)]);
+    let trie = balances_to_trie(&balances);
+
+    assert_eq!(balance_of(&trie, User::Alice), 100);
+    assert_eq!(balance_of(&trie, User::Bob), 0);
+    assert_eq!(trie_to_balances(&trie), balances);
+}
+
+#[test]
+fn bc_8_malformed_balances_do_not_panic() {
+    let mut trie = balances_to_trie(&Balances::from([(User::Alice, 100)]));
+    trie.insert(balance_key(User::Bob), vec![1, 2, 3]);
+
+    assert_eq!(balance_of(&trie, User::Bob), 0);
+    assert_eq!(
+        trie_to_balances(&trie),
+        Balances::from([(User::Alice, 100)])
+    );
+}
+
+#[test]
+fn bc_8_balance_proofs() {
+    let balances = Balances::from([(User::Alice, 100), (User::Charlie, 7)]);
+    let trie = balances_to_trie(&balances);
+    let root = trie.state_root();
+
+    let alice_proof = trie.prove(&balance_key(User::Alice));
+    assert!(verify_balance(root, User::Alice, 100, &alice_proof));
+    assert!(!verify_balance(root, User::Alice, 101, &alice_proof));
+
+    let bob_proof = trie.prove(&balance_key(User::Bob));
+    assert!(verify_balance(root, User::Bob, 0, &bob_proof));
+    assert!(!verify_balance(root, User::Bob, 5, &bob_proof));
+}
+
+#[test]
+fn bc_8_trie_accounted_currency() {
+    let start = balances_to_trie(&Balances::from([(User::Alice, 100), (User::Bob, 50)]));
+    let transfer = |sender, receiver, amount| AccountingTransaction::Transfer {
+        sender,
+        receiver,
+        amount,
+    };
+
+    let end = TrieAccountedCurrency::next_state(&start, &transfer(User::Bob, User::Charlie, 50));
+    assert_eq!(
+        trie_to_balances(&end),
+        Balances::from([(User::Alice, 100), (User::Charlie, 50)])
+    );
+    assert_eq!(end, balances_to_trie(&trie_to_balances(&end)));
+
+    // Invalid transfers leave the state, and so the root, untouched.
+    let overdrawn =
+        TrieAccountedCurrency::next_state(&start, &transfer(User::Bob, User::Alice, 51));
+    assert_eq!(overdrawn.state_root(), start.state_root());
+
+    let minted = TrieAccountedCurrency::next_state(
+        &StateTrie::new(),
+        &AccountingTransaction::Mint {
+            minter: User::Charlie,
+            amount: 7,
+        },
+    );
+    assert_eq!(balance_of(&minted, User::Charlie), 7);
+    let burned = TrieAccountedCurrency::next_state(
+        &minted,
+        &AccountingTransaction::Burn {
+            burner: User::Charlie,
+            amount: 10,
+        },
+    );
+    assert!(burned.is_empty());
+}
+
+#[test]
+fn bc_8_trie_accounted_currency_validation() {
+    use crate::c1_state_machine::p8_transaction_validity::InvalidTransaction;
+
+    let state = balances_to_trie(&Balances::from([(User::Alice, 10)]));
+    let transfer = |amount| AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount,
+    };
+
+    assert!(TrieAccountedCurrency::validate_transaction(&state, &transfer(10)).is_ok());
+    assert_eq!(
+        TrieAccountedCurrency::validate_transaction(&state, &transfer(11)),
+        Err(InvalidTransaction::InsufficientFunds)
+    );
+}
@@ -8,10 +8,18 @@ mod p3_poa; // exercise: dictator is a special case of poa. Create dictator in t
 mod p4_even_only;
 mod p5_interleave;
 mod p6_forking;
+pub mod p7_slots;
+pub mod p8_babe;
+pub mod p9_difficulty;
+pub mod p10_timestamps;
+pub mod p11_tendermint;
+pub mod p12_proof_of_stake;
+pub mod p13_authority_changes;
 
 // Re-export some individual consensus engines so they can be be re-used in the Client chapter.
 pub use p1_pow::Pow;
 pub use p3_poa::SimplePoa;
+pub use p7_slots::{SlotClock, SlotConsensus, SystemSlotClock};
 
 type Hash = u64;
 
@@ -25,11 +33,14 @@ type Hash = u64;
 /// the complete blocks.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Header<Digest> {
-    parent: Hash,
-    height: u64,
-    state_root: Hash,
-    extrinsics_root: Hash,
-    consensus_digest: Digest,
+    pub(crate) parent: Hash,
+    pub(crate) height: u64,
+    pub(crate) state_root: Hash,
+    pub(crate) extrinsics_root: Hash,
+    /// When the block was authored, in milliseconds since the Unix epoch. See `p10_timestamps`
+    /// for the rules a timestamp must follow.
+    pub(crate) timestamp: u64,
+    pub(crate) consensus_digest: Digest,
 }
 /// A Consensus Engine. Responsible for Sealing blocks and verifying their seals
 ///
@@ -64,7 +75,8 @@ pub trait Consensus {
         parent_digest: &Self::Digest,
         partial_header: Header<()>,
     ) -> Option<Header<Self::Digest>>;
-    // NOTE TO SELF. For slot-based PoA etc, just look at the system time. It's what real-world aura does
+    // Slot-based engines find the current slot with a `SlotClock`, which usually just looks at
+    // the system time. It's what real-world aura does.
 
     /// Verify that all the given headers are valid according to the consensus rules.
     ///
@@ -79,6 +91,13 @@ pub trait Consensus {
         todo!("Exercise 1")
     }
 
+    /// Whether a valid header is final as soon as it is imported. Engines that agree on each
+    /// block before sealing it, like Tendermint, return true, and clients finalize such blocks
+    /// when they import them. Other engines leave finality to the fork choice or a finality gadget.
+    fn is_final(&self, _header: &Header<Self::Digest>) -> bool {
+        false
+    }
+
     /// A human-readable name for this engine. This may be used in user-facing
     /// programs error reporting. This is not in any way related to
     /// the correctness of the consensus logic.
@@ -0,0 +1,283 @@
+//! Every header carries a timestamp, which difficulty adjustment, slot checks and time locks all
+//! rely on. The author picks the timestamp, so nodes must not simply trust it. There is no single
+//! right time in a distributed system, but we can still rule out timestamps that are obviously
+//! wrong:
+//! * A timestamp must be greater than the median of the timestamps of the last few ancestors.
+//!   Using the median instead of the parent alone means a single author with a fast clock can not
+//!   drag everyone after them into the future. This is Bitcoin's "median time past" rule.
+//! * A timestamp may not be more than a little way into the future according to our own clock.
+//!   A block that fails this check is not necessarily invalid forever, it may just be early.
+//! * In slot-based engines, the timestamp must fall within the block's slot. Otherwise an author
+//!   could claim a slot that has not begun yet.
+//!
+//! Like slots, the current time comes from a pluggable source, so that the rules can be tested
+//! without depending on the real time.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Header;
+
+/// A source of the current time.
+pub trait TimeSource {
+    /// Milliseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// The time according to the system clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("the system clock is set after 1970")
+            .as_millis() as u64
+    }
+}
+
+/// A time source that only moves when it is advanced by hand, for testing.
+///
+/// Clones share the same time, just like `ManualSlotClock`.
+#[derive(Clone, Debug, Default)]
+pub struct ManualTimeSource {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualTimeSource {
+    /// Create a time source at the given time.
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// Move the time forward by the given number of milliseconds.
+    pub fn advance(&self, millis: u64) {
+        self.now.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl TimeSource for ManualTimeSource {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// The reasons a timestamp may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TimestampError {
+    /// The timestamp is not greater than the median of the recent ancestors' timestamps.
+    NotAfterMedian { timestamp: u64, median: u64 },
+    /// The timestamp is further into the future than the allowed drift.
+    TooFarInFuture { timestamp: u64, latest: u64 },
+    /// The timestamp does not fall within the block's slot.
+    OutsideSlot { timestamp: u64, slot: u64 },
+}
+
+/// The median of the given timestamps, or `None` if there are none. For an even number of
+/// timestamps, the later of the two middle ones is used.
+pub fn median_time_past(timestamps: &[u64]) -> Option<u64> {
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
+
+/// The rules a header's timestamp must follow, independent of the consensus engine.
+pub struct TimestampRules<Time: TimeSource = SystemTimeSource> {
+    /// How many of the most recent ancestors the median is taken over.
+    median_window: usize,
+    /// How far into the future a timestamp may be, in milliseconds.
+    max_drift: u64,
+    time: Time,
+}
+
+impl<Time: TimeSource> TimestampRules<Time> {
+    pub fn new(median_window: usize, max_drift: u64, time: Time) -> Self {
+        Self {
+            median_window,
+            max_drift,
+            time,
+        }
+    }
+
+    /// How many of the most recent ancestors the median is taken over.
+    pub fn median_window(&self) -> usize {
+        self.median_window
+    }
+
+    /// Check the timestamp of the given header.
+    ///
+    /// The ancestors are given oldest first and end with the header's parent. Only the last
+    /// `median_window` of them are looked at, so callers may pass fewer near the genesis block.
+    /// A header with no ancestors at all is only checked against the allowed drift.
+    pub fn check<D>(
+        &self,
+        ancestors: &[Header<D>],
+        header: &Header<D>,
+    ) -> Result<(), TimestampError> {
+        let recent = &ancestors[ancestors.len().saturating_sub(self.median_window)..];
+        let timestamps: Vec<u64> = recent.iter().map(|h| h.timestamp).collect();
+        if let Some(median) = median_time_past(&timestamps) {
+            if header.timestamp <= median {
+                return Err(TimestampError::NotAfterMedian {
+                    timestamp: header.timestamp,
+                    median,
+                });
+            }
+        }
+
+        let latest = self.time.now().saturating_add(self.max_drift);
+        if header.timestamp > latest {
+            return Err(TimestampError::TooFarInFuture {
+                timestamp: header.timestamp,
+                latest,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the timestamps of a chain of headers that extends the given ancestors, each
+    /// against the ones before it.
+    pub fn check_chain<D: Clone>(
+        &self,
+        ancestors: &[Header<D>],
+        chain: &[Header<D>],
+    ) -> Result<(), TimestampError> {
+        let mut all = ancestors.to_vec();
+        for header in chain {
+            self.check(&all, header)?;
+            all.push(header.clone());
+        }
+        Ok(())
+    }
+}
+
+/// The time the given slot begins, in milliseconds since the Unix epoch, rounded up to the first
+/// whole millisecond within the slot.
+///
+/// Slots shorter than a millisecond do not always contain a whole millisecond. There is no
+/// timestamp that `check_slot` would place in such a slot, so this returns `None` for them.
+pub fn slot_start(slot: u64, slot_duration: Duration) -> Option<u64> {
+    let start = slot as u128 * slot_duration.as_nanos();
+    let millis = start.div_ceil(1_000_000);
+    if !slot_duration.is_zero() && millis * 1_000_000 >= start + slot_duration.as_nanos() {
+        return None;
+    }
+    u64::try_from(millis).ok()
+}
+
+/// Check that the timestamp falls within the given slot.
+///
+/// Slots with no duration do not correspond to any real time, so every timestamp is consistent
+/// with them.
+pub fn check_slot(
+    timestamp: u64,
+    slot: u64,
+    slot_duration: Duration,
+) -> Result<(), TimestampError> {
+    // Timestamps are in milliseconds, but slots may be shorter than that, so compare in nanoseconds.
+    if slot_duration.is_zero()
+        || timestamp as u128 * 1_000_000 / slot_duration.as_nanos() == slot as u128
+    {
+        Ok(())
+    } else {
+        Err(TimestampError::OutsideSlot { timestamp, slot })
+    }
+}
+
+#[cfg(test)]
+fn header_at(timestamp: u64) -> Header<()> {
+    Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn consensus_10_median_time_past() {
+    assert_eq!(median_time_past(&[]), None);
+    assert_eq!(median_time_past(&[5]), Some(5));
+    assert_eq!(median_time_past(&[9, 1, 5]), Some(5));
+    assert_eq!(median_time_past(&[4, 1, 3, 2]), Some(3));
+}
+
+#[test]
+fn consensus_10_timestamp_must_follow_the_median() {
+    let rules = TimestampRules::new(3, 0, ManualTimeSource::new(1000));
+    let ancestors: Vec<_> = [10, 20, 30, 500].into_iter().map(header_at).collect();
+
+    // Only the last three count, and their median is 30. A timestamp may be earlier than its
+    // parent's.
+    assert_eq!(
+        rules.check(&ancestors, &header_at(30)),
+        Err(TimestampError::NotAfterMedian {
+            timestamp: 30,
+            median: 30
+        })
+    );
+    assert_eq!(rules.check(&ancestors, &header_at(31)), Ok(()));
+    assert_eq!(rules.check(&[], &header_at(0)), Ok(()));
+}
+
+#[test]
+fn consensus_10_timestamp_drift_follows_the_clock() {
+    let time = ManualTimeSource::new(1000);
+    let rules = TimestampRules::new(3, 50, time.clone());
+    let early = header_at(1100);
+
+    assert_eq!(rules.check(&[], &header_at(1050)), Ok(()));
+    assert_eq!(
+        rules.check(&[], &early),
+        Err(TimestampError::TooFarInFuture {
+            timestamp: 1100,
+            latest: 1050
+        })
+    );
+
+    // The same block becomes acceptable once time catches up.
+    time.advance(50);
+    assert_eq!(rules.check(&[], &early), Ok(()));
+}
+
+#[test]
+fn consensus_10_check_chain() {
+    let rules = TimestampRules::new(3, 0, ManualTimeSource::new(100));
+    let ancestors = vec![header_at(1)];
+    let good: Vec<_> = [2, 3, 5, 4].into_iter().map(header_at).collect();
+    let bad: Vec<_> = [2, 3, 5, 4, 3].into_iter().map(header_at).collect();
+
+    assert_eq!(rules.check_chain(&ancestors, &good), Ok(()));
+    assert!(rules.check_chain(&ancestors, &bad).is_err());
+}
+
+#[test]
+fn consensus_10_timestamp_must_match_the_slot() {
+    let duration = Duration::from_secs(6);
+    assert_eq!(slot_start(3, duration), Some(18_000));
+    assert_eq!(check_slot(18_000, 3, duration), Ok(()));
+    assert_eq!(check_slot(23_999, 3, duration), Ok(()));
+    assert_eq!(
+        check_slot(24_000, 3, duration),
+        Err(TimestampError::OutsideSlot {
+            timestamp: 24_000,
+            slot: 3
+        })
+    );
+    assert_eq!(check_slot(24_000, 3, Duration::ZERO), Ok(()));
+
+    // Slots shorter than a millisecond have a time only if they contain a whole millisecond.
+    let short = Duration::from_micros(250);
+    assert_eq!(slot_start(8, short), Some(2));
+    assert_eq!(check_slot(2, 8, short), Ok(()));
+    assert!(check_slot(2, 9, short).is_err());
+    assert_eq!(slot_start(9, short), None);
+    assert_eq!(slot_start(10, short), None);
+    assert_eq!(slot_start(12, short), Some(3));
+}
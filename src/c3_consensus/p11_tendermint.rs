@@ -0,0 +1,822 @@
+//! Every engine so far either lets a single signer seal a block, or makes blocks probabilistically
+//! final, with forks possible for a while. Tendermint takes a different approach. The validators
+//! agree on each block before it is sealed, by playing a BFT voting game at every height:
+//! 1. Propose: the proposer for the round broadcasts a proposed header.
+//! 2. Prevote: every validator prevotes for the proposal, or for nothing (nil) if it has no valid
+//!    proposal or is locked on a different one.
+//! 3. Precommit: once more than 2/3 of the weight has prevoted for the proposal, every validator
+//!    locks on it and precommits for it. If more than 2/3 prevoted nil, it precommits nil.
+//! 4. Once more than 2/3 of the weight has precommitted for the proposal, it is decided. If more than
+//!    2/3 precommitted nil, or the round times out, the next round begins with the next proposer.
+//!
+//! The precommits for the decided proposal form a commit certificate, which becomes the block's
+//! digest. Anyone who knows the validator set can check the certificate, and as long as fewer than
+//! 1/3 of the weight misbehaves, there can never be two certificates for different blocks at the
+//! same height. So blocks from this engine are final the moment they are sealed, and there is no
+//! fork choice to make.
+//!
+//! Locking is what keeps the game safe across rounds. A validator that precommitted for a proposal
+//! keeps proposing and prevoting for it in later rounds, until it sees more than 2/3 prevote for
+//! something else in a later round.
+//!
+//! Timeouts are not driven by a clock here. Whoever runs the nodes calls `timeout` when no progress
+//! is being made, which keeps the state machine deterministic and easy to test.
+//!
+//! As in our Proof of Authority engine, votes and proposals are "signed" simply by naming their
+//! author.
+
+use std::collections::HashMap;
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::hash;
+
+type Hash = u64;
+
+/// The two kinds of votes cast in each round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A vote for a proposal, or for nothing at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Vote {
+    pub height: u64,
+    pub round: u64,
+    pub kind: VoteKind,
+    /// The proposal hash of the block voted for, or None for a nil vote.
+    pub block: Option<Hash>,
+    pub voter: ConsensusAuthority,
+}
+
+/// The digest of a Tendermint block: the precommits that decided it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitCertificate {
+    pub round: u64,
+    pub precommits: Vec<Vote>,
+}
+
+/// The certificate is not part of the block's identity. Nodes may hear different, equally valid
+/// sets of precommits for the same block, and they must still agree on its hash, or their
+/// proposals for the next height would build on different parents. So a block hashes the same as
+/// its proposal.
+impl std::hash::Hash for CommitCertificate {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+}
+
+/// The reasons a commit certificate may be invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificateError {
+    /// One of the votes is not a precommit for this block in the certificate's round.
+    InvalidVote(Vote),
+    /// One of the votes is from an authority that is not a validator.
+    UnknownVoter(ConsensusAuthority),
+    /// The same voter appears more than once.
+    DuplicateVoter(ConsensusAuthority),
+    /// The precommits do not carry more than 2/3 of the validators' weight.
+    NotEnoughWeight { found: u64, required: u64 },
+}
+
+/// The hash validators vote on: the hash of the header without its certificate. The certificate
+/// can not be part of what is signed, because it is made of the signatures. Since certificates
+/// are not hashed, this is also the hash of the sealed block.
+pub fn proposal_hash<D>(header: &Header<D>) -> Hash {
+    hash(&Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        timestamp: header.timestamp,
+        consensus_digest: (),
+    })
+}
+
+/// A Tendermint consensus engine with a fixed, weighted validator set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tendermint {
+    validators: Vec<(ConsensusAuthority, u64)>,
+}
+
+impl Tendermint {
+    /// Create an engine with the given validators and weights. If an authority is listed more
+    /// than once, only its first entry counts.
+    pub fn new(validators: Vec<(ConsensusAuthority, u64)>) -> Self {
+        let mut unique: Vec<(ConsensusAuthority, u64)> = Vec::new();
+        for (validator, weight) in validators {
+            if !unique.iter().any(|(v, _)| *v == validator) {
+                unique.push((validator, weight));
+            }
+        }
+        assert!(!unique.is_empty(), "there must be at least one validator");
+        Self { validators: unique }
+    }
+
+    /// The weight of the given validator, or None if it is not a validator.
+    pub fn weight(&self, validator: ConsensusAuthority) -> Option<u64> {
+        self.validators
+            .iter()
+            .find(|(v, _)| *v == validator)
+            .map(|(_, weight)| *weight)
+    }
+
+    /// The smallest weight that is more than 2/3 of the total.
+    pub fn threshold(&self) -> u64 {
+        let total: u64 = self.validators.iter().map(|(_, weight)| weight).sum();
+        2 * total / 3 + 1
+    }
+
+    /// The validator that proposes in the given round at the given height. Proposers take turns,
+    /// and the turn moves on with every round so a missing proposer can not stall the chain.
+    pub fn proposer(&self, height: u64, round: u64) -> ConsensusAuthority {
+        let index = height.wrapping_add(round) % self.validators.len() as u64;
+        self.validators[index as usize].0
+    }
+
+    /// Check that the header's certificate holds valid precommits for it from more than 2/3 of
+    /// the validators' weight.
+    pub fn check_certificate(
+        &self,
+        header: &Header<CommitCertificate>,
+    ) -> Result<(), CertificateError> {
+        let certificate = &header.consensus_digest;
+        let block = Some(proposal_hash(header));
+        let mut seen = Vec::new();
+        let mut weight = 0;
+        for vote in &certificate.precommits {
+            if vote.kind != VoteKind::Precommit
+                || vote.height != header.height
+                || vote.round != certificate.round
+                || vote.block != block
+            {
+                return Err(CertificateError::InvalidVote(*vote));
+            }
+            let voter_weight = self
+                .weight(vote.voter)
+                .ok_or(CertificateError::UnknownVoter(vote.voter))?;
+            if seen.contains(&vote.voter) {
+                return Err(CertificateError::DuplicateVoter(vote.voter));
+            }
+            seen.push(vote.voter);
+            weight += voter_weight;
+        }
+
+        let required = self.threshold();
+        if weight < required {
+            return Err(CertificateError::NotEnoughWeight {
+                found: weight,
+                required,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Consensus for Tendermint {
+    type Digest = CommitCertificate;
+
+    /// A block is valid if it carries a valid commit certificate. Nothing about the parent matters.
+    fn validate(&self, _: &CommitCertificate, header: &Header<CommitCertificate>) -> bool {
+        self.check_certificate(header).is_ok()
+    }
+
+    /// No single node can make a commit certificate, so this always returns None. Blocks are
+    /// sealed by running `TendermintNode`s among the validators instead.
+    fn seal(&self, _: &CommitCertificate, _: Header<()>) -> Option<Header<CommitCertificate>> {
+        None
+    }
+
+    /// A block with a valid certificate can never be reverted.
+    fn is_final(&self, header: &Header<CommitCertificate>) -> bool {
+        self.check_certificate(header).is_ok()
+    }
+
+    fn human_name() -> String {
+        "Tendermint".into()
+    }
+}
+
+/// The messages exchanged by Tendermint nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TendermintMessage {
+    /// A proposed header, which only counts if it comes from the round's proposer.
+    Proposal {
+        round: u64,
+        proposer: ConsensusAuthority,
+        header: Header<()>,
+    },
+    Vote(Vote),
+}
+
+/// Where a node is in the current round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// One validator taking part in the protocol, one height at a time.
+pub struct TendermintNode {
+    engine: Tendermint,
+    local: ConsensusAuthority,
+    /// The hash and height of the most recently decided block, which proposals must build on.
+    parent: (Hash, u64),
+    round: u64,
+    step: Step,
+    /// The round in which we last precommitted for a proposal, and that proposal.
+    locked: Option<(u64, Header<()>)>,
+    /// The proposal for each round at the current height, from that round's proposer.
+    proposals: HashMap<u64, Header<()>>,
+    /// Votes received at the current height, in the order they arrived.
+    votes: HashMap<(u64, VoteKind), Vec<Vote>>,
+    decision: Option<Header<CommitCertificate>>,
+    /// Messages produced while handling others, waiting to be sent.
+    outbox: Vec<TendermintMessage>,
+}
+
+impl TendermintNode {
+    /// Create a node that votes as the given validator on the block after the given one.
+    pub fn new(engine: Tendermint, local: ConsensusAuthority, parent: Hash, height: u64) -> Self {
+        Self {
+            engine,
+            local,
+            parent: (parent, height),
+            round: 0,
+            step: Step::Propose,
+            locked: None,
+            proposals: HashMap::new(),
+            votes: HashMap::new(),
+            decision: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    /// The height being decided.
+    pub fn height(&self) -> u64 {
+        self.parent.1 + 1
+    }
+
+    /// The round currently being played.
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    /// The block decided at the current height, if any.
+    pub fn decision(&self) -> Option<&Header<CommitCertificate>> {
+        self.decision.as_ref()
+    }
+
+    /// Move on to the height after the decided block. Returns the decided block, or None,
+    /// without moving, if nothing was decided yet.
+    pub fn next_height(&mut self) -> Option<Header<CommitCertificate>> {
+        let decision = self.decision.take()?;
+        self.parent = (hash(&decision), decision.height);
+        self.round = 0;
+        self.step = Step::Propose;
+        self.locked = None;
+        self.proposals.clear();
+        self.votes.clear();
+        Some(decision)
+    }
+
+    /// Handle a message from another validator. Returns whether it was new and valid, and so
+    /// worth passing on.
+    pub fn import_message(&mut self, message: TendermintMessage) -> bool {
+        match message {
+            TendermintMessage::Proposal {
+                round,
+                proposer,
+                header,
+            } => {
+                let valid = proposer == self.engine.proposer(self.height(), round)
+                    && header.parent == self.parent.0
+                    && header.height == self.height()
+                    && !self.proposals.contains_key(&round);
+                if valid {
+                    self.proposals.insert(round, header);
+                }
+                valid
+            }
+            TendermintMessage::Vote(vote) => self.import_vote(vote),
+        }
+    }
+
+    /// Record a vote. Votes for other heights, from unknown voters, and second votes of the
+    /// same kind from the same voter in the same round are ignored.
+    fn import_vote(&mut self, vote: Vote) -> bool {
+        if vote.height != self.height() || self.engine.weight(vote.voter).is_none() {
+            return false;
+        }
+        let votes = self.votes.entry((vote.round, vote.kind)).or_default();
+        if votes.iter().any(|v| v.voter == vote.voter) {
+            return false;
+        }
+        votes.push(vote);
+        true
+    }
+
+    /// Take part in the protocol as far as the messages received so far allow, and return the
+    /// messages that should be sent to the other validators. If we are the proposer and are not
+    /// locked, we propose the given candidate block.
+    pub fn poll(&mut self, candidate: &Header<()>) -> Vec<TendermintMessage> {
+        while self.step_once(candidate) {}
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Give up waiting in the current step: vote nil, or move on to the next round if we have
+    /// already precommitted. Returns the messages that should be sent.
+    pub fn timeout(&mut self) -> Vec<TendermintMessage> {
+        if self.decision.is_none() {
+            match self.step {
+                Step::Propose => {
+                    self.cast(VoteKind::Prevote, None);
+                    self.step = Step::Prevote;
+                }
+                Step::Prevote => {
+                    self.cast(VoteKind::Precommit, None);
+                    self.step = Step::Precommit;
+                }
+                Step::Precommit => self.start_round(self.round + 1),
+            }
+        }
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Make one step of progress. Returns whether anything changed.
+    fn step_once(&mut self, candidate: &Header<()>) -> bool {
+        if self.decision.is_some() {
+            return false;
+        }
+        if let Some(decision) = self.try_decide() {
+            self.decision = Some(decision);
+            return false;
+        }
+
+        let round = self.round;
+        self.relock();
+        match self.step {
+            Step::Propose => {
+                let proposer = self.engine.proposer(self.height(), round);
+                if proposer == self.local && !self.proposals.contains_key(&round) {
+                    let header = match &self.locked {
+                        Some((_, locked)) => locked.clone(),
+                        None => candidate.clone(),
+                    };
+                    self.proposals.insert(round, header.clone());
+                    self.outbox.push(TendermintMessage::Proposal {
+                        round,
+                        proposer,
+                        header,
+                    });
+                }
+                let Some(proposal) = self.proposals.get(&round) else {
+                    return false;
+                };
+                let block = match &self.locked {
+                    Some((_, locked)) if locked != proposal => None,
+                    _ => Some(proposal_hash(proposal)),
+                };
+                self.cast(VoteKind::Prevote, block);
+                self.step = Step::Prevote;
+                true
+            }
+            Step::Prevote => match self.supermajority(round, VoteKind::Prevote) {
+                Some(Some(block)) => {
+                    let Some(proposal) = self.proposal_for(round, block) else {
+                        return false;
+                    };
+                    self.locked = Some((round, proposal));
+                    self.cast(VoteKind::Precommit, Some(block));
+                    self.step = Step::Precommit;
+                    true
+                }
+                Some(None) => {
+                    self.cast(VoteKind::Precommit, None);
+                    self.step = Step::Precommit;
+                    true
+                }
+                None => false,
+            },
+            Step::Precommit => {
+                if self.supermajority(round, VoteKind::Precommit) == Some(None) {
+                    self.start_round(round + 1);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// If more than 2/3 prevoted for a proposal in a round later than the one we locked in, that
+    /// proposal replaces our lock.
+    fn relock(&mut self) {
+        let Some((locked_round, _)) = self.locked else {
+            return;
+        };
+        for round in locked_round + 1..=self.round {
+            if let Some(Some(block)) = self.supermajority(round, VoteKind::Prevote) {
+                if let Some(proposal) = self.proposal_for(round, block) {
+                    self.locked = Some((round, proposal));
+                }
+            }
+        }
+    }
+
+    /// The value that more than 2/3 of the weight voted for in the given round, if any.
+    fn supermajority(&self, round: u64, kind: VoteKind) -> Option<Option<Hash>> {
+        let votes = self.votes.get(&(round, kind))?;
+        let mut weights: HashMap<Option<Hash>, u64> = HashMap::new();
+        for vote in votes {
+            *weights.entry(vote.block).or_default() += self.engine.weight(vote.voter).unwrap_or(0);
+        }
+        weights
+            .into_iter()
+            .find(|(_, weight)| *weight >= self.engine.threshold())
+            .map(|(block, _)| block)
+    }
+
+    /// The proposal of the given round, if we know it and it has the given hash.
+    fn proposal_for(&self, round: u64, block: Hash) -> Option<Header<()>> {
+        self.proposals
+            .get(&round)
+            .filter(|proposal| proposal_hash(*proposal) == block)
+            .cloned()
+    }
+
+    /// A decided block, if more than 2/3 precommitted for a proposal we know in any round. The
+    /// certificate holds just enough precommits to pass the threshold, taken in validator set
+    /// order.
+    fn try_decide(&self) -> Option<Header<CommitCertificate>> {
+        self.votes
+            .iter()
+            .filter(|((_, kind), _)| *kind == VoteKind::Precommit)
+            .find_map(|((round, _), votes)| {
+                let block = self.supermajority(*round, VoteKind::Precommit)??;
+                let proposal = self.proposal_for(*round, block)?;
+                let mut precommits = Vec::new();
+                let mut weight = 0;
+                for (validator, validator_weight) in &self.engine.validators {
+                    if weight >= self.engine.threshold() {
+                        break;
+                    }
+                    let vote = votes.iter().find(|v| v.voter == *validator);
+                    if let Some(vote) = vote.filter(|v| v.block == Some(block)) {
+                        precommits.push(*vote);
+                        weight += validator_weight;
+                    }
+                }
+                Some(Header {
+                    parent: proposal.parent,
+                    height: proposal.height,
+                    state_root: proposal.state_root,
+                    extrinsics_root: proposal.extrinsics_root,
+                    timestamp: proposal.timestamp,
+                    consensus_digest: CommitCertificate {
+                        round: *round,
+                        precommits,
+                    },
+                })
+            })
+    }
+
+    fn cast(&mut self, kind: VoteKind, block: Option<Hash>) {
+        let vote = Vote {
+            height: self.height(),
+            round: self.round,
+            kind,
+            block,
+            voter: self.local,
+        };
+        self.import_vote(vote);
+        self.outbox.push(TendermintMessage::Vote(vote));
+    }
+
+    fn start_round(&mut self, round: u64) {
+        self.round = round;
+        self.step = Step::Propose;
+    }
+}
+
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::AccountingTransaction;
+#[cfg(test)]
+use crate::c2_blockchain::{
+    p7_merkle_tree::merkle_root,
+    p8_state_trie::{StateTrie, TrieAccountedCurrency},
+};
+#[cfg(test)]
+use crate::c4_client::{test_support::FakeClient, Block, ClientApi, ImportBlock};
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+/// Alice and Bob together carry more than 2/3 of the weight, so the chain can make progress
+/// without Charlie.
+#[cfg(test)]
+fn test_engine() -> Tendermint {
+    Tendermint::new(vec![(Alice, 2), (Bob, 2), (Charlie, 1)])
+}
+
+#[cfg(test)]
+fn test_nodes(online: &[ConsensusAuthority]) -> Vec<TendermintNode> {
+    online
+        .iter()
+        .map(|v| TendermintNode::new(test_engine(), *v, 0, 0))
+        .collect()
+}
+
+/// Each node proposes a different child of its most recently decided block.
+#[cfg(test)]
+fn candidate(node: &TendermintNode) -> Header<()> {
+    Header {
+        parent: node.parent.0,
+        height: node.height(),
+        state_root: 0,
+        extrinsics_root: node.local as u64,
+        timestamp: 0,
+        consensus_digest: (),
+    }
+}
+
+/// Run the nodes in lock step until they all decide, delivering every message to every node,
+/// and timing out whenever nobody has anything to say. Each node proposes the block `candidate`
+/// gives for it. Returns whether they all decided.
+#[cfg(test)]
+fn run_height(
+    nodes: &mut [TendermintNode],
+    max_steps: usize,
+    candidate: impl Fn(&TendermintNode) -> Header<()>,
+) -> bool {
+    for _ in 0..max_steps {
+        if nodes.iter().all(|n| n.decision().is_some()) {
+            return true;
+        }
+        let mut messages: Vec<_> = nodes
+            .iter_mut()
+            .flat_map(|n| {
+                let candidate = candidate(n);
+                n.poll(&candidate)
+            })
+            .collect();
+        if messages.is_empty() {
+            messages = nodes.iter_mut().flat_map(|n| n.timeout()).collect();
+        }
+        for node in nodes.iter_mut() {
+            for message in &messages {
+                node.import_message(message.clone());
+            }
+        }
+    }
+    nodes.iter().all(|n| n.decision().is_some())
+}
+
+#[test]
+fn consensus_11_threshold_and_proposers() {
+    let engine = test_engine();
+    assert_eq!(engine.threshold(), 4);
+    assert_eq!(engine.proposer(1, 0), Bob);
+    assert_eq!(engine.proposer(1, 1), Charlie);
+    assert_eq!(engine.proposer(1, 2), Alice);
+}
+
+#[test]
+fn consensus_11_all_validators_decide_the_same_block() {
+    let engine = test_engine();
+    let mut nodes = test_nodes(&[Alice, Bob, Charlie]);
+    let mut parent_digest = CommitCertificate {
+        round: 0,
+        precommits: vec![],
+    };
+
+    for height in 1..=3 {
+        assert!(run_height(&mut nodes, 20, candidate));
+        let decided: Vec<_> = nodes.iter_mut().map(|n| n.next_height().unwrap()).collect();
+        assert!(decided.iter().all(|header| *header == decided[0]));
+
+        let header = &decided[0];
+        assert_eq!(header.height, height);
+        assert_eq!(header.consensus_digest.round, 0);
+        assert_eq!(
+            header.extrinsics_root,
+            engine.proposer(height, 0) as u64,
+            "the round's proposer's block is decided"
+        );
+        assert!(engine.validate(&parent_digest, header));
+        parent_digest = header.consensus_digest.clone();
+    }
+}
+
+#[test]
+fn consensus_11_certificates_do_not_change_the_block_hash() {
+    let engine = test_engine();
+    let mut nodes = test_nodes(&[Alice, Bob, Charlie]);
+    assert!(run_height(&mut nodes, 20, candidate));
+
+    // Alice and Bob are enough to decide, but another node may also have Charlie's precommit.
+    let header = nodes[0].decision().unwrap().clone();
+    assert_eq!(header.consensus_digest.precommits.len(), 2);
+    let mut other = header.clone();
+    other.consensus_digest.precommits = [Alice, Bob, Charlie]
+        .map(|voter| Vote {
+            height: 1,
+            round: 0,
+            kind: VoteKind::Precommit,
+            block: Some(proposal_hash(&header)),
+            voter,
+        })
+        .into();
+    assert_eq!(engine.check_certificate(&other), Ok(()));
+    assert_ne!(other, header);
+    assert_eq!(hash(&other), hash(&header));
+    assert_eq!(hash(&header), proposal_hash(&header));
+}
+
+#[test]
+fn consensus_11_missing_proposer_is_skipped() {
+    // Charlie proposes first at height 2, but is offline.
+    let mut nodes: Vec<_> = [Alice, Bob]
+        .map(|v| TendermintNode::new(test_engine(), v, 0, 1))
+        .into();
+    assert!(run_height(&mut nodes, 20, candidate));
+
+    let header = nodes[0].decision().unwrap();
+    assert_eq!(header.height, 2);
+    assert_eq!(header.consensus_digest.round, 1);
+    assert_eq!(header.extrinsics_root, Alice as u64);
+    assert_eq!(test_engine().check_certificate(header), Ok(()));
+}
+
+#[test]
+fn consensus_11_no_decision_without_supermajority() {
+    let mut nodes = test_nodes(&[Alice, Charlie]);
+    assert!(!run_height(&mut nodes, 50, candidate));
+    assert!(nodes.iter().all(|n| n.round() > 0));
+}
+
+#[test]
+fn consensus_11_certificate_verification_catches_tampering() {
+    let engine = test_engine();
+    let mut nodes = test_nodes(&[Alice, Bob, Charlie]);
+    assert!(run_height(&mut nodes, 20, candidate));
+    let header = nodes[0].decision().unwrap().clone();
+    assert_eq!(engine.check_certificate(&header), Ok(()));
+
+    // The certificate does not cover a different block.
+    let mut other_block = header.clone();
+    other_block.state_root = 1;
+    assert!(matches!(
+        engine.check_certificate(&other_block),
+        Err(CertificateError::InvalidVote(_))
+    ));
+
+    // Dropping signatures below the threshold.
+    let mut too_few = header.clone();
+    too_few
+        .consensus_digest
+        .precommits
+        .retain(|v| v.voter != Alice);
+    assert!(matches!(
+        engine.check_certificate(&too_few),
+        Err(CertificateError::NotEnoughWeight { required: 4, .. })
+    ));
+
+    // Counting one voter twice.
+    let mut duplicated = header.clone();
+    let first = duplicated.consensus_digest.precommits[0];
+    duplicated.consensus_digest.precommits.push(first);
+    assert_eq!(
+        engine.check_certificate(&duplicated),
+        Err(CertificateError::DuplicateVoter(first.voter))
+    );
+
+    // A different validator set does not accept Bob's signature.
+    let other_set = Tendermint::new(vec![(Alice, 1), (Charlie, 1)]);
+    assert_eq!(
+        other_set.check_certificate(&header),
+        Err(CertificateError::UnknownVoter(Bob))
+    );
+}
+
+#[test]
+fn consensus_11_locked_validator_reproposes_its_lock() {
+    let engine = test_engine();
+    let mut alice = TendermintNode::new(engine.clone(), Alice, 0, 0);
+    let proposal = Header {
+        parent: 0,
+        height: 1,
+        state_root: 7,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: (),
+    };
+    let block = Some(proposal_hash(&proposal));
+    alice.import_message(TendermintMessage::Proposal {
+        round: 0,
+        proposer: Bob,
+        header: proposal.clone(),
+    });
+    for voter in [Bob, Charlie] {
+        alice.import_message(TendermintMessage::Vote(Vote {
+            height: 1,
+            round: 0,
+            kind: VoteKind::Prevote,
+            block,
+            voter,
+        }));
+    }
+    let unrelated = candidate(&alice);
+    alice.poll(&unrelated);
+    assert_eq!(alice.step(), Step::Precommit);
+
+    // The round fails to decide, and it is Alice's turn to propose in round 2.
+    for _ in 0..4 {
+        alice.timeout();
+    }
+    assert_eq!(alice.round(), 2);
+    let messages = alice.poll(&unrelated);
+    assert!(messages.contains(&TendermintMessage::Proposal {
+        round: 2,
+        proposer: Alice,
+        header: proposal
+    }));
+}
+
+#[test]
+fn consensus_11_proposals_from_others_are_ignored() {
+    let mut alice = TendermintNode::new(test_engine(), Alice, 0, 0);
+    let proposal = candidate(&alice);
+
+    // Bob proposes in round 0 at height 1, not Charlie.
+    assert!(!alice.import_message(TendermintMessage::Proposal {
+        round: 0,
+        proposer: Charlie,
+        header: proposal.clone(),
+    }));
+    let messages = alice.timeout();
+    assert!(messages.contains(&TendermintMessage::Vote(Vote {
+        height: 1,
+        round: 0,
+        kind: VoteKind::Prevote,
+        block: None,
+        voter: Alice,
+    })));
+
+    assert!(alice.import_message(TendermintMessage::Proposal {
+        round: 0,
+        proposer: Bob,
+        header: proposal,
+    }));
+}
+
+#[test]
+fn consensus_11_clients_finalize_every_decided_block() {
+    let genesis_digest = CommitCertificate {
+        round: 0,
+        precommits: vec![],
+    };
+    let mut clients: Vec<_> = (0..3)
+        .map(|_| {
+            FakeClient::<_, TrieAccountedCurrency>::new(
+                test_engine(),
+                StateTrie::new(),
+                genesis_digest.clone(),
+                StateTrie::state_root,
+            )
+        })
+        .collect();
+    let genesis = clients[0].genesis();
+    let mut nodes: Vec<_> = [Alice, Bob, Charlie]
+        .map(|v| TendermintNode::new(test_engine(), v, genesis, 0))
+        .into();
+
+    for height in 1..=3 {
+        // The clients agree on the chain so far, so every proposer offers the same empty block.
+        let parent = clients[0].best_block();
+        assert!(run_height(&mut nodes, 20, |node| Header {
+            parent,
+            height: node.height(),
+            state_root: StateTrie::state_root(&StateTrie::new()),
+            extrinsics_root: merkle_root::<AccountingTransaction>(&[]),
+            timestamp: 0,
+            consensus_digest: (),
+        }));
+
+        for (node, client) in nodes.iter_mut().zip(clients.iter_mut()) {
+            let header = node.next_height().unwrap();
+            let block_hash = hash(&header);
+            assert_eq!(header.height, height);
+            assert!(client.import_block(Block {
+                header,
+                body: vec![],
+            }));
+            // A block with a commit certificate is final as soon as it is imported.
+            assert_eq!(client.finalized_block(), block_hash);
+            assert_eq!(client.best_block(), block_hash);
+        }
+    }
+}
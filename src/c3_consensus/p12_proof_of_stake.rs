@@ -0,0 +1,351 @@
+//! In Proof of Authority every authority is equal. In Proof of Stake, each authority's influence is
+//! proportional to how much it has at stake, so that misbehaving costs it more the more power it
+//! has. Here we give each slot to one authority, chosen by a lottery in which every unit of stake
+//! is one ticket.
+//!
+//! The stakes are not part of the consensus engine's configuration. They live in the chain's
+//! state, where they can change as users bond and unbond funds. That is a problem for our
+//! `Consensus` trait, which deliberately never sees the state. So we add a small interface between
+//! the two sides:
+//! * The runtime (the state machine) implements `ConsensusRuntime` to read the stake table out of a
+//!   state.
+//! * The engine implements `RuntimeConsensus` to validate and seal headers given the state of the
+//!   parent block.
+//!
+//! The stakes used for a block always come from its parent's state, never from its own. Every node
+//! that has the parent block computes the same snapshot, and the stakes can not be changed by the
+//! very block whose author they decide.
+//!
+//! A client that only knows the `Consensus` trait can not check the stakes, so the plain `validate`
+//! rejects every header, unless the engine is told that the stakes are checked somewhere else.
+
+use super::p10_timestamps::check_slot;
+use super::{Consensus, ConsensusAuthority, Header, SlotClock, SystemSlotClock};
+use crate::c1_state_machine::p4_accounted_currency::{AccountedCurrency, Balances};
+use crate::c1_state_machine::{StateMachine, User};
+use crate::hash;
+
+type Hash = u64;
+
+/// A snapshot of how much each authority has at stake. Authorities without stake are left out,
+/// and the rest are kept in a fixed order so that every node draws the lottery the same way.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StakeTable {
+    stakes: Vec<(ConsensusAuthority, u64)>,
+}
+
+impl StakeTable {
+    /// Create a table from the given stakes. Stakes listed more than once for the same authority
+    /// are added together.
+    pub fn new(stakes: impl IntoIterator<Item = (ConsensusAuthority, u64)>) -> Self {
+        let mut table: Vec<(ConsensusAuthority, u64)> = Vec::new();
+        for (authority, stake) in stakes {
+            match table.iter_mut().find(|(a, _)| *a == authority) {
+                Some((_, existing)) => *existing = existing.saturating_add(stake),
+                None => table.push((authority, stake)),
+            }
+        }
+        table.retain(|(_, stake)| *stake > 0);
+        table.sort_by_key(|(authority, _)| *authority as usize);
+        Self { stakes: table }
+    }
+
+    /// The stake of the given authority.
+    pub fn stake(&self, authority: ConsensusAuthority) -> u64 {
+        self.stakes
+            .iter()
+            .find(|(a, _)| *a == authority)
+            .map_or(0, |(_, stake)| *stake)
+    }
+
+    /// The combined stake of all authorities.
+    pub fn total(&self) -> u64 {
+        self.stakes
+            .iter()
+            .fold(0, |total, (_, stake)| total.saturating_add(*stake))
+    }
+
+    /// The winner of the lottery for the given slot, or None if nobody has any stake.
+    ///
+    /// The randomness comes from the parent hash and the slot. That is easy for the previous author
+    /// to bias by trying several blocks, which the VRF based lottery in BABE avoids, but it is
+    /// enough to show stake weighting.
+    pub fn slot_author(&self, parent: Hash, slot: u64) -> Option<ConsensusAuthority> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let mut ticket = hash(&(parent, slot)) % total;
+        for (authority, stake) in &self.stakes {
+            if ticket < *stake {
+                return Some(*authority);
+            }
+            ticket -= stake;
+        }
+        None
+    }
+}
+
+/// The consensus-runtime interface: what the consensus engine needs to know about the chain state.
+pub trait ConsensusRuntime: StateMachine {
+    /// The authorities' stakes in the given state.
+    fn stake_table(state: &Self::State) -> StakeTable;
+}
+
+/// In the accounted currency, each user's balance is their stake, and every user authors as the
+/// consensus authority of the same name.
+impl ConsensusRuntime for AccountedCurrency {
+    fn stake_table(state: &Balances) -> StakeTable {
+        StakeTable::new(state.iter().map(|(user, balance)| {
+            let authority = match user {
+                User::Alice => ConsensusAuthority::Alice,
+                User::Bob => ConsensusAuthority::Bob,
+                User::Charlie => ConsensusAuthority::Charlie,
+            };
+            (authority, *balance)
+        }))
+    }
+}
+
+/// A consensus engine that needs the parent block's state to validate and seal headers.
+///
+/// Clients call these methods instead of `validate` and `seal`, which can only check the parts
+/// of the consensus rules that do not depend on the state.
+pub trait RuntimeConsensus<SM: StateMachine>: Consensus {
+    /// Validate the header against the consensus rules, using the parent block's state.
+    fn validate_with_state(
+        &self,
+        parent_state: &SM::State,
+        parent_digest: &Self::Digest,
+        header: &Header<Self::Digest>,
+    ) -> bool;
+
+    /// Seal the header, using the parent block's state.
+    fn seal_with_state(
+        &self,
+        parent_state: &SM::State,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>>;
+}
+
+/// A digest used for ProofOfStake.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StakeDigest {
+    pub slot: u64,
+    pub author: ConsensusAuthority,
+}
+
+/// A stake weighted slot engine. In each slot, a single authority chosen by the stake lottery may
+/// author.
+#[derive(Clone, Debug)]
+pub struct ProofOfStake<Clock: SlotClock = SystemSlotClock> {
+    clock: Clock,
+    /// Whether `validate` may leave the stakes to someone else and check only the slot.
+    stakes_checked_elsewhere: bool,
+}
+
+impl<Clock: SlotClock> ProofOfStake<Clock> {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            stakes_checked_elsewhere: false,
+        }
+    }
+
+    /// An engine whose `validate` checks only the slot and timestamp, for clients that check the
+    /// stakes themselves before importing, like those wrapped in a `RuntimeCheckedImport`.
+    pub fn without_stake_checks(clock: Clock) -> Self {
+        Self {
+            clock,
+            stakes_checked_elsewhere: true,
+        }
+    }
+
+    /// Whether the slot is later than the parent's and matches the header's timestamp.
+    fn slot_is_valid(&self, parent_digest: &StakeDigest, header: &Header<StakeDigest>) -> bool {
+        let slot = header.consensus_digest.slot;
+        slot > parent_digest.slot
+            && check_slot(header.timestamp, slot, self.clock.slot_duration()).is_ok()
+    }
+
+    /// Check the header against the given stake snapshot.
+    pub fn validate_with_stakes(
+        &self,
+        stakes: &StakeTable,
+        parent_digest: &StakeDigest,
+        header: &Header<StakeDigest>,
+    ) -> bool {
+        self.slot_is_valid(parent_digest, header)
+            && stakes.slot_author(header.parent, header.consensus_digest.slot)
+                == Some(header.consensus_digest.author)
+    }
+
+    /// Seal the header in the clock's current slot, as that slot's winner under the given stakes.
+    pub fn seal_with_stakes(
+        &self,
+        stakes: &StakeTable,
+        parent_digest: &StakeDigest,
+        partial_header: Header<()>,
+    ) -> Option<Header<StakeDigest>> {
+        let slot = self.clock.current_slot();
+        if slot <= parent_digest.slot {
+            return None;
+        }
+        check_slot(partial_header.timestamp, slot, self.clock.slot_duration()).ok()?;
+        let author = stakes.slot_author(partial_header.parent, slot)?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: StakeDigest { slot, author },
+        })
+    }
+}
+
+impl<Clock: SlotClock> Consensus for ProofOfStake<Clock> {
+    type Digest = StakeDigest;
+
+    /// Whether the author won the slot depends on the stakes, which only `validate_with_state`
+    /// can check. So this rejects every header, unless the engine was made with
+    /// `without_stake_checks`, in which case it checks only that the slot is increasing and
+    /// matches the timestamp.
+    fn validate(&self, parent_digest: &StakeDigest, header: &Header<StakeDigest>) -> bool {
+        self.stakes_checked_elsewhere && self.slot_is_valid(parent_digest, header)
+    }
+
+    /// The author can not be chosen without the stakes, so this always returns None. Use
+    /// `seal_with_state` instead.
+    fn seal(&self, _: &StakeDigest, _: Header<()>) -> Option<Header<StakeDigest>> {
+        None
+    }
+}
+
+impl<Clock: SlotClock, SM: ConsensusRuntime> RuntimeConsensus<SM> for ProofOfStake<Clock> {
+    fn validate_with_state(
+        &self,
+        parent_state: &SM::State,
+        parent_digest: &StakeDigest,
+        header: &Header<StakeDigest>,
+    ) -> bool {
+        self.validate_with_stakes(&SM::stake_table(parent_state), parent_digest, header)
+    }
+
+    fn seal_with_state(
+        &self,
+        parent_state: &SM::State,
+        parent_digest: &StakeDigest,
+        partial_header: Header<()>,
+    ) -> Option<Header<StakeDigest>> {
+        self.seal_with_stakes(
+            &SM::stake_table(parent_state),
+            parent_digest,
+            partial_header,
+        )
+    }
+}
+
+#[cfg(test)]
+use super::p7_slots::ManualSlotClock;
+#[cfg(test)]
+use std::time::Duration;
+
+/// A header for slot 1 of six second slots.
+#[cfg(test)]
+fn partial_child(parent: Hash) -> Header<()> {
+    Header {
+        parent,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 6_000,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn consensus_12_stake_table_from_state() {
+    let balances = Balances::from([(User::Alice, 5), (User::Charlie, 2)]);
+    let table = AccountedCurrency::stake_table(&balances);
+
+    assert_eq!(table.stake(ConsensusAuthority::Alice), 5);
+    assert_eq!(table.stake(ConsensusAuthority::Bob), 0);
+    assert_eq!(table.total(), 7);
+    assert_eq!(
+        table,
+        StakeTable::new([
+            (ConsensusAuthority::Charlie, 2),
+            (ConsensusAuthority::Alice, 5)
+        ])
+    );
+}
+
+#[test]
+fn consensus_12_slots_are_won_in_proportion_to_stake() {
+    use ConsensusAuthority::*;
+    let table = StakeTable::new([(Alice, 1), (Bob, 0), (Charlie, 9)]);
+    let mut wins = [0; 3];
+    for slot in 0..1000 {
+        wins[table.slot_author(42, slot).unwrap() as usize] += 1;
+    }
+
+    assert_eq!(wins[Bob as usize], 0);
+    assert!(wins[Charlie as usize] > 5 * wins[Alice as usize]);
+    assert!(wins[Alice as usize] > 0);
+    assert_eq!(StakeTable::default().slot_author(42, 0), None);
+}
+
+#[test]
+fn consensus_12_validation_uses_the_parent_state() {
+    let clock = ManualSlotClock::new(Duration::from_secs(6), 1);
+    let pos = ProofOfStake::new(clock.clone());
+    let genesis = StakeDigest {
+        slot: 0,
+        author: ConsensusAuthority::Alice,
+    };
+    let only_bob = Balances::from([(User::Bob, 10)]);
+    let only_alice = Balances::from([(User::Alice, 10)]);
+
+    let header = RuntimeConsensus::<AccountedCurrency>::seal_with_state(
+        &pos,
+        &only_bob,
+        &genesis,
+        partial_child(7),
+    )
+    .unwrap();
+    assert_eq!(header.consensus_digest.author, ConsensusAuthority::Bob);
+    assert!(RuntimeConsensus::<AccountedCurrency>::validate_with_state(
+        &pos, &only_bob, &genesis, &header
+    ));
+
+    // Nodes with a different idea of the parent state disagree.
+    assert!(!RuntimeConsensus::<AccountedCurrency>::validate_with_state(
+        &pos,
+        &only_alice,
+        &genesis,
+        &header
+    ));
+
+    // Without any stake nobody can author, and slots must increase.
+    assert!(RuntimeConsensus::<AccountedCurrency>::seal_with_state(
+        &pos,
+        &Balances::new(),
+        &genesis,
+        partial_child(7)
+    )
+    .is_none());
+    // The stateless rules can not tell who won the slot.
+    assert!(!pos.validate(&genesis, &header));
+    assert!(ProofOfStake::without_stake_checks(clock.clone()).validate(&genesis, &header));
+
+    clock.set_slot(0);
+    assert!(pos
+        .seal_with_stakes(
+            &AccountedCurrency::stake_table(&only_bob),
+            &genesis,
+            partial_child(7)
+        )
+        .is_none());
+}
@@ -0,0 +1,451 @@
+//! The `Forked` engine lets us change the authorities at a height chosen when the node software is
+//! written. But a running chain needs to rotate its authorities regularly, for example when new
+//! validators are elected, and shipping new node software every time is not practical. Instead, the
+//! chain itself can announce the change.
+//!
+//! Real-world headers carry a list of digest log items next to the seal. Here, an author may log:
+//! * `ScheduledAuthorityChange`: the new set takes over once `delay` more blocks have been built on
+//!   top of the announcing block. Only one change may be pending at a time.
+//! * `ForcedChange`: like a scheduled change, but it replaces any change that is already pending.
+//!   It exists for recovering a chain whose pending change can never happen, for example because
+//!   the old authorities went offline.
+//!
+//! The delay gives everyone time to notice the change, and lets the new authorities get ready.
+//!
+//! `AuthorityChanges` wraps an ordinary PoA or slot engine. It keeps track of the authority set
+//! in each block's digest, so that the set for any block follows from its parent's digest alone.
+//! For each block it builds the inner engine with the set in effect at that height, and lets it
+//! check the seal.
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::hash;
+
+/// An item in a header's digest log.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DigestItem {
+    /// Hand over to the new set after `delay` more blocks, unless a change is already pending.
+    ScheduledAuthorityChange {
+        delay: u64,
+        new_set: Vec<ConsensusAuthority>,
+    },
+    /// Hand over to the new set after `delay` more blocks, replacing any pending change.
+    ForcedChange {
+        delay: u64,
+        new_set: Vec<ConsensusAuthority>,
+    },
+}
+
+/// The reasons a block's digest log may be invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthorityChangeError {
+    /// A scheduled change was announced while another change was still pending.
+    ChangeAlreadyPending,
+    /// A change to an empty authority set was announced, which would halt the chain.
+    EmptySet,
+}
+
+/// A change that has been announced but has not happened yet.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PendingChange {
+    /// The first height at which the new set seals blocks.
+    pub effective_at: u64,
+    pub new_set: Vec<ConsensusAuthority>,
+}
+
+/// The authorities, along with any change that is waiting to happen.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AuthoritySet {
+    pub current: Vec<ConsensusAuthority>,
+    pub pending: Option<PendingChange>,
+}
+
+impl AuthoritySet {
+    /// A set with no pending change.
+    pub fn new(current: Vec<ConsensusAuthority>) -> Self {
+        Self {
+            current,
+            pending: None,
+        }
+    }
+
+    /// The set in effect at the given height, with the pending change applied if it is due.
+    pub fn at(&self, height: u64) -> AuthoritySet {
+        match &self.pending {
+            Some(change) if change.effective_at <= height => {
+                AuthoritySet::new(change.new_set.clone())
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Apply the digest log of the block at the given height to this set, which must already be
+    /// the set in effect at that height.
+    pub fn with_logs(
+        mut self,
+        height: u64,
+        logs: &[DigestItem],
+    ) -> Result<AuthoritySet, AuthorityChangeError> {
+        for item in logs {
+            let (delay, new_set) = match item {
+                DigestItem::ScheduledAuthorityChange { delay, new_set } => {
+                    if self.pending.is_some() {
+                        return Err(AuthorityChangeError::ChangeAlreadyPending);
+                    }
+                    (delay, new_set)
+                }
+                DigestItem::ForcedChange { delay, new_set } => (delay, new_set),
+            };
+            if new_set.is_empty() {
+                return Err(AuthorityChangeError::EmptySet);
+            }
+            self.pending = Some(PendingChange {
+                effective_at: height.saturating_add(*delay).saturating_add(1),
+                new_set: new_set.clone(),
+            });
+        }
+        Ok(self)
+    }
+}
+
+/// A digest that carries a log alongside the inner engine's seal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LoggedDigest<D> {
+    pub seal: D,
+    pub logs: Vec<DigestItem>,
+    /// The authority set after this block's logs were applied, which its children start from.
+    pub authorities: AuthoritySet,
+}
+
+impl<D> LoggedDigest<D> {
+    /// The digest for a genesis block with the given inner digest, which starts the chain with
+    /// the given authorities.
+    pub fn genesis(authorities: Vec<ConsensusAuthority>, seal: D) -> Self {
+        Self {
+            seal,
+            logs: Vec::new(),
+            authorities: AuthoritySet::new(authorities),
+        }
+    }
+}
+
+/// A higher-order consensus engine that lets the chain change the authorities of an inner PoA or
+/// slot engine through digest logs.
+pub struct AuthorityChanges<F> {
+    /// Builds the inner engine for a given authority set.
+    engine_for: F,
+}
+
+impl<F, E> AuthorityChanges<F>
+where
+    F: Fn(&[ConsensusAuthority]) -> E,
+    E: Consensus,
+{
+    pub fn new(engine_for: F) -> Self {
+        Self { engine_for }
+    }
+
+    /// Seal a header that carries the given log items, with the authorities in effect at its
+    /// height. Returns None if the log is invalid, or if the inner engine can not seal.
+    pub fn seal_with_logs(
+        &self,
+        parent_digest: &LoggedDigest<E::Digest>,
+        partial_header: Header<()>,
+        logs: Vec<DigestItem>,
+    ) -> Option<Header<LoggedDigest<E::Digest>>> {
+        let set = parent_digest.authorities.at(partial_header.height);
+        let inner = (self.engine_for)(&set.current);
+        let authorities = set.with_logs(partial_header.height, &logs).ok()?;
+        let sealed = inner.seal(
+            &parent_digest.seal,
+            inner_header(&partial_header, &logs, &authorities, ()),
+        )?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: LoggedDigest {
+                seal: sealed.consensus_digest,
+                logs,
+                authorities,
+            },
+        })
+    }
+}
+
+/// The header the inner engine seals and checks. Its extrinsics root also commits to the log and
+/// the resulting authorities, so that whatever the inner seal covers, it covers those too.
+fn inner_header<D, S>(
+    header: &Header<D>,
+    logs: &[DigestItem],
+    authorities: &AuthoritySet,
+    seal: S,
+) -> Header<S> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: hash(&(header.extrinsics_root, logs, authorities)),
+        timestamp: header.timestamp,
+        consensus_digest: seal,
+    }
+}
+
+impl<F, E> Consensus for AuthorityChanges<F>
+where
+    F: Fn(&[ConsensusAuthority]) -> E,
+    E: Consensus,
+{
+    type Digest = LoggedDigest<E::Digest>;
+
+    /// Check the seal with the authorities in effect at the header's height, and check that the
+    /// digest records the right authorities for the children.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        let set = parent_digest.authorities.at(header.height);
+        let inner = (self.engine_for)(&set.current);
+        let seal_valid = inner.validate(
+            &parent_digest.seal,
+            &inner_header(
+                header,
+                &digest.logs,
+                &digest.authorities,
+                digest.seal.clone(),
+            ),
+        );
+        seal_valid && set.with_logs(header.height, &digest.logs) == Ok(digest.authorities.clone())
+    }
+
+    /// Seal a header without any log items.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        self.seal_with_logs(parent_digest, partial_header, Vec::new())
+    }
+}
+
+#[cfg(test)]
+use super::p10_timestamps::slot_start;
+#[cfg(test)]
+use super::p7_slots::ManualSlotClock;
+#[cfg(test)]
+use super::p8_babe::{Babe, BabeDigest};
+#[cfg(test)]
+use std::time::Duration;
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+const TEST_SLOT_DURATION: Duration = Duration::from_secs(6);
+
+/// A child of the given header, authored in the slot that matches its height.
+#[cfg(test)]
+fn partial_child<D: std::hash::Hash>(parent: &Header<D>) -> Header<()> {
+    Header {
+        parent: hash(parent),
+        height: parent.height + 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: slot_start(parent.height + 1, TEST_SLOT_DURATION).unwrap(),
+        consensus_digest: (),
+    }
+}
+
+/// A PoA engine for testing, in which any authority may seal. It seals as `author`, which is only
+/// allowed if they are in the set.
+#[cfg(test)]
+struct TestPoa {
+    authorities: Vec<ConsensusAuthority>,
+    author: ConsensusAuthority,
+}
+
+#[cfg(test)]
+impl Consensus for TestPoa {
+    type Digest = ConsensusAuthority;
+
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.authorities.contains(&header.consensus_digest)
+    }
+
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: self.author,
+        };
+        self.validate(&self.author, &header).then_some(header)
+    }
+}
+
+/// An authority changing PoA engine that seals as the given author.
+#[cfg(test)]
+fn poa_as(
+    author: ConsensusAuthority,
+) -> AuthorityChanges<impl Fn(&[ConsensusAuthority]) -> TestPoa> {
+    AuthorityChanges::new(move |set: &[ConsensusAuthority]| TestPoa {
+        authorities: set.to_vec(),
+        author,
+    })
+}
+
+#[test]
+fn consensus_13_changes_take_effect_after_the_delay() {
+    let set = AuthoritySet::new(vec![Alice]);
+    let scheduled = DigestItem::ScheduledAuthorityChange {
+        delay: 2,
+        new_set: vec![Bob],
+    };
+    let after = set.with_logs(5, std::slice::from_ref(&scheduled)).unwrap();
+
+    assert_eq!(after.at(7).current, vec![Alice]);
+    assert_eq!(after.at(8), AuthoritySet::new(vec![Bob]));
+    assert_eq!(
+        after.clone().with_logs(6, &[scheduled]),
+        Err(AuthorityChangeError::ChangeAlreadyPending)
+    );
+
+    // A forced change replaces the pending one.
+    let forced = DigestItem::ForcedChange {
+        delay: 0,
+        new_set: vec![Charlie],
+    };
+    let forced_after = after.with_logs(6, &[forced]).unwrap();
+    assert_eq!(forced_after.at(7).current, vec![Charlie]);
+    assert_eq!(forced_after.at(8).current, vec![Charlie]);
+
+    let empty = DigestItem::ForcedChange {
+        delay: 0,
+        new_set: vec![],
+    };
+    assert_eq!(
+        AuthoritySet::new(vec![Alice]).with_logs(1, &[empty]),
+        Err(AuthorityChangeError::EmptySet)
+    );
+}
+
+#[test]
+fn consensus_13_slot_engine_rotates_authorities() {
+    let clock = ManualSlotClock::new(TEST_SLOT_DURATION, 0);
+    let babe_for = |clock: ManualSlotClock| {
+        move |set: &[ConsensusAuthority]| {
+            Babe::new(
+                set.iter().map(|a| (*a, 1)).collect(),
+                10,
+                0.5,
+                clock.clone(),
+            )
+        }
+    };
+    let engine = AuthorityChanges::new(babe_for(clock.clone()));
+    let genesis = Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: LoggedDigest::genesis(
+            vec![Alice],
+            Babe::<ManualSlotClock>::genesis_digest(7),
+        ),
+    };
+    let author = |header: &Header<LoggedDigest<BabeDigest>>| header.consensus_digest.seal.author;
+
+    // Block 1 announces that Bob takes over after one more block.
+    clock.advance(1);
+    let change = DigestItem::ScheduledAuthorityChange {
+        delay: 1,
+        new_set: vec![Bob],
+    };
+    let block_1 = engine
+        .seal_with_logs(
+            &genesis.consensus_digest,
+            partial_child(&genesis),
+            vec![change],
+        )
+        .unwrap();
+    clock.advance(1);
+    let block_2 = engine
+        .seal(&block_1.consensus_digest, partial_child(&block_1))
+        .unwrap();
+    clock.advance(1);
+    let block_3 = engine
+        .seal(&block_2.consensus_digest, partial_child(&block_2))
+        .unwrap();
+
+    assert_eq!(
+        [author(&block_1), author(&block_2), author(&block_3)],
+        [Alice, Alice, Bob]
+    );
+    assert!(engine.validate(&genesis.consensus_digest, &block_1));
+    assert!(engine.validate(&block_1.consensus_digest, &block_2));
+    assert!(engine.validate(&block_2.consensus_digest, &block_3));
+
+    // An engine that never heard of the change would seal block 3 as Alice, which is now invalid.
+    let stale = AuthorityChanges::new(babe_for(clock.clone()));
+    let mut stale_digest = block_2.consensus_digest.clone();
+    stale_digest.authorities = AuthoritySet::new(vec![Alice]);
+    let alice_3 = stale.seal(&stale_digest, partial_child(&block_2)).unwrap();
+    assert_eq!(author(&alice_3), Alice);
+    assert!(!engine.validate(&block_2.consensus_digest, &alice_3));
+
+    // The digest must record the right authorities for the children.
+    let mut wrong_set = block_1.clone();
+    wrong_set.consensus_digest.authorities = AuthoritySet::new(vec![Alice]);
+    assert!(!engine.validate(&genesis.consensus_digest, &wrong_set));
+}
+
+#[test]
+fn consensus_13_poa_engine_rotates_authorities() {
+    let (alice, bob) = (poa_as(Alice), poa_as(Bob));
+    let genesis = Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: LoggedDigest::genesis(vec![Alice, Charlie], Alice),
+    };
+
+    // Block 1 announces that Bob and Charlie take over after one more block.
+    let change = DigestItem::ScheduledAuthorityChange {
+        delay: 1,
+        new_set: vec![Bob, Charlie],
+    };
+    assert!(bob
+        .seal(&genesis.consensus_digest, partial_child(&genesis))
+        .is_none());
+    let block_1 = alice
+        .seal_with_logs(
+            &genesis.consensus_digest,
+            partial_child(&genesis),
+            vec![change],
+        )
+        .unwrap();
+    let block_2 = alice
+        .seal(&block_1.consensus_digest, partial_child(&block_1))
+        .unwrap();
+    assert!(bob.validate(&genesis.consensus_digest, &block_1));
+    assert!(bob.validate(&block_1.consensus_digest, &block_2));
+
+    // From block 3 on, Alice may no longer seal, and her blocks are invalid.
+    assert!(alice
+        .seal(&block_2.consensus_digest, partial_child(&block_2))
+        .is_none());
+    let block_3 = bob
+        .seal(&block_2.consensus_digest, partial_child(&block_2))
+        .unwrap();
+    assert!(alice.validate(&block_2.consensus_digest, &block_3));
+    assert_eq!(
+        block_3.consensus_digest.authorities,
+        AuthoritySet::new(vec![Bob, Charlie])
+    );
+    let mut forged = block_3;
+    forged.consensus_digest.seal = Alice;
+    assert!(!bob.validate(&block_2.consensus_digest, &forged));
+}
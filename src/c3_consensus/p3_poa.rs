@@ -9,7 +9,7 @@
 //! Even when using the Proof of Stake configuration, the underlying consensus logic is identical to
 //! the proof of authority we are writing here.
 
-use super::{Consensus, ConsensusAuthority, Header};
+use super::{Consensus, ConsensusAuthority, Header, SlotClock, SlotConsensus, SystemSlotClock};
 
 /// A Proof of Authority consensus engine. If any of the authorities have signed the block, it is valid.
 pub struct SimplePoa {
@@ -63,20 +63,24 @@ impl Consensus for PoaRoundRobinByHeight {
 ///
 /// A common PoA scheme that works around these weaknesses is to divide time into slots, and then do a round robin
 /// by slot instead of by height
-struct PoaRoundRobinBySlot {
+///
+/// To seal a block, the engine needs to know what slot it is right now, so it has a clock.
+struct PoaRoundRobinBySlot<Clock: SlotClock = SystemSlotClock> {
     authorities: Vec<ConsensusAuthority>,
+    clock: Clock,
 }
 
 /// A digest used for PoaRoundRobinBySlot. The digest contains the slot number as well as the signature.
 /// In addition to checking that the right signer has signed for the slot, you must check that the slot is
 /// always strictly increasing. But remember that slots may be skipped.
+/// The header's timestamp must also fall within the slot, which `p10_timestamps::check_slot` can check.
 #[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
 struct SlotDigest {
     slot: u64,
     signature: ConsensusAuthority,
 }
 
-impl Consensus for PoaRoundRobinBySlot {
+impl<Clock: SlotClock> Consensus for PoaRoundRobinBySlot<Clock> {
     type Digest = SlotDigest;
 
     fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
@@ -91,3 +95,16 @@ impl Consensus for PoaRoundRobinBySlot {
         todo!("Exercise 6")
     }
 }
+
+/// An authoring worker does not seal with the clock's slot directly. Instead it asks whether its
+/// own authority may author in the slot it is handling.
+impl<Clock: SlotClock> SlotConsensus for PoaRoundRobinBySlot<Clock> {
+    fn claim_slot(
+        &self,
+        parent_digest: &Self::Digest,
+        slot: u64,
+        authority: ConsensusAuthority,
+    ) -> Option<Self::Digest> {
+        todo!("Exercise 7")
+    }
+}
@@ -43,6 +43,9 @@ where
 
 /// Create a PoA consensus engine that changes authorities part way through the chain's history.
 /// Given the initial authorities, the authorities after the fork, and the height at which the fork occurs.
+///
+/// A chain can also change its authorities without a fork by announcing the change in a header, see
+/// `p13_authority_changes`.
 fn change_authorities(
     fork_height: u64,
     initial_authorities: Vec<ConsensusAuthority>,
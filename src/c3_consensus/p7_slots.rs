@@ -0,0 +1,148 @@
+//! Several of the engines in this chapter divide time into slots, and decide who may author a
+//! block based on the slot. Real-world engines like Aura and BABE work this way. To author or
+//! check such blocks, nodes need to agree on what slot it is right now, which means they need a
+//! clock.
+//!
+//! We abstract over the clock so that engines and authoring code can be tested without waiting
+//! for real time to pass. The system clock counts slots from the Unix epoch, so every node with
+//! a reasonably accurate clock agrees on the current slot. The manual clock only moves when it
+//! is told to.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{Consensus, ConsensusAuthority};
+
+/// A source of the current slot.
+pub trait SlotClock {
+    /// The length of each slot.
+    fn slot_duration(&self) -> Duration;
+
+    /// The slot we are currently in.
+    fn current_slot(&self) -> u64;
+
+    /// How long until the next slot begins.
+    fn time_until_next_slot(&self) -> Duration;
+}
+
+/// A slot clock driven by the system time. Slot 0 began at the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemSlotClock {
+    slot_duration: Duration,
+}
+
+impl SystemSlotClock {
+    /// Create a clock with the given slot duration, which must not be zero.
+    pub fn new(slot_duration: Duration) -> Self {
+        assert!(!slot_duration.is_zero(), "slots must have some duration");
+        Self { slot_duration }
+    }
+
+    fn since_epoch() -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("the system clock is set after 1970")
+    }
+}
+
+impl SlotClock for SystemSlotClock {
+    fn slot_duration(&self) -> Duration {
+        self.slot_duration
+    }
+
+    fn current_slot(&self) -> u64 {
+        (Self::since_epoch().as_nanos() / self.slot_duration.as_nanos()) as u64
+    }
+
+    fn time_until_next_slot(&self) -> Duration {
+        let into_slot = Self::since_epoch().as_nanos() % self.slot_duration.as_nanos();
+        self.slot_duration - Duration::from_nanos(into_slot as u64)
+    }
+}
+
+/// A slot clock that only moves when it is advanced by hand, for testing.
+///
+/// Clones share the same slot, so a test can keep one handle and give another to the code
+/// under test.
+#[derive(Clone, Debug)]
+pub struct ManualSlotClock {
+    slot_duration: Duration,
+    slot: Arc<AtomicU64>,
+}
+
+impl ManualSlotClock {
+    /// Create a clock with slots of the given duration, at the given slot.
+    pub fn new(slot_duration: Duration, slot: u64) -> Self {
+        Self {
+            slot_duration,
+            slot: Arc::new(AtomicU64::new(slot)),
+        }
+    }
+
+    /// Move the clock forward by the given number of slots.
+    pub fn advance(&self, slots: u64) {
+        self.slot.fetch_add(slots, Ordering::SeqCst);
+    }
+
+    /// Move the clock to the given slot.
+    pub fn set_slot(&self, slot: u64) {
+        self.slot.store(slot, Ordering::SeqCst);
+    }
+}
+
+impl SlotClock for ManualSlotClock {
+    /// The duration blocks authored with this clock are timestamped with. Time only passes when
+    /// the clock is advanced, however long that takes.
+    fn slot_duration(&self) -> Duration {
+        self.slot_duration
+    }
+
+    fn current_slot(&self) -> u64 {
+        self.slot.load(Ordering::SeqCst)
+    }
+
+    /// Nobody ever has to wait for a manual clock.
+    fn time_until_next_slot(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// A consensus engine in which authorities take turns by slot.
+pub trait SlotConsensus: Consensus {
+    /// Check whether the given authority may author a child of a block with the given digest in
+    /// the given slot. If it may, returns the digest to seal the new block with.
+    fn claim_slot(
+        &self,
+        parent_digest: &Self::Digest,
+        slot: u64,
+        authority: ConsensusAuthority,
+    ) -> Option<Self::Digest>;
+}
+
+#[test]
+fn consensus_7_manual_clock_is_shared_between_clones() {
+    let clock = ManualSlotClock::new(Duration::from_secs(6), 5);
+    let handle = clock.clone();
+    assert_eq!(clock.current_slot(), 5);
+    assert_eq!(handle.slot_duration(), Duration::from_secs(6));
+
+    handle.advance(2);
+    assert_eq!(clock.current_slot(), 7);
+    handle.set_slot(3);
+    assert_eq!(clock.current_slot(), 3);
+    assert_eq!(clock.time_until_next_slot(), Duration::ZERO);
+}
+
+#[test]
+fn consensus_7_system_clock_counts_from_the_epoch() {
+    let duration = Duration::from_secs(6);
+    let clock = SystemSlotClock::new(duration);
+    let before = SystemSlotClock::since_epoch().as_secs() / 6;
+    let slot = clock.current_slot();
+    let after = SystemSlotClock::since_epoch().as_secs() / 6;
+
+    assert!(before <= slot && slot <= after);
+    assert!(clock.time_until_next_slot() <= duration);
+    assert_eq!(clock.slot_duration(), duration);
+}
@@ -0,0 +1,398 @@
+//! Round robin by slot makes it obvious, far in advance, who will author each block. An attacker
+//! who knows that can target the next few authors and stall the chain. BABE, the engine used by
+//! Polkadot, keeps the slot leaders secret until they reveal themselves.
+//!
+//! In every slot, each authority evaluates a verifiable random function (VRF) over the epoch's
+//! randomness and the slot number. If the output is below a threshold, the authority is a primary
+//! slot leader and may author a block, with the VRF output as proof. The threshold grows with the
+//! authority's share of the total stake, so authorities with more stake win more often.
+//! Sometimes several authorities win the same slot, and sometimes nobody does.
+//!
+//! To keep the chain moving when nobody wins, there are also secondary slots, assigned round robin
+//! like before. A secondary claim is only valid when the slot has no primary leader.
+//!
+//! The randomness for each epoch comes from the chain itself. Every primary VRF output is mixed
+//! into an accumulator carried in the block digests, and the accumulator at the end of one epoch
+//! seeds the randomness of the next.
+//!
+//! Real VRFs need a secret key to evaluate but only a public key to verify, so nobody can learn who
+//! wins a slot before the winner reveals it. We have no keys in this tutorial, so our VRF is just
+//! a hash that anyone can compute. That is also what lets `validate` check that a secondary slot
+//! really had no primary leader. A real implementation cannot do this, so it accepts secondary
+//! blocks in any slot, and has fork choice prefer primary blocks instead.
+
+use super::p10_timestamps::check_slot;
+use super::{Consensus, ConsensusAuthority, Header, SlotClock, SlotConsensus, SystemSlotClock};
+use crate::hash;
+
+/// How an authority claimed its slot.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SlotClaim {
+    /// The authority won the lottery. The VRF output is the proof.
+    Primary { vrf_output: u64 },
+    /// Nobody won the lottery, so the round robin author claims the slot.
+    Secondary,
+}
+
+/// A digest used for Babe.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BabeDigest {
+    pub slot: u64,
+    pub author: ConsensusAuthority,
+    pub claim: SlotClaim,
+    /// The randomness of the epoch this slot belongs to.
+    pub epoch_randomness: u64,
+    /// The randomness accumulated in this epoch so far, including this block. It seeds the next
+    /// epoch's randomness.
+    pub accumulator: u64,
+}
+
+/// A Babe-like consensus engine.
+pub struct Babe<Clock: SlotClock = SystemSlotClock> {
+    /// The authorities along with their stakes.
+    authorities: Vec<(ConsensusAuthority, u64)>,
+    /// The number of slots in each epoch.
+    epoch_length: u64,
+    /// The probability that a slot has at least one primary leader, which is roughly the
+    /// fraction of slots that are not filled by secondary blocks.
+    c: f64,
+    /// Used when sealing, to find the current slot.
+    clock: Clock,
+}
+
+impl<Clock: SlotClock> Babe<Clock> {
+    /// Create an engine with the given authorities and stakes, epoch length, and primary slot
+    /// probability `c`, which must be between 0 and 1.
+    pub fn new(
+        authorities: Vec<(ConsensusAuthority, u64)>,
+        epoch_length: u64,
+        c: f64,
+        clock: Clock,
+    ) -> Self {
+        assert!(epoch_length > 0, "epochs must contain some slots");
+        assert!((0.0..=1.0).contains(&c), "c is a probability");
+        Self {
+            authorities,
+            epoch_length,
+            c,
+            clock,
+        }
+    }
+
+    /// The digest for the genesis block, which starts the chain with the given randomness.
+    pub fn genesis_digest(randomness: u64) -> BabeDigest {
+        BabeDigest {
+            slot: 0,
+            author: ConsensusAuthority::Alice,
+            claim: SlotClaim::Secondary,
+            epoch_randomness: randomness,
+            accumulator: randomness,
+        }
+    }
+
+    /// The epoch the given slot belongs to.
+    pub fn epoch(&self, slot: u64) -> u64 {
+        slot / self.epoch_length
+    }
+
+    /// The lottery output of the given authority in the given slot.
+    pub fn vrf(authority: ConsensusAuthority, epoch_randomness: u64, slot: u64) -> u64 {
+        hash(&(authority, epoch_randomness, slot))
+    }
+
+    /// The value below which the given authority's VRF output must fall for it to be a primary
+    /// slot leader. Authorities that are not in the set never win.
+    ///
+    /// As in BABE, the probability of winning is `1 - (1 - c)^share`, where share is the
+    /// authority's fraction of the total stake.
+    pub fn threshold(&self, authority: ConsensusAuthority) -> u64 {
+        let total: u64 = self.authorities.iter().map(|(_, stake)| stake).sum();
+        let Some((_, stake)) = self.authorities.iter().find(|(a, _)| *a == authority) else {
+            return 0;
+        };
+        if total == 0 {
+            return 0;
+        }
+        let share = *stake as f64 / total as f64;
+        let probability = 1.0 - (1.0 - self.c).powf(share);
+        (probability * u64::MAX as f64) as u64
+    }
+
+    /// The authorities that win the lottery in the given slot.
+    pub fn primary_leaders(&self, epoch_randomness: u64, slot: u64) -> Vec<ConsensusAuthority> {
+        self.authorities
+            .iter()
+            .map(|(authority, _)| *authority)
+            .filter(|authority| {
+                Self::vrf(*authority, epoch_randomness, slot) < self.threshold(*authority)
+            })
+            .collect()
+    }
+
+    /// The authority that may claim the given slot if nobody wins the lottery.
+    pub fn secondary_author(&self, slot: u64) -> Option<ConsensusAuthority> {
+        if self.authorities.is_empty() {
+            return None;
+        }
+        Some(self.authorities[(slot % self.authorities.len() as u64) as usize].0)
+    }
+
+    /// The epoch randomness and accumulator that a child of the given parent in the given slot
+    /// starts from.
+    fn randomness_after(&self, parent: &BabeDigest, slot: u64) -> (u64, u64) {
+        let epoch = self.epoch(slot);
+        if epoch == self.epoch(parent.slot) {
+            return (parent.epoch_randomness, parent.accumulator);
+        }
+        let randomness = hash(&(parent.accumulator, epoch));
+        (randomness, randomness)
+    }
+}
+
+impl<Clock: SlotClock> Consensus for Babe<Clock> {
+    type Digest = BabeDigest;
+
+    fn validate(&self, parent_digest: &BabeDigest, header: &Header<BabeDigest>) -> bool {
+        let digest = &header.consensus_digest;
+        if digest.slot <= parent_digest.slot
+            || check_slot(header.timestamp, digest.slot, self.clock.slot_duration()).is_err()
+        {
+            return false;
+        }
+        self.claim_slot(parent_digest, digest.slot, digest.author)
+            .is_some_and(|expected| expected == *digest)
+    }
+
+    /// Seals the block in the clock's current slot, for the first authority that may author in it.
+    /// The header's timestamp must fall within that slot.
+    fn seal(
+        &self,
+        parent_digest: &BabeDigest,
+        partial_header: Header<()>,
+    ) -> Option<Header<BabeDigest>> {
+        let slot = self.clock.current_slot();
+        check_slot(partial_header.timestamp, slot, self.clock.slot_duration()).ok()?;
+        let consensus_digest = self
+            .authorities
+            .iter()
+            .find_map(|(authority, _)| self.claim_slot(parent_digest, slot, *authority))?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest,
+        })
+    }
+}
+
+impl<Clock: SlotClock> SlotConsensus for Babe<Clock> {
+    fn claim_slot(
+        &self,
+        parent_digest: &BabeDigest,
+        slot: u64,
+        authority: ConsensusAuthority,
+    ) -> Option<BabeDigest> {
+        if slot <= parent_digest.slot {
+            return None;
+        }
+        let (epoch_randomness, accumulator) = self.randomness_after(parent_digest, slot);
+        let vrf_output = Self::vrf(authority, epoch_randomness, slot);
+        let (claim, accumulator) = if vrf_output < self.threshold(authority) {
+            (
+                SlotClaim::Primary { vrf_output },
+                hash(&(accumulator, vrf_output)),
+            )
+        } else if self.primary_leaders(epoch_randomness, slot).is_empty()
+            && self.secondary_author(slot) == Some(authority)
+        {
+            (SlotClaim::Secondary, accumulator)
+        } else {
+            return None;
+        };
+        Some(BabeDigest {
+            slot,
+            author: authority,
+            claim,
+            epoch_randomness,
+            accumulator,
+        })
+    }
+}
+
+#[cfg(test)]
+use super::p10_timestamps::slot_start;
+#[cfg(test)]
+use super::p7_slots::ManualSlotClock;
+#[cfg(test)]
+use std::time::Duration;
+
+#[cfg(test)]
+const TEST_SLOT_DURATION: Duration = Duration::from_secs(6);
+
+#[cfg(test)]
+fn test_babe(stakes: [u64; 3], c: f64) -> Babe<ManualSlotClock> {
+    use ConsensusAuthority::*;
+    Babe::new(
+        vec![(Alice, stakes[0]), (Bob, stakes[1]), (Charlie, stakes[2])],
+        10,
+        c,
+        ManualSlotClock::new(TEST_SLOT_DURATION, 1),
+    )
+}
+
+#[cfg(test)]
+fn header_with(digest: BabeDigest) -> Header<BabeDigest> {
+    Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: slot_start(digest.slot, TEST_SLOT_DURATION).unwrap(),
+        consensus_digest: digest,
+    }
+}
+
+#[test]
+fn consensus_8_more_stake_wins_more_slots() {
+    let babe = test_babe([1, 1, 8], 0.5);
+    assert!(
+        babe.threshold(ConsensusAuthority::Charlie) > babe.threshold(ConsensusAuthority::Alice)
+    );
+
+    let mut wins = [0; 3];
+    for slot in 0..2000 {
+        for leader in babe.primary_leaders(42, slot) {
+            wins[leader as usize] += 1;
+        }
+    }
+    assert!(wins[2] > wins[0] * 3);
+    assert!(wins[2] > wins[1] * 3);
+}
+
+#[test]
+fn consensus_8_primary_claims_validate() {
+    let babe = test_babe([1, 1, 1], 0.5);
+    let genesis = Babe::<ManualSlotClock>::genesis_digest(7);
+    let (slot, leader) = (1..)
+        .find_map(|slot| Some((slot, *babe.primary_leaders(7, slot).first()?)))
+        .unwrap();
+
+    let digest = babe.claim_slot(&genesis, slot, leader).unwrap();
+    assert!(matches!(digest.claim, SlotClaim::Primary { .. }));
+    assert!(babe.validate(&genesis, &header_with(digest)));
+
+    // Tampering with the proof or claiming for someone else is caught.
+    let mut forged = digest;
+    forged.claim = SlotClaim::Primary { vrf_output: 0 };
+    assert!(!babe.validate(&genesis, &header_with(forged)));
+    for other in [
+        ConsensusAuthority::Alice,
+        ConsensusAuthority::Bob,
+        ConsensusAuthority::Charlie,
+    ] {
+        if babe.claim_slot(&genesis, slot, other).is_none() {
+            let mut stolen = digest;
+            stolen.author = other;
+            assert!(!babe.validate(&genesis, &header_with(stolen)));
+        }
+    }
+}
+
+#[test]
+fn consensus_8_secondary_slots_fill_gaps() {
+    let babe = test_babe([1, 1, 1], 0.3);
+    let genesis = Babe::<ManualSlotClock>::genesis_digest(7);
+    let empty = (1..)
+        .find(|s| babe.primary_leaders(7, *s).is_empty())
+        .unwrap();
+    let busy = (1..)
+        .find(|s| !babe.primary_leaders(7, *s).is_empty())
+        .unwrap();
+
+    let secondary = babe.secondary_author(empty).unwrap();
+    let digest = babe.claim_slot(&genesis, empty, secondary).unwrap();
+    assert_eq!(digest.claim, SlotClaim::Secondary);
+    assert!(babe.validate(&genesis, &header_with(digest)));
+
+    // Nobody else may claim the empty slot.
+    assert_eq!(babe.primary_leaders(7, empty), vec![]);
+    let mut stolen = digest;
+    stolen.author = babe.secondary_author(empty + 1).unwrap();
+    assert!(!babe.validate(&genesis, &header_with(stolen)));
+
+    // Secondary claims are not valid when somebody won the lottery.
+    let forged = BabeDigest {
+        slot: busy,
+        author: babe.secondary_author(busy).unwrap(),
+        claim: SlotClaim::Secondary,
+        epoch_randomness: 7,
+        accumulator: 7,
+    };
+    assert!(!babe.validate(&genesis, &header_with(forged)));
+}
+
+#[test]
+fn consensus_8_randomness_comes_from_the_chain() {
+    let babe = test_babe([1, 1, 1], 0.8);
+    let mut parent = Babe::<ManualSlotClock>::genesis_digest(7);
+    let mut primaries = Vec::new();
+    for slot in 1..25 {
+        let digest = [
+            ConsensusAuthority::Alice,
+            ConsensusAuthority::Bob,
+            ConsensusAuthority::Charlie,
+        ]
+        .into_iter()
+        .find_map(|a| babe.claim_slot(&parent, slot, a))
+        .unwrap();
+        assert!(babe.validate(&parent, &header_with(digest)));
+        if let SlotClaim::Primary { vrf_output } = digest.claim {
+            primaries.push((slot, vrf_output));
+        }
+
+        if slot == 10 {
+            // The first block of epoch 1 takes its randomness from epoch 0's accumulator.
+            assert_eq!(digest.epoch_randomness, hash(&(parent.accumulator, 1u64)));
+        } else if slot != 20 {
+            assert_eq!(digest.epoch_randomness, parent.epoch_randomness);
+        }
+        parent = digest;
+    }
+    assert!(!primaries.is_empty());
+
+    // A block that does not carry its epoch's randomness forward is invalid.
+    let mut next = [
+        ConsensusAuthority::Alice,
+        ConsensusAuthority::Bob,
+        ConsensusAuthority::Charlie,
+    ]
+    .into_iter()
+    .find_map(|a| babe.claim_slot(&parent, 25, a))
+    .unwrap();
+    next.epoch_randomness += 1;
+    assert!(!babe.validate(&parent, &header_with(next)));
+}
+
+#[test]
+fn consensus_8_seal_uses_the_clock() {
+    let babe = test_babe([1, 1, 1], 0.5);
+    let genesis = Babe::<ManualSlotClock>::genesis_digest(7);
+    let partial = Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: slot_start(3, TEST_SLOT_DURATION).unwrap(),
+        consensus_digest: (),
+    };
+
+    babe.clock.set_slot(3);
+    let sealed = babe.seal(&genesis, partial.clone()).unwrap();
+    assert_eq!(sealed.consensus_digest.slot, 3);
+    assert!(babe.validate(&genesis, &sealed));
+
+    // Slots must increase.
+    assert_eq!(babe.seal(&sealed.consensus_digest, partial), None);
+}
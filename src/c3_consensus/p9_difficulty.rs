@@ -0,0 +1,279 @@
+//! The Proof of Work engine at the start of this chapter has a fixed threshold. But the amount of
+//! hash power working on a chain changes all the time. With a fixed threshold, blocks come faster
+//! and faster as miners join, and may stop coming at all when they leave. Changing the threshold by
+//! hand means coordinating a fork for every change.
+//!
+//! Real Proof of Work chains adjust the difficulty automatically, based on how quickly recent
+//! blocks were actually produced compared to a target block time. There are two common styles:
+//! * Retarget every N blocks, as Bitcoin does. The threshold stays fixed for N blocks, and is then
+//!   scaled by how long those blocks took compared to how long they should have taken.
+//! * Retarget every block using a linearly weighted moving average (LWMA) of recent solve times,
+//!   which reacts faster to changes in hash power. Recent blocks weigh more than older ones.
+//!
+//! The digest carries the threshold the block was mined against, and the timestamps of the recent
+//! blocks the next retarget needs, ending with the block's own header timestamp. Every node
+//! computes the expected threshold from the parent's digest, which was itself checked against its
+//! own parent, so the whole calculation follows deterministically from the ancestry. `validate`
+//! checks both that the committed threshold is the expected one, and that the block's hash meets
+//! it.
+
+use super::{Consensus, Header};
+use crate::hash;
+
+/// When and how the difficulty is adjusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retarget {
+    /// Scale the threshold once every `interval` blocks by how long those blocks took.
+    EveryN { interval: u64 },
+    /// Adjust the threshold every block from a linearly weighted moving average of the solve
+    /// times of the last `window` blocks.
+    Lwma { window: u64 },
+}
+
+/// A digest used for the difficulty adjusting PoW.
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct DifficultyDigest {
+    /// The threshold the block's hash must be below.
+    pub threshold: u64,
+    pub nonce: u64,
+    /// The timestamps of the most recent blocks, oldest first, ending with this one.
+    pub recent_timestamps: Vec<u64>,
+}
+
+/// A Proof of Work engine whose threshold adjusts to keep blocks coming at the target rate.
+pub struct AdjustingPow {
+    retarget: Retarget,
+    /// The desired time between blocks, in milliseconds.
+    target_block_time: u64,
+}
+
+/// A single retarget never changes the threshold by more than this factor, so that a few blocks
+/// with extreme timestamps can not swing the difficulty wildly.
+const MAX_ADJUSTMENT: u128 = 4;
+
+impl AdjustingPow {
+    /// Create an engine with the given retarget rule and target block time in milliseconds.
+    pub fn new(retarget: Retarget, target_block_time: u64) -> Self {
+        assert!(target_block_time > 0, "blocks must take some time");
+        Self {
+            retarget,
+            target_block_time,
+        }
+    }
+
+    /// The digest for a genesis block with the given timestamp, which starts the chain with the
+    /// given threshold.
+    pub fn genesis_digest(threshold: u64, timestamp: u64) -> DifficultyDigest {
+        DifficultyDigest {
+            threshold,
+            nonce: 0,
+            recent_timestamps: vec![timestamp],
+        }
+    }
+
+    /// How many recent timestamps each digest carries.
+    fn history_len(&self) -> usize {
+        match self.retarget {
+            Retarget::EveryN { interval } => interval as usize + 1,
+            Retarget::Lwma { window } => window as usize + 1,
+        }
+    }
+
+    /// The recent timestamps a child of the given parent, with the given timestamp, carries.
+    fn extend_history(&self, parent: &DifficultyDigest, timestamp: u64) -> Vec<u64> {
+        let mut history = parent.recent_timestamps.clone();
+        history.push(timestamp);
+        let excess = history.len().saturating_sub(self.history_len());
+        history.drain(..excess);
+        history
+    }
+
+    /// The threshold a block at the given height, on top of the given parent, must commit to.
+    pub fn next_threshold(&self, parent: &DifficultyDigest, height: u64) -> u64 {
+        let history = &parent.recent_timestamps;
+        let old = parent.threshold as u128;
+        let target = self.target_block_time as u128;
+        let new = match self.retarget {
+            Retarget::EveryN { interval } => {
+                if interval == 0 || !height.is_multiple_of(interval) || history.len() < 2 {
+                    return parent.threshold;
+                }
+                let blocks = history.len() as u128 - 1;
+                let actual = history[history.len() - 1].saturating_sub(history[0]) as u128;
+                old * actual / (target * blocks)
+            }
+            Retarget::Lwma { .. } => {
+                if history.len() < 2 {
+                    return parent.threshold;
+                }
+                // Clamping solve times stops out of order or far future timestamps from
+                // dominating the average.
+                let weighted: u128 = history
+                    .windows(2)
+                    .enumerate()
+                    .map(|(i, pair)| {
+                        let solve_time = pair[1].saturating_sub(pair[0]) as u128;
+                        (i as u128 + 1) * solve_time.clamp(1, 6 * target)
+                    })
+                    .sum();
+                let blocks = history.len() as u128 - 1;
+                let expected = target * blocks * (blocks + 1) / 2;
+                old * weighted / expected
+            }
+        };
+        new.clamp(old / MAX_ADJUSTMENT, old * MAX_ADJUSTMENT)
+            .clamp(1, u64::MAX as u128) as u64
+    }
+}
+
+impl Consensus for AdjustingPow {
+    type Digest = DifficultyDigest;
+
+    /// Check that the header commits to the expected threshold and history, and that its hash
+    /// is below the threshold.
+    fn validate(
+        &self,
+        parent_digest: &DifficultyDigest,
+        header: &Header<DifficultyDigest>,
+    ) -> bool {
+        let digest = &header.consensus_digest;
+        digest.threshold == self.next_threshold(parent_digest, header.height)
+            && digest.recent_timestamps == self.extend_history(parent_digest, header.timestamp)
+            && hash(header) < digest.threshold
+    }
+
+    /// Mine a new seal against the expected threshold.
+    fn seal(
+        &self,
+        parent_digest: &DifficultyDigest,
+        partial_header: Header<()>,
+    ) -> Option<Header<DifficultyDigest>> {
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: DifficultyDigest {
+                threshold: self.next_threshold(parent_digest, partial_header.height),
+                nonce: 0,
+                recent_timestamps: self.extend_history(parent_digest, partial_header.timestamp),
+            },
+        };
+        while hash(&header) >= header.consensus_digest.threshold {
+            header.consensus_digest.nonce = header.consensus_digest.nonce.checked_add(1)?;
+        }
+        Some(header)
+    }
+}
+
+/// Mine a chain on top of a genesis block with the given threshold, with the given time between
+/// each block's timestamp. Returns the headers after genesis.
+#[cfg(test)]
+fn mine_chain(
+    retarget: Retarget,
+    threshold: u64,
+    solve_time: u64,
+    blocks: u64,
+) -> Vec<Header<DifficultyDigest>> {
+    let pow = AdjustingPow::new(retarget, 1000);
+    let mut parent = Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 1_000_000,
+        consensus_digest: AdjustingPow::genesis_digest(threshold, 1_000_000),
+    };
+    let mut chain = Vec::new();
+    for _ in 0..blocks {
+        let partial = Header {
+            parent: hash(&parent),
+            height: parent.height + 1,
+            state_root: 0,
+            extrinsics_root: 0,
+            timestamp: parent.timestamp + solve_time,
+            consensus_digest: (),
+        };
+        let header = pow.seal(&parent.consensus_digest, partial).unwrap();
+        assert!(pow.validate(&parent.consensus_digest, &header));
+        chain.push(header.clone());
+        parent = header;
+    }
+    chain
+}
+
+#[test]
+fn consensus_9_retarget_every_n_blocks() {
+    let start = u64::MAX / 2;
+    let retarget = Retarget::EveryN { interval: 4 };
+
+    // Blocks twice as fast as the target make mining harder, but only at the boundary.
+    let fast = mine_chain(retarget, start, 500, 5);
+    let thresholds: Vec<_> = fast.iter().map(|h| h.consensus_digest.threshold).collect();
+    assert_eq!(&thresholds[..3], &[start; 3]);
+    assert_eq!(thresholds[3], start / 2);
+    assert_eq!(thresholds[4], start / 2);
+
+    // Blocks slower than the target make mining easier.
+    let slow = mine_chain(retarget, start / 4, 2000, 4);
+    assert_eq!(slow[3].consensus_digest.threshold, start / 4 * 2);
+
+    // On target, nothing changes.
+    let steady = mine_chain(retarget, start, 1000, 8);
+    assert!(steady.iter().all(|h| h.consensus_digest.threshold == start));
+}
+
+#[test]
+fn consensus_9_retarget_is_clamped() {
+    let start = u64::MAX / 100;
+    let instant = mine_chain(Retarget::EveryN { interval: 2 }, start, 0, 2);
+    assert_eq!(instant[1].consensus_digest.threshold, start / 4);
+
+    let instant = mine_chain(Retarget::Lwma { window: 5 }, start, 0, 3);
+    assert_eq!(instant[1].consensus_digest.threshold, start / 4);
+    assert_eq!(instant[2].consensus_digest.threshold, start / 16);
+}
+
+#[test]
+fn consensus_9_lwma_adjusts_every_block() {
+    let start = u64::MAX / 2;
+    let chain = mine_chain(Retarget::Lwma { window: 5 }, start, 500, 6);
+    let thresholds: Vec<_> = chain.iter().map(|h| h.consensus_digest.threshold).collect();
+
+    // The first block has no solve times to go on.
+    assert_eq!(thresholds[0], start);
+    assert!(thresholds.windows(2).all(|pair| pair[1] < pair[0]));
+    assert_eq!(chain[5].consensus_digest.recent_timestamps.len(), 6);
+
+    let steady = mine_chain(Retarget::Lwma { window: 5 }, start, 1000, 6);
+    assert!(steady.iter().all(|h| h.consensus_digest.threshold == start));
+}
+
+#[test]
+fn consensus_9_validate_checks_target_and_work() {
+    let pow = AdjustingPow::new(Retarget::Lwma { window: 3 }, 1000);
+    let chain = mine_chain(Retarget::Lwma { window: 3 }, u64::MAX / 2, 500, 3);
+    let (parent, header) = (&chain[1].consensus_digest, &chain[2]);
+    assert!(pow.validate(parent, header));
+
+    // Committing to an easier threshold than the ancestry calls for.
+    let mut easier = header.clone();
+    easier.consensus_digest.threshold = u64::MAX;
+    assert!(!pow.validate(parent, &easier));
+
+    // Lying about recent timestamps, or about its own.
+    let mut rewritten = header.clone();
+    rewritten.consensus_digest.recent_timestamps[0] -= 1;
+    assert!(!pow.validate(parent, &rewritten));
+    let mut retimed = header.clone();
+    retimed.timestamp += 1;
+    assert!(!pow.validate(parent, &retimed));
+
+    // Not enough work.
+    let mut lazy = header.clone();
+    while hash(&lazy) < lazy.consensus_digest.threshold {
+        lazy.consensus_digest.nonce += 1;
+    }
+    assert!(!pow.validate(parent, &lazy));
+}
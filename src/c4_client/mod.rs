@@ -16,7 +16,8 @@ use crate::{
     c1_state_machine::StateMachine,
     c3_consensus::{Consensus, Header},
 };
-use p1_data_structure::Block;
+pub use p1_data_structure::Block;
+pub(crate) use p2_importing_blocks::ImportBlock;
 use p3_fork_choice::ForkChoice;
 
 mod p1_data_structure;
@@ -25,6 +26,21 @@ mod p3_fork_choice;
 mod p4_transaction_pool;
 mod p5_authoring_blocks;
 mod p6_finality;
+mod p7_database;
+mod p8_chain_export;
+mod p9_network_simulator;
+mod p10_networking;
+mod p11_sync;
+mod p12_light_client;
+mod p13_warp_sync;
+mod p14_finality_gadget;
+mod p15_tagged_pool;
+mod p16_bounded_pool;
+mod p17_block_builder;
+mod p18_slot_worker;
+mod p19_checked_import;
+#[cfg(test)]
+pub(crate) mod test_support;
 
 type Hash = u64;
 
@@ -64,4 +80,133 @@ pub struct FullClient<C, SM, FC, P>
     // Please document them as you add them.
 }
 
-//TODO Consider exploring LightClient as well. It may import headers but not blocks for example.
+/// What the later sections of this chapter need from a client.
+///
+/// Our `FullClient` provides all of it through the methods you write in the first few sections.
+/// The later sections are written against this trait rather than directly against `FullClient`,
+/// so that their tests can run with a simple fake client before those exercises are done.
+pub trait ClientApi<C: Consensus, SM: StateMachine>: ImportBlock<C, SM> {
+    /// The hash of the genesis block.
+    fn genesis(&self) -> Hash;
+
+    /// The best block according to the client's fork choice.
+    fn best_block(&self) -> Hash;
+
+    /// Import a block whose post state is already known, for example because it was stored
+    /// along with the block. Clients may check the given state against the header's state root
+    /// instead of executing the body again. By default the state is ignored.
+    fn import_block_with_state(&mut self, block: Block<C, SM>, _state: Option<SM::State>) -> bool {
+        self.import_block(block)
+    }
+
+    /// Mark the given block as final. Returns whether it was known and marked successfully.
+    fn manually_finalize_block(&mut self, block_hash: Hash) -> bool;
+
+    /// The most recently finalized block.
+    fn finalized_block(&self) -> Hash;
+}
+
+impl<C, SM, FC, P> ClientApi<C, SM> for FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+    Self: ImportBlock<C, SM>,
+{
+    fn genesis(&self) -> Hash {
+        FullClient::genesis(self)
+    }
+
+    fn best_block(&self) -> Hash {
+        FullClient::best_block(self)
+    }
+
+    fn manually_finalize_block(&mut self, block_hash: Hash) -> bool {
+        FullClient::manually_finalize_block(self, block_hash)
+    }
+
+    fn finalized_block(&self) -> Hash {
+        FullClient::finalized_block(self)
+    }
+}
+
+/// Implement `ImportBlock` and `ClientApi` for a client that wraps another one in its `client`
+/// field, by forwarding every call to the wrapped client.
+///
+/// Wrappers usually exist to refuse some blocks. Naming a method with `checked by` makes both
+/// kinds of import call it with the block first, and only forward blocks it returns true for.
+/// A wrapper that knows the genesis block better than the client it wraps can name the field to
+/// take it from with `genesis from`. The generic parameters must include `C`, `SM` and `I`, for
+/// the consensus engine, the state machine and the wrapped client.
+macro_rules! forward_client_api {
+    (
+        impl<$($param:ident),*> for $wrapper:ty
+        $(, checked by $check:ident)?
+        $(, genesis from $genesis:ident)?
+        where $($bounds:tt)*
+    ) => {
+        impl<$($param),*> $crate::c4_client::ImportBlock<C, SM> for $wrapper
+        where
+            $($bounds)*,
+            I: $crate::c4_client::ImportBlock<C, SM>,
+        {
+            fn import_block(&mut self, block: $crate::c4_client::Block<C, SM>) -> bool {
+                $(self.$check(&block) &&)? self.client.import_block(block)
+            }
+
+            fn get_block(&self, block_hash: u64) -> Option<$crate::c4_client::Block<C, SM>> {
+                self.client.get_block(block_hash)
+            }
+
+            fn get_state(&self, block_hash: u64) -> Option<SM::State> {
+                self.client.get_state(block_hash)
+            }
+
+            fn is_leaf(&self, block_hash: u64) -> Option<bool> {
+                self.client.is_leaf(block_hash)
+            }
+
+            fn all_leaves(&self) -> Vec<u64> {
+                self.client.all_leaves()
+            }
+        }
+
+        impl<$($param),*> $crate::c4_client::ClientApi<C, SM> for $wrapper
+        where
+            $($bounds)*,
+            I: $crate::c4_client::ClientApi<C, SM>,
+        {
+            $crate::c4_client::forward_client_api!(@genesis $($genesis)?);
+
+            fn best_block(&self) -> u64 {
+                self.client.best_block()
+            }
+
+            fn import_block_with_state(
+                &mut self,
+                block: $crate::c4_client::Block<C, SM>,
+                state: Option<SM::State>,
+            ) -> bool {
+                $(self.$check(&block) &&)? self.client.import_block_with_state(block, state)
+            }
+
+            fn manually_finalize_block(&mut self, block_hash: u64) -> bool {
+                self.client.manually_finalize_block(block_hash)
+            }
+
+            fn finalized_block(&self) -> u64 {
+                self.client.finalized_block()
+            }
+        }
+    };
+    (@genesis) => {
+        fn genesis(&self) -> u64 {
+            self.client.genesis()
+        }
+    };
+    (@genesis $field:ident) => {
+        fn genesis(&self) -> u64 {
+            self.$field
+        }
+    };
+}
+pub(crate) use forward_client_api;
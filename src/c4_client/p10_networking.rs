@@ -0,0 +1,848 @@
+//! In the previous section we simulated a network inside a single process. Now we will connect
+//! real nodes to each other over TCP. Each node listens on a socket, dials the bootnodes it was
+//! configured with, and learns about further peers from the peers it connects to.
+//!
+//! Everything that crosses the wire is a frame: a four byte little endian length followed by that
+//! many bytes of payload. The payload is a `WireMessage` encoded with our own codec.
+//!
+//! When two nodes connect, each immediately sends a handshake containing its genesis hash and its
+//! current best block. Nodes on different chains (different genesis hashes) disconnect right away.
+//! After the handshake, nodes exchange the following messages:
+//! * Announce - A node tells its peers about a block it just imported.
+//! * GetBlock / Block - A peer that does not know an announced block asks for it. If the block's
+//!   parent is also unknown, the peer keeps asking for parents until it reaches a block it knows.
+//! * Transaction - Transactions are forwarded to every peer the first time a node sees them.
+//! * GetPeers / Peers - Nodes share the addresses of their peers so the network can grow beyond
+//!   the bootnodes.
+//!
+//! Peers are identified by their connection rather than by the listen address they announce in
+//! their handshake, since anyone can claim any address. The address is only used to tell other
+//! nodes where the peer can be reached, and to avoid dialing a peer we are already connected to.
+//!
+//! Each connection gets its own reading thread and writing thread. All of the threads of one node
+//! share that node's state behind a single mutex. Outgoing frames are handed to the writing thread
+//! through a channel, so no thread ever blocks on the network while holding the lock.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::p9_network_simulator::SimNode;
+use crate::c3_consensus::p10_timestamps::{SystemTimeSource, TimeSource};
+use crate::codec::{Decode, DecodeError, Encode};
+
+type Hash = u64;
+
+/// Frames larger than this are rejected, so that a misbehaving peer cannot make us allocate
+/// arbitrary amounts of memory.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long we wait for a peer to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait when dialing a peer.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many blocks we keep while waiting for their parents. Beyond this the oldest are dropped,
+/// and will be fetched again if they are announced again.
+const MAX_ORPHANS: usize = 1024;
+
+/// Write a single frame containing the given payload.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame too large",
+        ));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Read a single frame and return its payload.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// The first message each side sends on a new connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// The hash of the genesis block. Peers must agree on it.
+    pub genesis: Hash,
+    /// The sender's best block.
+    pub best: Hash,
+    /// The height of the sender's best block.
+    pub best_height: u64,
+    /// The address on which the sender accepts connections.
+    pub listen_address: String,
+}
+
+/// Every message that can be sent between nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WireMessage<B, T> {
+    Handshake(Handshake),
+    /// The sender has imported a new block.
+    Announce {
+        hash: Hash,
+        height: u64,
+    },
+    /// Ask the receiver for the block with the given hash.
+    GetBlock(Hash),
+    Block(B),
+    Transaction(T),
+    /// Ask the receiver for the addresses of its peers.
+    GetPeers,
+    Peers(Vec<String>),
+}
+
+impl<B: Encode, T: Encode> Encode for WireMessage<B, T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            WireMessage::Handshake(h) => {
+                0u8.encode_to(out);
+                (h.genesis, h.best, h.best_height).encode_to(out);
+                h.listen_address.encode_to(out);
+            }
+            WireMessage::Announce { hash, height } => {
+                1u8.encode_to(out);
+                (*hash, *height).encode_to(out);
+            }
+            WireMessage::GetBlock(hash) => {
+                2u8.encode_to(out);
+                hash.encode_to(out);
+            }
+            WireMessage::Block(block) => {
+                3u8.encode_to(out);
+                block.encode_to(out);
+            }
+            WireMessage::Transaction(t) => {
+                4u8.encode_to(out);
+                t.encode_to(out);
+            }
+            WireMessage::GetPeers => 5u8.encode_to(out),
+            WireMessage::Peers(peers) => {
+                6u8.encode_to(out);
+                peers.encode_to(out);
+            }
+        }
+    }
+}
+
+impl<B: Decode, T: Decode> Decode for WireMessage<B, T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(match u8::decode_from(input)? {
+            0 => {
+                let (genesis, best, best_height) = Decode::decode_from(input)?;
+                WireMessage::Handshake(Handshake {
+                    genesis,
+                    best,
+                    best_height,
+                    listen_address: String::decode_from(input)?,
+                })
+            }
+            1 => {
+                let (hash, height) = Decode::decode_from(input)?;
+                WireMessage::Announce { hash, height }
+            }
+            2 => WireMessage::GetBlock(Hash::decode_from(input)?),
+            3 => WireMessage::Block(B::decode_from(input)?),
+            4 => WireMessage::Transaction(T::decode_from(input)?),
+            5 => WireMessage::GetPeers,
+            6 => WireMessage::Peers(Vec::decode_from(input)?),
+            _ => return Err(DecodeError::Invalid("wire message")),
+        })
+    }
+}
+
+/// Where a node listens and who it initially connects to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// The address to accept connections on. Use port 0 to let the OS pick a free port.
+    pub listen_address: SocketAddr,
+    /// Nodes to connect to on startup.
+    pub bootnodes: Vec<SocketAddr>,
+}
+
+/// What we know about a connected peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The address on which the peer accepts connections. For peers we dialed this is the address
+    /// we dialed, otherwise it is the address the peer announced in its handshake.
+    pub address: SocketAddr,
+    /// The peer's best block, as of its handshake or latest announcement.
+    pub best: Hash,
+    /// The height of the peer's best block.
+    pub best_height: u64,
+}
+
+/// A live connection to a peer.
+struct Peer {
+    info: PeerInfo,
+    /// Whether we dialed the peer, as opposed to the peer dialing us.
+    outbound: bool,
+    /// Frames sent here are written to the peer by the connection's writing thread.
+    frames: Sender<Vec<u8>>,
+    /// Kept so the connection can be closed from outside its threads.
+    stream: TcpStream,
+}
+
+/// The state shared by all of a node's threads.
+struct Inner<N: SimNode> {
+    node: N,
+    genesis: Hash,
+    local_address: SocketAddr,
+    /// Connected peers, keyed by connection id.
+    peers: BTreeMap<u64, Peer>,
+    /// Addresses we are currently trying to connect to.
+    dialing: HashSet<SocketAddr>,
+    /// Blocks we received before their parents.
+    orphans: Vec<N::Block>,
+    next_connection: u64,
+    shutting_down: bool,
+}
+
+impl<N: SimNode> Inner<N>
+where
+    N::Block: Encode,
+    N::Transaction: Encode,
+{
+    fn send(&self, to: u64, message: &WireMessage<N::Block, N::Transaction>) {
+        if let Some(peer) = self.peers.get(&to) {
+            // If the writing thread is gone the connection is closing anyway.
+            let _ = peer.frames.send(message.encode());
+        }
+    }
+
+    fn broadcast(&self, except: Option<u64>, message: &WireMessage<N::Block, N::Transaction>) {
+        let frame = message.encode();
+        for (connection, peer) in &self.peers {
+            if Some(*connection) != except {
+                let _ = peer.frames.send(frame.clone());
+            }
+        }
+    }
+
+    fn handshake(&self) -> Handshake {
+        let best = self.node.best_block();
+        Handshake {
+            genesis: self.genesis,
+            best,
+            best_height: self.node.block_height(best).unwrap_or(0),
+            listen_address: self.local_address.to_string(),
+        }
+    }
+
+    /// Import a block received from a peer, along with any orphans that were waiting for it.
+    fn receive_block(&mut self, from: u64, block: N::Block) {
+        let block_hash = N::hash_of(&block);
+        if self.node.block_height(block_hash).is_some() {
+            return;
+        }
+        let parent = N::parent_of(&block);
+        if self.node.block_height(parent).is_none() {
+            if !self.orphans.iter().any(|o| N::hash_of(o) == block_hash) {
+                if self.orphans.len() == MAX_ORPHANS {
+                    self.orphans.remove(0);
+                }
+                self.orphans.push(block);
+                self.send(from, &WireMessage::GetBlock(parent));
+            }
+            return;
+        }
+        if !self.node.import_block(block) {
+            return;
+        }
+        let height = self.node.block_height(block_hash).unwrap_or(0);
+        self.broadcast(
+            Some(from),
+            &WireMessage::Announce {
+                hash: block_hash,
+                height,
+            },
+        );
+
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.orphans)
+            .into_iter()
+            .partition(|orphan| N::parent_of(orphan) == block_hash);
+        self.orphans = waiting;
+        for orphan in ready {
+            self.receive_block(from, orphan);
+        }
+    }
+}
+
+/// A node connected to other nodes over TCP.
+pub struct NetworkNode<N: SimNode> {
+    inner: Arc<Mutex<Inner<N>>>,
+    local_address: SocketAddr,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl<N> NetworkNode<N>
+where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    /// Start listening for connections and dial the configured bootnodes.
+    pub fn start(node: N, config: NetworkConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(config.listen_address)?;
+        let local_address = listener.local_addr()?;
+        let inner = Arc::new(Mutex::new(Inner {
+            genesis: genesis_of(&node),
+            node,
+            local_address,
+            peers: BTreeMap::new(),
+            dialing: HashSet::new(),
+            orphans: Vec::new(),
+            next_connection: 0,
+            shutting_down: false,
+        }));
+
+        let accepting = inner.clone();
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.lock().unwrap().shutting_down {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let inner = accepting.clone();
+                    thread::spawn(move || run_connection(inner, stream, false));
+                }
+            }
+        });
+
+        for bootnode in config.bootnodes {
+            dial(&inner, bootnode);
+        }
+
+        Ok(Self {
+            inner,
+            local_address,
+            listener: Some(listener),
+        })
+    }
+
+    /// The address this node accepts connections on.
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// The node's current best block.
+    pub fn best_block(&self) -> Hash {
+        self.inner.lock().unwrap().node.best_block()
+    }
+
+    /// The peers this node is currently connected to.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.values().map(|p| p.info.clone()).collect()
+    }
+
+    /// Inspect the underlying node.
+    pub fn with_node<R>(&self, f: impl FnOnce(&N) -> R) -> R {
+        f(&self.inner.lock().unwrap().node)
+    }
+
+    /// Submit a transaction to this node and propagate it to the network.
+    pub fn submit_transaction(&self, t: N::Transaction) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.node.submit_transaction(t.clone()) {
+            inner.broadcast(None, &WireMessage::Transaction(t));
+        }
+    }
+
+    /// Author a block on this node and announce it to the network.
+    pub fn author_block(&self) -> Option<Hash> {
+        let mut inner = self.inner.lock().unwrap();
+        let block = inner.node.author_block(SystemTimeSource.now())?;
+        let hash = N::hash_of(&block);
+        let height = inner.node.block_height(hash).unwrap_or(0);
+        inner.broadcast(None, &WireMessage::Announce { hash, height });
+        Some(hash)
+    }
+
+    /// Disconnect from all peers and stop accepting connections.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+}
+
+impl<N: SimNode> NetworkNode<N> {
+    fn stop(&mut self) {
+        let Some(listener) = self.listener.take() else {
+            return;
+        };
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.shutting_down = true;
+            for peer in std::mem::take(&mut inner.peers).into_values() {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+            }
+        }
+        // The listening thread only notices the flag when a connection arrives, so make one.
+        let _ = TcpStream::connect_timeout(&self.local_address, DIAL_TIMEOUT);
+        let _ = listener.join();
+    }
+}
+
+impl<N: SimNode> Drop for NetworkNode<N> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Find the genesis block by walking back from the best block.
+fn genesis_of<N: SimNode>(node: &N) -> Hash {
+    let mut current = node.best_block();
+    while let Some(parent) = node.block_parent(current) {
+        if node.block_height(parent).is_none() {
+            break;
+        }
+        current = parent;
+    }
+    current
+}
+
+/// Connect to the given address in the background, unless we already are or are trying to.
+fn dial<N>(inner: &Arc<Mutex<Inner<N>>>, address: SocketAddr)
+where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    {
+        let mut state = inner.lock().unwrap();
+        if state.shutting_down
+            || address == state.local_address
+            || state.peers.values().any(|p| p.info.address == address)
+            || !state.dialing.insert(address)
+        {
+            return;
+        }
+    }
+    let inner = inner.clone();
+    thread::spawn(move || {
+        if let Ok(stream) = TcpStream::connect_timeout(&address, DIAL_TIMEOUT) {
+            run_connection(inner.clone(), stream, true);
+        }
+        inner.lock().unwrap().dialing.remove(&address);
+    });
+}
+
+/// Perform the handshake on a new connection, then handle its messages until it closes.
+fn run_connection<N>(inner: Arc<Mutex<Inner<N>>>, mut stream: TcpStream, outbound: bool)
+where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    let ours = inner.lock().unwrap().handshake();
+    let theirs = (|| {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        write_frame(
+            &mut stream,
+            &WireMessage::<N::Block, N::Transaction>::Handshake(ours.clone()).encode(),
+        )?;
+        let frame = read_frame(&mut stream)?;
+        stream.set_read_timeout(None)?;
+        match WireMessage::<N::Block, N::Transaction>::decode(&frame) {
+            Ok(WireMessage::Handshake(theirs)) => Ok(theirs),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected handshake",
+            )),
+        }
+    })();
+    let Ok(theirs) = theirs else {
+        return;
+    };
+    // We only trust the announced address as far as we can check it.
+    let address = if outbound {
+        stream.peer_addr()
+    } else {
+        theirs
+            .listen_address
+            .parse::<SocketAddr>()
+            .map_err(|_| io::ErrorKind::InvalidData.into())
+    };
+    let Ok(address) = address else {
+        return;
+    };
+    if theirs.genesis != ours.genesis || address.to_string() == ours.listen_address {
+        return;
+    }
+
+    let Some(connection) = register_peer(&inner, &stream, address, outbound, &theirs) else {
+        return;
+    };
+
+    let mut reader = stream;
+    while let Ok(frame) = read_frame(&mut reader) {
+        let Ok(message) = WireMessage::decode(&frame) else {
+            break;
+        };
+        handle_message(&inner, connection, message);
+    }
+
+    inner.lock().unwrap().peers.remove(&connection);
+}
+
+/// Add a peer that completed the handshake, and start its writing thread. Returns the id of the
+/// connection, or None if the connection should be dropped.
+fn register_peer<N>(
+    inner: &Arc<Mutex<Inner<N>>>,
+    stream: &TcpStream,
+    address: SocketAddr,
+    outbound: bool,
+    handshake: &Handshake,
+) -> Option<u64>
+where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    let mut state = inner.lock().unwrap();
+    if state.shutting_down {
+        return None;
+    }
+
+    // If both nodes dial each other at the same time we end up with two connections. The node with
+    // the lower address closes the one the other node dialed, and the other node sees it close.
+    // We only do this once we have dialed the address ourselves, so announcing someone else's
+    // address never gets their connection closed.
+    let dialed = |peer: &Peer| peer.outbound && peer.info.address == address;
+    if state.local_address < address && (outbound || state.peers.values().any(dialed)) {
+        if !outbound {
+            return None;
+        }
+        for peer in state.peers.values() {
+            if !peer.outbound && peer.info.address == address {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    let (frames, outgoing) = mpsc::channel::<Vec<u8>>();
+    let mut writer = stream.try_clone().ok()?;
+    thread::spawn(move || {
+        for frame in outgoing {
+            if write_frame(&mut writer, &frame).is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let connection = state.next_connection;
+    state.next_connection += 1;
+    state.peers.insert(
+        connection,
+        Peer {
+            info: PeerInfo {
+                address,
+                best: handshake.best,
+                best_height: handshake.best_height,
+            },
+            outbound,
+            frames,
+            stream: stream.try_clone().ok()?,
+        },
+    );
+
+    state.send(connection, &WireMessage::GetPeers);
+    if state.node.block_height(handshake.best).is_none() {
+        state.send(connection, &WireMessage::GetBlock(handshake.best));
+    }
+    Some(connection)
+}
+
+/// React to a single message from a connected peer.
+fn handle_message<N>(
+    inner: &Arc<Mutex<Inner<N>>>,
+    from: u64,
+    message: WireMessage<N::Block, N::Transaction>,
+) where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    let mut state = inner.lock().unwrap();
+    match message {
+        WireMessage::Handshake(_) => {}
+        WireMessage::Announce { hash, height } => {
+            if let Some(peer) = state.peers.get_mut(&from) {
+                if height > peer.info.best_height {
+                    peer.info.best = hash;
+                    peer.info.best_height = height;
+                }
+            }
+            if state.node.block_height(hash).is_none() {
+                state.send(from, &WireMessage::GetBlock(hash));
+            }
+        }
+        WireMessage::GetBlock(hash) => {
+            if let Some(block) = state.node.get_block(hash) {
+                state.send(from, &WireMessage::Block(block));
+            }
+        }
+        WireMessage::Block(block) => state.receive_block(from, block),
+        WireMessage::Transaction(t) => {
+            if state.node.submit_transaction(t.clone()) {
+                state.broadcast(Some(from), &WireMessage::Transaction(t));
+            }
+        }
+        WireMessage::GetPeers => {
+            let peers: BTreeSet<_> = state
+                .peers
+                .iter()
+                .filter(|(connection, _)| **connection != from)
+                .map(|(_, peer)| peer.info.address.to_string())
+                .collect();
+            let peers = peers.into_iter().collect();
+            state.send(from, &WireMessage::Peers(peers));
+        }
+        WireMessage::Peers(addresses) => {
+            drop(state);
+            for address in addresses.iter().filter_map(|a| a.parse().ok()) {
+                dial(inner, address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::test_support::{test_chain, test_client, TestBlock};
+#[cfg(test)]
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, User};
+
+#[cfg(test)]
+fn mint(amount: u64) -> AccountingTransaction {
+    AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    }
+}
+
+#[cfg(test)]
+fn start_node<N>(node: N, bootnodes: Vec<SocketAddr>) -> NetworkNode<N>
+where
+    N: SimNode + Send + 'static,
+    N::Block: Encode + Decode + Send,
+    N::Transaction: Encode + Decode + Send,
+{
+    let config = NetworkConfig {
+        listen_address: "127.0.0.1:0".parse().unwrap(),
+        bootnodes,
+    };
+    NetworkNode::start(node, config).unwrap()
+}
+
+/// Poll the condition for a while, returning whether it ever held.
+#[cfg(test)]
+fn eventually(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..400 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(25));
+    }
+    false
+}
+
+#[test]
+fn client_10_frames_round_trip() {
+    let mut buffer = Vec::new();
+    write_frame(&mut buffer, b"hello").unwrap();
+    write_frame(&mut buffer, b"").unwrap();
+    assert_eq!(&buffer[..4], &5u32.to_le_bytes());
+
+    let mut reader = &buffer[..];
+    assert_eq!(read_frame(&mut reader).unwrap(), b"hello");
+    assert_eq!(read_frame(&mut reader).unwrap(), b"");
+    assert_eq!(
+        read_frame(&mut reader).unwrap_err().kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn client_10_oversized_frame_rejected() {
+    let header = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes();
+    let error = read_frame(&mut &header[..]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn client_10_wire_messages_round_trip() {
+    let block = test_chain(&[], 1, 0).remove(1);
+    let messages: Vec<WireMessage<TestBlock, AccountingTransaction>> = vec![
+        WireMessage::Handshake(Handshake {
+            genesis: 1,
+            best: 2,
+            best_height: 3,
+            listen_address: "127.0.0.1:30333".into(),
+        }),
+        WireMessage::Announce { hash: 9, height: 8 },
+        WireMessage::GetBlock(7),
+        WireMessage::Block(block),
+        WireMessage::Transaction(mint(6)),
+        WireMessage::GetPeers,
+        WireMessage::Peers(vec!["127.0.0.1:1".into(), "127.0.0.1:2".into()]),
+    ];
+    for message in messages {
+        assert_eq!(WireMessage::decode(&message.encode()), Ok(message));
+    }
+    assert!(WireMessage::<TestBlock, AccountingTransaction>::decode(&[42]).is_err());
+}
+
+#[test]
+fn client_10_nodes_sync_existing_chain_on_connect() {
+    let mut first = test_client();
+    for _ in 0..3 {
+        SimNode::author_block(&mut first, 0);
+    }
+    let expected = first.best_block();
+
+    let a = start_node(first, vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+
+    assert!(eventually(|| b.best_block() == expected));
+    assert_eq!(a.peers().len(), 1);
+    assert_eq!(b.peers()[0].address, a.local_address());
+    assert_eq!(b.peers()[0].best, expected);
+}
+
+#[test]
+fn client_10_peers_are_discovered_through_bootnodes() {
+    let a = start_node(test_client(), vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+    assert!(eventually(|| a.peers().len() == 1));
+    let c = start_node(test_client(), vec![b.local_address()]);
+
+    // C only knew about B, but learns about A from B.
+    assert!(eventually(|| [&a, &b, &c]
+        .iter()
+        .all(|n| n.peers().len() == 2)));
+}
+
+#[test]
+fn client_10_blocks_and_transactions_propagate() {
+    let a = start_node(test_client(), vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+    let c = start_node(test_client(), vec![b.local_address()]);
+    let nodes = [&a, &b, &c];
+    assert!(eventually(|| nodes.iter().all(|n| n.peers().len() == 2)));
+
+    c.submit_transaction(mint(42));
+    assert!(eventually(|| nodes
+        .iter()
+        .all(|n| n.with_node(|m| m.pool_contains(mint(42))))));
+
+    let hash = a.author_block().unwrap();
+    let b_hash = b.author_block().unwrap();
+    assert!(eventually(|| nodes.iter().all(|n| n.with_node(|m| {
+        m.get_block(hash).is_some() && m.get_block(b_hash).is_some()
+    }))));
+    c.author_block();
+
+    let best = c.best_block();
+    assert!(eventually(|| nodes.iter().all(|n| n.best_block() == best)));
+    assert!(nodes.iter().all(|n| n.with_node(|m| m.pool_size() == 0)));
+}
+
+#[test]
+fn client_10_peer_on_other_chain_is_rejected() {
+    let a = start_node(test_client(), vec![]);
+
+    let mut stream = TcpStream::connect(a.local_address()).unwrap();
+    let handshake = WireMessage::<TestBlock, AccountingTransaction>::Handshake(Handshake {
+        genesis: 12345,
+        best: 12345,
+        best_height: 0,
+        listen_address: "127.0.0.1:1".into(),
+    });
+    write_frame(&mut stream, &handshake.encode()).unwrap();
+
+    // We receive A's handshake, then A hangs up on us.
+    let frame = read_frame(&mut stream).unwrap();
+    assert!(matches!(
+        WireMessage::<TestBlock, AccountingTransaction>::decode(&frame),
+        Ok(WireMessage::Handshake(_))
+    ));
+    assert!(read_frame(&mut stream).is_err());
+    assert!(a.peers().is_empty());
+}
+
+#[test]
+fn client_10_shutdown_disconnects_peers() {
+    let a = start_node(test_client(), vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+    assert!(eventually(|| a.peers().len() == 1 && b.peers().len() == 1));
+
+    a.shutdown();
+    assert!(eventually(|| b.peers().is_empty()));
+}
+
+#[test]
+fn client_10_announced_address_does_not_replace_a_peer() {
+    let a = start_node(test_client(), vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+    assert!(eventually(|| a.peers().len() == 1));
+
+    // An impostor claims to listen on B's address.
+    let mut stream = TcpStream::connect(a.local_address()).unwrap();
+    let handshake = WireMessage::<TestBlock, AccountingTransaction>::Handshake(Handshake {
+        genesis: a.best_block(),
+        best: a.best_block(),
+        best_height: 0,
+        listen_address: b.local_address().to_string(),
+    });
+    write_frame(&mut stream, &handshake.encode()).unwrap();
+    assert!(eventually(|| a.peers().len() == 2));
+
+    // B is still connected, so its blocks still reach A.
+    let hash = b.author_block().unwrap();
+    assert!(eventually(|| a.best_block() == hash));
+    assert_eq!(b.peers().len(), 1);
+}
+
+#[test]
+fn client_10_full_clients_sync_over_tcp() {
+    let a = start_node(test_client(), vec![]);
+    let b = start_node(test_client(), vec![a.local_address()]);
+    assert!(eventually(|| a.peers().len() == 1));
+
+    b.submit_transaction(mint(10));
+    assert!(eventually(
+        || a.with_node(|client| client.pool_contains(mint(10)))
+    ));
+    let hash = a.author_block().unwrap();
+
+    assert!(eventually(|| b.best_block() == hash));
+    let block = b
+        .with_node(|client| SimNode::get_block(client, hash))
+        .unwrap();
+    assert_eq!(block.body, vec![mint(10)]);
+    assert!(b.with_node(|client| client.pool_size() == 0));
+}
@@ -0,0 +1,782 @@
+//! A freshly started client only knows the genesis block. Before it can do anything useful it must
+//! catch up with the rest of the network. The simplest approach, asking peers for complete blocks
+//! one at a time, is slow and wasteful: a malicious peer can make us download and execute lots of
+//! bodies before we realize its chain is invalid.
+//!
+//! Instead, real clients usually sync "headers first". Headers are small and can be verified
+//! cheaply with only the consensus engine, so we first download the header chain from the best peer
+//! in large batches and check it with `Consensus::verify_sub_chain`. Only once we know the headers
+//! are valid do we download the bodies. Because we already know exactly which bodies we need, we
+//! can download them in parallel batches from several peers at once, and then execute them in order.
+//!
+//! Several things can go wrong along the way:
+//! * Fork discovered mid-sync - The peer re-orgs while we are downloading its headers, so a new
+//!   batch no longer builds on the previous one. We walk back through the peer's new chain by hash
+//!   until we find a header we already know, and continue from there.
+//! * Bad peers - A peer may serve headers that fail consensus checks, or bodies that do not match
+//!   their headers' extrinsics roots. Such peers are banned and the data is requested from someone
+//!   else. If a body matches its header but the block still fails to import, the header itself is
+//!   bad, so the peer that served that header is banned instead. Headers may come from several
+//!   peers over time, so we remember which peer served each one.
+//! * Interruption - Peers may disappear at any time. All verified headers and downloaded bodies are
+//!   kept, so calling `sync` again resumes where the last attempt left off.
+//!
+//! The sync logic is written against the `SyncPeer` trait, which makes it easy to test with mock
+//! peers in a single process.
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    marker::PhantomData,
+    thread,
+};
+
+use super::{p2_importing_blocks::ImportBlock, Block, Consensus, Header, StateMachine};
+use crate::c2_blockchain::p7_merkle_tree::merkle_root;
+use crate::hash;
+
+type Hash = u64;
+
+/// Identifies a peer by its index in the slice of peers given to the syncer.
+pub type PeerId = usize;
+
+/// The requests a peer must be able to answer in order for us to sync from it.
+///
+/// Every method returns None if the peer is unavailable, for example because it disconnected.
+pub trait SyncPeer<C: Consensus, SM: StateMachine> {
+    /// The hash and height of the peer's best block.
+    fn best_block(&self) -> Option<(Hash, u64)>;
+
+    /// Up to `max` consecutive headers from the peer's best chain, starting at the given height.
+    fn headers_by_height(&self, from: u64, max: usize) -> Option<Vec<Header<C::Digest>>>;
+
+    /// The header with the given hash, preceded by up to `max - 1` of its ancestors, in
+    /// ascending order.
+    fn headers_by_hash(&self, hash: Hash, max: usize) -> Option<Vec<Header<C::Digest>>>;
+
+    /// The bodies of the blocks with the given hashes, in the same order.
+    fn bodies(&self, hashes: &[Hash]) -> Option<Vec<Vec<SM::Transition>>>;
+}
+
+/// Tuning knobs for the sync process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncConfig {
+    /// The number of headers to request at a time.
+    pub header_batch: usize,
+    /// The number of bodies to request at a time.
+    pub body_batch: usize,
+    /// The number of body requests to have in flight at once.
+    pub max_parallel_requests: usize,
+    /// How many times we may ask a peer for older headers while looking for a common ancestor.
+    pub max_ancestor_requests: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            header_batch: 128,
+            body_batch: 16,
+            max_parallel_requests: 4,
+            max_ancestor_requests: 16,
+        }
+    }
+}
+
+/// What happened during a call to `sync`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// The number of headers downloaded and verified.
+    pub headers_downloaded: usize,
+    /// The number of blocks executed and imported into the client.
+    pub blocks_imported: usize,
+    /// Peers that were banned during this call.
+    pub banned: Vec<PeerId>,
+}
+
+/// The reasons sync may not complete.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncError {
+    /// We have verified headers whose bodies no available peer could provide. Calling `sync`
+    /// again later resumes from where we left off.
+    Stalled { remaining_headers: usize },
+}
+
+/// Why a request to a peer did not succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PeerFault {
+    /// The peer did not answer. It may come back later.
+    Unavailable,
+    /// The peer answered with invalid data.
+    Misbehaved,
+}
+
+/// Syncs a client with its peers by downloading headers first and bodies second.
+pub struct HeadersFirstSync<C: Consensus, SM: StateMachine> {
+    consensus: C,
+    config: SyncConfig,
+    /// Verified headers whose blocks have not been imported yet, in ascending order, and which
+    /// peer served them. The first one's parent is always known to the client.
+    pending: VecDeque<(Header<C::Digest>, PeerId)>,
+    /// Downloaded bodies that have not been imported yet, and which peer served them.
+    bodies: HashMap<Hash, (Vec<SM::Transition>, PeerId)>,
+    banned: BTreeSet<PeerId>,
+    _state_machine: PhantomData<SM>,
+}
+
+impl<C, SM> HeadersFirstSync<C, SM>
+where
+    C: Consensus,
+    C::Digest: Send,
+    SM: StateMachine,
+    SM::Transition: std::hash::Hash + Clone + Send,
+{
+    pub fn new(consensus: C, config: SyncConfig) -> Self {
+        Self {
+            consensus,
+            config,
+            pending: VecDeque::new(),
+            bodies: HashMap::new(),
+            banned: BTreeSet::new(),
+            _state_machine: PhantomData,
+        }
+    }
+
+    /// All peers that have been banned for serving bad data.
+    pub fn banned_peers(&self) -> &BTreeSet<PeerId> {
+        &self.banned
+    }
+
+    /// The number of verified headers whose blocks are not imported yet.
+    pub fn pending_headers(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bring the client up to date with the best of the given peers.
+    pub fn sync<I, P>(&mut self, client: &mut I, peers: &[P]) -> Result<SyncReport, SyncError>
+    where
+        I: ImportBlock<C, SM>,
+        P: SyncPeer<C, SM> + Sync,
+    {
+        let mut report = SyncReport::default();
+        let mut unavailable = BTreeSet::new();
+
+        loop {
+            // Pick the usable peer with the highest best block above our own tip.
+            let (_, tip_height) = self.tip(client);
+            let mut target = None;
+            for (id, peer) in peers.iter().enumerate() {
+                if self.banned.contains(&id) || unavailable.contains(&id) {
+                    continue;
+                }
+                match peer.best_block() {
+                    None => {
+                        unavailable.insert(id);
+                    }
+                    Some((_, height)) if height > tip_height => {
+                        if target.is_none_or(|(_, best)| height > best) {
+                            target = Some((id, height));
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            let Some((id, _)) = target else {
+                break;
+            };
+
+            match self.download_headers(client, &peers[id], id) {
+                Ok(downloaded) => report.headers_downloaded += downloaded,
+                Err(PeerFault::Unavailable) => {
+                    unavailable.insert(id);
+                }
+                Err(PeerFault::Misbehaved) => self.ban(id, &mut report),
+            }
+            self.download_bodies(client, peers, &mut unavailable, &mut report);
+        }
+        self.download_bodies(client, peers, &mut unavailable, &mut report);
+
+        if self.pending.is_empty() {
+            Ok(report)
+        } else {
+            Err(SyncError::Stalled {
+                remaining_headers: self.pending.len(),
+            })
+        }
+    }
+
+    /// The hash and height of the highest header we know of, pending or imported.
+    fn tip<I: ImportBlock<C, SM>>(&self, client: &I) -> (Hash, u64) {
+        if let Some((header, _)) = self.pending.back() {
+            return (hash(header), header.height);
+        }
+        client
+            .all_leaves()
+            .into_iter()
+            .filter_map(|leaf| client.get_block(leaf).map(|b| (leaf, b.header.height)))
+            .max_by_key(|(leaf, height)| (*height, std::cmp::Reverse(*leaf)))
+            .unwrap_or((0, 0))
+    }
+
+    /// Look up a header among the pending headers and the client's blocks.
+    fn known_header<I: ImportBlock<C, SM>>(
+        &self,
+        client: &I,
+        block_hash: Hash,
+    ) -> Option<Header<C::Digest>> {
+        self.pending
+            .iter()
+            .find(|(h, _)| hash(h) == block_hash)
+            .map(|(h, _)| h.clone())
+            .or_else(|| client.get_block(block_hash).map(|b| b.header))
+    }
+
+    fn ban(&mut self, id: PeerId, report: &mut SyncReport) {
+        if self.banned.insert(id) {
+            report.banned.push(id);
+        }
+        self.bodies.retain(|_, (_, server)| *server != id);
+    }
+
+    /// Download and verify headers from the peer until we reach its best block.
+    fn download_headers<I, P>(
+        &mut self,
+        client: &I,
+        peer: &P,
+        id: PeerId,
+    ) -> Result<usize, PeerFault>
+    where
+        I: ImportBlock<C, SM>,
+        P: SyncPeer<C, SM>,
+    {
+        let (_, peer_height) = peer.best_block().ok_or(PeerFault::Unavailable)?;
+        let mut downloaded = 0;
+        loop {
+            let (_, tip_height) = self.tip(client);
+            if tip_height >= peer_height {
+                return Ok(downloaded);
+            }
+            let max = self
+                .config
+                .header_batch
+                .min((peer_height - tip_height) as usize);
+            let batch = peer
+                .headers_by_height(tip_height + 1, max)
+                .ok_or(PeerFault::Unavailable)?;
+            if batch.is_empty() || batch.len() > max || batch[0].height != tip_height + 1 {
+                return Err(PeerFault::Misbehaved);
+            }
+
+            let segment = self.connect(client, peer, batch)?;
+            downloaded += segment.len();
+            self.append(client, segment, id)?;
+        }
+    }
+
+    /// Extend a segment of headers backwards by asking the peer for ancestors, until the first
+    /// header's parent is one we already know. This is how we discover that the peer is on a
+    /// different fork than our pending headers.
+    fn connect<I, P>(
+        &self,
+        client: &I,
+        peer: &P,
+        mut segment: Vec<Header<C::Digest>>,
+    ) -> Result<Vec<Header<C::Digest>>, PeerFault>
+    where
+        I: ImportBlock<C, SM>,
+        P: SyncPeer<C, SM>,
+    {
+        for _ in 0..=self.config.max_ancestor_requests {
+            let parent = segment[0].parent;
+            if self.known_header(client, parent).is_some() {
+                return Ok(segment);
+            }
+            let ancestors = peer
+                .headers_by_hash(parent, self.config.header_batch)
+                .ok_or(PeerFault::Unavailable)?;
+            match ancestors.last() {
+                Some(last) if hash(last) == parent && last.height + 1 == segment[0].height => {}
+                _ => return Err(PeerFault::Misbehaved),
+            }
+            // Only keep the ancestors above the most recent one we already know.
+            let known = ancestors
+                .iter()
+                .rposition(|h| self.known_header(client, hash(h)).is_some())
+                .map_or(0, |index| index + 1);
+            segment.splice(0..0, ancestors.into_iter().skip(known));
+        }
+        Err(PeerFault::Misbehaved)
+    }
+
+    /// Verify a segment of headers served by the given peer whose first parent is known, and add it
+    /// to the pending headers, retracting any pending headers on a different fork.
+    fn append<I: ImportBlock<C, SM>>(
+        &mut self,
+        client: &I,
+        segment: Vec<Header<C::Digest>>,
+        source: PeerId,
+    ) -> Result<(), PeerFault> {
+        let parent_hash = segment[0].parent;
+        let parent = self
+            .known_header(client, parent_hash)
+            .expect("connect only returns segments with a known parent");
+
+        let mut previous = (parent_hash, parent.height);
+        for header in &segment {
+            if header.parent != previous.0 || header.height != previous.1 + 1 {
+                return Err(PeerFault::Misbehaved);
+            }
+            previous = (hash(header), header.height);
+        }
+        if !self
+            .consensus
+            .verify_sub_chain(&parent.consensus_digest, &segment)
+        {
+            return Err(PeerFault::Misbehaved);
+        }
+
+        match self
+            .pending
+            .iter()
+            .position(|(h, _)| hash(h) == parent_hash)
+        {
+            Some(index) => self.pending.truncate(index + 1),
+            None => self.pending.clear(),
+        }
+        self.pending
+            .extend(segment.into_iter().map(|header| (header, source)));
+        Ok(())
+    }
+
+    /// Download bodies for the pending headers in parallel batches, importing blocks as soon as
+    /// their bodies are available.
+    fn download_bodies<I, P>(
+        &mut self,
+        client: &mut I,
+        peers: &[P],
+        unavailable: &mut BTreeSet<PeerId>,
+        report: &mut SyncReport,
+    ) where
+        I: ImportBlock<C, SM>,
+        P: SyncPeer<C, SM> + Sync,
+    {
+        loop {
+            self.import_ready(client, report);
+
+            let missing: Vec<Hash> = self
+                .pending
+                .iter()
+                .map(|(header, _)| hash(header))
+                .filter(|h| !self.bodies.contains_key(h))
+                .collect();
+            let usable: Vec<PeerId> = (0..peers.len())
+                .filter(|id| !self.banned.contains(id) && !unavailable.contains(id))
+                .collect();
+            if missing.is_empty() || usable.is_empty() {
+                return;
+            }
+
+            let batches: Vec<&[Hash]> = missing
+                .chunks(self.config.body_batch.max(1))
+                .take(self.config.max_parallel_requests.max(1))
+                .collect();
+            let responses: Vec<_> = thread::scope(|s| {
+                let requests: Vec<_> = batches
+                    .iter()
+                    .enumerate()
+                    .map(|(i, batch)| {
+                        let id = usable[i % usable.len()];
+                        let peer = &peers[id];
+                        (id, s.spawn(move || peer.bodies(batch)))
+                    })
+                    .collect();
+                requests
+                    .into_iter()
+                    .map(|(id, request)| (id, request.join().unwrap_or(None)))
+                    .collect()
+            });
+
+            for (batch, (id, response)) in batches.iter().zip(responses) {
+                match response {
+                    None => {
+                        unavailable.insert(id);
+                    }
+                    Some(bodies) if bodies.len() == batch.len() => {
+                        if !self.banned.contains(&id) {
+                            for (block_hash, body) in batch.iter().zip(bodies) {
+                                self.bodies.insert(*block_hash, (body, id));
+                            }
+                        }
+                    }
+                    Some(_) => self.ban(id, report),
+                }
+            }
+        }
+    }
+
+    /// Import pending blocks in order for as long as their bodies are available.
+    fn import_ready<I: ImportBlock<C, SM>>(&mut self, client: &mut I, report: &mut SyncReport) {
+        while let Some((header, source)) = self.pending.front() {
+            let block_hash = hash(header);
+            if client.get_block(block_hash).is_some() {
+                self.pending.pop_front();
+                continue;
+            }
+            let Some((body, server)) = self.bodies.remove(&block_hash) else {
+                return;
+            };
+            if merkle_root(&body) != header.extrinsics_root {
+                self.ban(server, report);
+                return;
+            }
+            let block = Block {
+                header: header.clone(),
+                body,
+            };
+            if client.import_block(block) {
+                report.blocks_imported += 1;
+                self.pending.pop_front();
+                continue;
+            }
+
+            // The body is the one the header commits to, so the header itself is bad. Every pending
+            // header after it descends from it, so they all go.
+            let source = *source;
+            self.pending.clear();
+            self.ban(source, report);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+use super::test_support::{
+    test_chain, test_client, CountingConsensus, TestBlock, TestClient, TestCurrency,
+};
+#[cfg(test)]
+use super::ClientApi;
+#[cfg(test)]
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, User};
+
+/// The height of the client's best block.
+#[cfg(test)]
+fn best_height(client: &TestClient) -> u64 {
+    client.get_block(client.best_block()).unwrap().header.height
+}
+
+/// A peer serving a fixed chain, which can be configured to misbehave.
+#[cfg(test)]
+#[derive(Default)]
+struct MockPeer {
+    /// The peer's best chain, starting at genesis.
+    chain: std::sync::Mutex<Vec<TestBlock>>,
+    /// Every block the peer has ever known, including those on abandoned forks.
+    known: std::sync::Mutex<HashMap<Hash, TestBlock>>,
+    /// After this many header requests, the peer re-orgs to this chain.
+    reorg: std::sync::Mutex<Option<(usize, Vec<TestBlock>)>>,
+    /// The peer stops answering after this many requests.
+    requests_left: std::sync::Mutex<Option<usize>>,
+    /// The peer serves bodies that do not match the headers.
+    bad_bodies: bool,
+    /// The heights from which headers were requested.
+    header_requests: std::sync::Mutex<Vec<u64>>,
+    /// The number of bodies requests served.
+    body_requests: std::sync::Mutex<usize>,
+}
+
+#[cfg(test)]
+impl MockPeer {
+    fn new(chain: Vec<TestBlock>) -> Self {
+        let peer = Self::default();
+        peer.set_chain(chain);
+        peer
+    }
+
+    fn set_chain(&self, chain: Vec<TestBlock>) {
+        let mut known = self.known.lock().unwrap();
+        for block in &chain {
+            known.insert(hash(&block.header), block.clone());
+        }
+        *self.chain.lock().unwrap() = chain;
+    }
+
+    /// Count a request, returning false if the peer is no longer answering.
+    fn answer(&self) -> bool {
+        match &mut *self.requests_left.lock().unwrap() {
+            Some(0) => false,
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+impl SyncPeer<CountingConsensus, TestCurrency> for MockPeer {
+    fn best_block(&self) -> Option<(Hash, u64)> {
+        let chain = self.chain.lock().unwrap();
+        let best = &chain.last().unwrap().header;
+        Some((hash(best), best.height))
+    }
+
+    fn headers_by_height(&self, from: u64, max: usize) -> Option<Vec<Header<u64>>> {
+        if !self.answer() {
+            return None;
+        }
+        let requests = {
+            let mut log = self.header_requests.lock().unwrap();
+            log.push(from);
+            log.len()
+        };
+        let reorg = self
+            .reorg
+            .lock()
+            .unwrap()
+            .take_if(|(after, _)| requests > *after);
+        if let Some((_, chain)) = reorg {
+            self.set_chain(chain);
+        }
+        let chain = self.chain.lock().unwrap();
+        Some(
+            chain
+                .iter()
+                .skip(from as usize)
+                .take(max)
+                .map(|b| b.header.clone())
+                .collect(),
+        )
+    }
+
+    fn headers_by_hash(&self, block_hash: Hash, max: usize) -> Option<Vec<Header<u64>>> {
+        if !self.answer() {
+            return None;
+        }
+        let known = self.known.lock().unwrap();
+        let mut headers = Vec::new();
+        let mut current = known.get(&block_hash);
+        while let Some(block) = current.filter(|_| headers.len() < max) {
+            headers.push(block.header.clone());
+            current = known.get(&block.header.parent);
+        }
+        headers.reverse();
+        Some(headers)
+    }
+
+    fn bodies(&self, hashes: &[Hash]) -> Option<Vec<Vec<AccountingTransaction>>> {
+        if !self.answer() {
+            return None;
+        }
+        *self.body_requests.lock().unwrap() += 1;
+        let known = self.known.lock().unwrap();
+        hashes
+            .iter()
+            .map(|h| {
+                let mut body = known.get(h)?.body.clone();
+                if self.bad_bodies {
+                    body.push(AccountingTransaction::Burn {
+                        burner: User::Bob,
+                        amount: 1,
+                    });
+                }
+                Some(body)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn small_batches() -> SyncConfig {
+    SyncConfig {
+        header_batch: 8,
+        body_batch: 4,
+        max_parallel_requests: 3,
+        max_ancestor_requests: 8,
+    }
+}
+
+#[test]
+fn client_11_syncs_from_genesis() {
+    let chain = test_chain(&[], 50, 0);
+    let tip = hash(&chain[50].header);
+    let peers = [MockPeer::new(chain.clone()), MockPeer::new(chain)];
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.headers_downloaded, 50);
+    assert_eq!(report.blocks_imported, 50);
+    assert!(report.banned.is_empty());
+    assert!(client.get_block(tip).is_some());
+    assert_eq!(sync.pending_headers(), 0);
+    // Bodies were downloaded from both peers.
+    assert!(peers.iter().all(|p| *p.body_requests.lock().unwrap() > 0));
+}
+
+#[test]
+fn client_11_nothing_to_do_when_up_to_date() {
+    let chain = test_chain(&[], 5, 0);
+    let peers = [MockPeer::new(chain.clone())];
+    let mut client = test_client();
+    for block in chain.into_iter().skip(1) {
+        assert!(client.import_block(block));
+    }
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    assert_eq!(sync.sync(&mut client, &peers), Ok(SyncReport::default()));
+    assert!(peers[0].header_requests.lock().unwrap().is_empty());
+}
+
+#[test]
+fn client_11_syncs_from_best_peer_onto_fork() {
+    // The client is on a short fork, and the peer has a longer chain branching at height 3.
+    let common = test_chain(&[], 3, 0);
+    let ours = test_chain(&common, 6, 1);
+    let theirs = test_chain(&common, 20, 2);
+    let peers = [MockPeer::new(ours.clone()), MockPeer::new(theirs.clone())];
+    let mut client = test_client();
+    for block in ours.into_iter().skip(1) {
+        assert!(client.import_block(block));
+    }
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.blocks_imported, 17);
+    assert!(client.get_block(hash(&theirs[20].header)).is_some());
+    assert!(report.banned.is_empty());
+}
+
+#[test]
+fn client_11_peer_with_invalid_headers_is_banned() {
+    let honest = test_chain(&[], 30, 0);
+    let mut bad = test_chain(&honest[..20], 40, 0);
+    bad[21].header.consensus_digest = 999;
+    let peers = [MockPeer::new(bad), MockPeer::new(honest.clone())];
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.banned, vec![0]);
+    assert_eq!(best_height(&client), 30);
+    assert!(client.get_block(hash(&honest[30].header)).is_some());
+}
+
+#[test]
+fn client_11_peer_with_bad_bodies_is_banned() {
+    let chain = test_chain(&[], 24, 0);
+    let mut liar = MockPeer::new(chain.clone());
+    liar.bad_bodies = true;
+    let peers = [MockPeer::new(chain.clone()), liar];
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.banned, vec![1]);
+    assert_eq!(sync.banned_peers(), &BTreeSet::from([1]));
+    assert_eq!(report.blocks_imported, 24);
+    assert!(client.get_block(hash(&chain[24].header)).is_some());
+}
+
+#[test]
+fn client_11_peer_with_bad_state_roots_is_banned() {
+    let honest = test_chain(&[], 30, 0);
+    // The liar's headers pass consensus checks and match their bodies, but the state root at
+    // height 21 is wrong, so the block cannot be imported.
+    let mut bad = test_chain(&honest[..20], 40, 1);
+    bad[21].header.state_root = 999;
+    for height in 22..=40 {
+        bad[height].header.parent = hash(&bad[height - 1].header);
+    }
+    // The honest peer has seen the liar's blocks too, so it can serve their bodies.
+    let peers = [MockPeer::new(bad.clone()), MockPeer::new(bad)];
+    peers[1].set_chain(honest.clone());
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.banned, vec![0]);
+    assert_eq!(sync.banned_peers(), &BTreeSet::from([0]));
+    assert_eq!(best_height(&client), 30);
+    assert!(client.get_block(hash(&honest[30].header)).is_some());
+}
+
+#[test]
+fn client_11_peer_that_served_the_bad_header_is_banned() {
+    let honest = test_chain(&[], 35, 0);
+    let mut bad = test_chain(&honest[..20], 30, 1);
+    bad[21].header.state_root = 999;
+    for height in 22..=30 {
+        bad[height].header.parent = hash(&bad[height - 1].header);
+    }
+    let liar = MockPeer::new(bad.clone());
+    // Another peer builds on the liar's chain.
+    let extender = MockPeer::new(test_chain(&bad, 40, 1));
+    // The honest peer has seen the liar's blocks too, so it can serve their bodies.
+    let honest_peer = MockPeer::new(bad);
+    honest_peer.set_chain(honest.clone());
+    let peers = [liar, extender, honest_peer];
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    // The liar serves all of its headers but only a few bodies, so the sync stalls before the
+    // bad block is reached.
+    *peers[0].requests_left.lock().unwrap() = Some(7);
+    assert!(sync.sync(&mut client, &peers[..1]).is_err());
+    assert!(best_height(&client) < 21);
+
+    // The extender serves the latest headers and then disappears too.
+    *peers[1].requests_left.lock().unwrap() = Some(2);
+    let report = sync.sync(&mut client, &peers).unwrap();
+
+    assert_eq!(report.banned, vec![0]);
+    assert_eq!(best_height(&client), 35);
+    assert!(client.get_block(hash(&honest[35].header)).is_some());
+}
+
+#[test]
+fn client_11_fork_discovered_mid_sync() {
+    let original = test_chain(&[], 30, 0);
+    // After serving two batches of headers, the peer re-orgs to a longer fork from height 10.
+    let fork = test_chain(&original[..=10], 40, 1);
+    let peer = MockPeer::new(original);
+    *peer.reorg.lock().unwrap() = Some((2, fork.clone()));
+    let mut client = test_client();
+    let mut sync = HeadersFirstSync::new(CountingConsensus, small_batches());
+
+    let report = sync.sync(&mut client, &[peer]).unwrap();
+
+    assert!(report.banned.is_empty());
+    assert!(client.get_block(hash(&fork[40].header)).is_some());
+    assert_eq!(best_height(&client), 40);
+}
+
+#[test]
+fn client_11_resumes_after_interruption() {
+    let chain = test_chain(&[], 40, 0);
+    let flaky = MockPeer::new(chain.clone());
+    // Enough requests to download all the headers, but only some of the bodies.
+    *flaky.requests_left.lock().unwrap() = Some(7);
+    let mut client = test_client();
+    let config = SyncConfig {
+        max_parallel_requests: 1,
+        ..small_batches()
+    };
+    let mut sync = HeadersFirstSync::new(CountingConsensus, config);
+
+    let Err(SyncError::Stalled { remaining_headers }) = sync.sync(&mut client, &[flaky]) else {
+        panic!("sync should stall when the only peer disappears");
+    };
+    assert!(remaining_headers > 0 && remaining_headers < 40);
+    assert_eq!(best_height(&client), 40 - remaining_headers as u64);
+
+    // A new peer only needs to serve the missing bodies, not any headers.
+    let fresh = MockPeer::new(chain.clone());
+    let report = sync.sync(&mut client, &[fresh]).unwrap();
+    assert_eq!(report.headers_downloaded, 0);
+    assert_eq!(report.blocks_imported, remaining_headers);
+    assert!(client.get_block(hash(&chain[40].header)).is_some());
+}
@@ -0,0 +1,457 @@
+//! Not every participant in a blockchain network wants to download and execute every block. A phone
+//! wallet, for example, only cares about a handful of accounts. A light client follows the chain by
+//! importing only headers. Headers are small, and thanks to the consensus abstraction they can be
+//! fully validated without any knowledge of the state machine.
+//!
+//! Because the light client has no state of its own, it must ask full nodes when it wants to know
+//! something about the state. But it does not have to trust their answers! Every header commits to
+//! the state with its `state_root` and to the block body with its `extrinsics_root`. A full node can
+//! accompany each answer with a Merkle proof, which the light client checks against the header.
+//! * Storage proofs - Prove the value (or absence) of a storage key against the `state_root`.
+//! * Extrinsic proofs - Prove that a transaction was included in a block against the `extrinsics_root`.
+//!
+//! Both of these only work if the full node builds the roots with the structures from chapter 2:
+//! a `StateTrie` for the state and a Merkle tree over the transactions for the extrinsics.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::p6_finality::{check_finality, descends_from, non_canonical_blocks, FinalityError};
+use super::{Consensus, ForkChoice, Header};
+use crate::c1_state_machine::User;
+use crate::c2_blockchain::{
+    p7_merkle_tree::MerkleProof,
+    p8_state_trie::{verify_balance, StorageProof},
+};
+use crate::hash;
+
+type Hash = u64;
+
+/// The reasons a header may be rejected by the light client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderImportError {
+    /// The header's parent has not been imported.
+    UnknownParent(Hash),
+    /// The header's height is not exactly one more than its parent's.
+    BadHeight { expected: u64, found: u64 },
+    /// The consensus engine rejected the header.
+    InvalidSeal,
+    /// The header does not build on the finalized header.
+    Finality(FinalityError),
+}
+
+/// A client that imports and validates headers, but never blocks or state.
+pub struct LightClient<C: Consensus, FC> {
+    consensus: C,
+    fork_choice: FC,
+    /// Every header we have imported, by hash.
+    headers: HashMap<Hash, Header<C::Digest>>,
+    /// The headers that do not have any children yet.
+    leaves: BTreeSet<Hash>,
+    genesis: Hash,
+    /// The most recent finalized block. Finality only ever moves forward.
+    finalized: Hash,
+}
+
+impl<C, FC> LightClient<C, FC>
+where
+    C: Consensus,
+    FC: ForkChoice<C>,
+{
+    /// Create a light client that trusts the given genesis header.
+    pub fn new(consensus: C, mut fork_choice: FC, genesis: Header<C::Digest>) -> Self {
+        let genesis_hash = hash(&genesis);
+        fork_choice.import_hook(genesis.clone());
+        Self {
+            consensus,
+            fork_choice,
+            headers: HashMap::from([(genesis_hash, genesis)]),
+            leaves: BTreeSet::from([genesis_hash]),
+            genesis: genesis_hash,
+            finalized: genesis_hash,
+        }
+    }
+
+    /// Validate a header and, if it is valid, add it to our view of the chain.
+    /// Importing a header that is already known succeeds without doing anything.
+    pub fn import_header(&mut self, header: Header<C::Digest>) -> Result<Hash, HeaderImportError> {
+        let header_hash = hash(&header);
+        if self.headers.contains_key(&header_hash) {
+            return Ok(header_hash);
+        }
+        let parent = self
+            .headers
+            .get(&header.parent)
+            .ok_or(HeaderImportError::UnknownParent(header.parent))?;
+        if header.height != parent.height + 1 {
+            return Err(HeaderImportError::BadHeight {
+                expected: parent.height + 1,
+                found: header.height,
+            });
+        }
+        if !self.consensus.validate(&parent.consensus_digest, &header) {
+            return Err(HeaderImportError::InvalidSeal);
+        }
+        check_finality(|h| self.lookup(h), self.finalized, header.parent)
+            .map_err(HeaderImportError::Finality)?;
+
+        self.leaves.remove(&header.parent);
+        self.leaves.insert(header_hash);
+        self.fork_choice.import_hook(header.clone());
+        self.headers.insert(header_hash, header);
+        Ok(header_hash)
+    }
+
+    /// Retrieve an imported header.
+    pub fn get_header(&self, header_hash: Hash) -> Option<&Header<C::Digest>> {
+        self.headers.get(&header_hash)
+    }
+
+    /// The hash of the genesis header.
+    pub fn genesis(&self) -> Hash {
+        self.genesis
+    }
+
+    /// All the tips of the known forks.
+    pub fn all_leaves(&self) -> Vec<Hash> {
+        self.leaves.iter().copied().collect()
+    }
+
+    /// Check whether a header is a leaf. Returns None if the header is not known.
+    pub fn is_leaf(&self, header_hash: Hash) -> Option<bool> {
+        self.headers.get(&header_hash)?;
+        Some(self.leaves.contains(&header_hash))
+    }
+
+    /// The best header according to the fork choice rule. The fork choice is given the finalized
+    /// header so that it only considers blocks built on top of it.
+    pub fn best_header(&self) -> Hash {
+        let finalized = self.headers[&self.finalized].clone();
+        self.fork_choice
+            .best_block(finalized)
+            .filter(|best| descends_from(|h| self.lookup(h), self.finalized, *best))
+            .unwrap_or(self.finalized)
+    }
+
+    /// The most recently finalized header.
+    pub fn finalized_header(&self) -> Hash {
+        self.finalized
+    }
+
+    /// Whether `ancestor` is the given header or one of its ancestors.
+    pub fn is_ancestor(&self, ancestor: Hash, mut descendant: Hash) -> bool {
+        let Some(target) = self.headers.get(&ancestor) else {
+            return false;
+        };
+        while let Some(header) = self.headers.get(&descendant) {
+            if descendant == ancestor {
+                return true;
+            }
+            if header.height <= target.height {
+                return false;
+            }
+            descendant = header.parent;
+        }
+        false
+    }
+
+    /// Mark the given header as final. It must be known and descend from the current finalized
+    /// header. Returns whether the header was finalized.
+    ///
+    /// Headers on forks that branched off below the newly finalized header are pruned.
+    pub fn finalize(&mut self, header_hash: Hash) -> bool {
+        if !self.is_ancestor(self.finalized, header_hash) {
+            return false;
+        }
+        self.finalized = header_hash;
+
+        let pruned = non_canonical_blocks(
+            self.headers.keys().copied(),
+            |h| self.lookup(h),
+            header_hash,
+        );
+        for dead in pruned {
+            self.headers.remove(&dead);
+            self.leaves.remove(&dead);
+        }
+        true
+    }
+
+    /// The parent and height of a known header.
+    fn lookup(&self, header_hash: Hash) -> Option<(Hash, u64)> {
+        self.headers.get(&header_hash).map(|h| (h.parent, h.height))
+    }
+
+    /// Check a full node's claim about a storage value at the given block. A value of None claims
+    /// that the key is absent. Returns false if the header is not known.
+    pub fn verify_storage(
+        &self,
+        block: Hash,
+        key: &[u8],
+        value: Option<&[u8]>,
+        proof: &StorageProof,
+    ) -> bool {
+        self.headers
+            .get(&block)
+            .is_some_and(|h| proof.verify(h.state_root, key, value))
+    }
+
+    /// Check a full node's claim about a user's balance at the given block.
+    pub fn verify_balance(
+        &self,
+        block: Hash,
+        user: User,
+        balance: u64,
+        proof: &StorageProof,
+    ) -> bool {
+        self.headers
+            .get(&block)
+            .is_some_and(|h| verify_balance(h.state_root, user, balance, proof))
+    }
+
+    /// Check a full node's claim that a transaction was included in the given block.
+    pub fn verify_transaction<T: std::hash::Hash>(
+        &self,
+        block: Hash,
+        transaction: &T,
+        proof: &MerkleProof,
+    ) -> bool {
+        self.headers
+            .get(&block)
+            .is_some_and(|h| proof.verify(h.extrinsics_root, transaction))
+    }
+}
+
+#[cfg(test)]
+use super::test_support::{CountingConsensus, TestLongestChain};
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::AccountingTransaction;
+#[cfg(test)]
+use crate::c2_blockchain::{
+    p7_merkle_tree::{merkle_proof, merkle_root},
+    p8_state_trie::{balance_key, balances_to_trie, StateTrie},
+};
+
+#[cfg(test)]
+type TestLightClient = LightClient<CountingConsensus, TestLongestChain<u64>>;
+
+#[cfg(test)]
+fn test_genesis() -> Header<u64> {
+    Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: 0,
+    }
+}
+
+#[cfg(test)]
+fn test_child(parent: &Header<u64>, fork: u64) -> Header<u64> {
+    Header {
+        parent: hash(parent),
+        height: parent.height + 1,
+        state_root: fork,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: parent.consensus_digest + 1,
+    }
+}
+
+/// Import a chain of n headers on top of the given parent and return the last one.
+#[cfg(test)]
+fn extend(client: &mut TestLightClient, parent: &Header<u64>, n: u64, fork: u64) -> Header<u64> {
+    let mut current = parent.clone();
+    for _ in 0..n {
+        current = test_child(&current, fork);
+        client.import_header(current.clone()).unwrap();
+    }
+    current
+}
+
+#[cfg(test)]
+fn new_light_client() -> TestLightClient {
+    LightClient::new(
+        CountingConsensus,
+        TestLongestChain::default(),
+        test_genesis(),
+    )
+}
+
+#[test]
+fn client_12_imports_valid_headers() {
+    let mut client = new_light_client();
+    let tip = extend(&mut client, &test_genesis(), 3, 0);
+
+    assert_eq!(client.best_header(), hash(&tip));
+    assert_eq!(client.all_leaves(), vec![hash(&tip)]);
+    assert_eq!(client.is_leaf(client.genesis()), Some(false));
+    assert_eq!(client.get_header(hash(&tip)), Some(&tip));
+}
+
+#[test]
+fn client_12_rejects_invalid_headers() {
+    let mut client = new_light_client();
+    let genesis = test_genesis();
+
+    let orphan = test_child(&test_child(&genesis, 0), 0);
+    assert_eq!(
+        client.import_header(orphan.clone()),
+        Err(HeaderImportError::UnknownParent(orphan.parent))
+    );
+
+    let mut wrong_height = test_child(&genesis, 0);
+    wrong_height.height = 5;
+    assert_eq!(
+        client.import_header(wrong_height),
+        Err(HeaderImportError::BadHeight {
+            expected: 1,
+            found: 5
+        })
+    );
+
+    let mut bad_seal = test_child(&genesis, 0);
+    bad_seal.consensus_digest = 7;
+    assert_eq!(
+        client.import_header(bad_seal),
+        Err(HeaderImportError::InvalidSeal)
+    );
+    assert_eq!(client.all_leaves(), vec![hash(&genesis)]);
+}
+
+#[test]
+fn client_12_tracks_forks() {
+    let mut client = new_light_client();
+    let common = extend(&mut client, &test_genesis(), 2, 0);
+    let short = extend(&mut client, &common, 1, 1);
+    let long = extend(&mut client, &common, 3, 2);
+
+    let mut expected = vec![hash(&short), hash(&long)];
+    expected.sort();
+    assert_eq!(client.all_leaves(), expected);
+    assert_eq!(client.best_header(), hash(&long));
+    assert!(client.is_ancestor(hash(&common), hash(&short)));
+    assert!(!client.is_ancestor(hash(&short), hash(&long)));
+}
+
+#[test]
+fn client_12_finality_restricts_best_header() {
+    let mut client = new_light_client();
+    let common = extend(&mut client, &test_genesis(), 1, 0);
+    let short = extend(&mut client, &common, 1, 1);
+    let long = extend(&mut client, &common, 3, 2);
+    assert_eq!(client.best_header(), hash(&long));
+
+    assert!(client.finalize(hash(&short)));
+    assert_eq!(client.finalized_header(), hash(&short));
+    assert_eq!(client.best_header(), hash(&short));
+
+    // Finality cannot move backwards or sideways.
+    assert!(!client.finalize(hash(&common)));
+    assert!(!client.finalize(hash(&long)));
+    assert!(!client.finalize(12345));
+}
+
+#[test]
+fn client_12_finality_prunes_and_rejects_forks() {
+    let mut client = new_light_client();
+    let common = extend(&mut client, &test_genesis(), 1, 0);
+    let dead = extend(&mut client, &common, 2, 1);
+    let canonical = extend(&mut client, &common, 1, 2);
+
+    assert!(client.finalize(hash(&canonical)));
+    // The longer fork branched off below the finalized header, so it is gone.
+    assert_eq!(client.get_header(hash(&dead)), None);
+    assert_eq!(client.all_leaves(), vec![hash(&canonical)]);
+    assert_eq!(client.best_header(), hash(&canonical));
+    assert!(client.get_header(hash(&common)).is_some());
+
+    // A new fork from a finalized ancestor is rejected with a clear error.
+    let sibling = test_child(&common, 3);
+    assert_eq!(
+        client.import_header(sibling),
+        Err(HeaderImportError::Finality(
+            FinalityError::NotDescendantOfFinalized {
+                block: hash(&common),
+                finalized: hash(&canonical),
+            }
+        ))
+    );
+    // Blocks on the pruned fork are simply unknown.
+    assert_eq!(
+        client.import_header(test_child(&dead, 1)),
+        Err(HeaderImportError::UnknownParent(hash(&dead)))
+    );
+    extend(&mut client, &canonical, 2, 2);
+}
+
+#[test]
+fn client_12_verifies_storage_proofs() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"name".to_vec(), b"alice".to_vec());
+    let mut client = new_light_client();
+    let mut header = test_child(&test_genesis(), 0);
+    header.state_root = trie.state_root();
+    let block = client.import_header(header).unwrap();
+
+    let proof = trie.prove(b"name");
+    assert!(client.verify_storage(block, b"name", Some(b"alice"), &proof));
+    assert!(!client.verify_storage(block, b"name", Some(b"mallory"), &proof));
+    assert!(!client.verify_storage(client.genesis(), b"name", Some(b"alice"), &proof));
+    assert!(!client.verify_storage(999, b"name", Some(b"alice"), &proof));
+
+    let absent = trie.prove(b"age");
+    assert!(client.verify_storage(block, b"age", None, &absent));
+}
+
+#[test]
+fn client_12_verifies_balance_proofs() {
+    let trie = balances_to_trie(&[(User::Alice, 50), (User::Bob, 7)].into_iter().collect());
+    let mut client = new_light_client();
+    let mut header = test_child(&test_genesis(), 0);
+    header.state_root = trie.state_root();
+    let block = client.import_header(header).unwrap();
+
+    assert!(client.verify_balance(
+        block,
+        User::Alice,
+        50,
+        &trie.prove(&balance_key(User::Alice))
+    ));
+    assert!(!client.verify_balance(
+        block,
+        User::Alice,
+        51,
+        &trie.prove(&balance_key(User::Alice))
+    ));
+    assert!(client.verify_balance(
+        block,
+        User::Charlie,
+        0,
+        &trie.prove(&balance_key(User::Charlie))
+    ));
+}
+
+#[test]
+fn client_12_verifies_transaction_inclusion() {
+    let body = vec![
+        AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 10,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 4,
+        },
+    ];
+    let mut client = new_light_client();
+    let mut header = test_child(&test_genesis(), 0);
+    header.extrinsics_root = merkle_root(&body);
+    let block = client.import_header(header).unwrap();
+
+    let proof = merkle_proof(&body, 1).unwrap();
+    assert!(client.verify_transaction(block, &body[1], &proof));
+    assert!(!client.verify_transaction(block, &body[0], &proof));
+    assert!(!client.verify_transaction(client.genesis(), &body[1], &proof));
+}
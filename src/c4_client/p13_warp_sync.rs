@@ -0,0 +1,664 @@
+//! Headers-first sync still executes every block since genesis. On a long-lived chain that can
+//! take days. But once a block is finalized, nobody needs to re-check its history; what a new client
+//! really needs is the state at that block. Warp sync (also called snapshot sync) takes advantage of
+//! this. A new client:
+//! 1. Downloads and verifies the header chain up to a recent finalized block. This is cheap.
+//! 2. Downloads a snapshot of the state at that block in chunks. Each entry in each chunk comes with
+//!    a storage proof against the block's `state_root`, so a bad chunk is detected as soon as it
+//!    arrives, and can be re-requested from another peer.
+//! 3. Once all chunks are in, rebuilds the state and checks that its root matches `state_root`.
+//!    This proves that no entries were left out.
+//! 4. Starts following the chain from that block, executing only new blocks.
+//!
+//! Snapshots require the state to be stored in a `StateTrie`, so that individual entries can be
+//! proven against the state root.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::p6_finality::descends_from;
+use super::{
+    forward_client_api, p2_importing_blocks::ImportBlock, Block, ClientApi, Consensus, Header,
+    StateMachine,
+};
+use crate::c2_blockchain::{
+    p7_merkle_tree::merkle_root,
+    p8_state_trie::{StateTrie, StorageProof},
+};
+use crate::hash;
+
+type Hash = u64;
+
+/// Describes a snapshot so that the importer knows what to expect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// The block whose state this is.
+    pub block: Hash,
+    /// The state root from the block's header.
+    pub state_root: Hash,
+    /// The number of chunks the snapshot is split into.
+    pub chunk_count: usize,
+    /// The total number of entries across all chunks.
+    pub entry_count: usize,
+}
+
+/// A piece of a snapshot. Every entry carries its own proof.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateChunk {
+    /// The position of this chunk in the snapshot.
+    pub index: usize,
+    /// Key-value pairs, each with a proof against the snapshot's state root.
+    pub entries: Vec<(Vec<u8>, Vec<u8>, StorageProof)>,
+}
+
+impl StateChunk {
+    /// Check every entry in this chunk against the given state root.
+    pub fn verify(&self, state_root: Hash) -> bool {
+        self.entries
+            .iter()
+            .all(|(key, value, proof)| proof.verify(state_root, key, Some(value)))
+    }
+}
+
+/// Split a state into chunks of at most `chunk_size` entries.
+pub fn create_snapshot(
+    state: &StateTrie,
+    block: Hash,
+    chunk_size: usize,
+) -> (SnapshotManifest, Vec<StateChunk>) {
+    let entries: Vec<_> = state
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone(), state.prove(key)))
+        .collect();
+    let chunks: Vec<StateChunk> = entries
+        .chunks(chunk_size.max(1))
+        .enumerate()
+        .map(|(index, entries)| StateChunk {
+            index,
+            entries: entries.to_vec(),
+        })
+        .collect();
+    let manifest = SnapshotManifest {
+        block,
+        state_root: state.state_root(),
+        chunk_count: chunks.len(),
+        entry_count: entries.len(),
+    };
+    (manifest, chunks)
+}
+
+/// Export a snapshot of a client's state at the given block. Returns None if the block is not
+/// finalized, since a client warped to it could never follow a reorg below it, or if the client
+/// does not have the block's state.
+pub fn export_snapshot<C, SM>(
+    client: &impl ClientApi<C, SM>,
+    block: Hash,
+    chunk_size: usize,
+) -> Option<(SnapshotManifest, Vec<StateChunk>)>
+where
+    C: Consensus,
+    SM: StateMachine<State = StateTrie>,
+{
+    let lookup = |h| {
+        client
+            .get_block(h)
+            .map(|b| (b.header.parent, b.header.height))
+    };
+    if !descends_from(lookup, block, client.finalized_block()) {
+        return None;
+    }
+    let state = client.get_state(block)?;
+    Some(create_snapshot(&state, block, chunk_size))
+}
+
+/// The ways warp sync can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WarpSyncError {
+    /// The header chain does not start at the expected genesis block.
+    WrongGenesis,
+    /// The header chain is not linked correctly, or fails consensus checks.
+    InvalidHeaderChain,
+    /// The snapshot or block is not for the last header in the chain, or the block's body does
+    /// not match its header.
+    TargetMismatch,
+    /// The proof does not show that the snapshot block is final.
+    NotFinal,
+    /// A chunk does not belong to this snapshot, or its proofs do not verify.
+    InvalidChunk(usize),
+    /// Some chunks have not been received yet.
+    MissingChunks(Vec<usize>),
+    /// All chunks verified, but the rebuilt state has a different root. The snapshot left some
+    /// entries out.
+    StateRootMismatch,
+}
+
+/// Collects and verifies the chunks of a snapshot as they arrive, in any order.
+pub struct SnapshotImport {
+    manifest: SnapshotManifest,
+    chunks: BTreeMap<usize, StateChunk>,
+}
+
+impl SnapshotImport {
+    /// Start importing the snapshot described by the manifest. The manifest's state root must be
+    /// checked against a trusted header before any chunks are accepted.
+    pub fn new(manifest: SnapshotManifest) -> Self {
+        Self {
+            manifest,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// Verify a chunk and keep it if it is valid.
+    pub fn import_chunk(&mut self, chunk: StateChunk) -> Result<(), WarpSyncError> {
+        if chunk.index >= self.manifest.chunk_count || !chunk.verify(self.manifest.state_root) {
+            return Err(WarpSyncError::InvalidChunk(chunk.index));
+        }
+        self.chunks.insert(chunk.index, chunk);
+        Ok(())
+    }
+
+    /// The indices of the chunks we still need.
+    pub fn missing_chunks(&self) -> Vec<usize> {
+        (0..self.manifest.chunk_count)
+            .filter(|i| !self.chunks.contains_key(i))
+            .collect()
+    }
+
+    /// Rebuild the complete state, checking that nothing was left out.
+    pub fn finish(self) -> Result<StateTrie, WarpSyncError> {
+        let missing = self.missing_chunks();
+        if !missing.is_empty() {
+            return Err(WarpSyncError::MissingChunks(missing));
+        }
+        let mut state = StateTrie::new();
+        for chunk in self.chunks.into_values() {
+            for (key, value, _) in chunk.entries {
+                state.insert(key, value);
+            }
+        }
+        if state.state_root() != self.manifest.state_root {
+            return Err(WarpSyncError::StateRootMismatch);
+        }
+        Ok(state)
+    }
+}
+
+/// Evidence that a block is final, such as a justification from a finality gadget.
+///
+/// A client only warps to a block that is proven final. Otherwise a peer could hand it a snapshot
+/// of a block that is later reverted, and the client could never follow the reorg, since it knows
+/// nothing below the snapshot block.
+pub trait FinalityProof<D> {
+    /// Check that this proves the given header final.
+    fn proves_final(&self, header: &Header<D>) -> bool;
+}
+
+/// A client that starts from a snapshot instead of from genesis.
+///
+/// It wraps an ordinary client that is seeded at the snapshot block, so blocks after it are
+/// imported, finalized and pruned exactly as usual. On top of that it remembers the headers of
+/// all historical blocks, even though it only has complete blocks and states from the snapshot
+/// block onwards.
+pub struct WarpSyncedClient<C: Consensus, I> {
+    /// The client, which treats the snapshot block as its first block.
+    client: I,
+    /// The real genesis block.
+    genesis: Hash,
+    /// The headers of the blocks before the snapshot block.
+    history: HashMap<Hash, Header<C::Digest>>,
+    /// The block the snapshot was taken at.
+    base: Hash,
+}
+
+impl<C: Consensus, I> WarpSyncedClient<C, I> {
+    /// Build a client from a verified header chain and snapshot.
+    ///
+    /// * `consensus_engine` - Checks the header chain.
+    /// * `genesis` - The hash of the genesis block, which we trust.
+    /// * `headers` - Every header from genesis up to and including the snapshot block.
+    /// * `base` - The complete snapshot block.
+    /// * `proof` - Proof that the snapshot block is final.
+    /// * `snapshot` - The snapshot, with all of its chunks imported.
+    /// * `start` - Creates the client that follows the chain, seeded with the snapshot block and
+    ///   its state. For our `FullClient`, this is what `FullClient::starting_at` is for.
+    pub fn from_snapshot<SM>(
+        consensus_engine: &C,
+        genesis: Hash,
+        headers: Vec<Header<C::Digest>>,
+        base: Block<C, SM>,
+        proof: &impl FinalityProof<C::Digest>,
+        snapshot: SnapshotImport,
+        start: impl FnOnce(Block<C, SM>, StateTrie) -> I,
+    ) -> Result<Self, WarpSyncError>
+    where
+        SM: StateMachine<State = StateTrie>,
+        SM::Transition: std::hash::Hash,
+    {
+        let first = headers.first().ok_or(WarpSyncError::InvalidHeaderChain)?;
+        if hash(first) != genesis {
+            return Err(WarpSyncError::WrongGenesis);
+        }
+        let linked = headers
+            .windows(2)
+            .all(|w| w[1].parent == hash(&w[0]) && w[1].height == w[0].height + 1);
+        if !linked || !consensus_engine.verify_sub_chain(&first.consensus_digest, &headers[1..]) {
+            return Err(WarpSyncError::InvalidHeaderChain);
+        }
+
+        let base_hash = hash(&base.header);
+        let last = headers
+            .last()
+            .expect("there is at least the genesis header");
+        if hash(last) != base_hash
+            || base.header.extrinsics_root != merkle_root(&base.body)
+            || snapshot.manifest.block != base_hash
+            || snapshot.manifest.state_root != base.header.state_root
+        {
+            return Err(WarpSyncError::TargetMismatch);
+        }
+        if !proof.proves_final(&base.header) {
+            return Err(WarpSyncError::NotFinal);
+        }
+        let state = snapshot.finish()?;
+
+        let mut history: HashMap<Hash, Header<C::Digest>> =
+            headers.into_iter().map(|h| (hash(&h), h)).collect();
+        history.remove(&base_hash);
+        Ok(Self {
+            client: start(base, state),
+            genesis,
+            history,
+            base: base_hash,
+        })
+    }
+}
+
+impl<C: Consensus, I> WarpSyncedClient<C, I> {
+    /// The block the client was warped to.
+    pub fn base(&self) -> Hash {
+        self.base
+    }
+
+    /// The client that follows the chain from the snapshot block.
+    pub fn client(&self) -> &I {
+        &self.client
+    }
+
+    /// Look up any header, including those from before the snapshot.
+    pub fn get_header<SM>(&self, block_hash: Hash) -> Option<Header<C::Digest>>
+    where
+        SM: StateMachine,
+        I: ImportBlock<C, SM>,
+    {
+        self.history
+            .get(&block_hash)
+            .cloned()
+            .or_else(|| self.client.get_block(block_hash).map(|b| b.header))
+    }
+}
+
+forward_client_api! {
+    impl<C, SM, I> for WarpSyncedClient<C, I>, genesis from genesis
+    where
+        C: Consensus,
+        SM: StateMachine
+}
+
+#[cfg(test)]
+use super::test_support::{CountingConsensus, FakeClient};
+
+/// A state machine for testing whose transitions simply write a value to a key in the trie.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TrieWrites;
+
+#[cfg(test)]
+impl StateMachine for TrieWrites {
+    type State = StateTrie;
+    type Transition = (Vec<u8>, Vec<u8>);
+
+    fn next_state(starting_state: &StateTrie, (key, value): &(Vec<u8>, Vec<u8>)) -> StateTrie {
+        let mut state = starting_state.clone();
+        state.insert(key.clone(), value.clone());
+        state
+    }
+}
+
+#[cfg(test)]
+type TestBlock = Block<CountingConsensus, TrieWrites>;
+
+#[cfg(test)]
+type TestWarpedClient =
+    WarpSyncedClient<CountingConsensus, FakeClient<CountingConsensus, TrieWrites>>;
+
+/// A finality proof for testing, which proves exactly one block final.
+#[cfg(test)]
+struct FinalBlock(Hash);
+
+#[cfg(test)]
+impl FinalityProof<u64> for FinalBlock {
+    fn proves_final(&self, header: &Header<u64>) -> bool {
+        hash(header) == self.0
+    }
+}
+
+/// Warp a new client to the snapshot block of the given chain, following it with the fake
+/// client.
+#[cfg(test)]
+fn warp_with(
+    headers: Vec<Header<u64>>,
+    base: TestBlock,
+    proof: &FinalBlock,
+    snapshot: SnapshotImport,
+) -> Result<TestWarpedClient, WarpSyncError> {
+    let genesis = hash(&headers[0]);
+    WarpSyncedClient::from_snapshot(
+        &CountingConsensus,
+        genesis,
+        headers,
+        base,
+        proof,
+        snapshot,
+        |block, state| {
+            FakeClient::starting_at(CountingConsensus, block, state, StateTrie::state_root)
+        },
+    )
+}
+
+/// Build a chain of blocks that each write the block height under one of three rotating keys,
+/// along with each block's state. The genesis state contains some unrelated entries so that
+/// snapshots have several chunks.
+#[cfg(test)]
+fn test_chain(len: u64) -> Vec<(TestBlock, StateTrie)> {
+    let mut state = StateTrie::new();
+    for i in 0..10u8 {
+        state.insert(vec![b'x', i], vec![i; 3]);
+    }
+    let genesis = Block {
+        header: Header {
+            parent: 0,
+            height: 0,
+            state_root: state.state_root(),
+            extrinsics_root: merkle_root::<(Vec<u8>, Vec<u8>)>(&[]),
+            timestamp: 0,
+            consensus_digest: 0,
+        },
+        body: vec![],
+    };
+    let mut chain = vec![(genesis, state)];
+    for height in 1..=len {
+        let (parent, parent_state) = chain.last().unwrap();
+        let body = vec![(
+            vec![b'k', (height % 3) as u8],
+            height.to_le_bytes().to_vec(),
+        )];
+        let state = TrieWrites::next_state(parent_state, &body[0]);
+        let block = Block {
+            header: Header {
+                parent: hash(&parent.header),
+                height,
+                state_root: state.state_root(),
+                extrinsics_root: merkle_root(&body),
+                timestamp: 0,
+                consensus_digest: parent.header.consensus_digest + 1,
+            },
+            body,
+        };
+        chain.push((block, state));
+    }
+    chain
+}
+
+/// Warp a new client to the given height of the chain.
+#[cfg(test)]
+fn warp_to(
+    chain: &[(TestBlock, StateTrie)],
+    height: usize,
+) -> Result<TestWarpedClient, WarpSyncError> {
+    let (base, state) = &chain[height];
+    let (manifest, chunks) = create_snapshot(state, hash(&base.header), 4);
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks.into_iter().rev() {
+        snapshot.import_chunk(chunk)?;
+    }
+    let headers = chain[..=height]
+        .iter()
+        .map(|(b, _)| b.header.clone())
+        .collect();
+    warp_with(
+        headers,
+        base.clone(),
+        &FinalBlock(hash(&base.header)),
+        snapshot,
+    )
+}
+
+#[test]
+fn client_13_snapshot_chunks_verify() {
+    let chain = test_chain(5);
+    let (block, state) = &chain[5];
+    let (manifest, chunks) = create_snapshot(state, hash(&block.header), 4);
+
+    assert_eq!(manifest.state_root, block.header.state_root);
+    assert_eq!(manifest.entry_count, state.len());
+    assert_eq!(manifest.chunk_count, chunks.len());
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|c| c.verify(manifest.state_root)));
+    assert!(!chunks[0].verify(chain[4].1.state_root()));
+}
+
+#[test]
+fn client_13_warp_and_follow_chain() {
+    let chain = test_chain(15);
+    let mut client = warp_to(&chain, 10).unwrap();
+
+    assert_eq!(client.base(), hash(&chain[10].0.header));
+    assert_eq!(client.get_state(client.base()), Some(chain[10].1.clone()));
+    // History headers are known, but not their blocks or states.
+    let early = hash(&chain[3].0.header);
+    assert_eq!(
+        client.get_header::<TrieWrites>(early),
+        Some(chain[3].0.header.clone())
+    );
+    assert_eq!(client.genesis(), hash(&chain[0].0.header));
+    assert!(client.get_block(early).is_none());
+    assert!(client.get_state(early).is_none());
+
+    // Blocks before the snapshot cannot be imported, but new blocks are executed normally.
+    assert!(!client.import_block(chain[5].0.clone()));
+    for (block, _) in &chain[11..] {
+        assert!(client.import_block(block.clone()));
+    }
+    let tip = hash(&chain[15].0.header);
+    assert_eq!(client.all_leaves(), vec![tip]);
+    let state = client.get_state(tip).unwrap();
+    assert_eq!(state.get(b"k\x00"), Some(&15u64.to_le_bytes()[..]));
+}
+
+#[test]
+fn client_13_rejects_block_with_wrong_state_root() {
+    let chain = test_chain(11);
+    let mut client = warp_to(&chain, 10).unwrap();
+    let mut block = chain[11].0.clone();
+    block.header.state_root += 1;
+
+    assert!(!client.import_block(block));
+}
+
+#[test]
+fn client_13_tampered_chunk_rejected() {
+    let chain = test_chain(5);
+    let (block, state) = &chain[5];
+    let (manifest, mut chunks) = create_snapshot(state, hash(&block.header), 4);
+    chunks[1].entries[0].1 = b"stolen".to_vec();
+    let mut snapshot = SnapshotImport::new(manifest);
+
+    assert_eq!(
+        snapshot.import_chunk(chunks[1].clone()),
+        Err(WarpSyncError::InvalidChunk(1))
+    );
+    assert_eq!(snapshot.import_chunk(chunks[0].clone()), Ok(()));
+    assert_eq!(snapshot.missing_chunks().len(), chunks.len() - 1);
+    assert!(matches!(
+        snapshot.finish(),
+        Err(WarpSyncError::MissingChunks(_))
+    ));
+}
+
+#[test]
+fn client_13_incomplete_snapshot_detected() {
+    let chain = test_chain(5);
+    let (block, state) = &chain[5];
+    let (mut manifest, mut chunks) = create_snapshot(state, hash(&block.header), 4);
+    // A dishonest peer drops an entry. Every remaining proof still verifies.
+    chunks[0].entries.pop();
+    manifest.entry_count -= 1;
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks {
+        snapshot.import_chunk(chunk).unwrap();
+    }
+
+    assert_eq!(snapshot.finish(), Err(WarpSyncError::StateRootMismatch));
+}
+
+#[test]
+fn client_13_invalid_header_chain_rejected() {
+    let mut chain = test_chain(6);
+    chain[3].0.header.consensus_digest = 99;
+    // Re-link the chain so that only the seal is wrong.
+    for i in 4..=6 {
+        chain[i].0.header.parent = hash(&chain[i - 1].0.header);
+    }
+
+    assert_eq!(
+        warp_to(&chain, 6).err(),
+        Some(WarpSyncError::InvalidHeaderChain)
+    );
+}
+
+#[test]
+fn client_13_snapshot_must_match_target() {
+    let chain = test_chain(6);
+    let (base, _) = &chain[6];
+    let (manifest, chunks) = create_snapshot(&chain[5].1, hash(&base.header), 4);
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks {
+        snapshot.import_chunk(chunk).unwrap();
+    }
+    let headers = chain.iter().map(|(b, _)| b.header.clone()).collect();
+    let result = warp_with(
+        headers,
+        base.clone(),
+        &FinalBlock(hash(&base.header)),
+        snapshot,
+    );
+
+    assert_eq!(result.err(), Some(WarpSyncError::TargetMismatch));
+}
+
+#[test]
+fn client_13_snapshot_block_must_be_final() {
+    let chain = test_chain(6);
+    let (base, state) = &chain[6];
+    let (manifest, chunks) = create_snapshot(state, hash(&base.header), 4);
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks {
+        snapshot.import_chunk(chunk).unwrap();
+    }
+    let headers = chain.iter().map(|(b, _)| b.header.clone()).collect();
+    let result = warp_with(
+        headers,
+        base.clone(),
+        &FinalBlock(hash(&chain[5].0.header)),
+        snapshot,
+    );
+
+    assert_eq!(result.err(), Some(WarpSyncError::NotFinal));
+}
+
+#[test]
+fn client_13_snapshot_block_body_must_match_header() {
+    let chain = test_chain(6);
+    let (base, state) = &chain[6];
+    let (manifest, chunks) = create_snapshot(state, hash(&base.header), 4);
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks {
+        snapshot.import_chunk(chunk).unwrap();
+    }
+    let headers = chain.iter().map(|(b, _)| b.header.clone()).collect();
+    let mut base = base.clone();
+    base.body.push((b"extra".to_vec(), b"body".to_vec()));
+    let result = warp_with(
+        headers,
+        base.clone(),
+        &FinalBlock(hash(&base.header)),
+        snapshot,
+    );
+
+    assert_eq!(result.err(), Some(WarpSyncError::TargetMismatch));
+}
+
+#[test]
+fn client_13_export_from_warped_client() {
+    let chain = test_chain(12);
+    let mut first = warp_to(&chain, 8).unwrap();
+    for (block, _) in &chain[9..] {
+        assert!(first.import_block(block.clone()));
+    }
+
+    // Only finalized blocks are exported.
+    let tip = hash(&chain[12].0.header);
+    assert!(export_snapshot(&first, tip, 3).is_none());
+    assert!(first.manually_finalize_block(tip));
+
+    let (manifest, chunks) = export_snapshot(&first, tip, 3).unwrap();
+    let mut snapshot = SnapshotImport::new(manifest);
+    for chunk in chunks {
+        snapshot.import_chunk(chunk).unwrap();
+    }
+    let headers = chain.iter().map(|(b, _)| b.header.clone()).collect();
+    let second = warp_with(headers, chain[12].0.clone(), &FinalBlock(tip), snapshot).unwrap();
+
+    assert_eq!(second.get_state(tip), first.get_state(tip));
+    assert!(export_snapshot(&first, hash(&chain[2].0.header), 3).is_none());
+}
+
+#[test]
+fn client_13_finality_prunes_forks_and_states() {
+    let chain = test_chain(12);
+    let mut client = warp_to(&chain, 10).unwrap();
+    assert_eq!(client.finalized_block(), client.base());
+    for (block, _) in &chain[11..] {
+        assert!(client.import_block(block.clone()));
+    }
+
+    // A competing child of the snapshot block.
+    let (base, base_state) = &chain[10];
+    let body = vec![(b"fork".to_vec(), b"yes".to_vec())];
+    let fork = Block {
+        header: Header {
+            parent: hash(&base.header),
+            height: 11,
+            state_root: TrieWrites::next_state(base_state, &body[0]).state_root(),
+            extrinsics_root: merkle_root(&body),
+            timestamp: 0,
+            consensus_digest: base.header.consensus_digest + 1,
+        },
+        body,
+    };
+    let fork_hash = hash(&fork.header);
+    assert!(client.import_block(fork.clone()));
+    assert_eq!(client.all_leaves().len(), 2);
+
+    let finalized = hash(&chain[11].0.header);
+    assert!(client.manually_finalize_block(finalized));
+    assert_eq!(client.finalized_block(), finalized);
+    assert_eq!(client.best_block(), hash(&chain[12].0.header));
+    assert!(client.get_block(fork_hash).is_none());
+    assert!(client.get_state(fork_hash).is_none());
+    assert_eq!(client.all_leaves(), vec![hash(&chain[12].0.header)]);
+
+    // The fork cannot come back, and finality cannot move backwards.
+    assert!(!client.import_block(fork));
+    assert!(!client.manually_finalize_block(client.base()));
+    assert!(!client.manually_finalize_block(fork_hash));
+}
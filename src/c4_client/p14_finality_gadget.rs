@@ -0,0 +1,1196 @@
+//! In the finality section we let node operators finalize blocks by hand, and mentioned that in
+//! practice finality usually comes from a BFT consensus game. Here we play that game. Our gadget is
+//! modeled on GRANDPA, the finality gadget used by Polkadot. It runs alongside whatever consensus
+//! engine authors the blocks, and it never authors anything itself. It only decides which of the
+//! blocks that already exist will never be reverted.
+//!
+//! A fixed set of voters, each a `ConsensusAuthority` with some voting weight, plays in rounds.
+//! In each round every voter:
+//! 1. Prevotes for its best block.
+//! 2. Once more than 2/3 of the weight has prevoted, precommits for the highest block that more
+//!    than 2/3 of the prevotes are for or build upon. This block is called the prevote GHOST.
+//! 3. Once more than 2/3 of the weight has precommitted, the highest block that more than 2/3 of
+//!    the precommits are for or build upon is finalized, and the next round begins.
+//!
+//! Voting on chains rather than individual blocks is what makes this work in the presence of forks.
+//! Even if the voters disagree about the tip of the chain, they usually agree on some ancestor of it,
+//! and that ancestor gets finalized.
+//!
+//! The precommits that finalized a block, along with the headers that connect them to it, form a
+//! justification. Anyone who knows the voter set can check a justification without taking part in
+//! the vote, so it is stored with the block and handed to nodes that import the block later.
+//!
+//! As in our Proof of Authority engine, a vote is "signed" simply by naming its voter.
+
+use std::collections::HashMap;
+
+use super::p13_warp_sync::FinalityProof;
+use super::p9_network_simulator::{SimNode, Time};
+use super::{Block, Consensus, Header, StateMachine};
+use crate::c3_consensus::ConsensusAuthority;
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+
+type Hash = u64;
+
+/// The few things the finality gadget needs to know about a header.
+pub trait ChainHeader: Clone {
+    /// The hash that identifies this header's block.
+    fn hash(&self) -> Hash;
+
+    /// The hash of the parent block.
+    fn parent_hash(&self) -> Hash;
+
+    /// The height of the block.
+    fn number(&self) -> u64;
+}
+
+impl<D: Clone + std::hash::Hash> ChainHeader for Header<D> {
+    fn hash(&self) -> Hash {
+        hash(self)
+    }
+
+    fn parent_hash(&self) -> Hash {
+        self.parent
+    }
+
+    fn number(&self) -> u64 {
+        self.height
+    }
+}
+
+impl<C: Consensus, SM: StateMachine> ChainHeader for Block<C, SM>
+where
+    Self: Clone,
+{
+    fn hash(&self) -> Hash {
+        hash(&self.header)
+    }
+
+    fn parent_hash(&self) -> Hash {
+        self.header.parent
+    }
+
+    fn number(&self) -> u64 {
+        self.header.height
+    }
+}
+
+/// The chain the gadget votes on.
+pub trait VotingChain {
+    type Header: ChainHeader;
+
+    /// The hash of the current best block according to the node's fork choice.
+    fn best_hash(&self) -> Hash;
+
+    /// The header of the given block, or None if the block is not known.
+    fn header(&self, block_hash: Hash) -> Option<Self::Header>;
+}
+
+/// Any simulated node whose blocks carry their own headers can be voted on.
+impl<N: SimNode> VotingChain for N
+where
+    N::Block: ChainHeader,
+{
+    type Header = N::Block;
+
+    fn best_hash(&self) -> Hash {
+        self.best_block()
+    }
+
+    fn header(&self, block_hash: Hash) -> Option<N::Block> {
+        self.get_block(block_hash)
+    }
+}
+
+/// The authorities that take part in finality, along with their voting weights.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VoterSet {
+    voters: Vec<(ConsensusAuthority, u64)>,
+}
+
+impl VoterSet {
+    /// Create a voter set with the given weights. If an authority is listed more than once,
+    /// only its first entry counts.
+    pub fn new(voters: Vec<(ConsensusAuthority, u64)>) -> Self {
+        let mut unique: Vec<(ConsensusAuthority, u64)> = Vec::new();
+        for (voter, weight) in voters {
+            if !unique.iter().any(|(v, _)| *v == voter) {
+                unique.push((voter, weight));
+            }
+        }
+        Self { voters: unique }
+    }
+
+    /// Create a voter set in which every authority has the same weight.
+    pub fn equal(voters: &[ConsensusAuthority]) -> Self {
+        Self::new(voters.iter().map(|v| (*v, 1)).collect())
+    }
+
+    /// The weight of the given voter, or None if it is not in the set.
+    pub fn weight(&self, voter: ConsensusAuthority) -> Option<u64> {
+        self.voters
+            .iter()
+            .find(|(v, _)| *v == voter)
+            .map(|(_, weight)| *weight)
+    }
+
+    /// The combined weight of all voters.
+    pub fn total_weight(&self) -> u64 {
+        self.voters.iter().map(|(_, weight)| weight).sum()
+    }
+
+    /// The smallest weight that is more than 2/3 of the total.
+    pub fn threshold(&self) -> u64 {
+        2 * self.total_weight() / 3 + 1
+    }
+}
+
+/// The two kinds of votes cast in each round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A single vote for a block, and implicitly for all of its ancestors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Vote {
+    pub round: u64,
+    pub kind: VoteKind,
+    pub target: Hash,
+    pub target_height: u64,
+    pub voter: ConsensusAuthority,
+}
+
+impl Encode for VoteKind {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for VoteKind {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(VoteKind::Prevote),
+            1 => Ok(VoteKind::Precommit),
+            _ => Err(DecodeError::Invalid("vote kind")),
+        }
+    }
+}
+
+impl Encode for Vote {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.round.encode_to(out);
+        self.kind.encode_to(out);
+        self.target.encode_to(out);
+        self.target_height.encode_to(out);
+        self.voter.encode_to(out);
+    }
+}
+
+impl Decode for Vote {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Vote {
+            round: Decode::decode_from(input)?,
+            kind: Decode::decode_from(input)?,
+            target: Decode::decode_from(input)?,
+            target_height: Decode::decode_from(input)?,
+            voter: Decode::decode_from(input)?,
+        })
+    }
+}
+
+/// The reasons a justification may be invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JustificationError {
+    /// The justification is for a different block than the one it accompanies.
+    WrongTarget,
+    /// One of the votes is not a precommit from the justification's round.
+    InvalidVote(Vote),
+    /// One of the votes is from an authority that is not in the voter set.
+    UnknownVoter(ConsensusAuthority),
+    /// The same voter appears more than once.
+    DuplicateVoter(ConsensusAuthority),
+    /// A precommit's target is not shown to descend from the justified block.
+    NotDescendant(Hash),
+    /// The precommits do not carry more than 2/3 of the voting weight.
+    NotEnoughWeight { found: u64, required: u64 },
+}
+
+/// Proof that a block was finalized: the precommits of the round that finalized it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Justification<H> {
+    pub round: u64,
+    pub target: Hash,
+    pub target_height: u64,
+    pub precommits: Vec<Vote>,
+    /// The headers between each precommit's target and the justified block, which show that
+    /// the precommits build upon it.
+    pub ancestry: Vec<H>,
+}
+
+impl<H: ChainHeader> Justification<H> {
+    /// Check that the precommits are valid and carry more than 2/3 of the given voters' weight.
+    /// This needs nothing but the justification itself and the voter set.
+    pub fn verify(&self, voters: &VoterSet) -> Result<(), JustificationError> {
+        let ancestry: HashMap<Hash, &H> = self.ancestry.iter().map(|h| (h.hash(), h)).collect();
+        let mut seen = Vec::new();
+        let mut weight = 0;
+        for vote in &self.precommits {
+            if vote.kind != VoteKind::Precommit || vote.round != self.round {
+                return Err(JustificationError::InvalidVote(*vote));
+            }
+            let voter_weight = voters
+                .weight(vote.voter)
+                .ok_or(JustificationError::UnknownVoter(vote.voter))?;
+            if seen.contains(&vote.voter) {
+                return Err(JustificationError::DuplicateVoter(vote.voter));
+            }
+            seen.push(vote.voter);
+
+            let mut current = vote.target;
+            while current != self.target {
+                match ancestry.get(&current) {
+                    Some(header) if header.number() > self.target_height => {
+                        current = header.parent_hash()
+                    }
+                    _ => return Err(JustificationError::NotDescendant(vote.target)),
+                }
+            }
+            weight += voter_weight;
+        }
+
+        let required = voters.threshold();
+        if weight < required {
+            return Err(JustificationError::NotEnoughWeight {
+                found: weight,
+                required,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Nodes store justifications with their blocks in encoded form, without knowing what is inside.
+impl<H: Encode> Encode for Justification<H> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.round.encode_to(out);
+        self.target.encode_to(out);
+        self.target_height.encode_to(out);
+        self.precommits.encode_to(out);
+        self.ancestry.encode_to(out);
+    }
+}
+
+impl<H: Decode> Decode for Justification<H> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Justification {
+            round: Decode::decode_from(input)?,
+            target: Decode::decode_from(input)?,
+            target_height: Decode::decode_from(input)?,
+            precommits: Decode::decode_from(input)?,
+            ancestry: Decode::decode_from(input)?,
+        })
+    }
+}
+
+/// Anyone who knows the voter set can check that a justification proves a header final, which
+/// is what a client needs before it warps to that header's state.
+impl<H: ChainHeader, D> FinalityProof<D> for (&VoterSet, &Justification<H>)
+where
+    Header<D>: ChainHeader,
+{
+    fn proves_final(&self, header: &Header<D>) -> bool {
+        let (voters, justification) = self;
+        justification.target == header.hash()
+            && justification.target_height == header.number()
+            && justification.verify(voters).is_ok()
+    }
+}
+
+/// The messages exchanged by finality gadgets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinalityMessage<H> {
+    Vote(Vote),
+    Justification(Justification<H>),
+}
+
+/// One participant in the finality protocol. Nodes that are not voters can still run a gadget
+/// to follow along and finalize blocks from the votes they see.
+pub struct FinalityGadget<H> {
+    voters: VoterSet,
+    /// The voter this gadget votes as, if any.
+    local: Option<ConsensusAuthority>,
+    round: u64,
+    /// The hash and height of the most recently finalized block.
+    finalized: (Hash, u64),
+    /// Votes received for the current and future rounds, in the order they arrived.
+    votes: HashMap<(u64, VoteKind), Vec<Vote>>,
+    /// Whether the local voter has prevoted in the current round.
+    prevoted: bool,
+    /// Whether the local voter has precommitted in the current round.
+    precommitted: bool,
+    /// The justifications of blocks finalized since the node last took them.
+    finalized_justifications: Vec<Justification<H>>,
+    /// Valid justifications for blocks we have not imported yet.
+    pending_justifications: Vec<Justification<H>>,
+    /// Messages produced while handling others, waiting to be sent.
+    outbox: Vec<FinalityMessage<H>>,
+}
+
+impl<H: ChainHeader> FinalityGadget<H> {
+    /// Create a gadget that starts voting on top of the given genesis block.
+    pub fn new(voters: VoterSet, local: Option<ConsensusAuthority>, genesis: Hash) -> Self {
+        Self {
+            voters,
+            local,
+            round: 0,
+            finalized: (genesis, 0),
+            votes: HashMap::new(),
+            prevoted: false,
+            precommitted: false,
+            finalized_justifications: Vec::new(),
+            pending_justifications: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// The voter set this gadget uses.
+    pub fn voters(&self) -> &VoterSet {
+        &self.voters
+    }
+
+    /// The round currently being played.
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// The hash and height of the most recently finalized block.
+    pub fn finalized(&self) -> (Hash, u64) {
+        self.finalized
+    }
+
+    /// Take the justifications of the blocks finalized since the last call, oldest first. The
+    /// node running the gadget should finalize those blocks and store the justifications with
+    /// them.
+    pub fn take_justifications(&mut self) -> Vec<Justification<H>> {
+        std::mem::take(&mut self.finalized_justifications)
+    }
+
+    /// Handle a message from another participant. Returns whether it was new and valid, and so
+    /// worth passing on.
+    pub fn import_message<V: VotingChain<Header = H>>(
+        &mut self,
+        chain: &V,
+        message: FinalityMessage<H>,
+    ) -> bool {
+        match message {
+            FinalityMessage::Vote(vote) => self.import_vote(vote),
+            FinalityMessage::Justification(justification) => self
+                .import_justification(chain, justification)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Record a vote. Votes from old rounds, from unknown voters, and second votes of the same
+    /// kind from the same voter in the same round are ignored.
+    pub fn import_vote(&mut self, vote: Vote) -> bool {
+        if vote.round < self.round || self.voters.weight(vote.voter).is_none() {
+            return false;
+        }
+        let votes = self.votes.entry((vote.round, vote.kind)).or_default();
+        if votes.iter().any(|v| v.voter == vote.voter) {
+            return false;
+        }
+        votes.push(vote);
+        true
+    }
+
+    /// Verify a justification and finalize its target. If the target has not been imported yet
+    /// the justification is kept until it is. Returns whether the justification was new.
+    ///
+    /// The height is only claimed, so a pending justification only counts as the same one if it
+    /// claims the same height too. Otherwise a copy with a wrong height could shut out the real one.
+    pub fn import_justification<V: VotingChain<Header = H>>(
+        &mut self,
+        chain: &V,
+        justification: Justification<H>,
+    ) -> Result<bool, JustificationError> {
+        if justification.target_height <= self.finalized.1
+            || self.pending_justifications.iter().any(|j| {
+                (j.target, j.target_height) == (justification.target, justification.target_height)
+            })
+        {
+            return Ok(false);
+        }
+        justification.verify(&self.voters)?;
+        self.pending_justifications.push(justification);
+        self.apply_pending_justifications(chain);
+        Ok(true)
+    }
+
+    /// Take part in the protocol as far as the votes received so far allow, and return the
+    /// messages that should be sent to the other participants.
+    pub fn poll<V: VotingChain<Header = H>>(&mut self, chain: &V) -> Vec<FinalityMessage<H>> {
+        self.apply_pending_justifications(chain);
+        while self.step(chain) {}
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Make one step of progress in the current round. Returns whether the round completed.
+    fn step<V: VotingChain<Header = H>>(&mut self, chain: &V) -> bool {
+        if let Some(voter) = self.local {
+            if !self.prevoted {
+                let best = chain.best_hash();
+                if let Some(header) = chain.header(best) {
+                    if header.number() > self.finalized.1 && self.route(chain, best).is_some() {
+                        self.cast(voter, VoteKind::Prevote, best, header.number());
+                        self.prevoted = true;
+                    }
+                }
+            }
+            if !self.precommitted {
+                if let Some((target, height)) = self.ghost(chain, VoteKind::Prevote) {
+                    self.cast(voter, VoteKind::Precommit, target, height);
+                    self.precommitted = true;
+                }
+            }
+        }
+
+        let Some((target, height)) = self.ghost(chain, VoteKind::Precommit) else {
+            return false;
+        };
+        if height > self.finalized.1 {
+            let justification = self.build_justification(chain, target, height);
+            self.outbox
+                .push(FinalityMessage::Justification(justification.clone()));
+            self.finalize(justification);
+        }
+        self.start_round(self.round + 1);
+        true
+    }
+
+    fn cast(&mut self, voter: ConsensusAuthority, kind: VoteKind, target: Hash, height: u64) {
+        let vote = Vote {
+            round: self.round,
+            kind,
+            target,
+            target_height: height,
+            voter,
+        };
+        self.import_vote(vote);
+        self.outbox.push(FinalityMessage::Vote(vote));
+    }
+
+    /// The blocks from the given block back to the finalized block, inclusive, or None if the
+    /// given block is unknown or does not descend from the finalized block.
+    fn route<V: VotingChain<Header = H>>(&self, chain: &V, from: Hash) -> Option<Vec<(Hash, u64)>> {
+        let mut route = Vec::new();
+        let mut current = from;
+        while current != self.finalized.0 {
+            let header = chain.header(current)?;
+            if header.number() <= self.finalized.1 {
+                return None;
+            }
+            route.push((current, header.number()));
+            current = header.parent_hash();
+        }
+        route.push(self.finalized);
+        Some(route)
+    }
+
+    /// The highest block that more than 2/3 of the current round's votes of the given kind are
+    /// for or build upon. Votes for blocks we do not know are not counted.
+    fn ghost<V: VotingChain<Header = H>>(&self, chain: &V, kind: VoteKind) -> Option<(Hash, u64)> {
+        let votes = self.votes.get(&(self.round, kind))?;
+        let mut counted = 0;
+        let mut weights: HashMap<(Hash, u64), u64> = HashMap::new();
+        for vote in votes {
+            let Some(route) = self.route(chain, vote.target) else {
+                continue;
+            };
+            let weight = self.voters.weight(vote.voter).unwrap_or(0);
+            counted += weight;
+            for block in route {
+                *weights.entry(block).or_default() += weight;
+            }
+        }
+
+        let threshold = self.voters.threshold();
+        if counted < threshold {
+            return None;
+        }
+        weights
+            .into_iter()
+            .filter(|(_, weight)| *weight >= threshold)
+            .map(|(block, _)| block)
+            .max_by_key(|(block_hash, height)| (*height, std::cmp::Reverse(*block_hash)))
+    }
+
+    /// Collect the current round's precommits that build upon the given block into a justification.
+    fn build_justification<V: VotingChain<Header = H>>(
+        &self,
+        chain: &V,
+        target: Hash,
+        target_height: u64,
+    ) -> Justification<H> {
+        let mut precommits = Vec::new();
+        let mut ancestry: Vec<H> = Vec::new();
+        for vote in &self.votes[&(self.round, VoteKind::Precommit)] {
+            let Some(route) = self.route(chain, vote.target) else {
+                continue;
+            };
+            let Some(position) = route.iter().position(|(h, _)| *h == target) else {
+                continue;
+            };
+            precommits.push(*vote);
+            for (block_hash, _) in &route[..position] {
+                if !ancestry.iter().any(|h| h.hash() == *block_hash) {
+                    ancestry.extend(chain.header(*block_hash));
+                }
+            }
+        }
+        Justification {
+            round: self.round,
+            target,
+            target_height,
+            precommits,
+            ancestry,
+        }
+    }
+
+    /// Finalize the targets of any pending justifications that we can now place on our chain.
+    fn apply_pending_justifications<V: VotingChain<Header = H>>(&mut self, chain: &V) {
+        let mut pending = std::mem::take(&mut self.pending_justifications);
+        pending.sort_by_key(|j| j.target_height);
+        for justification in pending {
+            if justification.target_height <= self.finalized.1 {
+                continue;
+            }
+            let Some(header) = chain.header(justification.target) else {
+                self.pending_justifications.push(justification);
+                continue;
+            };
+            // The votes commit to the target's hash, but its height is only claimed.
+            if header.number() != justification.target_height {
+                continue;
+            }
+            // A verified justification for a block that conflicts with our finalized chain
+            // means more than 1/3 of the voters misbehaved. We keep our own finality.
+            if self.route(chain, justification.target).is_some() {
+                let round = justification.round;
+                self.finalize(justification);
+                if round >= self.round {
+                    self.start_round(round + 1);
+                }
+            }
+        }
+    }
+
+    fn finalize(&mut self, justification: Justification<H>) {
+        self.finalized = (justification.target, justification.target_height);
+        self.finalized_justifications.push(justification);
+    }
+
+    fn start_round(&mut self, round: u64) {
+        self.round = round;
+        self.prevoted = false;
+        self.precommitted = false;
+        self.votes.retain(|(r, _), _| *r >= round);
+    }
+}
+
+/// The reasons a block imported along with its justification may be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinalizedImportError {
+    /// The justification is invalid.
+    BadJustification(JustificationError),
+    /// The node refused to import the block itself.
+    InvalidBlock,
+    /// The block is valid and justified, but conflicts with a block we already finalized.
+    ConflictsWithFinalized,
+}
+
+/// A node that runs a finality gadget next to its regular block import. Every block the gadget
+/// finalizes is finalized in the node as well, and its justification is stored with it there.
+pub struct FinalityNode<N: SimNode> {
+    node: N,
+    gadget: FinalityGadget<N::Block>,
+}
+
+impl<N: SimNode> FinalityNode<N>
+where
+    N::Block: ChainHeader + Encode,
+{
+    /// Wrap a node, voting as the given authority if any. Finality starts from the node's
+    /// genesis block.
+    pub fn new(node: N, voters: VoterSet, local: Option<ConsensusAuthority>) -> Self {
+        let mut genesis = node.best_block();
+        while let Some(parent) = node.block_parent(genesis) {
+            if node.block_height(parent).is_none() {
+                break;
+            }
+            genesis = parent;
+        }
+        Self {
+            gadget: FinalityGadget::new(voters, local, genesis),
+            node,
+        }
+    }
+
+    /// The wrapped node.
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    /// The finality gadget.
+    pub fn gadget(&self) -> &FinalityGadget<N::Block> {
+        &self.gadget
+    }
+
+    /// Hand the blocks the gadget finalized to the node, along with their justifications.
+    fn forward_finality(&mut self) {
+        for justification in self.gadget.take_justifications() {
+            self.node
+                .finalize_block(justification.target, justification.encode());
+        }
+    }
+
+    /// Import a block along with the justification that finalized it, as a node does when it
+    /// syncs blocks that were finalized while it was away. The justification is checked
+    /// before the block is imported.
+    pub fn import_block_with_justification(
+        &mut self,
+        block: N::Block,
+        justification: Justification<N::Block>,
+    ) -> Result<(), FinalizedImportError> {
+        if justification.target != block.hash() || justification.target_height != block.number() {
+            return Err(FinalizedImportError::BadJustification(
+                JustificationError::WrongTarget,
+            ));
+        }
+        justification
+            .verify(self.gadget.voters())
+            .map_err(FinalizedImportError::BadJustification)?;
+        let block_hash = block.hash();
+        if self.node.block_height(block_hash).is_none() && !self.node.import_block(block) {
+            return Err(FinalizedImportError::InvalidBlock);
+        }
+        self.gadget
+            .import_justification(&self.node, justification)
+            .map_err(FinalizedImportError::BadJustification)?;
+        self.forward_finality();
+        if self.node.justification(block_hash).is_none() {
+            return Err(FinalizedImportError::ConflictsWithFinalized);
+        }
+        Ok(())
+    }
+}
+
+impl<N: SimNode> FinalityNode<N>
+where
+    N::Block: ChainHeader + Decode,
+{
+    /// The justification the node stored with the given block, if any.
+    pub fn justification(&self, block_hash: Hash) -> Option<Justification<N::Block>> {
+        Justification::decode(&self.node.justification(block_hash)?).ok()
+    }
+}
+
+impl<N: SimNode> SimNode for FinalityNode<N>
+where
+    N::Block: ChainHeader + Encode,
+{
+    type Block = N::Block;
+    type Transaction = N::Transaction;
+    type Vote = FinalityMessage<N::Block>;
+
+    fn hash_of(block: &Self::Block) -> Hash {
+        N::hash_of(block)
+    }
+
+    fn parent_of(block: &Self::Block) -> Hash {
+        N::parent_of(block)
+    }
+
+    fn block_size(block: &Self::Block) -> usize {
+        N::block_size(block)
+    }
+
+    fn transaction_size(t: &Self::Transaction) -> usize {
+        N::transaction_size(t)
+    }
+
+    fn import_block(&mut self, block: Self::Block) -> bool {
+        self.node.import_block(block)
+    }
+
+    fn get_block(&self, block_hash: Hash) -> Option<Self::Block> {
+        self.node.get_block(block_hash)
+    }
+
+    fn block_height(&self, block_hash: Hash) -> Option<u64> {
+        self.node.block_height(block_hash)
+    }
+
+    fn block_parent(&self, block_hash: Hash) -> Option<Hash> {
+        self.node.block_parent(block_hash)
+    }
+
+    fn best_block(&self) -> Hash {
+        self.node.best_block()
+    }
+
+    fn submit_transaction(&mut self, t: Self::Transaction) -> bool {
+        self.node.submit_transaction(t)
+    }
+
+    fn author_block(&mut self, now: Time) -> Option<Self::Block> {
+        self.node.author_block(now)
+    }
+
+    fn vote_size(vote: &Self::Vote) -> usize {
+        match vote {
+            FinalityMessage::Vote(_) => 64,
+            FinalityMessage::Justification(j) => {
+                64 * j.precommits.len() + j.ancestry.iter().map(N::block_size).sum::<usize>()
+            }
+        }
+    }
+
+    fn import_vote(&mut self, vote: Self::Vote) -> bool {
+        let new = self.gadget.import_message(&self.node, vote);
+        self.forward_finality();
+        new
+    }
+
+    fn poll_votes(&mut self) -> Vec<Self::Vote> {
+        let messages = self.gadget.poll(&self.node);
+        self.forward_finality();
+        messages
+    }
+
+    fn justification(&self, block_hash: Hash) -> Option<Vec<u8>> {
+        self.node.justification(block_hash)
+    }
+
+    fn finalized_block(&self) -> Option<Hash> {
+        Some(self.gadget.finalized().0)
+    }
+}
+
+#[cfg(test)]
+use super::p9_network_simulator::{LinkConfig, NetworkSimulator};
+#[cfg(test)]
+use super::test_support::{test_client, MockBlock, MockNode};
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+impl ChainHeader for MockBlock {
+    fn hash(&self) -> Hash {
+        hash(self)
+    }
+
+    fn parent_hash(&self) -> Hash {
+        self.parent
+    }
+
+    fn number(&self) -> u64 {
+        self.height
+    }
+}
+
+/// Create finality nodes over mock nodes, one for each entry, voting as the given authority.
+#[cfg(test)]
+fn finality_network(
+    voters: &VoterSet,
+    locals: &[Option<ConsensusAuthority>],
+) -> Vec<FinalityNode<MockNode>> {
+    locals
+        .iter()
+        .enumerate()
+        .map(|(id, local)| FinalityNode::new(MockNode::new(id), voters.clone(), *local))
+        .collect()
+}
+
+/// Build a mock block on top of the given parent.
+#[cfg(test)]
+fn child_of(parent: &MockBlock, author: usize) -> MockBlock {
+    MockBlock {
+        parent: hash(parent),
+        height: parent.height + 1,
+        author,
+        transactions: vec![],
+    }
+}
+
+/// Deliver finality messages between the nodes in-process until none are left.
+#[cfg(test)]
+fn exchange_votes(nodes: &mut [FinalityNode<MockNode>]) {
+    loop {
+        let mut messages = Vec::new();
+        for (i, node) in nodes.iter_mut().enumerate() {
+            messages.extend(node.poll_votes().into_iter().map(|m| (i, m)));
+        }
+        if messages.is_empty() {
+            return;
+        }
+        for (from, message) in messages {
+            for (to, node) in nodes.iter_mut().enumerate() {
+                if to != from {
+                    node.import_vote(message.clone());
+                }
+            }
+        }
+    }
+}
+
+/// A chain of the given length built on top of the mock genesis block.
+#[cfg(test)]
+fn mock_chain(length: usize) -> Vec<MockBlock> {
+    let mut chain = vec![MockNode::new(0).blocks.into_values().next().unwrap()];
+    for _ in 0..length {
+        chain.push(child_of(chain.last().unwrap(), 0));
+    }
+    chain.remove(0);
+    chain
+}
+
+#[test]
+fn client_14_voter_set_threshold() {
+    let equal = VoterSet::equal(&[Alice, Bob, Charlie]);
+    assert_eq!(equal.total_weight(), 3);
+    assert_eq!(equal.threshold(), 3);
+
+    let weighted = VoterSet::new(vec![(Alice, 2), (Bob, 1), (Charlie, 1), (Alice, 5)]);
+    assert_eq!(weighted.total_weight(), 4);
+    assert_eq!(weighted.threshold(), 3);
+    assert_eq!(weighted.weight(Alice), Some(2));
+    assert_eq!(VoterSet::equal(&[Alice]).weight(Bob), None);
+}
+
+#[test]
+fn client_14_single_voter_finalizes_its_best_block() {
+    let mut nodes = finality_network(&VoterSet::equal(&[Alice]), &[Some(Alice)]);
+    let block = nodes[0].author_block(0).unwrap();
+    exchange_votes(&mut nodes);
+
+    assert_eq!(nodes[0].gadget().finalized(), (hash(&block), 1));
+    assert_eq!(nodes[0].gadget().round(), 1);
+    assert!(nodes[0].justification(hash(&block)).is_some());
+}
+
+#[test]
+fn client_14_three_voters_finalize_shared_chain() {
+    let voters = VoterSet::equal(&[Alice, Bob, Charlie]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), Some(Bob), Some(Charlie), None]);
+    let chain = mock_chain(3);
+    for node in nodes.iter_mut() {
+        for block in &chain {
+            assert!(node.import_block(block.clone()));
+        }
+    }
+    exchange_votes(&mut nodes);
+
+    let tip = hash(chain.last().unwrap());
+    for node in &nodes {
+        assert_eq!(node.gadget().finalized(), (tip, 3));
+        let justification = node.justification(tip).unwrap();
+        assert_eq!(justification.verify(&voters), Ok(()));
+    }
+}
+
+#[test]
+fn client_14_no_finality_without_supermajority() {
+    // Bob and Charlie together have only half of the weight.
+    let voters = VoterSet::new(vec![(Alice, 2), (Bob, 1), (Charlie, 1)]);
+    let mut nodes = finality_network(&voters, &[Some(Bob), Some(Charlie)]);
+    let chain = mock_chain(2);
+    for node in nodes.iter_mut() {
+        for block in &chain {
+            node.import_block(block.clone());
+        }
+    }
+    exchange_votes(&mut nodes);
+
+    assert!(nodes.iter().all(|n| n.gadget().finalized().1 == 0));
+}
+
+#[test]
+fn client_14_forked_prevotes_finalize_common_ancestor() {
+    let voters = VoterSet::equal(&[Alice, Bob, Charlie]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), Some(Bob), Some(Charlie)]);
+    let a1 = mock_chain(1).remove(0);
+    let a2 = child_of(&a1, 0);
+    let b2 = child_of(&a1, 1);
+
+    // Alice and Bob see only the a2 fork, and Charlie sees only b2.
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.import_block(a1.clone());
+        node.import_block(if i < 2 { a2.clone() } else { b2.clone() });
+    }
+    exchange_votes(&mut nodes);
+
+    // Nobody can place every prevote, so nobody precommits.
+    for node in &nodes {
+        assert_eq!(node.gadget().finalized().1, 0);
+    }
+
+    // Once everyone knows both forks, the prevotes only agree on a1, so a1 is finalized.
+    // The next round then finalizes whichever fork the voters now all consider best.
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.import_block(if i < 2 { b2.clone() } else { a2.clone() });
+    }
+    exchange_votes(&mut nodes);
+    for node in &nodes {
+        let justification = node.justification(hash(&a1)).unwrap();
+        assert_eq!(justification.round, 0);
+        assert_eq!(node.gadget().finalized().1, 2);
+    }
+}
+
+#[test]
+fn client_14_justification_verification_catches_tampering() {
+    let voters = VoterSet::equal(&[Alice, Bob, Charlie]);
+    let chain = mock_chain(2);
+    let precommit = |voter, block: &MockBlock| Vote {
+        round: 4,
+        kind: VoteKind::Precommit,
+        target: hash(block),
+        target_height: block.height,
+        voter,
+    };
+    // Alice precommitted to a descendant of the justified block.
+    let valid = Justification {
+        round: 4,
+        target: hash(&chain[0]),
+        target_height: 1,
+        precommits: vec![
+            precommit(Alice, &chain[1]),
+            precommit(Bob, &chain[0]),
+            precommit(Charlie, &chain[0]),
+        ],
+        ancestry: vec![chain[1].clone()],
+    };
+    assert_eq!(valid.verify(&voters), Ok(()));
+
+    let mut missing_vote = valid.clone();
+    missing_vote.precommits.pop();
+    assert_eq!(
+        missing_vote.verify(&voters),
+        Err(JustificationError::NotEnoughWeight {
+            found: 2,
+            required: 3
+        })
+    );
+
+    let mut duplicate = valid.clone();
+    duplicate.precommits[1] = Vote {
+        voter: duplicate.precommits[0].voter,
+        ..duplicate.precommits[1]
+    };
+    assert!(matches!(
+        duplicate.verify(&voters),
+        Err(JustificationError::DuplicateVoter(_))
+    ));
+
+    let mut prevote = valid.clone();
+    prevote.precommits[2].kind = VoteKind::Prevote;
+    assert!(matches!(
+        prevote.verify(&voters),
+        Err(JustificationError::InvalidVote(_))
+    ));
+
+    assert_eq!(
+        valid.verify(&VoterSet::equal(&[Bob, Charlie])),
+        Err(JustificationError::UnknownVoter(Alice))
+    );
+
+    let mut no_ancestry = valid;
+    no_ancestry.ancestry.clear();
+    assert_eq!(
+        no_ancestry.verify(&voters),
+        Err(JustificationError::NotDescendant(hash(&chain[1])))
+    );
+}
+
+#[test]
+fn client_14_import_block_with_justification() {
+    let voters = VoterSet::equal(&[Alice, Bob, Charlie]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), Some(Bob), Some(Charlie)]);
+    let chain = mock_chain(2);
+    for node in nodes.iter_mut() {
+        for block in &chain {
+            node.import_block(block.clone());
+        }
+    }
+    exchange_votes(&mut nodes);
+    let tip = hash(&chain[1]);
+    let justification = nodes[0].justification(tip).unwrap().clone();
+
+    let mut follower = FinalityNode::new(MockNode::new(9), voters, None);
+    follower.import_block(chain[0].clone());
+
+    let mut forged = justification.clone();
+    forged.precommits.truncate(1);
+    assert!(matches!(
+        follower.import_block_with_justification(chain[1].clone(), forged),
+        Err(FinalizedImportError::BadJustification(
+            JustificationError::NotEnoughWeight { .. }
+        ))
+    ));
+    assert_eq!(follower.block_height(tip), None);
+
+    assert_eq!(
+        follower.import_block_with_justification(chain[0].clone(), justification.clone()),
+        Err(FinalizedImportError::BadJustification(
+            JustificationError::WrongTarget
+        ))
+    );
+
+    assert_eq!(
+        follower.import_block_with_justification(chain[1].clone(), justification.clone()),
+        Ok(())
+    );
+    assert_eq!(follower.gadget().finalized(), (tip, 2));
+    assert_eq!(follower.justification(tip), Some(justification));
+}
+
+#[test]
+fn client_14_justification_for_unknown_block_waits() {
+    let voters = VoterSet::equal(&[Alice]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), None]);
+    let block = nodes[0].author_block(0).unwrap();
+    let message = nodes[0].poll_votes().pop().unwrap();
+    assert!(matches!(message, FinalityMessage::Justification(_)));
+
+    assert!(nodes[1].import_vote(message.clone()));
+    assert!(!nodes[1].import_vote(message));
+    assert_eq!(nodes[1].gadget().finalized().1, 0);
+
+    nodes[1].import_block(block.clone());
+    nodes[1].poll_votes();
+    assert_eq!(nodes[1].gadget().finalized(), (hash(&block), 1));
+}
+
+#[test]
+fn client_14_gadget_runs_in_simulator() {
+    let voters = VoterSet::new(vec![(Alice, 2), (Bob, 1), (Charlie, 1)]);
+    // Charlie is offline, but Alice and Bob have more than 2/3 of the weight between them.
+    let nodes = finality_network(&voters, &[Some(Alice), Some(Bob), None, None]);
+    let mut sim = NetworkSimulator::new(nodes, LinkConfig::default(), 7);
+    sim.author_randomly(0, 20_000, 1_000);
+    sim.run_until(25_000);
+
+    let report = sim.report();
+    let best = sim.node(0).best_block();
+    let best_height = sim.node(0).block_height(best).unwrap();
+    assert!(report.finalized_height > 0);
+    assert_eq!(report.finalized_height, best_height);
+    let finalized = sim.node(0).finalized_block().unwrap();
+    for i in 0..sim.node_count() {
+        assert_eq!(sim.node(i).finalized_block(), Some(finalized));
+        assert_eq!(
+            sim.node(i)
+                .justification(finalized)
+                .unwrap()
+                .verify(&voters),
+            Ok(())
+        );
+    }
+}
+
+#[test]
+fn client_14_finality_reaches_the_client() {
+    let voters = VoterSet::equal(&[Alice]);
+    let mut node = FinalityNode::new(test_client(), voters.clone(), Some(Alice));
+    for now in 1..=3 {
+        let block = node.author_block(now).unwrap();
+        node.poll_votes();
+
+        let block_hash = block.hash();
+        assert_eq!(node.gadget().finalized(), (block_hash, now));
+        assert_eq!(super::ClientApi::finalized_block(node.node()), block_hash);
+        let stored = SimNode::justification(node.node(), block_hash).unwrap();
+        let justification = Justification::<Block<_, _>>::decode(&stored).unwrap();
+        assert_eq!(justification.verify(&voters), Ok(()));
+        assert_eq!(node.justification(block_hash), Some(justification));
+    }
+}
+
+#[test]
+fn client_14_justification_with_wrong_height_does_not_shut_out_the_real_one() {
+    let voters = VoterSet::equal(&[Alice]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), None]);
+    let block = nodes[0].author_block(0).unwrap();
+    let Some(FinalityMessage::Justification(justification)) = nodes[0].poll_votes().pop() else {
+        panic!("a single voter finalizes its block at once");
+    };
+
+    // Both arrive before the block they justify.
+    let mut lying = justification.clone();
+    lying.target_height = 7;
+    assert!(nodes[1].import_vote(FinalityMessage::Justification(lying)));
+    assert!(nodes[1].import_vote(FinalityMessage::Justification(justification)));
+
+    nodes[1].import_block(block.clone());
+    nodes[1].poll_votes();
+    assert_eq!(nodes[1].gadget().finalized(), (hash(&block), 1));
+}
+
+#[test]
+fn client_14_justification_with_wrong_height_ignored() {
+    let voters = VoterSet::equal(&[Alice]);
+    let mut nodes = finality_network(&voters, &[Some(Alice), None]);
+    let block = nodes[0].author_block(0).unwrap();
+    let Some(FinalityMessage::Justification(justification)) = nodes[0].poll_votes().pop() else {
+        panic!("a single voter finalizes its block at once");
+    };
+    nodes[1].import_block(block.clone());
+
+    let mut lying = justification.clone();
+    lying.target_height = 7;
+    assert_eq!(lying.verify(&voters), Ok(()));
+    nodes[1].import_vote(FinalityMessage::Justification(lying));
+    assert_eq!(nodes[1].gadget().finalized().1, 0);
+
+    assert!(nodes[1].import_vote(FinalityMessage::Justification(justification)));
+    assert_eq!(nodes[1].gadget().finalized(), (hash(&block), 1));
+}
+
+#[test]
+fn client_14_justification_proves_header_final() {
+    let voters = VoterSet::equal(&[Alice, Bob, Charlie]);
+    let header = Header {
+        parent: 0,
+        height: 4,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: 0u64,
+    };
+    let precommit = |voter| Vote {
+        round: 2,
+        kind: VoteKind::Precommit,
+        target: hash(&header),
+        target_height: 4,
+        voter,
+    };
+    let justification: Justification<Header<u64>> = Justification {
+        round: 2,
+        target: hash(&header),
+        target_height: 4,
+        precommits: vec![precommit(Alice), precommit(Bob), precommit(Charlie)],
+        ancestry: vec![],
+    };
+
+    assert!((&voters, &justification).proves_final(&header));
+    let other = Header {
+        height: 5,
+        ..header.clone()
+    };
+    assert!(!(&voters, &justification).proves_final(&other));
+    assert!(!(&VoterSet::equal(&[Alice, Bob]), &justification).proves_final(&header));
+}
@@ -0,0 +1,504 @@
+//! The pools we wrote earlier treat every transaction on its own. But transactions often depend on
+//! one another. A user may send several transactions in quick succession, each spending money that
+//! the previous one received, or a cash transaction may spend a bill that another pending
+//! transaction creates. A pool that ignores these dependencies either rejects the later
+//! transactions or hands them to the block author in an order in which they cannot be applied.
+//!
+//! Validation tells us about dependencies through tags. Each transaction provides some tags and
+//! requires others. The tags a transaction requires are those that the current state does not
+//! already satisfy, so they must be provided by other transactions in the pool.
+//!
+//! This pool keeps two queues:
+//! * Ready - Transactions whose required tags are all provided by other ready transactions, or by
+//!   transactions that were already taken out of the pool for inclusion in a block.
+//! * Future - Transactions that are still waiting for some tag. They move to the ready queue as
+//!   soon as transactions providing their tags arrive.
+//!
+//! When a block author asks for the next transaction, it gets the highest priority ready
+//! transaction whose providers have all already been handed out, so transactions always come out
+//! in an order in which they can be applied. The pool remembers what it handed out until the
+//! block is imported and the pool is revalidated. A transaction that is put back, because it did
+//! not fit or the block failed, or that is removed, stops providing its tags.
+//!
+//! Transactions do not stay in the pool forever. Each one is valid for a number of blocks, its
+//! longevity, counted from the best block when it entered the pool. The pool is told about every
+//! new best block, and drops the transactions whose longevity has run out.
+
+use super::p4_transaction_pool::TransactionPool;
+use super::StateMachine;
+use crate::c1_state_machine::p8_transaction_validity::{TransactionTag, ValidTransaction};
+
+/// A transaction in the pool along with its validity information.
+#[derive(Clone, Debug)]
+struct PoolEntry<T> {
+    transaction: T,
+    validity: ValidTransaction,
+    /// When the transaction entered the pool. Older transactions go first among equals.
+    sequence: u64,
+    /// The height of the best block at which the transaction expires.
+    valid_till: u64,
+}
+
+/// A transaction pool with separate ready and future queues driven by dependency tags.
+#[derive(Clone, Debug)]
+pub struct TaggedPool<T> {
+    ready: Vec<PoolEntry<T>>,
+    future: Vec<PoolEntry<T>>,
+    /// Transactions that were taken out of the pool by `next_from_pool`, along with the tags
+    /// they provide. They will be provided by the block being built.
+    taken: Vec<(T, Vec<TransactionTag>)>,
+    next_sequence: u64,
+    /// The height of the best block, as of the last call to `prune_expired`.
+    best_number: u64,
+}
+
+impl<T> Default for TaggedPool<T> {
+    fn default() -> Self {
+        Self {
+            ready: Vec::new(),
+            future: Vec::new(),
+            taken: Vec::new(),
+            next_sequence: 0,
+            best_number: 0,
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> TaggedPool<T> {
+    /// Create an empty pool.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a transaction along with its validity information. It goes to the ready queue if its
+    /// requirements are met, and the future queue otherwise.
+    ///
+    /// A transaction that was taken earlier is being put back, so its tags are no longer
+    /// provided by the block being built.
+    ///
+    /// Returns false if the transaction is already in the pool, or provides a tag that another
+    /// pooled transaction already provides.
+    pub fn import(&mut self, transaction: T, validity: ValidTransaction) -> bool {
+        if self.contains(&transaction) {
+            return false;
+        }
+        if self.release(&transaction) {
+            self.demote();
+        }
+        if !self.conflicting(&validity).is_empty() {
+            return false;
+        }
+        let entry = PoolEntry {
+            transaction,
+            sequence: self.next_sequence,
+            valid_till: self.best_number.saturating_add(validity.longevity),
+            validity,
+        };
+        self.next_sequence += 1;
+        if self.requirements_met(&entry) {
+            self.ready.push(entry);
+            self.promote();
+        } else {
+            self.future.push(entry);
+        }
+        true
+    }
+
+    /// Whether the transaction is in either queue.
+    pub fn contains(&self, transaction: &T) -> bool {
+        self.entries().any(|e| e.transaction == *transaction)
+    }
+
+    /// The number of transactions that are ready to be included.
+    pub fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// The number of transactions waiting for their requirements.
+    pub fn future_count(&self) -> usize {
+        self.future.len()
+    }
+
+    /// Whether the transaction is in the ready queue.
+    pub fn is_ready(&self, transaction: &T) -> bool {
+        self.ready.iter().any(|e| e.transaction == *transaction)
+    }
+
+    /// Every pooled transaction along with its validity information, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, &ValidTransaction)> {
+        let mut entries: Vec<_> = self.entries().collect();
+        entries.sort_by_key(|e| e.sequence);
+        entries.into_iter().map(|e| (&e.transaction, &e.validity))
+    }
+
+    /// The pooled transactions that provide any of the tags the given validity provides.
+    pub fn conflicting(&self, validity: &ValidTransaction) -> Vec<T> {
+        self.entries()
+            .filter(|e| {
+                validity
+                    .provides
+                    .iter()
+                    .any(|tag| e.validity.provides.contains(tag))
+            })
+            .map(|e| e.transaction.clone())
+            .collect()
+    }
+
+    /// Remove a transaction from whichever queue it is in, or forget that it was taken. Ready
+    /// transactions that depended on it go back to the future queue.
+    pub fn remove(&mut self, transaction: &T) -> bool {
+        let before = self.ready.len() + self.future.len();
+        self.ready.retain(|e| e.transaction != *transaction);
+        self.future.retain(|e| e.transaction != *transaction);
+        let released = self.release(transaction);
+        if self.ready.len() + self.future.len() == before && !released {
+            return false;
+        }
+        self.demote();
+        true
+    }
+
+    /// Take the highest priority ready transaction whose providers have all been taken already.
+    pub fn next_ready(&mut self) -> Option<T> {
+        let index = self
+            .ready
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| self.unblocked(entry))
+            .max_by_key(|(_, e)| (e.validity.priority, std::cmp::Reverse(e.sequence)))
+            .map(|(i, _)| i)?;
+        let entry = self.ready.remove(index);
+        self.taken
+            .push((entry.transaction.clone(), entry.validity.provides));
+        self.promote();
+        Some(entry.transaction)
+    }
+
+    /// Record a new best block height, and remove the transactions whose longevity has run out
+    /// by that height. Returns the removed transactions.
+    pub fn prune_expired(&mut self, best_number: u64) -> Vec<T> {
+        self.best_number = best_number;
+        let mut expired = Vec::new();
+        for queue in [&mut self.ready, &mut self.future] {
+            let (gone, kept): (Vec<_>, _) =
+                queue.drain(..).partition(|e| e.valid_till <= best_number);
+            *queue = kept;
+            expired.extend(gone);
+        }
+        self.demote();
+        expired.sort_by_key(|e| e.sequence);
+        expired.into_iter().map(|e| e.transaction).collect()
+    }
+
+    /// Validate every pooled transaction again with the given function, replacing its validity
+    /// information, and remove those it rejects. Returns the removed transactions.
+    ///
+    /// Transactions that stay keep their place in line, and their longevity keeps counting from
+    /// when they entered the pool. A shorter longevity can bring their expiry closer, but a
+    /// longer one never pushes it back. Since revalidation happens against the state of a new best
+    /// block, the tags of transactions taken earlier are forgotten.
+    pub fn revalidate(
+        &mut self,
+        mut validate: impl FnMut(&T) -> Option<ValidTransaction>,
+    ) -> Vec<T> {
+        self.taken.clear();
+        let mut entries: Vec<_> = self.ready.drain(..).chain(self.future.drain(..)).collect();
+        entries.sort_by_key(|e| e.sequence);
+        let mut removed = Vec::new();
+        for mut entry in entries {
+            match validate(&entry.transaction) {
+                Some(validity) if self.conflicting(&validity).is_empty() => {
+                    let valid_till = self.best_number.saturating_add(validity.longevity);
+                    entry.valid_till = entry.valid_till.min(valid_till);
+                    entry.validity = validity;
+                    self.future.push(entry);
+                }
+                _ => removed.push(entry.transaction),
+            }
+        }
+        self.promote();
+        removed
+    }
+
+    /// Take every transaction out of the pool, ready ones first, and forget which tags were
+    /// provided by transactions taken earlier.
+    pub fn drain_all(&mut self) -> Vec<T> {
+        self.taken.clear();
+        let mut entries: Vec<_> = self.ready.drain(..).chain(self.future.drain(..)).collect();
+        entries.sort_by_key(|e| e.sequence);
+        entries.into_iter().map(|e| e.transaction).collect()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &PoolEntry<T>> {
+        self.ready.iter().chain(self.future.iter())
+    }
+
+    /// Forget that the transaction was taken. Returns whether it was.
+    fn release(&mut self, transaction: &T) -> bool {
+        let before = self.taken.len();
+        self.taken.retain(|(t, _)| t != transaction);
+        self.taken.len() != before
+    }
+
+    /// Whether the tag is provided by a ready transaction or one that was already taken.
+    fn available(&self, tag: &TransactionTag) -> bool {
+        self.taken
+            .iter()
+            .any(|(_, provides)| provides.contains(tag))
+            || self.ready.iter().any(|e| e.validity.provides.contains(tag))
+    }
+
+    fn requirements_met(&self, entry: &PoolEntry<T>) -> bool {
+        entry
+            .validity
+            .requires
+            .iter()
+            .all(|tag| self.available(tag))
+    }
+
+    /// Whether none of the entry's requirements are still waiting in the ready queue.
+    fn unblocked(&self, entry: &PoolEntry<T>) -> bool {
+        entry.validity.requires.iter().all(|tag| {
+            !self
+                .ready
+                .iter()
+                .any(|other| other.validity.provides.contains(tag))
+        })
+    }
+
+    /// Move future transactions whose requirements are now met to the ready queue.
+    fn promote(&mut self) {
+        while let Some(index) = self.future.iter().position(|e| self.requirements_met(e)) {
+            let entry = self.future.remove(index);
+            self.ready.push(entry);
+        }
+    }
+
+    /// Move ready transactions whose requirements are no longer met back to the future queue.
+    fn demote(&mut self) {
+        while let Some(index) = self.ready.iter().position(|e| !self.requirements_met(e)) {
+            let entry = self.ready.remove(index);
+            self.future.push(entry);
+        }
+    }
+}
+
+impl<SM> TransactionPool<SM> for TaggedPool<SM::Transition>
+where
+    SM: StateMachine,
+    SM::Transition: Clone + PartialEq,
+{
+    /// Transactions inserted without validity information have no dependencies.
+    fn try_insert(&mut self, t: SM::Transition) -> bool {
+        self.import(t, ValidTransaction::default())
+    }
+
+    fn remove(&mut self, t: SM::Transition) {
+        TaggedPool::remove(self, &t);
+    }
+
+    fn size(&self) -> usize {
+        self.ready.len() + self.future.len()
+    }
+
+    fn contains(&self, t: SM::Transition) -> bool {
+        TaggedPool::contains(self, &t)
+    }
+
+    fn next_from_pool(&mut self) -> Option<SM::Transition> {
+        self.next_ready()
+    }
+
+    fn try_insert_validated(&mut self, t: SM::Transition, validity: ValidTransaction) -> bool {
+        self.import(t, validity)
+    }
+
+    fn drain(&mut self) -> Vec<SM::Transition> {
+        self.drain_all()
+    }
+
+    fn prune_expired(&mut self, best_number: u64) -> usize {
+        TaggedPool::prune_expired(self, best_number).len()
+    }
+
+    fn revalidate(
+        &mut self,
+        validate: &mut dyn FnMut(&SM::Transition) -> Option<ValidTransaction>,
+    ) -> usize {
+        TaggedPool::revalidate(self, validate).len()
+    }
+}
+
+/// Validity information for testing, with tags given as strings.
+#[cfg(test)]
+fn validity(priority: u64, provides: &[&str], requires: &[&str]) -> ValidTransaction {
+    ValidTransaction {
+        priority,
+        provides: provides.iter().map(|t| t.as_bytes().to_vec()).collect(),
+        requires: requires.iter().map(|t| t.as_bytes().to_vec()).collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn client_15_independent_transactions_by_priority() {
+    let mut pool = TaggedPool::new();
+    assert!(pool.import(1, validity(5, &[], &[])));
+    assert!(pool.import(2, validity(9, &[], &[])));
+    assert!(pool.import(3, validity(5, &[], &[])));
+    assert!(!pool.import(3, validity(5, &[], &[])));
+
+    assert_eq!(pool.ready_count(), 3);
+    let order: Vec<_> = std::iter::from_fn(|| pool.next_ready()).collect();
+    assert_eq!(order, vec![2, 1, 3]);
+}
+
+#[test]
+fn client_15_future_transactions_wait_for_their_tags() {
+    let mut pool = TaggedPool::new();
+    // Alice's nonce 2 arrives before nonce 1.
+    pool.import(2, validity(0, &["alice-2"], &["alice-1"]));
+    assert_eq!(pool.future_count(), 1);
+    assert_eq!(pool.next_ready(), None);
+
+    pool.import(1, validity(0, &["alice-1"], &[]));
+    assert_eq!(pool.ready_count(), 2);
+    assert!(pool.is_ready(&2));
+}
+
+#[test]
+fn client_15_dependencies_come_out_in_order() {
+    let mut pool = TaggedPool::new();
+    // The dependent transaction has the highest priority, but must still wait for its parent.
+    pool.import(30, validity(100, &["c"], &["b"]));
+    pool.import(20, validity(1, &["b"], &["a"]));
+    pool.import(10, validity(1, &["a"], &[]));
+    pool.import(99, validity(50, &[], &[]));
+
+    let order: Vec<_> = std::iter::from_fn(|| pool.next_ready()).collect();
+    assert_eq!(order, vec![99, 10, 20, 30]);
+}
+
+#[test]
+fn client_15_taken_transactions_still_satisfy_dependents() {
+    let mut pool = TaggedPool::new();
+    pool.import(1, validity(0, &["a"], &[]));
+    assert_eq!(pool.next_ready(), Some(1));
+
+    // Transaction 1 is on its way into a block, so its dependent is ready.
+    pool.import(2, validity(0, &[], &["a"]));
+    assert!(pool.is_ready(&2));
+    assert_eq!(pool.next_ready(), Some(2));
+}
+
+#[test]
+fn client_15_putting_back_or_removing_taken_transactions_releases_their_tags() {
+    let mut pool = TaggedPool::new();
+    pool.import(1, validity(0, &["a"], &[]));
+    pool.import(2, validity(0, &["b"], &[]));
+    assert_eq!(pool.next_ready(), Some(1));
+    assert_eq!(pool.next_ready(), Some(2));
+    pool.import(3, validity(0, &[], &["a"]));
+    pool.import(4, validity(0, &[], &["b"]));
+    assert_eq!(pool.ready_count(), 2);
+
+    // Transaction 2 was dropped from the block, so nothing provides what 4 requires.
+    assert!(pool.remove(&2));
+    assert!(!pool.is_ready(&4));
+
+    // The block failed, so transaction 1 goes back and must be taken again before 3.
+    assert!(pool.import(1, validity(0, &["a"], &[])));
+    assert_eq!(pool.next_ready(), Some(1));
+    assert_eq!(pool.next_ready(), Some(3));
+    assert_eq!(pool.next_ready(), None);
+}
+
+#[test]
+fn client_15_removing_a_provider_demotes_dependents() {
+    let mut pool = TaggedPool::new();
+    pool.import(1, validity(0, &["a"], &[]));
+    pool.import(2, validity(0, &["b"], &["a"]));
+    pool.import(3, validity(0, &[], &["b"]));
+    assert_eq!(pool.ready_count(), 3);
+
+    assert!(pool.remove(&1));
+    assert_eq!(pool.ready_count(), 0);
+    assert_eq!(pool.future_count(), 2);
+    assert!(!pool.remove(&1));
+}
+
+#[test]
+fn client_15_conflicting_tags_are_rejected() {
+    let mut pool = TaggedPool::new();
+    assert!(pool.import(1, validity(0, &["alice-1"], &[])));
+    assert!(!pool.import(2, validity(0, &["alice-1"], &[])));
+    assert!(!pool.contains(&2));
+}
+
+#[test]
+fn client_15_expired_transactions_are_pruned() {
+    let mut pool = TaggedPool::new();
+    let short_lived = ValidTransaction {
+        longevity: 2,
+        ..validity(0, &["a"], &[])
+    };
+    pool.import(1, short_lived);
+    pool.import(2, validity(0, &[], &["a"]));
+    pool.import(3, validity(0, &[], &[]));
+
+    assert_eq!(pool.prune_expired(1), Vec::<u64>::new());
+    assert_eq!(pool.prune_expired(2), vec![1]);
+    // The dependent transaction is waiting for a provider again.
+    assert!(!pool.is_ready(&2));
+    assert!(pool.is_ready(&3));
+
+    // Longevity counts from the best block when the transaction entered the pool.
+    let from_now = ValidTransaction {
+        longevity: 2,
+        ..Default::default()
+    };
+    pool.import(4, from_now);
+    assert_eq!(pool.prune_expired(3), Vec::<u64>::new());
+    assert_eq!(pool.prune_expired(4), vec![4]);
+}
+
+#[test]
+fn client_15_revalidation_keeps_the_expiry() {
+    let mut pool = TaggedPool::new();
+    let short_lived = ValidTransaction {
+        longevity: 2,
+        ..Default::default()
+    };
+    pool.import(1, short_lived.clone());
+    pool.import(2, validity(0, &[], &[]));
+    pool.prune_expired(1);
+
+    // Revalidating does not restart the countdown, and drops what is no longer valid.
+    let removed = pool.revalidate(|t| (*t == 1).then(|| short_lived.clone()));
+    assert_eq!(removed, vec![2]);
+    assert_eq!(pool.prune_expired(2), vec![1]);
+}
+
+#[test]
+fn client_15_works_as_a_transaction_pool() {
+    use crate::c1_state_machine::p4_accounted_currency::{
+        AccountedCurrency, AccountingTransaction,
+    };
+    use crate::c1_state_machine::User;
+
+    let mint = |amount| AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    };
+    let mut pool = TaggedPool::new();
+    let as_pool: &mut dyn TransactionPool<AccountedCurrency> = &mut pool;
+    assert!(as_pool.try_insert(mint(1)));
+    assert!(as_pool.try_insert_validated(mint(2), validity(0, &[], &["never"])));
+    assert_eq!(as_pool.size(), 2);
+    assert!(as_pool.contains(mint(2)));
+
+    assert_eq!(as_pool.next_from_pool(), Some(mint(1)));
+    assert_eq!(as_pool.next_from_pool(), None);
+    assert_eq!(as_pool.drain(), vec![mint(2)]);
+    assert_eq!(as_pool.size(), 0);
+}
@@ -0,0 +1,406 @@
+//! A real node cannot keep every transaction it hears about. Memory is limited, and anyone on the
+//! network can send transactions for free until they are included in a block. A pool that grows
+//! without bound is an easy target for a denial of service attack.
+//!
+//! Here we wrap the tagged pool from the previous part with some limits:
+//! * A maximum number of transactions, and a maximum total encoded size.
+//!   When the pool is full, the lowest priority transactions are evicted to make room for better
+//!   ones. A transaction that is no better than anything in a full pool is rejected.
+//! * Replace by fee - A transaction from the same sender with the same nonce as a pooled one, or
+//!   one that provides the same tags, replaces it only if it pays a higher priority.
+//! * A maximum number of transactions from any single sender, so that one account cannot fill
+//!   the pool by itself.
+//!
+//! Every rejection comes with a reason, so that the submitter can learn what went wrong.
+
+use super::p15_tagged_pool::TaggedPool;
+use super::p4_transaction_pool::TransactionPool;
+use super::StateMachine;
+use crate::c1_state_machine::p8_transaction_validity::ValidTransaction;
+use crate::c1_state_machine::User;
+use crate::codec::Encode;
+
+/// Who sent a transaction, and its position among that sender's transactions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SenderNonce {
+    pub sender: User,
+    pub nonce: u64,
+}
+
+/// The limits a `BoundedPool` enforces. The default has no limits at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolLimits {
+    /// The maximum number of transactions in the pool.
+    pub max_count: usize,
+    /// The maximum total encoded size of the transactions in the pool.
+    pub max_bytes: usize,
+    /// The maximum number of transactions from any one sender.
+    pub max_per_sender: usize,
+}
+
+impl Default for PoolLimits {
+    fn default() -> Self {
+        Self {
+            max_count: usize::MAX,
+            max_bytes: usize::MAX,
+            max_per_sender: usize::MAX,
+        }
+    }
+}
+
+/// The reasons a bounded pool may refuse a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolRejection {
+    /// The transaction is already in the pool.
+    AlreadyInPool,
+    /// The transaction alone is bigger than the whole pool may be.
+    TooLarge { size: usize, max: usize },
+    /// The transaction would replace a pooled one, but does not pay a higher priority.
+    ReplacementUnderpriced { existing: u64 },
+    /// The sender already has as many transactions in the pool as allowed.
+    SenderLimitReached { sender: User, limit: usize },
+    /// The pool is full, and nothing in it has a lower priority than this transaction.
+    PoolFull,
+}
+
+/// A pooled transaction along with what the limits need to know about it.
+struct Candidate<T> {
+    transaction: T,
+    priority: u64,
+    sender: Option<SenderNonce>,
+    size: usize,
+}
+
+/// A tagged pool with limits on its size, and on the number of transactions per sender.
+pub struct BoundedPool<T, F: Fn(&T) -> Option<SenderNonce>> {
+    pool: TaggedPool<T>,
+    limits: PoolLimits,
+    /// A means of determining a transaction's sender and nonce. Transactions without one are
+    /// not subject to the per-sender limit.
+    sender_nonce: F,
+}
+
+impl<T, F> BoundedPool<T, F>
+where
+    T: Clone + PartialEq + Encode,
+    F: Fn(&T) -> Option<SenderNonce>,
+{
+    /// Create an empty pool with the given limits.
+    pub fn new(limits: PoolLimits, sender_nonce: F) -> Self {
+        Self {
+            pool: TaggedPool::new(),
+            limits,
+            sender_nonce,
+        }
+    }
+
+    /// The limits this pool enforces.
+    pub fn limits(&self) -> PoolLimits {
+        self.limits
+    }
+
+    /// The underlying tagged pool.
+    pub fn pool(&self) -> &TaggedPool<T> {
+        &self.pool
+    }
+
+    /// The total encoded size of the pooled transactions.
+    pub fn encoded_size(&self) -> usize {
+        self.pool.iter().map(|(t, _)| t.encoded_size()).sum()
+    }
+
+    /// Add a transaction along with its validity information.
+    ///
+    /// On success, returns the transactions that were replaced or evicted to make room for it.
+    /// Nothing in the pool changes when the transaction is rejected.
+    pub fn import(
+        &mut self,
+        transaction: T,
+        validity: ValidTransaction,
+    ) -> Result<Vec<T>, PoolRejection> {
+        if self.pool.contains(&transaction) {
+            return Err(PoolRejection::AlreadyInPool);
+        }
+        let size = transaction.encoded_size();
+        if size > self.limits.max_bytes {
+            return Err(PoolRejection::TooLarge {
+                size,
+                max: self.limits.max_bytes,
+            });
+        }
+        let sender = (self.sender_nonce)(&transaction);
+
+        // Find the transactions this one would replace.
+        let conflicting = self.pool.conflicting(&validity);
+        let (replaced, remaining): (Vec<_>, Vec<_>) =
+            self.candidates().into_iter().partition(|c| {
+                conflicting.contains(&c.transaction) || (sender.is_some() && c.sender == sender)
+            });
+        if let Some(existing) = replaced.iter().map(|c| c.priority).max() {
+            if existing >= validity.priority {
+                return Err(PoolRejection::ReplacementUnderpriced { existing });
+            }
+        }
+
+        if let Some(SenderNonce { sender, .. }) = sender {
+            let from_sender = remaining
+                .iter()
+                .filter(|c| c.sender.map(|s| s.sender) == Some(sender))
+                .count();
+            if from_sender >= self.limits.max_per_sender {
+                return Err(PoolRejection::SenderLimitReached {
+                    sender,
+                    limit: self.limits.max_per_sender,
+                });
+            }
+        }
+
+        // Evict the lowest priority transactions, newest first among equals, until there is room.
+        let mut count = remaining.len() + 1;
+        let mut bytes = remaining.iter().map(|c| c.size).sum::<usize>() + size;
+        let mut by_priority = remaining;
+        by_priority.reverse();
+        by_priority.sort_by_key(|c| c.priority);
+        let mut evicted = Vec::new();
+        for candidate in by_priority {
+            if count <= self.limits.max_count && bytes <= self.limits.max_bytes {
+                break;
+            }
+            if candidate.priority >= validity.priority {
+                return Err(PoolRejection::PoolFull);
+            }
+            count -= 1;
+            bytes -= candidate.size;
+            evicted.push(candidate.transaction);
+        }
+        if count > self.limits.max_count || bytes > self.limits.max_bytes {
+            return Err(PoolRejection::PoolFull);
+        }
+
+        let removed: Vec<_> = replaced
+            .into_iter()
+            .map(|c| c.transaction)
+            .chain(evicted)
+            .collect();
+        for t in &removed {
+            self.pool.remove(t);
+        }
+        let imported = self.pool.import(transaction, validity);
+        debug_assert!(imported, "conflicting transactions were removed above");
+        Ok(removed)
+    }
+
+    fn candidates(&self) -> Vec<Candidate<T>> {
+        self.pool
+            .iter()
+            .map(|(t, validity)| Candidate {
+                transaction: t.clone(),
+                priority: validity.priority,
+                sender: (self.sender_nonce)(t),
+                size: t.encoded_size(),
+            })
+            .collect()
+    }
+}
+
+impl<SM, F> TransactionPool<SM> for BoundedPool<SM::Transition, F>
+where
+    SM: StateMachine,
+    SM::Transition: Clone + PartialEq + Encode,
+    F: Fn(&SM::Transition) -> Option<SenderNonce>,
+{
+    fn try_insert(&mut self, t: SM::Transition) -> bool {
+        self.import(t, ValidTransaction::default()).is_ok()
+    }
+
+    fn remove(&mut self, t: SM::Transition) {
+        self.pool.remove(&t);
+    }
+
+    fn size(&self) -> usize {
+        self.pool.ready_count() + self.pool.future_count()
+    }
+
+    fn contains(&self, t: SM::Transition) -> bool {
+        self.pool.contains(&t)
+    }
+
+    fn next_from_pool(&mut self) -> Option<SM::Transition> {
+        self.pool.next_ready()
+    }
+
+    fn try_insert_validated(&mut self, t: SM::Transition, validity: ValidTransaction) -> bool {
+        self.import(t, validity).is_ok()
+    }
+
+    fn drain(&mut self) -> Vec<SM::Transition> {
+        self.pool.drain_all()
+    }
+
+    fn prune_expired(&mut self, best_number: u64) -> usize {
+        self.pool.prune_expired(best_number).len()
+    }
+
+    fn revalidate(
+        &mut self,
+        validate: &mut dyn FnMut(&SM::Transition) -> Option<ValidTransaction>,
+    ) -> usize {
+        self.pool.revalidate(validate).len()
+    }
+}
+
+/// A transaction for testing: the sender, the nonce, and some payload.
+#[cfg(test)]
+type TestTransaction = (User, u64, u64);
+
+#[cfg(test)]
+fn test_pool(
+    limits: PoolLimits,
+) -> BoundedPool<TestTransaction, impl Fn(&TestTransaction) -> Option<SenderNonce>> {
+    BoundedPool::new(limits, |t: &TestTransaction| {
+        Some(SenderNonce {
+            sender: t.0,
+            nonce: t.1,
+        })
+    })
+}
+
+#[cfg(test)]
+fn priority(priority: u64) -> ValidTransaction {
+    ValidTransaction {
+        priority,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn client_16_full_pool_evicts_lowest_priority() {
+    let mut pool = test_pool(PoolLimits {
+        max_count: 2,
+        ..Default::default()
+    });
+    assert_eq!(pool.import((User::Alice, 0, 0), priority(5)), Ok(vec![]));
+    assert_eq!(pool.import((User::Bob, 0, 0), priority(3)), Ok(vec![]));
+
+    assert_eq!(
+        pool.import((User::Charlie, 0, 0), priority(3)),
+        Err(PoolRejection::PoolFull)
+    );
+    assert_eq!(
+        pool.import((User::Charlie, 0, 0), priority(4)),
+        Ok(vec![(User::Bob, 0, 0)])
+    );
+    assert!(pool.pool().contains(&(User::Alice, 0, 0)));
+    assert!(!pool.pool().contains(&(User::Bob, 0, 0)));
+}
+
+#[test]
+fn client_16_byte_limit() {
+    // Each test transaction encodes to 17 bytes.
+    let mut pool = test_pool(PoolLimits {
+        max_bytes: 40,
+        ..Default::default()
+    });
+    pool.import((User::Alice, 0, 0), priority(1)).unwrap();
+    pool.import((User::Bob, 0, 0), priority(2)).unwrap();
+    assert_eq!(pool.encoded_size(), 34);
+
+    assert_eq!(
+        pool.import((User::Charlie, 0, 0), priority(3)),
+        Ok(vec![(User::Alice, 0, 0)])
+    );
+    assert_eq!(pool.encoded_size(), 34);
+
+    let mut tiny = test_pool(PoolLimits {
+        max_bytes: 10,
+        ..Default::default()
+    });
+    assert_eq!(
+        tiny.import((User::Alice, 0, 0), priority(1)),
+        Err(PoolRejection::TooLarge { size: 17, max: 10 })
+    );
+}
+
+#[test]
+fn client_16_replace_by_fee() {
+    let mut pool = test_pool(PoolLimits::default());
+    pool.import((User::Alice, 7, 100), priority(5)).unwrap();
+
+    assert_eq!(
+        pool.import((User::Alice, 7, 200), priority(5)),
+        Err(PoolRejection::ReplacementUnderpriced { existing: 5 })
+    );
+    assert_eq!(
+        pool.import((User::Alice, 7, 200), priority(6)),
+        Ok(vec![(User::Alice, 7, 100)])
+    );
+    // A different nonce does not replace anything.
+    assert_eq!(pool.import((User::Alice, 8, 100), priority(1)), Ok(vec![]));
+    assert_eq!(
+        pool.import((User::Alice, 8, 100), priority(1)),
+        Err(PoolRejection::AlreadyInPool)
+    );
+}
+
+#[test]
+fn client_16_conflicting_tags_replace_by_fee() {
+    let tagged = |p: u64| ValidTransaction {
+        priority: p,
+        provides: vec![b"bill".to_vec()],
+        ..Default::default()
+    };
+    let mut pool = BoundedPool::new(PoolLimits::default(), |_: &u64| None);
+    pool.import(1, tagged(1)).unwrap();
+    assert_eq!(
+        pool.import(2, tagged(1)),
+        Err(PoolRejection::ReplacementUnderpriced { existing: 1 })
+    );
+    assert_eq!(pool.import(2, tagged(2)), Ok(vec![1]));
+}
+
+#[test]
+fn client_16_per_sender_limit() {
+    let mut pool = test_pool(PoolLimits {
+        max_per_sender: 2,
+        ..Default::default()
+    });
+    pool.import((User::Alice, 0, 0), priority(1)).unwrap();
+    pool.import((User::Alice, 1, 0), priority(1)).unwrap();
+    assert_eq!(
+        pool.import((User::Alice, 2, 0), priority(100)),
+        Err(PoolRejection::SenderLimitReached {
+            sender: User::Alice,
+            limit: 2
+        })
+    );
+
+    // Replacing one of Alice's transactions does not count against her limit.
+    assert!(pool.import((User::Alice, 1, 1), priority(2)).is_ok());
+    assert!(pool.import((User::Bob, 0, 0), priority(1)).is_ok());
+}
+
+#[test]
+fn client_16_works_as_a_transaction_pool() {
+    use crate::c1_state_machine::p4_accounted_currency::{
+        AccountedCurrency, AccountingTransaction,
+    };
+
+    let mint = |amount| AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    };
+    let mut pool = BoundedPool::new(
+        PoolLimits {
+            max_count: 1,
+            ..Default::default()
+        },
+        |_: &AccountingTransaction| None,
+    );
+    let as_pool: &mut dyn TransactionPool<AccountedCurrency> = &mut pool;
+    assert!(as_pool.try_insert(mint(1)));
+    assert!(!as_pool.try_insert(mint(2)));
+    assert!(as_pool.try_insert_validated(mint(3), priority(1)));
+    assert!(!as_pool.contains(mint(1)));
+    assert_eq!(as_pool.size(), 1);
+    assert_eq!(as_pool.next_from_pool(), Some(mint(3)));
+}
@@ -0,0 +1,489 @@
+//! So far, an author simply puts every transaction from the pool into its block. But blocks can
+//! not grow without bound. Every node has to download, store, and execute every block, and must
+//! do so before the next one arrives. So chains limit how much may go into a single block:
+//! * The number of extrinsics.
+//! * The encoded size of the body.
+//! * The total weight of the body. Weight estimates how expensive a transaction is to execute,
+//!   which the number of transactions or their size alone does not capture.
+//!
+//! A block builder fills a block greedily. It takes transactions from the pool one at a time, in
+//! whatever order the pool hands them out, and applies each on top of the state built so far.
+//! Transactions that turn out to be invalid on that state are skipped, and may optionally be
+//! banned so that they do not waste the author's time again. Building stops as soon as the next
+//! transaction does not fit within the limits.
+//!
+//! Limits mean nothing if only honest authors respect them. Importers must check every block
+//! body against the very same limits, with `BlockLimits::check`, and reject blocks that exceed
+//! them. Wrapping any client in a `LimitedImport` does exactly that.
+
+use super::p4_transaction_pool::TransactionPool;
+use super::{forward_client_api, Block, Consensus, StateMachine};
+use crate::c1_state_machine::p4_accounted_currency::AccountingTransaction;
+use crate::c1_state_machine::p8_transaction_validity::{ValidTransaction, ValidateTransaction};
+use crate::codec::Encode;
+
+/// An estimate of how expensive something is to execute.
+pub type Weight = u64;
+
+/// Transactions whose execution cost can be estimated before executing them.
+pub trait Weigh {
+    /// The weight of this transaction.
+    fn weight(&self) -> Weight;
+}
+
+/// Transfers touch two accounts, while mints and burns only touch one.
+impl Weigh for AccountingTransaction {
+    fn weight(&self) -> Weight {
+        match self {
+            AccountingTransaction::Mint { .. } | AccountingTransaction::Burn { .. } => 1,
+            AccountingTransaction::Transfer { .. } => 2,
+        }
+    }
+}
+
+/// The limits on a single block's body. The default has no limits at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLimits {
+    /// The maximum number of extrinsics in a block.
+    pub max_extrinsics: usize,
+    /// The maximum encoded size of a block's body.
+    pub max_encoded_size: usize,
+    /// The maximum total weight of a block's extrinsics.
+    pub max_weight: Weight,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        Self {
+            max_extrinsics: usize::MAX,
+            max_encoded_size: usize::MAX,
+            max_weight: Weight::MAX,
+        }
+    }
+}
+
+/// The ways a block body may exceed the limits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockLimitError {
+    /// The body has too many extrinsics.
+    ExtrinsicCount { count: usize, max: usize },
+    /// The body's encoded size is too big.
+    EncodedSize { size: usize, max: usize },
+    /// The body's total weight is too high.
+    Weight { weight: Weight, max: Weight },
+}
+
+impl BlockLimits {
+    /// Check that a block body is within these limits. Importers should reject any block for
+    /// which this fails.
+    pub fn check<T: Encode + Weigh>(&self, body: &[T]) -> Result<(), BlockLimitError> {
+        if body.len() > self.max_extrinsics {
+            return Err(BlockLimitError::ExtrinsicCount {
+                count: body.len(),
+                max: self.max_extrinsics,
+            });
+        }
+        let size = body.encoded_size();
+        if size > self.max_encoded_size {
+            return Err(BlockLimitError::EncodedSize {
+                size,
+                max: self.max_encoded_size,
+            });
+        }
+        let weight = body
+            .iter()
+            .fold(0, |total: Weight, t| total.saturating_add(t.weight()));
+        if weight > self.max_weight {
+            return Err(BlockLimitError::Weight {
+                weight,
+                max: self.max_weight,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A block body produced by the block builder, along with what it took to build it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuiltBody<SM: StateMachine> {
+    /// The extrinsics to include in the block.
+    pub body: Vec<SM::Transition>,
+    /// The state after applying the body to the parent state.
+    pub state: SM::State,
+    /// The total weight of the body.
+    pub weight: Weight,
+    /// The encoded size of the body.
+    pub encoded_size: usize,
+    /// The transactions that were skipped because they were invalid.
+    pub skipped: Vec<SM::Transition>,
+}
+
+/// Fills block bodies from a transaction pool without exceeding the block limits.
+#[derive(Clone, Debug)]
+pub struct BlockBuilder<T> {
+    limits: BlockLimits,
+    /// Whether transactions found to be invalid while building are banned.
+    ban_invalid: bool,
+    banned: Vec<T>,
+}
+
+impl<T: Clone + PartialEq + Encode + Weigh> BlockBuilder<T> {
+    /// Create a builder for blocks with the given limits. If `ban_invalid` is set, transactions
+    /// that fail while building are dropped from the pool for good. Otherwise they go back to
+    /// the pool, since they may yet become valid.
+    pub fn new(limits: BlockLimits, ban_invalid: bool) -> Self {
+        Self {
+            limits,
+            ban_invalid,
+            banned: Vec::new(),
+        }
+    }
+
+    /// The limits of the blocks this builder builds.
+    pub fn limits(&self) -> BlockLimits {
+        self.limits
+    }
+
+    /// Whether the given transaction has been banned. Clients should refuse to pool banned
+    /// transactions.
+    pub fn is_banned(&self, t: &T) -> bool {
+        self.banned.contains(t)
+    }
+
+    /// Lift the ban on every transaction.
+    pub fn clear_bans(&mut self) {
+        self.banned.clear();
+    }
+
+    /// Fill a block body from the pool on top of the given parent state.
+    ///
+    /// Every transaction taken from the pool is either included in the body, banned, or put
+    /// back into the pool for a later block.
+    pub fn build<SM, P>(&mut self, parent_state: &SM::State, pool: &mut P) -> BuiltBody<SM>
+    where
+        SM: ValidateTransaction<Transition = T>,
+        SM::State: Clone,
+        P: TransactionPool<SM>,
+    {
+        let mut state = parent_state.clone();
+        let mut body = Vec::new();
+        let mut weight: Weight = 0;
+        let mut encoded_size = Vec::<T>::new().encoded_size();
+        let mut skipped = Vec::new();
+        let mut put_back: Vec<(T, Option<ValidTransaction>)> = Vec::new();
+
+        while body.len() < self.limits.max_extrinsics {
+            let Some(t) = pool.next_from_pool() else {
+                break;
+            };
+            // Banned transactions leave the pool for good, along with the tags they provide.
+            if self.is_banned(&t) {
+                pool.remove(t);
+                continue;
+            }
+            let validity = match SM::validate_transaction(&state, &t) {
+                Ok(validity) => validity,
+                Err(_) => {
+                    skipped.push(t.clone());
+                    if self.ban_invalid {
+                        pool.remove(t.clone());
+                        self.banned.push(t);
+                    } else {
+                        put_back.push((t, None));
+                    }
+                    continue;
+                }
+            };
+            // It depends on something that is not in this block, so it must wait.
+            if !validity.requires.is_empty() {
+                put_back.push((t, Some(validity)));
+                continue;
+            }
+            let t_weight = t.weight();
+            let t_size = t.encoded_size();
+            if weight.saturating_add(t_weight) > self.limits.max_weight
+                || encoded_size.saturating_add(t_size) > self.limits.max_encoded_size
+            {
+                put_back.push((t, Some(validity)));
+                break;
+            }
+            state = SM::next_state(&state, &t);
+            weight += t_weight;
+            encoded_size += t_size;
+            body.push(t);
+        }
+
+        for (t, validity) in put_back {
+            match validity {
+                Some(validity) => pool.try_insert_validated(t, validity),
+                None => pool.try_insert(t),
+            };
+        }
+
+        BuiltBody {
+            body,
+            state,
+            weight,
+            encoded_size,
+            skipped,
+        }
+    }
+}
+
+/// A client that refuses to import blocks whose bodies exceed the given limits, and otherwise
+/// imports them as usual.
+pub struct LimitedImport<I> {
+    client: I,
+    limits: BlockLimits,
+}
+
+impl<I> LimitedImport<I> {
+    /// Enforce the given limits on every block the client imports.
+    pub fn new(client: I, limits: BlockLimits) -> Self {
+        Self { client, limits }
+    }
+
+    /// The limits that imported blocks must respect.
+    pub fn limits(&self) -> BlockLimits {
+        self.limits
+    }
+
+    /// The wrapped client.
+    pub fn client(&self) -> &I {
+        &self.client
+    }
+
+    /// Whether the block's body is within the limits.
+    fn admits<C, SM>(&self, block: &Block<C, SM>) -> bool
+    where
+        C: Consensus,
+        SM: StateMachine,
+        SM::Transition: Encode + Weigh,
+    {
+        self.limits.check(&block.body).is_ok()
+    }
+}
+
+forward_client_api! {
+    impl<C, SM, I> for LimitedImport<I>, checked by admits
+    where
+        C: Consensus,
+        SM: StateMachine,
+        SM::Transition: Encode + Weigh
+}
+
+#[cfg(test)]
+use super::p15_tagged_pool::TaggedPool;
+#[cfg(test)]
+use super::p2_importing_blocks::ImportBlock;
+#[cfg(test)]
+use super::test_support::{test_block, test_chain, test_client};
+#[cfg(test)]
+use super::ClientApi;
+#[cfg(test)]
+use crate::c1_state_machine::p8_transaction_validity::{InvalidTransaction, TransactionValidity};
+#[cfg(test)]
+use crate::hash;
+
+/// A state machine for testing. The state is some amount of funds, and each transition spends
+/// the given amount from it.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Wallet;
+
+#[cfg(test)]
+impl StateMachine for Wallet {
+    type State = u64;
+    type Transition = u64;
+
+    fn next_state(starting_state: &u64, t: &u64) -> u64 {
+        starting_state.saturating_sub(*t)
+    }
+}
+
+#[cfg(test)]
+impl ValidateTransaction for Wallet {
+    fn validate_transaction(state: &u64, t: &u64) -> TransactionValidity {
+        match t {
+            0 => Err(InvalidTransaction::BadTransaction),
+            t if t > state => Err(InvalidTransaction::InsufficientFunds),
+            _ => Ok(ValidTransaction::default()),
+        }
+    }
+}
+
+/// Spending more costs more to execute.
+#[cfg(test)]
+impl Weigh for u64 {
+    fn weight(&self) -> Weight {
+        *self
+    }
+}
+
+/// A pool containing the given transactions, to be taken out in the given order.
+#[cfg(test)]
+fn pool_of(transactions: &[u64]) -> TaggedPool<u64> {
+    let mut pool = TaggedPool::new();
+    for (i, t) in transactions.iter().enumerate() {
+        let validity = ValidTransaction {
+            priority: (transactions.len() - i) as u64,
+            ..Default::default()
+        };
+        pool.import(*t, validity);
+    }
+    pool
+}
+
+#[test]
+fn client_17_build_without_limits() {
+    let mut builder = BlockBuilder::new(BlockLimits::default(), false);
+    let mut pool = pool_of(&[1, 2, 3]);
+    let built = builder.build::<Wallet, _>(&100, &mut pool);
+
+    assert_eq!(built.body, vec![1, 2, 3]);
+    assert_eq!(built.state, 94);
+    assert_eq!(built.weight, 6);
+    assert_eq!(built.encoded_size, 4 + 3 * 8);
+    assert_eq!(pool.ready_count(), 0);
+}
+
+#[test]
+fn client_17_build_stops_at_weight_limit() {
+    let limits = BlockLimits {
+        max_weight: 10,
+        ..Default::default()
+    };
+    let mut builder = BlockBuilder::new(limits, false);
+    let mut pool = pool_of(&[4, 5, 3, 1]);
+    let built = builder.build::<Wallet, _>(&100, &mut pool);
+
+    assert_eq!(built.body, vec![4, 5]);
+    assert_eq!(limits.check(&built.body), Ok(()));
+    // The rest stay in the pool for the next block.
+    assert!(pool.contains(&3));
+    assert!(pool.contains(&1));
+}
+
+#[test]
+fn client_17_build_stops_at_count_and_size_limits() {
+    let mut builder = BlockBuilder::new(
+        BlockLimits {
+            max_extrinsics: 2,
+            ..Default::default()
+        },
+        false,
+    );
+    let mut pool = pool_of(&[1, 2, 3]);
+    assert_eq!(builder.build::<Wallet, _>(&100, &mut pool).body, vec![1, 2]);
+    assert!(pool.contains(&3));
+
+    let mut builder = BlockBuilder::new(
+        BlockLimits {
+            max_encoded_size: 4 + 2 * 8,
+            ..Default::default()
+        },
+        false,
+    );
+    let mut pool = pool_of(&[1, 2, 3]);
+    assert_eq!(builder.build::<Wallet, _>(&100, &mut pool).body, vec![1, 2]);
+    assert!(pool.contains(&3));
+}
+
+#[test]
+fn client_17_invalid_transactions_are_skipped() {
+    let mut builder = BlockBuilder::new(BlockLimits::default(), false);
+    // After spending 8, there is not enough left for 5.
+    let mut pool = pool_of(&[8, 5, 2]);
+    let built = builder.build::<Wallet, _>(&10, &mut pool);
+
+    assert_eq!(built.body, vec![8, 2]);
+    assert_eq!(built.skipped, vec![5]);
+    assert!(pool.contains(&5));
+    assert!(!builder.is_banned(&5));
+}
+
+#[test]
+fn client_17_invalid_transactions_may_be_banned() {
+    let mut builder = BlockBuilder::new(BlockLimits::default(), true);
+    let mut pool = pool_of(&[8, 5, 2]);
+    let built = builder.build::<Wallet, _>(&10, &mut pool);
+
+    assert_eq!(built.skipped, vec![5]);
+    assert!(!pool.contains(&5));
+    assert!(builder.is_banned(&5));
+
+    // Banned transactions are never included, even if they have become valid.
+    let mut pool = pool_of(&[5]);
+    assert_eq!(builder.build::<Wallet, _>(&10, &mut pool).body, vec![]);
+    builder.clear_bans();
+    let mut pool = pool_of(&[5]);
+    assert_eq!(builder.build::<Wallet, _>(&10, &mut pool).body, vec![5]);
+}
+
+#[test]
+fn client_17_importers_check_limits() {
+    use crate::c1_state_machine::User;
+
+    let body = vec![
+        AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 10,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 5,
+        },
+    ];
+    assert_eq!(BlockLimits::default().check(&body), Ok(()));
+    assert_eq!(
+        BlockLimits {
+            max_extrinsics: 1,
+            ..Default::default()
+        }
+        .check(&body),
+        Err(BlockLimitError::ExtrinsicCount { count: 2, max: 1 })
+    );
+    assert_eq!(
+        BlockLimits {
+            max_encoded_size: 20,
+            ..Default::default()
+        }
+        .check(&body),
+        Err(BlockLimitError::EncodedSize { size: 25, max: 20 })
+    );
+    assert_eq!(
+        BlockLimits {
+            max_weight: 2,
+            ..Default::default()
+        }
+        .check(&body),
+        Err(BlockLimitError::Weight { weight: 3, max: 2 })
+    );
+}
+
+#[test]
+fn client_17_limited_import_rejects_oversized_blocks() {
+    use crate::c1_state_machine::User;
+
+    let limits = BlockLimits {
+        max_weight: 2,
+        ..Default::default()
+    };
+    let mut client = LimitedImport::new(test_client(), limits);
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let state = client.get_state(hash(&genesis.header)).unwrap();
+    let mint = |amount| AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    };
+
+    let (heavy, _) = test_block(&genesis, &state, vec![mint(1), mint(2), mint(3)]);
+    assert!(!client.import_block(heavy.clone()));
+    assert!(!client.import_block_with_state(heavy.clone(), None));
+    assert!(client.get_block(hash(&heavy.header)).is_none());
+
+    let (light, _) = test_block(&genesis, &state, vec![mint(1), mint(2)]);
+    assert!(client.import_block(light.clone()));
+    assert_eq!(client.best_block(), hash(&light.header));
+}
@@ -0,0 +1,411 @@
+//! Up to now, blocks were only authored when someone called an authoring method by hand. A real
+//! authority runs a loop instead. For slot-based consensus, the loop looks like this:
+//! 1. Wait for the next slot to begin.
+//! 2. Ask the consensus engine whether the local authority may author in this slot.
+//! 3. If it may, build a block on top of the best block, seal it, and import it.
+//!
+//! The worker learns the time from a `SlotClock`. With the system clock it follows real time, and
+//! with the manual clock a test can step it through slots one at a time without ever sleeping.
+
+use std::time::Duration;
+
+use super::p17_block_builder::{BlockBuilder, Weigh};
+use super::p4_transaction_pool::TransactionPool;
+use super::{Block, ClientApi};
+use crate::c1_state_machine::p8_transaction_validity::ValidateTransaction;
+use crate::c2_blockchain::p7_merkle_tree::merkle_root;
+use crate::c3_consensus::p10_timestamps::slot_start;
+use crate::c3_consensus::{ConsensusAuthority, Header, SlotClock, SlotConsensus};
+use crate::codec::Encode;
+use crate::hash;
+
+type Hash = u64;
+
+/// What happened when the worker woke up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SlotOutcome {
+    /// The current slot was already handled.
+    AlreadyHandled,
+    /// Another authority may author in this slot.
+    NotOurSlot { slot: u64 },
+    /// A block was authored and imported.
+    Authored { slot: u64, block: Hash },
+    /// This was our slot, but it contains no whole millisecond to timestamp a block with, the
+    /// state of the best block was not available, or the client refused the block we authored.
+    Failed { slot: u64 },
+}
+
+/// Authors blocks in the local authority's slots.
+pub struct SlotWorker<Clock, T, R> {
+    clock: Clock,
+    authority: ConsensusAuthority,
+    builder: BlockBuilder<T>,
+    /// A means of computing the state root committed to in new headers.
+    state_root: R,
+    /// The most recent slot handled.
+    last_slot: Option<u64>,
+}
+
+impl<Clock, T, R> SlotWorker<Clock, T, R>
+where
+    Clock: SlotClock,
+    T: Clone + PartialEq + Encode + Weigh + std::hash::Hash,
+{
+    /// Create a worker that authors as the given authority, filling blocks with the given
+    /// builder.
+    pub fn new(
+        clock: Clock,
+        authority: ConsensusAuthority,
+        builder: BlockBuilder<T>,
+        state_root: R,
+    ) -> Self {
+        Self {
+            clock,
+            authority,
+            builder,
+            state_root,
+            last_slot: None,
+        }
+    }
+
+    /// The clock this worker follows.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// The builder this worker fills blocks with.
+    pub fn builder(&self) -> &BlockBuilder<T> {
+        &self.builder
+    }
+
+    /// Handle the current slot, unless it was already handled.
+    pub fn on_slot<C, SM, P>(
+        &mut self,
+        engine: &C,
+        client: &mut impl ClientApi<C, SM>,
+        pool: &mut P,
+    ) -> SlotOutcome
+    where
+        C: SlotConsensus,
+        SM: ValidateTransaction<Transition = T>,
+        SM::State: Clone,
+        P: TransactionPool<SM>,
+        R: Fn(&SM::State) -> Hash,
+    {
+        let slot = self.clock.current_slot();
+        if self.last_slot.is_some_and(|last| slot <= last) {
+            return SlotOutcome::AlreadyHandled;
+        }
+        self.last_slot = Some(slot);
+
+        let Some(parent) = client.get_block(client.best_block()) else {
+            return SlotOutcome::Failed { slot };
+        };
+        let Some(digest) = engine.claim_slot(&parent.header.consensus_digest, slot, self.authority)
+        else {
+            return SlotOutcome::NotOurSlot { slot };
+        };
+        let parent_hash = hash(&parent.header);
+        let Some(parent_state) = client.get_state(parent_hash) else {
+            return SlotOutcome::Failed { slot };
+        };
+        let Some(timestamp) = slot_start(slot, self.clock.slot_duration()) else {
+            return SlotOutcome::Failed { slot };
+        };
+
+        let built = self.builder.build::<SM, P>(&parent_state, pool);
+        let block = Block {
+            header: Header {
+                parent: parent_hash,
+                height: parent.header.height + 1,
+                state_root: (self.state_root)(&built.state),
+                extrinsics_root: merkle_root(&built.body),
+                timestamp,
+                consensus_digest: digest,
+            },
+            body: built.body,
+        };
+        let block_hash = hash(&block.header);
+        let body = block.body.clone();
+        if !client.import_block(block) {
+            // Give the transactions another chance in a later block. Putting them back also
+            // tells the pool that the tags they provide are not on chain after all.
+            for t in body {
+                match SM::validate_transaction(&parent_state, &t) {
+                    Ok(validity) => pool.try_insert_validated(t, validity),
+                    Err(_) => pool.try_insert(t),
+                };
+            }
+            return SlotOutcome::Failed { slot };
+        }
+        SlotOutcome::Authored {
+            slot,
+            block: block_hash,
+        }
+    }
+
+    /// Handle slots as they come, sleeping in between, for as long as `keep_going` says to.
+    /// It is called with the outcome of every wake up.
+    pub fn run<C, SM, P>(
+        &mut self,
+        engine: &C,
+        client: &mut impl ClientApi<C, SM>,
+        pool: &mut P,
+        mut keep_going: impl FnMut(&SlotOutcome) -> bool,
+    ) where
+        C: SlotConsensus,
+        SM: ValidateTransaction<Transition = T>,
+        SM::State: Clone,
+        P: TransactionPool<SM>,
+        R: Fn(&SM::State) -> Hash,
+    {
+        loop {
+            let outcome = self.on_slot(engine, client, pool);
+            if !keep_going(&outcome) {
+                return;
+            }
+            let wait = self.clock.time_until_next_slot();
+            if wait > Duration::ZERO {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::p15_tagged_pool::TaggedPool;
+#[cfg(test)]
+use super::p17_block_builder::{BlockLimits, Wallet};
+#[cfg(test)]
+use super::p2_importing_blocks::ImportBlock;
+#[cfg(test)]
+use super::test_support::FakeClient;
+#[cfg(test)]
+use super::Consensus;
+#[cfg(test)]
+use crate::c3_consensus::p10_timestamps::{check_slot, ManualTimeSource, TimestampRules};
+#[cfg(test)]
+use crate::c3_consensus::p7_slots::ManualSlotClock;
+
+#[cfg(test)]
+const TEST_SLOT_DURATION: Duration = Duration::from_secs(6);
+
+/// A round robin by slot engine for testing. The digest is the slot and the author.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TestSlots(Vec<ConsensusAuthority>);
+
+#[cfg(test)]
+impl TestSlots {
+    fn author(&self, slot: u64) -> ConsensusAuthority {
+        self.0[slot as usize % self.0.len()]
+    }
+}
+
+#[cfg(test)]
+impl Consensus for TestSlots {
+    type Digest = (u64, ConsensusAuthority);
+
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let (slot, author) = header.consensus_digest;
+        slot > parent_digest.0 && author == self.author(slot)
+    }
+
+    /// This engine has no clock, so blocks can only be authored through `claim_slot`.
+    fn seal(&self, _: &Self::Digest, _: Header<()>) -> Option<Header<Self::Digest>> {
+        None
+    }
+}
+
+#[cfg(test)]
+impl SlotConsensus for TestSlots {
+    fn claim_slot(
+        &self,
+        parent_digest: &Self::Digest,
+        slot: u64,
+        authority: ConsensusAuthority,
+    ) -> Option<Self::Digest> {
+        (slot > parent_digest.0 && authority == self.author(slot)).then_some((slot, authority))
+    }
+}
+
+/// A client that fully checks and executes blocks of wallet transactions.
+#[cfg(test)]
+type WalletClient = FakeClient<TestSlots, Wallet>;
+
+#[cfg(test)]
+fn wallet_client(engine: TestSlots, funds: u64) -> WalletClient {
+    FakeClient::new(
+        engine,
+        funds,
+        (0, ConsensusAuthority::Alice),
+        |state: &u64| hash(state),
+    )
+}
+
+#[cfg(test)]
+fn wallet_worker(
+    clock: &ManualSlotClock,
+    authority: ConsensusAuthority,
+) -> SlotWorker<ManualSlotClock, u64, impl Fn(&u64) -> Hash> {
+    let builder = BlockBuilder::new(BlockLimits::default(), false);
+    SlotWorker::new(clock.clone(), authority, builder, |state: &u64| hash(state))
+}
+
+#[test]
+fn client_18_authors_only_in_own_slots() {
+    use ConsensusAuthority::{Alice, Bob};
+
+    let engine = TestSlots(vec![Alice, Bob]);
+    let mut client = wallet_client(engine.clone(), 100);
+    let mut pool = TaggedPool::new();
+    pool.import(30, Default::default());
+    let clock = ManualSlotClock::new(TEST_SLOT_DURATION, 1);
+    let mut worker = wallet_worker(&clock, Alice);
+
+    assert_eq!(
+        worker.on_slot(&engine, &mut client, &mut pool),
+        SlotOutcome::NotOurSlot { slot: 1 }
+    );
+    assert_eq!(
+        worker.on_slot(&engine, &mut client, &mut pool),
+        SlotOutcome::AlreadyHandled
+    );
+
+    clock.advance(1);
+    let SlotOutcome::Authored { slot: 2, block } = worker.on_slot(&engine, &mut client, &mut pool)
+    else {
+        panic!("slot 2 belongs to Alice");
+    };
+    assert_eq!(client.best_block(), block);
+    assert_eq!(client.get_block(block).unwrap().body, vec![30]);
+    assert_eq!(client.get_state(block), Some(70));
+    assert_eq!(pool.ready_count(), 0);
+}
+
+#[test]
+fn client_18_authorities_take_turns() {
+    use ConsensusAuthority::{Alice, Bob};
+
+    let engine = TestSlots(vec![Alice, Bob]);
+    let mut client = wallet_client(engine.clone(), 100);
+    let mut pool = TaggedPool::new();
+    let clock = ManualSlotClock::new(TEST_SLOT_DURATION, 0);
+    let mut alice = wallet_worker(&clock, Alice);
+    let mut bob = wallet_worker(&clock, Bob);
+
+    let mut authored = Vec::new();
+    for _ in 0..4 {
+        clock.advance(1);
+        for worker in [&mut alice, &mut bob] {
+            if let SlotOutcome::Authored { slot, .. } =
+                worker.on_slot(&engine, &mut client, &mut pool)
+            {
+                authored.push(slot);
+            }
+        }
+    }
+
+    assert_eq!(authored, vec![1, 2, 3, 4]);
+    let best = client.best_block();
+    assert_eq!(client.get_block(best).unwrap().header.height, 4);
+}
+
+#[test]
+fn client_18_blocks_are_timestamped_with_their_slot() {
+    use ConsensusAuthority::{Alice, Bob};
+
+    let engine = TestSlots(vec![Alice, Bob]);
+    let mut client = wallet_client(engine.clone(), 100);
+    let mut pool = TaggedPool::new();
+    let clock = ManualSlotClock::new(TEST_SLOT_DURATION, 0);
+    let mut alice = wallet_worker(&clock, Alice);
+    let mut bob = wallet_worker(&clock, Bob);
+    for _ in 0..4 {
+        clock.advance(1);
+        alice.on_slot(&engine, &mut client, &mut pool);
+        bob.on_slot(&engine, &mut client, &mut pool);
+    }
+
+    let mut chain = Vec::new();
+    let mut block_hash = client.best_block();
+    while block_hash != client.genesis() {
+        let header = client.get_block(block_hash).unwrap().header;
+        block_hash = header.parent;
+        chain.insert(0, header);
+    }
+    let timestamps: Vec<u64> = chain.iter().map(|h| h.timestamp).collect();
+    assert_eq!(timestamps, vec![6_000, 12_000, 18_000, 24_000]);
+    for header in &chain {
+        assert_eq!(
+            check_slot(
+                header.timestamp,
+                header.consensus_digest.0,
+                TEST_SLOT_DURATION
+            ),
+            Ok(())
+        );
+    }
+    let genesis = client.get_block(client.genesis()).unwrap().header;
+    let rules = TimestampRules::new(3, 0, ManualTimeSource::new(24_000));
+    assert_eq!(rules.check_chain(&[genesis], &chain), Ok(()));
+}
+
+#[test]
+fn client_18_slots_without_a_whole_millisecond_are_skipped() {
+    let engine = TestSlots(vec![ConsensusAuthority::Alice]);
+    let mut client = wallet_client(engine.clone(), 100);
+    let mut pool = TaggedPool::new();
+    let clock = ManualSlotClock::new(Duration::from_micros(250), 9);
+    let mut worker = wallet_worker(&clock, ConsensusAuthority::Alice);
+
+    assert_eq!(
+        worker.on_slot(&engine, &mut client, &mut pool),
+        SlotOutcome::Failed { slot: 9 }
+    );
+    assert_eq!(client.best_block(), client.genesis());
+
+    clock.set_slot(12);
+    let SlotOutcome::Authored { block, .. } = worker.on_slot(&engine, &mut client, &mut pool)
+    else {
+        panic!("slot 12 starts at 3ms");
+    };
+    assert_eq!(client.get_block(block).unwrap().header.timestamp, 3);
+}
+
+#[test]
+fn client_18_run_loop_with_manual_clock() {
+    let engine = TestSlots(vec![ConsensusAuthority::Alice]);
+    let mut client = wallet_client(engine.clone(), 100);
+    let mut pool = TaggedPool::new();
+    for t in [10, 20, 30] {
+        pool.import(t, Default::default());
+    }
+    let clock = ManualSlotClock::new(TEST_SLOT_DURATION, 1);
+    let mut worker = SlotWorker::new(
+        clock.clone(),
+        ConsensusAuthority::Alice,
+        BlockBuilder::new(
+            BlockLimits {
+                max_extrinsics: 1,
+                ..Default::default()
+            },
+            false,
+        ),
+        |state: &u64| hash(state),
+    );
+
+    let mut outcomes = Vec::new();
+    worker.run(&engine, &mut client, &mut pool, |outcome| {
+        outcomes.push(outcome.clone());
+        clock.advance(1);
+        outcomes.len() < 3
+    });
+
+    assert!(outcomes
+        .iter()
+        .all(|o| matches!(o, SlotOutcome::Authored { .. })));
+    let best = client.best_block();
+    assert_eq!(client.get_state(best), Some(40));
+    assert_eq!(pool.ready_count(), 0);
+}
@@ -0,0 +1,210 @@
+//! Some rules can not be checked by looking at a block and its parent's digest alone. Who won a
+//! Proof of Stake slot depends on the parent block's state, and whether a timestamp is sensible
+//! depends on the recent ancestors and on the current time.
+//!
+//! The client we wrote in Part 2 checks neither. Rather than teach it about every such rule, we
+//! wrap it. A `RuntimeCheckedImport` or a `TimestampCheckedImport` checks its rule first, and
+//! then hands the block to the wrapped client to be imported as usual.
+
+use super::{forward_client_api, p2_importing_blocks::ImportBlock, Block, Consensus, StateMachine};
+use crate::c3_consensus::p10_timestamps::{TimeSource, TimestampRules};
+use crate::c3_consensus::p12_proof_of_stake::RuntimeConsensus;
+
+/// A client that refuses to import blocks breaking the consensus rules that depend on the parent
+/// block's state, and otherwise imports them as usual. Blocks whose parent state is not known
+/// can not be checked, so they are refused too.
+pub struct RuntimeCheckedImport<C, I> {
+    client: I,
+    engine: C,
+}
+
+impl<C, I> RuntimeCheckedImport<C, I> {
+    /// Check every block the client imports against the given engine's state dependent rules.
+    pub fn new(client: I, engine: C) -> Self {
+        Self { client, engine }
+    }
+
+    /// The wrapped client.
+    pub fn client(&self) -> &I {
+        &self.client
+    }
+
+    /// Whether the block's header follows the rules under its parent's state.
+    fn admits<SM>(&self, block: &Block<C, SM>) -> bool
+    where
+        C: RuntimeConsensus<SM>,
+        SM: StateMachine,
+        I: ImportBlock<C, SM>,
+    {
+        let header = &block.header;
+        let (Some(parent), Some(parent_state)) = (
+            self.client.get_block(header.parent),
+            self.client.get_state(header.parent),
+        ) else {
+            return false;
+        };
+        self.engine
+            .validate_with_state(&parent_state, &parent.header.consensus_digest, header)
+    }
+}
+
+forward_client_api! {
+    impl<C, SM, I> for RuntimeCheckedImport<C, I>, checked by admits
+    where
+        C: RuntimeConsensus<SM>,
+        SM: StateMachine
+}
+
+/// A client that refuses to import blocks whose timestamps break the given rules, and otherwise
+/// imports them as usual. The median is taken over the ancestors the client knows, so a warp
+/// synced client may look at fewer of them than the rules ask for.
+pub struct TimestampCheckedImport<Time: TimeSource, I> {
+    client: I,
+    rules: TimestampRules<Time>,
+}
+
+impl<Time: TimeSource, I> TimestampCheckedImport<Time, I> {
+    /// Check the timestamp of every block the client imports against the given rules.
+    pub fn new(client: I, rules: TimestampRules<Time>) -> Self {
+        Self { client, rules }
+    }
+
+    /// The wrapped client.
+    pub fn client(&self) -> &I {
+        &self.client
+    }
+
+    /// Whether the block's timestamp follows the rules, given its known ancestors.
+    fn admits<C, SM>(&self, block: &Block<C, SM>) -> bool
+    where
+        C: Consensus,
+        SM: StateMachine,
+        I: ImportBlock<C, SM>,
+    {
+        let header = &block.header;
+        let mut ancestors = Vec::new();
+        let mut next = header.parent;
+        while ancestors.len() < self.rules.median_window() {
+            let Some(block) = self.client.get_block(next) else {
+                break;
+            };
+            next = block.header.parent;
+            ancestors.push(block.header);
+        }
+        ancestors.reverse();
+        self.rules.check(&ancestors, header).is_ok()
+    }
+}
+
+forward_client_api! {
+    impl<Time, C, SM, I> for TimestampCheckedImport<Time, I>, checked by admits
+    where
+        Time: TimeSource,
+        C: Consensus,
+        SM: StateMachine
+}
+
+#[cfg(test)]
+use super::test_support::{test_block, test_client, FakeClient};
+#[cfg(test)]
+use super::ClientApi;
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::{AccountedCurrency, Balances};
+#[cfg(test)]
+use crate::c1_state_machine::User;
+#[cfg(test)]
+use crate::c2_blockchain::p7_merkle_tree::merkle_root;
+#[cfg(test)]
+use crate::c2_blockchain::p8_state_trie::StateTrie;
+#[cfg(test)]
+use crate::c3_consensus::p10_timestamps::ManualTimeSource;
+#[cfg(test)]
+use crate::c3_consensus::p12_proof_of_stake::{ProofOfStake, StakeDigest};
+#[cfg(test)]
+use crate::c3_consensus::p7_slots::ManualSlotClock;
+#[cfg(test)]
+use crate::c3_consensus::{ConsensusAuthority, Header};
+#[cfg(test)]
+use crate::hash;
+#[cfg(test)]
+use std::time::Duration;
+
+#[test]
+fn client_19_runtime_checked_import_uses_parent_stakes() {
+    let genesis_digest = StakeDigest {
+        slot: 0,
+        author: ConsensusAuthority::Alice,
+    };
+    let only_bob = Balances::from([(User::Bob, 10)]);
+    let clock = ManualSlotClock::new(Duration::from_secs(6), 1);
+    let mut client = RuntimeCheckedImport::new(
+        FakeClient::<_, AccountedCurrency>::new(
+            ProofOfStake::without_stake_checks(clock.clone()),
+            only_bob.clone(),
+            genesis_digest,
+            |_: &Balances| 0,
+        ),
+        ProofOfStake::new(clock),
+    );
+    let genesis = client.genesis();
+
+    let partial = Header {
+        parent: genesis,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: merkle_root::<<AccountedCurrency as StateMachine>::Transition>(&[]),
+        timestamp: 6_000,
+        consensus_digest: (),
+    };
+    let header = RuntimeConsensus::<AccountedCurrency>::seal_with_state(
+        &client.engine,
+        &only_bob,
+        &genesis_digest,
+        partial,
+    )
+    .unwrap();
+    assert_eq!(header.consensus_digest.author, ConsensusAuthority::Bob);
+
+    // Alice has no stake. The wrapped client only checks the slot, but the wrapper checks stakes.
+    let mut stolen = header.clone();
+    stolen.consensus_digest.author = ConsensusAuthority::Alice;
+    assert!(!client.engine.validate(&genesis_digest, &stolen));
+    assert!(!client.import_block(Block {
+        header: stolen,
+        body: vec![],
+    }));
+
+    let block_hash = hash(&header);
+    assert!(client.import_block(Block {
+        header,
+        body: vec![],
+    }));
+    assert_eq!(client.best_block(), block_hash);
+}
+
+#[test]
+fn client_19_timestamp_checked_import() {
+    let time = ManualTimeSource::new(1000);
+    let mut client =
+        TimestampCheckedImport::new(test_client(), TimestampRules::new(3, 500, time.clone()));
+    let genesis = client.get_block(client.genesis()).unwrap();
+    let child = |parent: &Block<_, _>, timestamp| {
+        let (mut block, _) = test_block(parent, &StateTrie::new(), vec![]);
+        block.header.timestamp = timestamp;
+        block
+    };
+
+    let block_1 = child(&genesis, 100);
+    assert!(client.import_block(block_1.clone()));
+
+    // The median of the genesis block and block 1 is block 1's timestamp.
+    assert!(!client.import_block(child(&block_1, 100)));
+    let block_2 = child(&block_1, 200);
+    assert!(client.import_block(block_2.clone()));
+
+    // Too early to import, until our clock catches up.
+    let early = child(&block_2, 2000);
+    assert!(!client.import_block(early.clone()));
+    time.advance(500);
+    assert!(client.import_block(early));
+}
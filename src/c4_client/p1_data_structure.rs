@@ -44,8 +44,8 @@ impl<Digest> Header<Digest> {
 }
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Block<C: Consensus, SM: StateMachine> {
-    header: Header<C::Digest>,
-    body: Vec<SM::Transition>,
+    pub(crate) header: Header<C::Digest>,
+    pub(crate) body: Vec<SM::Transition>,
 }
 
 impl<C: Consensus, SM: StateMachine> Block<C, SM> {
@@ -79,13 +79,63 @@ fn create_empty_chain<C: Consensus, SM: StateMachine>(
 // genesis block.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>
 where
+    C: Consensus,
     SM: StateMachine,
 {
-    fn new(genesis_state: SM::State) -> Self {
+    pub fn new(genesis_state: SM::State) -> Self {
         todo!("Exercise 9")
     }
 }
 
+impl<C, SM, FC, P> FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+    FC: ForkChoice<C>,
+{
+    /// Create a client whose genesis block has the given state and consensus digest, and no
+    /// extrinsics.
+    ///
+    /// Unlike `new`, this works with any consensus engine and state machine, because the caller
+    /// supplies what can not be made up generically: the genesis digest, and how to compute the
+    /// state root that headers commit to.
+    pub fn with_genesis(
+        consensus_engine: C,
+        fork_choice: FC,
+        transaction_pool: P,
+        genesis_state: SM::State,
+        genesis_digest: C::Digest,
+        state_root: fn(&SM::State) -> Hash,
+    ) -> Self {
+        todo!("Exercise 11")
+    }
+
+    /// Create a client that starts at the given block with the given state, as if it were the
+    /// genesis block. It is final from the start, so nothing that conflicts with it can ever be
+    /// imported. This is how a client that was warped to a snapshot begins.
+    pub fn starting_at(
+        consensus_engine: C,
+        fork_choice: FC,
+        transaction_pool: P,
+        block: Block<C, SM>,
+        state: SM::State,
+        state_root: fn(&SM::State) -> Hash,
+    ) -> Self {
+        todo!("Exercise 12")
+    }
+}
+
+impl<C, SM, FC, P> FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+{
+    /// The hash of the genesis block.
+    pub fn genesis(&self) -> Hash {
+        todo!("Exercise 13")
+    }
+}
+
 // The default client is initialized with the default genesis state.
 // Depending on the state machine definition there may not _be_ a default
 // genesis state. There is only a default client when there is also a
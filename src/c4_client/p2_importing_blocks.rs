@@ -64,6 +64,8 @@ impl<C, SM, FC, P> ImportBlock<C, SM> for FullClient<C, SM, FC, P>
 // import block with invalid state root
 // import block with invalid transactions root
 // import block with invalid seal
+// import block that does not descend from the finalized block
+// import block whose body exceeds the block limits (see `BlockLimits::check`)
 
 // Try to get_block genesis block
 // Try to get_block an unknown block
@@ -4,7 +4,7 @@
 //! The concepts are identical here, but now that we have a client tracking a proper block database,
 //! we can explore more advanced fork choice algorithms. In particular, we can now explore GHOST.
 
-use super::{Header, FullClient, Consensus};
+use super::{Header, FullClient, Consensus, StateMachine};
 use crate::c3_consensus::{Pow, SimplePoa, ConsensusAuthority};
 
 /// A means for a blockchain client to decide which chain is best among the many
@@ -92,9 +92,14 @@ impl ForkChoice<Pow> for Ghost {
 // Finally, we will provide a convenience method directly on our client that simply calls
 // into the corresponding method on the ForkChoice rule. You may need to add some trait
 // bounds to make this work.
-impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
-    /// Return the hash of the best block currently known to the client
-    fn best_block(&self) -> u64 {
+impl<C, SM, FC, P> FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+{
+    /// Return the hash of the best block currently known to the client.
+    /// This is always the finalized block or one of its descendants.
+    pub fn best_block(&self) -> u64 {
         todo!("Exercise 9")
     }
 }
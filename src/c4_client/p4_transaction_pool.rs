@@ -7,11 +7,36 @@
 //! * Accepting transactions from users
 //! * Removing transactions that are included in blocks as they are imported
 //! * Making the current transactions available for a block authoring process
-//! * Re-queueing transactions from orphaned blocks when re-orgs happen (This one happens IRL; might not cover it in BFS; TBD)
+//! * Re-queueing transactions from orphaned blocks when re-orgs happen
+//!
+//! Before accepting a transaction, the client checks it against the state of its best block with
+//! the state machine's `ValidateTransaction` implementation, so the pool does not fill up with
+//! transactions that can never be applied. The validity information, such as the transaction's
+//! priority, is handed to the pool along with the transaction.
+//!
+//! The last two duties are handled together by the `PoolMaintainer` at the bottom of this file,
+//! which the client tells about every block it imports. Whenever the best block changes, it finds
+//! the route from the old best block to the new one through their common ancestor. Transactions from blocks that were retracted go back into the
+//! pool, unless a block that was enacted includes them too, and pool transactions that an enacted
+//! block includes are removed. Finally, every pooled transaction is validated again against the
+//! new best state, and those that are no longer valid are dropped.
+//!
+//! Transactions should not wait in the pool forever either. Each one is valid for a number of
+//! blocks, and the pool drops it once that many blocks have passed. Expired transactions are
+//! pruned after every import. A transaction may also be
+//! mortal, meaning that it names a recent block, its anchor, and may only be included within some
+//! number of blocks after it. A mortal transaction is only valid on chains that contain its
+//! anchor, so it can not be replayed on some other fork, or long after its sender lost interest.
 
 use std::{collections::VecDeque, marker::PhantomData};
 
-use super::{FullClient, StateMachine};
+use super::{
+    p2_importing_blocks::ImportBlock, p6_finality::descends_from, ClientApi, Consensus, FullClient,
+    StateMachine,
+};
+use crate::c1_state_machine::p8_transaction_validity::{
+    InvalidTransaction, TransactionLongevity, ValidTransaction, ValidateTransaction, IMMORTAL,
+};
 
 /// An abstraction over the notion of transaction pool.
 pub trait TransactionPool<SM: StateMachine> {
@@ -34,6 +59,50 @@ pub trait TransactionPool<SM: StateMachine> {
     /// The notion of next is opaque and implementation dependent.
     /// Different chains prioritize transactions differently, usually by economic means.
     fn next_from_pool(&mut self) -> Option<SM::Transition>;
+
+    /// Try to add a new transaction to the pool along with the information returned by
+    /// validating it. Pools that make no use of that information can rely on this default,
+    /// which ignores it.
+    fn try_insert_validated(&mut self, t: SM::Transition, _validity: ValidTransaction) -> bool {
+        self.try_insert(t)
+    }
+
+    /// Take every transaction out of the pool.
+    ///
+    /// The default takes them out one at a time with `next_from_pool`, so pools that hold
+    /// transactions that `next_from_pool` would not return should override it.
+    fn drain(&mut self) -> Vec<SM::Transition> {
+        std::iter::from_fn(|| self.next_from_pool()).collect()
+    }
+
+    /// Tell the pool the height of the new best block, so that it can drop transactions whose
+    /// longevity has run out. Returns the number of transactions dropped.
+    ///
+    /// Pools that make no use of longevity can rely on this default, which keeps everything.
+    fn prune_expired(&mut self, _best_number: u64) -> usize {
+        0
+    }
+
+    /// Validate every transaction in the pool again with the given function, and drop those it
+    /// rejects. Returns the number of transactions dropped.
+    ///
+    /// The default takes everything out with `drain` and puts the valid transactions back, so
+    /// pools that track how long their transactions have been waiting should override it.
+    fn revalidate(
+        &mut self,
+        validate: &mut dyn FnMut(&SM::Transition) -> Option<ValidTransaction>,
+    ) -> usize {
+        let mut dropped = 0;
+        for t in self.drain() {
+            match validate(&t) {
+                Some(validity) => {
+                    self.try_insert_validated(t, validity);
+                }
+                None => dropped += 1,
+            }
+        }
+        dropped
+    }
 }
 
 
@@ -41,6 +110,7 @@ pub trait TransactionPool<SM: StateMachine> {
 // These are basically wrappers around methods that the pool itself provides.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>           
     where
+    C: Consensus,
     SM: StateMachine,
 {
     /// Submit a transaction to the client's transaction pool to hopefully
@@ -59,6 +129,18 @@ impl<C, SM, FC, P> FullClient<C, SM, FC, P>
     pub fn pool_contains(&self, t: SM::Transition) -> bool {
         todo!("Exercise 3")
     }
+
+    /// Bring the transaction pool up to date after importing a block.
+    ///
+    /// Your `import_block` should call this after every successful import, whether or not the
+    /// block became the best block. Expired transactions leave the pool on every import. When
+    /// the best block moves to another fork, transactions from the blocks that left the best
+    /// chain go back into the pool, unless the new best chain includes them too. The
+    /// `PoolMaintainer` at the bottom of this file does all of this, so you may want to keep one
+    /// in the client.
+    pub fn maintain_pool(&mut self) {
+        todo!("Exercise 4")
+    }
 }
 
 /// A simple state machine that is just a first-in-first-out queue.
@@ -163,7 +245,345 @@ where
     }
 }
 
-//TODO tests
+/// The path from one block to another through their most recent common ancestor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeRoute {
+    /// The blocks leaving the best chain, from the old best block down to just above the
+    /// common ancestor.
+    pub retracted: Vec<u64>,
+    /// The most recent block that is an ancestor of both blocks.
+    pub common_ancestor: u64,
+    /// The blocks joining the best chain, from just above the common ancestor up to the new
+    /// best block.
+    pub enacted: Vec<u64>,
+}
+
+/// Compute the route between two blocks known to the client.
+/// Returns None if either block, or any block between them, is not known.
+pub fn tree_route<C, SM>(client: &impl ImportBlock<C, SM>, from: u64, to: u64) -> Option<TreeRoute>
+where
+    C: Consensus,
+    SM: StateMachine,
+{
+    let mut from_header = client.get_block(from)?.header;
+    let mut to_header = client.get_block(to)?.header;
+    let (mut from, mut to) = (from, to);
+    let mut retracted = Vec::new();
+    let mut enacted = Vec::new();
+    while from != to {
+        if from_header.height >= to_header.height {
+            retracted.push(from);
+            from = from_header.parent;
+            from_header = client.get_block(from)?.header;
+        } else {
+            enacted.push(to);
+            to = to_header.parent;
+            to_header = client.get_block(to)?.header;
+        }
+    }
+    enacted.reverse();
+    Some(TreeRoute {
+        retracted,
+        common_ancestor: from,
+        enacted,
+    })
+}
+
+/// Update the pool for a move of the best block along the given route. Transactions from
+/// retracted blocks that are not in any enacted block are validated with the given function and
+/// returned to the pool, and transactions in enacted blocks are removed from it. Returns the
+/// number of transactions re-queued.
+pub fn update_pool_for_route<C, SM, P>(
+    client: &impl ImportBlock<C, SM>,
+    pool: &mut P,
+    route: &TreeRoute,
+    validate: &mut dyn FnMut(&SM::Transition) -> Option<ValidTransaction>,
+) -> usize
+where
+    C: Consensus,
+    SM: StateMachine,
+    SM::Transition: Clone + PartialEq,
+    P: TransactionPool<SM>,
+{
+    let body = |block_hash: &u64| {
+        client
+            .get_block(*block_hash)
+            .map(|b| b.body)
+            .unwrap_or_default()
+    };
+    let included: Vec<SM::Transition> = route.enacted.iter().flat_map(body).collect();
+
+    let mut requeued = 0;
+    for t in route.retracted.iter().flat_map(body) {
+        if included.contains(&t) || pool.contains(t.clone()) {
+            continue;
+        }
+        if let Some(validity) = validate(&t) {
+            if pool.try_insert_validated(t, validity) {
+                requeued += 1;
+            }
+        }
+    }
+    for t in included {
+        pool.remove(t);
+    }
+    requeued
+}
+
+/// Validate every transaction in the pool against the given state, and keep only those that are
+/// still valid. Returns the number of transactions dropped.
+pub fn revalidate_pool<SM, P>(state: &SM::State, pool: &mut P) -> usize
+where
+    SM: ValidateTransaction,
+    P: TransactionPool<SM>,
+{
+    pool.revalidate(&mut |t| SM::validate_transaction(state, t).ok())
+}
+
+/// The window of blocks in which a transaction may be included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Era {
+    /// The transaction may be included in any block on any chain.
+    Immortal,
+    /// The transaction may only be included in the `period` blocks following the anchor block,
+    /// on chains that contain the anchor block.
+    Mortal { anchor: u64, period: u64 },
+}
+
+impl Era {
+    /// Check whether a transaction with this era may be included in a child of the given best
+    /// block. If it may, returns the number of blocks it remains valid for.
+    pub fn check<C, SM>(
+        &self,
+        client: &impl ImportBlock<C, SM>,
+        best: u64,
+    ) -> Result<TransactionLongevity, InvalidTransaction>
+    where
+        C: Consensus,
+        SM: StateMachine,
+    {
+        let Era::Mortal { anchor, period } = *self else {
+            return Ok(IMMORTAL);
+        };
+        let lookup = |h| {
+            client
+                .get_block(h)
+                .map(|b| (b.header.parent, b.header.height))
+        };
+        if !descends_from(lookup, anchor, best) {
+            return Err(InvalidTransaction::UnknownAnchor);
+        }
+        let (Some((_, anchor_height)), Some((_, best_height))) = (lookup(anchor), lookup(best))
+        else {
+            return Err(InvalidTransaction::UnknownAnchor);
+        };
+        let end = anchor_height.saturating_add(period);
+        if best_height >= end {
+            return Err(InvalidTransaction::Expired);
+        }
+        Ok(end - best_height)
+    }
+}
+
+/// The reasons a submitted transaction may not make it into the pool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    /// The transaction is not valid on the best block's state.
+    Invalid(InvalidTransaction),
+    /// The transaction is already in the pool.
+    AlreadyInPool,
+    /// The state of the best block is not available, so the transaction cannot be validated.
+    UnknownBestState,
+    /// The pool refused the transaction.
+    Rejected,
+}
+
+/// Keeps a transaction pool in step with the client's best block.
+///
+/// The client should submit transactions through `submit_transaction`, and call
+/// `block_imported` after every import.
+pub struct PoolMaintainer<T> {
+    /// The best block as of the last update.
+    best: u64,
+    /// The eras of mortal transactions, whether they are pooled or included in the best chain.
+    /// An era is kept until it no longer allows its transaction on the best chain, so that the
+    /// transaction is still mortal if a re-org puts it back in the pool.
+    mortal: Vec<(T, Era)>,
+}
+
+impl<T: Clone + PartialEq> PoolMaintainer<T> {
+    /// Start maintaining a pool for a client whose best block is the given block.
+    pub fn new(best: u64) -> Self {
+        Self {
+            best,
+            mortal: Vec::new(),
+        }
+    }
+
+    /// The best block as of the last update.
+    pub fn best_block(&self) -> u64 {
+        self.best
+    }
+
+    /// Validate a transaction against the best block's state and, if it is valid, add it to
+    /// the pool.
+    pub fn submit_transaction<C, SM, P>(
+        &mut self,
+        client: &impl ImportBlock<C, SM>,
+        pool: &mut P,
+        t: T,
+    ) -> Result<ValidTransaction, SubmitError>
+    where
+        C: Consensus,
+        SM: ValidateTransaction<Transition = T>,
+        P: TransactionPool<SM>,
+    {
+        self.submit_transaction_with_era(client, pool, t, Era::Immortal)
+    }
+
+    /// Like `submit_transaction`, but the transaction is only valid within the given era.
+    /// Its longevity is cut short to the end of the era.
+    pub fn submit_transaction_with_era<C, SM, P>(
+        &mut self,
+        client: &impl ImportBlock<C, SM>,
+        pool: &mut P,
+        t: T,
+        era: Era,
+    ) -> Result<ValidTransaction, SubmitError>
+    where
+        C: Consensus,
+        SM: ValidateTransaction<Transition = T>,
+        P: TransactionPool<SM>,
+    {
+        if pool.contains(t.clone()) {
+            return Err(SubmitError::AlreadyInPool);
+        }
+        let state = client
+            .get_state(self.best)
+            .ok_or(SubmitError::UnknownBestState)?;
+        let validity =
+            validate_in_era(client, self.best, &state, &t, era).map_err(SubmitError::Invalid)?;
+        if !pool.try_insert_validated(t.clone(), validity.clone()) {
+            return Err(SubmitError::Rejected);
+        }
+        if era != Era::Immortal {
+            self.mortal.retain(|(other, _)| *other != t);
+            self.mortal.push((t, era));
+        }
+        Ok(validity)
+    }
+
+    /// Bring the pool up to date after the client imported a block. Call it after every import,
+    /// whether or not the block became the best block.
+    ///
+    /// If the best block changed, this does everything `best_block_changed` does, and returns
+    /// the route it took. Otherwise it still drops expired transactions, and mortal transactions
+    /// that are no longer valid on the best chain, for example because finality pruned their
+    /// anchor.
+    pub fn block_imported<C, SM, P>(
+        &mut self,
+        client: &impl ClientApi<C, SM>,
+        pool: &mut P,
+    ) -> Option<TreeRoute>
+    where
+        C: Consensus,
+        SM: ValidateTransaction<Transition = T>,
+        P: TransactionPool<SM>,
+    {
+        let new_best = client.best_block();
+        if new_best != self.best {
+            return self.best_block_changed(client, pool, new_best);
+        }
+        if let Some(best) = client.get_block(new_best) {
+            pool.prune_expired(best.header.height);
+        }
+        self.prune_mortal(client, pool);
+        None
+    }
+
+    /// Update the pool for a new best block, drop expired transactions, and re-validate the pool
+    /// against its state. Mortal transactions whose anchor is not on the new best chain, or
+    /// whose era has ended, are dropped too. Retracted transactions go back into the pool with
+    /// their era, so they are no more long-lived than when they were first submitted.
+    ///
+    /// Returns the route the best block took, or None if it did not change or the route could
+    /// not be computed. The route is lost when finality pruned the old best block, but the pool
+    /// still moves on to the new one.
+    pub fn best_block_changed<C, SM, P>(
+        &mut self,
+        client: &impl ImportBlock<C, SM>,
+        pool: &mut P,
+        new_best: u64,
+    ) -> Option<TreeRoute>
+    where
+        C: Consensus,
+        SM: ValidateTransaction<Transition = T>,
+        P: TransactionPool<SM>,
+    {
+        if new_best == self.best || client.get_block(new_best).is_none() {
+            return None;
+        }
+        let route = tree_route(client, self.best, new_best);
+        self.best = new_best;
+        if let Some(best) = client.get_block(new_best) {
+            pool.prune_expired(best.header.height);
+        }
+        let state = client.get_state(new_best);
+        if let (Some(route), Some(state)) = (&route, &state) {
+            let mut validate = |t: &T| {
+                let era = self
+                    .mortal
+                    .iter()
+                    .find(|(other, _)| other == t)
+                    .map_or(Era::Immortal, |(_, era)| *era);
+                validate_in_era(client, new_best, state, t, era).ok()
+            };
+            update_pool_for_route(client, pool, route, &mut validate);
+        }
+        if let Some(state) = &state {
+            revalidate_pool(state, pool);
+        }
+        self.prune_mortal(client, pool);
+        route
+    }
+
+    /// Drop mortal transactions whose era does not allow them in a child of the best block, and
+    /// forget their eras.
+    fn prune_mortal<C, SM, P>(&mut self, client: &impl ImportBlock<C, SM>, pool: &mut P)
+    where
+        C: Consensus,
+        SM: StateMachine<Transition = T>,
+        P: TransactionPool<SM>,
+    {
+        let best = self.best;
+        self.mortal.retain(|(t, era)| {
+            if era.check(client, best).is_ok() {
+                return true;
+            }
+            pool.remove(t.clone());
+            false
+        });
+    }
+}
+
+/// Validate a transaction against the state of the best block, for inclusion in a child of it.
+/// If the transaction is mortal, its longevity is cut short to the end of its era.
+fn validate_in_era<C, SM>(
+    client: &impl ImportBlock<C, SM>,
+    best: u64,
+    state: &SM::State,
+    t: &SM::Transition,
+    era: Era,
+) -> Result<ValidTransaction, InvalidTransaction>
+where
+    C: Consensus,
+    SM: ValidateTransaction,
+{
+    let remaining = era.check(client, best)?;
+    let mut validity = SM::validate_transaction(state, t)?;
+    validity.longevity = validity.longevity.min(remaining);
+    Ok(validity)
+}
 
 // #[test]
 // fn simple_pool_starts_empty() {
@@ -190,4 +610,373 @@ where
 
 
 // More tests for block importing to make sure that transactions that are imported
-// to the chain are correctly removed from the pool.
\ No newline at end of file
+// to the chain are correctly removed from the pool.
+
+#[cfg(test)]
+use super::p15_tagged_pool::TaggedPool;
+#[cfg(test)]
+use super::test_support::{
+    test_block, test_chain, test_client, TestBlock, TestClient, TestCurrency,
+};
+#[cfg(test)]
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, User};
+#[cfg(test)]
+use crate::hash;
+
+/// A first-in-first-out pool for testing the pool maintenance helpers.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestPool(pub(crate) Vec<AccountingTransaction>);
+
+#[cfg(test)]
+impl TransactionPool<TestCurrency> for TestPool {
+    fn try_insert(&mut self, t: AccountingTransaction) -> bool {
+        if self.0.contains(&t) {
+            return false;
+        }
+        self.0.push(t);
+        true
+    }
+
+    fn remove(&mut self, t: AccountingTransaction) {
+        self.0.retain(|other| *other != t);
+    }
+
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn contains(&self, t: AccountingTransaction) -> bool {
+        self.0.contains(&t)
+    }
+
+    fn next_from_pool(&mut self) -> Option<AccountingTransaction> {
+        (!self.0.is_empty()).then(|| self.0.remove(0))
+    }
+}
+
+/// A mint to Alice of the given amount, which makes it easy to tell transactions apart.
+#[cfg(test)]
+fn mint(amount: u64) -> AccountingTransaction {
+    AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    }
+}
+
+/// A transfer of the given amount from the given sender to Charlie.
+#[cfg(test)]
+fn transfer(sender: User, amount: u64) -> AccountingTransaction {
+    AccountingTransaction::Transfer {
+        sender,
+        receiver: User::Charlie,
+        amount,
+    }
+}
+
+/// Build a block with the given body on top of the given parent and import it.
+#[cfg(test)]
+fn import_child(
+    client: &mut TestClient,
+    parent: &TestBlock,
+    body: Vec<AccountingTransaction>,
+) -> TestBlock {
+    let parent_state = client.get_state(hash(&parent.header)).unwrap();
+    let (block, _) = test_block(parent, &parent_state, body);
+    assert!(client.import_block(block.clone()));
+    block
+}
+
+#[test]
+fn client_4_tree_route_across_forks() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let common = import_child(&mut client, &genesis, vec![mint(1)]);
+    let a1 = import_child(&mut client, &common, vec![mint(2)]);
+    let a2 = import_child(&mut client, &a1, vec![mint(3)]);
+    let b1 = import_child(&mut client, &common, vec![mint(4)]);
+    let [common, a1, a2, b1] = [common, a1, a2, b1].map(|b| hash(&b.header));
+
+    assert_eq!(
+        tree_route(&client, a2, b1),
+        Some(TreeRoute {
+            retracted: vec![a2, a1],
+            common_ancestor: common,
+            enacted: vec![b1],
+        })
+    );
+    assert_eq!(
+        tree_route(&client, common, a2),
+        Some(TreeRoute {
+            retracted: vec![],
+            common_ancestor: common,
+            enacted: vec![a1, a2],
+        })
+    );
+    assert_eq!(tree_route(&client, a2, 12345), None);
+}
+
+#[test]
+fn client_4_reorg_requeues_retracted_transactions() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let mut pool = TestPool::default();
+    let mut maintainer = PoolMaintainer::new(hash(&genesis.header));
+
+    let a1 = import_child(&mut client, &genesis, vec![mint(1), mint(2), mint(3)]);
+    pool.0 = vec![mint(4), mint(5)];
+    let route = maintainer.best_block_changed(&client, &mut pool, hash(&a1.header));
+    assert_eq!(route.unwrap().retracted, vec![]);
+    assert_eq!(pool.0, vec![mint(4), mint(5)]);
+
+    // The other fork includes transactions 2 and 4, and wins.
+    let b1 = import_child(&mut client, &genesis, vec![mint(2)]);
+    let b2 = import_child(&mut client, &b1, vec![mint(4)]);
+    let route = maintainer
+        .best_block_changed(&client, &mut pool, hash(&b2.header))
+        .unwrap();
+
+    assert_eq!(route.retracted, vec![hash(&a1.header)]);
+    assert_eq!(route.enacted, vec![hash(&b1.header), hash(&b2.header)]);
+    assert_eq!(pool.0, vec![mint(5), mint(1), mint(3)]);
+    assert_eq!(maintainer.best_block(), hash(&b2.header));
+    assert_eq!(
+        maintainer.best_block_changed(&client, &mut pool, hash(&b2.header)),
+        None
+    );
+}
+
+#[test]
+fn client_4_best_block_moves_on_when_old_best_is_pruned() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let a1 = import_child(&mut client, &genesis, vec![mint(1)]);
+    let b1 = import_child(&mut client, &genesis, vec![mint(2)]);
+    let b2 = import_child(&mut client, &b1, vec![mint(3)]);
+    let mut pool = TestPool::default();
+    let mut maintainer = PoolMaintainer::new(hash(&a1.header));
+
+    // Finalizing the other fork prunes the block the pool was following.
+    assert!(client.manually_finalize_block(hash(&b1.header)));
+    assert_eq!(
+        maintainer.best_block_changed(&client, &mut pool, hash(&b2.header)),
+        None
+    );
+    assert_eq!(maintainer.best_block(), hash(&b2.header));
+
+    // Unknown blocks are ignored, and the pool follows the chain as usual afterwards.
+    assert_eq!(
+        maintainer.best_block_changed(&client, &mut pool, 12345),
+        None
+    );
+    assert_eq!(maintainer.best_block(), hash(&b2.header));
+    let b3 = import_child(&mut client, &b2, vec![mint(4)]);
+    assert!(maintainer
+        .best_block_changed(&client, &mut pool, hash(&b3.header))
+        .is_some());
+}
+
+#[test]
+fn client_4_submission_is_validated_against_best_state() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let funded = import_child(&mut client, &genesis, vec![mint(50)]);
+    let mut pool = TestPool::default();
+
+    let mut lost = PoolMaintainer::new(12345);
+    assert_eq!(
+        lost.submit_transaction(&client, &mut pool, transfer(User::Alice, 10)),
+        Err(SubmitError::UnknownBestState)
+    );
+
+    let mut maintainer = PoolMaintainer::new(hash(&funded.header));
+    assert!(maintainer
+        .submit_transaction(&client, &mut pool, transfer(User::Alice, 10))
+        .is_ok());
+    assert_eq!(
+        maintainer.submit_transaction(&client, &mut pool, transfer(User::Alice, 10)),
+        Err(SubmitError::AlreadyInPool)
+    );
+    assert_eq!(
+        maintainer.submit_transaction(&client, &mut pool, transfer(User::Alice, 60)),
+        Err(SubmitError::Invalid(InvalidTransaction::InsufficientFunds))
+    );
+    assert_eq!(pool.0, vec![transfer(User::Alice, 10)]);
+}
+
+#[test]
+fn client_4_pool_is_revalidated_when_best_block_changes() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let bob_mint = AccountingTransaction::Mint {
+        minter: User::Bob,
+        amount: 50,
+    };
+    let funded = import_child(&mut client, &genesis, vec![mint(50), bob_mint]);
+    let mut pool = TestPool::default();
+    let mut maintainer = PoolMaintainer::new(hash(&funded.header));
+    for t in [transfer(User::Alice, 40), transfer(User::Bob, 40)] {
+        maintainer
+            .submit_transaction(&client, &mut pool, t)
+            .unwrap();
+    }
+
+    // A new block in which Alice spent most of her money elsewhere.
+    let spent = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 45,
+    };
+    let block = import_child(&mut client, &funded, vec![spent]);
+    maintainer.best_block_changed(&client, &mut pool, hash(&block.header));
+
+    assert_eq!(pool.0, vec![transfer(User::Bob, 40)]);
+}
+
+#[test]
+fn client_4_mortal_transactions() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let a1 = import_child(&mut client, &genesis, vec![mint(50)]);
+    let a2 = import_child(&mut client, &a1, vec![mint(2)]);
+    let b1 = import_child(&mut client, &genesis, vec![mint(51)]);
+    let b2 = import_child(&mut client, &b1, vec![mint(4)]);
+    let [a1, a2, b1, b2] = [a1, a2, b1, b2].map(|b| hash(&b.header));
+    let mut pool = TaggedPool::new();
+    let mut maintainer = PoolMaintainer::new(a2);
+
+    // Anchored at height 1, so valid in blocks up to height 4.
+    let era = Era::Mortal {
+        anchor: a1,
+        period: 3,
+    };
+    assert_eq!(era.check(&client, a2), Ok(2));
+    assert_eq!(
+        era.check(&client, b2),
+        Err(InvalidTransaction::UnknownAnchor)
+    );
+    assert_eq!(Era::Immortal.check(&client, b2), Ok(IMMORTAL));
+
+    let validity = maintainer
+        .submit_transaction_with_era(&client, &mut pool, transfer(User::Alice, 1), era)
+        .unwrap();
+    assert_eq!(validity.longevity, 2);
+    assert_eq!(
+        maintainer.submit_transaction_with_era(
+            &client,
+            &mut pool,
+            transfer(User::Alice, 2),
+            Era::Mortal {
+                anchor: b1,
+                period: 10
+            }
+        ),
+        Err(SubmitError::Invalid(InvalidTransaction::UnknownAnchor))
+    );
+    maintainer
+        .submit_transaction(&client, &mut pool, transfer(User::Alice, 3))
+        .unwrap();
+
+    // Switching to the other fork drops the mortal transaction, whose anchor is not there.
+    maintainer.best_block_changed(&client, &mut pool, b2);
+    assert!(!pool.contains(&transfer(User::Alice, 1)));
+    assert!(pool.contains(&transfer(User::Alice, 3)));
+}
+
+#[test]
+fn client_4_mortal_transactions_expire() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let b1 = import_child(&mut client, &genesis, vec![mint(1)]);
+    let b2 = import_child(&mut client, &b1, vec![mint(2)]);
+    let genesis_hash = hash(&genesis.header);
+    let mut pool = TaggedPool::new();
+    let mut maintainer = PoolMaintainer::new(genesis_hash);
+
+    let era = Era::Mortal {
+        anchor: genesis_hash,
+        period: 2,
+    };
+    maintainer
+        .submit_transaction_with_era(&client, &mut pool, mint(10), era)
+        .unwrap();
+
+    maintainer.best_block_changed(&client, &mut pool, hash(&b1.header));
+    assert!(pool.contains(&mint(10)));
+    maintainer.best_block_changed(&client, &mut pool, hash(&b2.header));
+    assert!(!pool.contains(&mint(10)));
+    assert_eq!(
+        era.check(&client, hash(&b2.header)),
+        Err(InvalidTransaction::Expired)
+    );
+}
+
+#[test]
+fn client_4_pool_is_maintained_on_every_import() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let genesis_hash = hash(&genesis.header);
+    let mut pool = TaggedPool::new();
+    let mut maintainer = PoolMaintainer::new(genesis_hash);
+
+    // Valid in blocks up to height 3.
+    let era = Era::Mortal {
+        anchor: genesis_hash,
+        period: 3,
+    };
+    maintainer
+        .submit_transaction_with_era(&client, &mut pool, mint(10), era)
+        .unwrap();
+
+    let a1 = import_child(&mut client, &genesis, vec![mint(1)]);
+    assert!(maintainer.block_imported(&client, &mut pool).is_some());
+    let a2 = import_child(&mut client, &a1, vec![mint(2)]);
+    assert!(maintainer.block_imported(&client, &mut pool).is_some());
+    assert!(pool.contains(&mint(10)));
+
+    // A block on a shorter fork leaves the best block where it was.
+    import_child(&mut client, &genesis, vec![mint(3)]);
+    assert_eq!(maintainer.block_imported(&client, &mut pool), None);
+    assert_eq!(maintainer.best_block(), hash(&a2.header));
+    assert!(pool.contains(&mint(10)));
+
+    import_child(&mut client, &a2, vec![mint(4)]);
+    maintainer.block_imported(&client, &mut pool);
+    assert!(!pool.contains(&mint(10)));
+}
+
+#[test]
+fn client_4_retracted_mortal_transactions_keep_their_era() {
+    let mut client = test_client();
+    let genesis = test_chain(&[], 0, 0).remove(0);
+    let genesis_hash = hash(&genesis.header);
+    let mut pool = TaggedPool::new();
+    let mut maintainer = PoolMaintainer::new(genesis_hash);
+
+    // Valid in blocks up to height 4.
+    let era = Era::Mortal {
+        anchor: genesis_hash,
+        period: 4,
+    };
+    maintainer
+        .submit_transaction_with_era(&client, &mut pool, mint(10), era)
+        .unwrap();
+    import_child(&mut client, &genesis, vec![mint(10)]);
+    maintainer.block_imported(&client, &mut pool);
+    assert!(!pool.contains(&mint(10)));
+
+    // A longer fork without the transaction wins, so it goes back into the pool.
+    let b1 = import_child(&mut client, &genesis, vec![mint(1)]);
+    let b2 = import_child(&mut client, &b1, vec![mint(2)]);
+    let route = maintainer.block_imported(&client, &mut pool).unwrap();
+    assert_eq!(route.enacted, vec![hash(&b1.header), hash(&b2.header)]);
+    assert!(pool.contains(&mint(10)));
+
+    // It is still mortal, and expires with its era.
+    let b3 = import_child(&mut client, &b2, vec![mint(3)]);
+    maintainer.block_imported(&client, &mut pool);
+    assert!(pool.contains(&mint(10)));
+    import_child(&mut client, &b3, vec![mint(4)]);
+    maintainer.block_imported(&client, &mut pool);
+    assert!(!pool.contains(&mint(10)));
+}
@@ -1,11 +1,12 @@
 //! We are now ready to give out client the ability to author blocks.
 //! Clients that perform this task are usually known as "miners", "authors", or "authorities".
 
-use super::{FullClient, StateMachine};
+use super::{p17_block_builder::BlockBuilder, Consensus, FullClient, StateMachine};
 
 // You may need to add trait bounds to make this work.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>
     where
+    C: Consensus,
     SM: StateMachine,
 {
     /// Author a new block with the given transactions on top of the given parent
@@ -19,6 +20,24 @@ impl<C, SM, FC, P> FullClient<C, SM, FC, P>
     pub fn author_and_import_automatic_block(&self) {
         todo!("Exercise 2")
     }
+
+    /// Author a new block on top of the "best" block, with a body filled from the pool by the
+    /// given block builder, and import the new block into the local database.
+    ///
+    /// Unlike the automatic block above, this block never exceeds the builder's limits, and
+    /// transactions that do not fit stay in the pool for the next block.
+    pub fn author_and_import_limited_block(&mut self, builder: &mut BlockBuilder<SM::Transition>) {
+        todo!("Exercise 3")
+    }
+
+    /// Author a new block with the transactions from the pool on top of the "best" block, stamped
+    /// with the given timestamp instead of the system time, and import it into the local database.
+    /// Returns the new block's hash, or None if the consensus engine could not seal it.
+    ///
+    /// Passing the time in makes authoring reproducible, which the network simulator relies on.
+    pub fn author_and_import_block_at(&mut self, timestamp: u64) -> Option<u64> {
+        todo!("Exercise 4")
+    }
 }
 
 //TODO tests
\ No newline at end of file
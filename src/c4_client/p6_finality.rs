@@ -1,18 +1,251 @@
 //! Sometimes you want a block to never be reverted.
 //! In practice this is usually implemented by some kind of BFT based consensus game.
 //! We will model a very simple alternative where node operators manually request finality.
-//! 
+//!
 //! Although we elide the details of the game itself, this model still allows us to explore
 //! the consequences of having some blocks that are never reverted.
+//!
+//! Once a block is finalized, the client must honor that decision:
+//! * Importing a block that does not descend from the finalized block is an error.
+//! * Fork choice never returns a best block outside the finalized chain.
+//! * Forks that branched off below the finalized block can never become canonical again,
+//!   so they are pruned along with their states.
+//!
+//! Some consensus engines make blocks final the moment they are sealed. When the engine's
+//! `is_final` says so, the client finalizes the block as soon as it is imported.
 
-use super::FullClient;
+use super::{Consensus, FullClient, StateMachine};
 
-impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
+/// The ways a block may conflict with finality.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinalityError {
+    /// The block is not known to the client.
+    UnknownBlock(u64),
+    /// The block does not build on the finalized block, so accepting it would revert
+    /// finalized history.
+    NotDescendantOfFinalized { block: u64, finalized: u64 },
+}
+
+/// Check whether `block` is `ancestor` or one of its descendants.
+///
+/// `lookup` returns the parent and height of any known block, which lets this work with
+/// whatever block storage a client uses.
+pub fn descends_from(
+    lookup: impl Fn(u64) -> Option<(u64, u64)>,
+    ancestor: u64,
+    mut block: u64,
+) -> bool {
+    let Some((_, ancestor_height)) = lookup(ancestor) else {
+        return false;
+    };
+    while let Some((parent, height)) = lookup(block) {
+        if block == ancestor {
+            return true;
+        }
+        if height <= ancestor_height {
+            return false;
+        }
+        block = parent;
+    }
+    false
+}
+
+/// Check that a block, typically the parent of a block being imported, is on the finalized chain
+/// at or above the finalized block.
+pub fn check_finality(
+    lookup: impl Fn(u64) -> Option<(u64, u64)>,
+    finalized: u64,
+    block: u64,
+) -> Result<(), FinalityError> {
+    if lookup(block).is_none() {
+        return Err(FinalityError::UnknownBlock(block));
+    }
+    if !descends_from(&lookup, finalized, block) {
+        return Err(FinalityError::NotDescendantOfFinalized { block, finalized });
+    }
+    Ok(())
+}
+
+/// Find every block that is neither an ancestor nor a descendant of the finalized block.
+/// These are the blocks on forks that branched off below the finalized block.
+pub fn non_canonical_blocks(
+    blocks: impl IntoIterator<Item = u64>,
+    lookup: impl Fn(u64) -> Option<(u64, u64)>,
+    finalized: u64,
+) -> Vec<u64> {
+    blocks
+        .into_iter()
+        .filter(|block| {
+            !descends_from(&lookup, finalized, *block) && !descends_from(&lookup, *block, finalized)
+        })
+        .collect()
+}
+
+impl<C, SM, FC, P> FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+{
     /// Mark the given block as final so that it will never be reverted.
     /// Returns whether or not the block was known and marked successfully.
+    ///
+    /// Only descendants of the current finalized block may be finalized. Once this succeeds,
+    /// `import_block` must reject blocks that do not descend from it, and `best_block` must
+    /// never return such a block. The forks it makes dead are pruned with `prune_non_canonical`.
     pub fn manually_finalize_block(&mut self, block_hash: u64) -> bool {
         todo!("Exercise 1")
     }
+
+    /// The most recently finalized block. Before anything is finalized, this is the genesis block.
+    pub fn finalized_block(&self) -> u64 {
+        todo!("Exercise 2")
+    }
+
+    /// Remove every block, along with its state, that is on a fork that branched off below the
+    /// finalized block. Returns how many blocks were pruned.
+    ///
+    /// The `non_canonical_blocks` helper above does the hard part.
+    pub fn prune_non_canonical(&mut self) -> usize {
+        todo!("Exercise 3")
+    }
+
+    /// Finalize the given block just like `manually_finalize_block`, and store the justification
+    /// that proves it final with the block. Finality gadgets call this, and peers that import the
+    /// block later can be handed the justification along with it.
+    ///
+    /// The justification is kept in encoded form. The client does not need to understand it.
+    pub fn finalize_block_with_justification(
+        &mut self,
+        block_hash: u64,
+        justification: Vec<u8>,
+    ) -> bool {
+        todo!("Exercise 4")
+    }
+
+    /// The justification stored with the given block, if it has one.
+    pub fn justification(&self, block_hash: u64) -> Option<Vec<u8>> {
+        todo!("Exercise 5")
+    }
 }
 
-//TODO tests
\ No newline at end of file
+#[cfg(test)]
+use super::p15_tagged_pool::TaggedPool;
+#[cfg(test)]
+use super::p2_importing_blocks::ImportBlock;
+#[cfg(test)]
+use super::test_support::{test_chain, CountingConsensus, TestCurrency, TestLongestChain};
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::AccountingTransaction;
+#[cfg(test)]
+use crate::c2_blockchain::p8_state_trie::StateTrie;
+#[cfg(test)]
+use crate::hash;
+#[cfg(test)]
+use std::collections::HashMap;
+
+/// A small block tree for testing, mapping each block to its parent and height.
+///
+/// ```text
+/// 0 - 1 - 2 - 3
+///      \
+///       12 - 13
+///   \
+///    21
+/// ```
+#[cfg(test)]
+fn test_tree() -> HashMap<u64, (u64, u64)> {
+    HashMap::from([
+        (0, (u64::MAX, 0)),
+        (1, (0, 1)),
+        (2, (1, 2)),
+        (3, (2, 3)),
+        (12, (1, 2)),
+        (13, (12, 3)),
+        (21, (0, 1)),
+    ])
+}
+
+#[test]
+fn client_6_descends_from() {
+    let tree = test_tree();
+    let lookup = |h| tree.get(&h).copied();
+
+    assert!(descends_from(lookup, 0, 3));
+    assert!(descends_from(lookup, 1, 13));
+    assert!(descends_from(lookup, 2, 2));
+    assert!(!descends_from(lookup, 2, 13));
+    assert!(!descends_from(lookup, 3, 1));
+    assert!(!descends_from(lookup, 99, 3));
+}
+
+#[test]
+fn client_6_check_finality() {
+    let tree = test_tree();
+    let lookup = |h| tree.get(&h).copied();
+
+    assert_eq!(check_finality(lookup, 2, 3), Ok(()));
+    assert_eq!(check_finality(lookup, 2, 2), Ok(()));
+    assert_eq!(
+        check_finality(lookup, 2, 12),
+        Err(FinalityError::NotDescendantOfFinalized {
+            block: 12,
+            finalized: 2
+        })
+    );
+    assert_eq!(
+        check_finality(lookup, 2, 1),
+        Err(FinalityError::NotDescendantOfFinalized {
+            block: 1,
+            finalized: 2
+        })
+    );
+    assert_eq!(
+        check_finality(lookup, 2, 99),
+        Err(FinalityError::UnknownBlock(99))
+    );
+}
+
+#[test]
+fn client_6_non_canonical_blocks() {
+    let tree = test_tree();
+    let lookup = |h| tree.get(&h).copied();
+
+    let mut pruned = non_canonical_blocks(tree.keys().copied(), lookup, 2);
+    pruned.sort();
+    assert_eq!(pruned, vec![12, 13, 21]);
+
+    let mut pruned = non_canonical_blocks(tree.keys().copied(), lookup, 1);
+    pruned.sort();
+    assert_eq!(pruned, vec![21]);
+
+    assert!(non_canonical_blocks(tree.keys().copied(), lookup, 0).is_empty());
+}
+
+#[test]
+fn client_6_finality_rejects_conflicting_imports_and_prunes_forks() {
+    let main = test_chain(&[], 3, 0);
+    let fork = test_chain(&main[..2], 4, 1);
+    let mut client = FullClient::<_, TestCurrency, _, _>::with_genesis(
+        CountingConsensus,
+        TestLongestChain::default(),
+        TaggedPool::<AccountingTransaction>::new(),
+        StateTrie::new(),
+        0,
+        StateTrie::state_root,
+    );
+    for block in main[1..].iter().chain(&fork[2..]) {
+        assert!(client.import_block(block.clone()));
+    }
+    assert_eq!(client.best_block(), hash(&fork[4].header));
+
+    // Finalizing the main chain prunes the longer fork, and the best block follows finality.
+    assert!(client.manually_finalize_block(hash(&main[2].header)));
+    assert_eq!(client.best_block(), hash(&main[3].header));
+    assert_eq!(client.all_leaves(), vec![hash(&main[3].header)]);
+    assert!(client.get_state(hash(&fork[3].header)).is_none());
+
+    // The fork cannot be imported again, and finality never moves backwards.
+    assert!(!client.import_block(fork[2].clone()));
+    assert!(!client.manually_finalize_block(hash(&main[1].header)));
+    assert_eq!(client.prune_non_canonical(), 0);
+}
@@ -0,0 +1,925 @@
+//! Our client keeps everything it knows in memory. That is convenient while learning, but it means
+//! that every restart forgets the entire chain and must sync it again from scratch. Real clients
+//! store their block database on disk.
+//!
+//! In this section we write a small embedded database ourselves rather than depending on an
+//! external one. The design is an append-only log:
+//! * Every write (a block, a state, a new best block, a new finalized block) is appended to the
+//!   end of a single log file as a self-contained record.
+//! * Each record is framed with its length and a checksum of its contents. The frame header has a
+//!   checksum of its own, so that a damaged length is never mistaken for the end of the log.
+//! * When the database is opened, the log is scanned from the beginning to rebuild an in-memory
+//!   index of where each block and state lives in the file.
+//!
+//! This design makes writes crash-safe with very little effort. If the process dies part way
+//! through appending a record, the next open will find an incomplete or corrupt record at the end
+//! of the log and simply truncate it away. Everything before it is still intact, because we never
+//! modify bytes that were already written. For the same reason, a corrupt record anywhere else in
+//! the log can not be explained by a crash. Opening fails in that case rather than throwing away
+//! the good records after it. So a write that fails while the process keeps running is truncated
+//! away right then, before anything else can be appended after it.
+//!
+//! The leaves set does not need records of its own. It can always be reconstructed from the parent
+//! links in the stored blocks, so we rebuild it along with the rest of the index.
+//!
+//! Since we never modify bytes that were already written, forgetting a block is a write like any
+//! other. When finality prunes a fork, a record for each pruned block is appended, and the index
+//! drops the block and its state when it reads that record. The bytes stay in the file.
+//!
+//! One limitation remains. Blocks are identified by the hashes from `crate::hash`, which uses the
+//! standard library's `DefaultHasher`. The standard library does not promise that it gives the
+//! same results in every Rust release, so a database should only be reopened by a client built
+//! with the same Rust release that wrote it. Our own checksums do not have this problem.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use super::{Block, ClientApi, Consensus, StateMachine};
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+
+type Hash = u64;
+
+/// The name of the log file inside the database directory.
+const LOG_FILE: &str = "chain.log";
+
+/// The number of bytes in a record frame before the payload: a u32 length, a u64 checksum of the
+/// payload, and a u32 checksum of the two fields before it.
+const FRAME_HEADER_LEN: usize = 16;
+
+/// A single entry in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Record {
+    /// A complete encoded block along with the metadata required to index it without decoding it.
+    Block {
+        hash: Hash,
+        parent: Hash,
+        height: u64,
+        block: Vec<u8>,
+    },
+    /// The encoded post state of a block.
+    State { block: Hash, state: Vec<u8> },
+    /// The best block has changed.
+    Best(Hash),
+    /// A block has been finalized.
+    Finalized(Hash),
+    /// A block was pruned, so it and its state are no longer stored.
+    Pruned(Hash),
+}
+
+impl Encode for Record {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Record::Block {
+                hash,
+                parent,
+                height,
+                block,
+            } => {
+                out.push(0);
+                (*hash, *parent, *height).encode_to(out);
+                block.encode_to(out);
+            }
+            Record::State { block, state } => {
+                out.push(1);
+                block.encode_to(out);
+                state.encode_to(out);
+            }
+            Record::Best(hash) => {
+                out.push(2);
+                hash.encode_to(out);
+            }
+            Record::Finalized(hash) => {
+                out.push(3);
+                hash.encode_to(out);
+            }
+            Record::Pruned(hash) => {
+                out.push(4);
+                hash.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for Record {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => {
+                let (hash, parent, height) = Decode::decode_from(input)?;
+                Ok(Record::Block {
+                    hash,
+                    parent,
+                    height,
+                    block: Decode::decode_from(input)?,
+                })
+            }
+            1 => Ok(Record::State {
+                block: Decode::decode_from(input)?,
+                state: Decode::decode_from(input)?,
+            }),
+            2 => Ok(Record::Best(Decode::decode_from(input)?)),
+            3 => Ok(Record::Finalized(Decode::decode_from(input)?)),
+            4 => Ok(Record::Pruned(Decode::decode_from(input)?)),
+            _ => Err(DecodeError::Invalid("database record")),
+        }
+    }
+}
+
+/// The 64 bit FNV-1a hash of the given bytes, which we use as our checksum.
+///
+/// We can not use `crate::hash` here. The standard library does not promise that its hasher gives
+/// the same results in every Rust release, and a log written by one build must be readable by the
+/// next. FNV-1a is fully specified by the two constants below.
+fn checksum(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |h, byte| {
+        (h ^ *byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+/// Frame a record's payload with its length and checksum, followed by a checksum of those two.
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    (payload.len() as u32).encode_to(&mut framed);
+    checksum(payload).encode_to(&mut framed);
+    (checksum(&framed) as u32).encode_to(&mut framed);
+    framed.extend_from_slice(payload);
+    framed
+}
+
+fn invalid_data(error: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{error:?}"))
+}
+
+/// The location of a value within the log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    offset: u64,
+    len: usize,
+}
+
+/// What the index knows about a stored block without reading it from disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockEntry {
+    parent: Hash,
+    height: u64,
+    location: Location,
+}
+
+/// A file-backed database of blocks, states, and chain metadata.
+pub struct BlockDatabase<C, SM> {
+    /// The path to the log file.
+    log_path: PathBuf,
+    /// The log file, opened for appending.
+    log: File,
+    /// Every stored block.
+    blocks: HashMap<Hash, BlockEntry>,
+    /// Every stored state, keyed by the hash of the block it belongs to.
+    states: HashMap<Hash, Location>,
+    /// The blocks that have no stored children.
+    leaves: HashSet<Hash>,
+    /// The blocks that have at least one stored child.
+    parents: HashSet<Hash>,
+    /// The most recently recorded best block.
+    best: Option<Hash>,
+    /// The most recently recorded finalized block.
+    finalized: Option<Hash>,
+    phantom: PhantomData<(C, SM)>,
+}
+
+impl<C, SM> BlockDatabase<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: Encode + Decode,
+    SM::Transition: Encode + Decode,
+    SM::State: Encode + Decode,
+{
+    /// Open the database in the given directory, creating it if it does not exist yet.
+    ///
+    /// An incomplete or corrupt last record is assumed to be the result of a crash during a
+    /// write, and is discarded. A corrupt record followed by more records is an error.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let log_path = dir.as_ref().join(LOG_FILE);
+        let mut log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&log_path)?;
+
+        let mut contents = Vec::new();
+        log.read_to_end(&mut contents)?;
+
+        let mut database = Self {
+            log_path,
+            log,
+            blocks: HashMap::new(),
+            states: HashMap::new(),
+            leaves: HashSet::new(),
+            parents: HashSet::new(),
+            best: None,
+            finalized: None,
+            phantom: PhantomData,
+        };
+
+        let mut offset = 0;
+        while offset < contents.len() {
+            match read_record(&contents, offset) {
+                Ok((record, next)) => {
+                    database.index(record, next as u64);
+                    offset = next;
+                }
+                Err(ReadError::Incomplete) => break,
+                Err(ReadError::Corrupt { end }) if end == contents.len() => break,
+                Err(ReadError::BadHeader | ReadError::Corrupt { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt record at offset {offset}, before the end of the log"),
+                    ));
+                }
+            }
+        }
+
+        if offset < contents.len() {
+            // The tail of the log is a torn write. Cut it off so that new records
+            // are appended after the last good one.
+            database.log.set_len(offset as u64)?;
+            database.log.sync_all()?;
+        }
+
+        Ok(database)
+    }
+
+    /// Store a block. Returns the block's hash.
+    ///
+    /// Storing a block that is already stored does nothing.
+    pub fn insert_block(&mut self, block: &Block<C, SM>) -> io::Result<Hash> {
+        let block_hash = hash(&block.header);
+        if !self.blocks.contains_key(&block_hash) {
+            self.append(Record::Block {
+                hash: block_hash,
+                parent: block.header.parent,
+                height: block.header.height,
+                block: block.encode(),
+            })?;
+        }
+        Ok(block_hash)
+    }
+
+    /// Store the post state of the given block.
+    pub fn insert_state(&mut self, block_hash: Hash, state: &SM::State) -> io::Result<()> {
+        self.append(Record::State {
+            block: block_hash,
+            state: state.encode(),
+        })
+    }
+
+    /// Record that the given block is now the best block.
+    pub fn set_best(&mut self, block_hash: Hash) -> io::Result<()> {
+        if self.best != Some(block_hash) {
+            self.append(Record::Best(block_hash))?;
+        }
+        Ok(())
+    }
+
+    /// Record that the given block has been finalized.
+    pub fn set_finalized(&mut self, block_hash: Hash) -> io::Result<()> {
+        if self.finalized != Some(block_hash) {
+            self.append(Record::Finalized(block_hash))?;
+        }
+        Ok(())
+    }
+
+    /// Record that the given block was pruned. It is no longer stored, and neither is its state.
+    ///
+    /// Pruning a block that is not stored does nothing.
+    pub fn prune_block(&mut self, block_hash: Hash) -> io::Result<()> {
+        if self.blocks.contains_key(&block_hash) {
+            self.append(Record::Pruned(block_hash))?;
+        }
+        Ok(())
+    }
+
+    /// Read a stored block.
+    pub fn block(&self, block_hash: Hash) -> io::Result<Option<Block<C, SM>>> {
+        match self.blocks.get(&block_hash) {
+            Some(entry) => Ok(Some(self.read_value(entry.location)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the post state of a stored block.
+    pub fn state(&self, block_hash: Hash) -> io::Result<Option<SM::State>> {
+        match self.states.get(&block_hash) {
+            Some(location) => Ok(Some(self.read_value(*location)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Append a record to the log, make sure it is on disk, and then index it.
+    fn append(&mut self, record: Record) -> io::Result<()> {
+        let framed = frame(&record.encode());
+        let start = self.log.seek(SeekFrom::End(0))?;
+        if let Err(error) = self
+            .log
+            .write_all(&framed)
+            .and_then(|()| self.log.sync_data())
+        {
+            // Cut off whatever part of the record made it to the file. Otherwise the next record
+            // would be appended after it, and the log could no longer be opened.
+            let _ = self.log.set_len(start);
+            return Err(error);
+        }
+        self.index(record, start + framed.len() as u64);
+        Ok(())
+    }
+
+    /// Read and decode a value from the log.
+    fn read_value<T: Decode>(&self, location: Location) -> io::Result<T> {
+        let mut file = File::open(&self.log_path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut bytes = vec![0; location.len];
+        file.read_exact(&mut bytes)?;
+        T::decode(&bytes).map_err(invalid_data)
+    }
+}
+
+impl<C, SM> BlockDatabase<C, SM> {
+    /// Check whether the given block is stored.
+    pub fn contains_block(&self, block_hash: Hash) -> bool {
+        self.blocks.contains_key(&block_hash)
+    }
+
+    /// The hashes of all stored blocks that have no stored children, in ascending order.
+    pub fn leaves(&self) -> Vec<Hash> {
+        let mut leaves: Vec<Hash> = self.leaves.iter().copied().collect();
+        leaves.sort();
+        leaves
+    }
+
+    /// The most recently recorded best block.
+    pub fn best(&self) -> Option<Hash> {
+        self.best
+    }
+
+    /// The most recently recorded finalized block.
+    pub fn finalized(&self) -> Option<Hash> {
+        self.finalized
+    }
+
+    /// The total number of stored blocks.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The hashes of every stored block, ordered so that every parent comes before its children.
+    pub fn blocks_in_import_order(&self) -> Vec<Hash> {
+        let mut hashes: Vec<Hash> = self.blocks.keys().copied().collect();
+        hashes.sort_by_key(|h| (self.blocks[h].height, *h));
+        hashes
+    }
+
+    /// Update the in-memory index to account for a record that ends at the given offset in the
+    /// log. Blocks and states are the last field of their records, so they end there too.
+    fn index(&mut self, record: Record, end: u64) {
+        let location = |value: &[u8]| Location {
+            offset: end - value.len() as u64,
+            len: value.len(),
+        };
+        match record {
+            Record::Block {
+                hash,
+                parent,
+                height,
+                block,
+            } => {
+                self.blocks.insert(
+                    hash,
+                    BlockEntry {
+                        parent,
+                        height,
+                        location: location(&block),
+                    },
+                );
+                self.parents.insert(parent);
+                self.leaves.remove(&parent);
+                if !self.parents.contains(&hash) {
+                    self.leaves.insert(hash);
+                }
+            }
+            Record::State { block, state } => {
+                self.states.insert(block, location(&state));
+            }
+            Record::Best(hash) => self.best = Some(hash),
+            Record::Finalized(hash) => self.finalized = Some(hash),
+            Record::Pruned(hash) => {
+                let Some(entry) = self.blocks.remove(&hash) else {
+                    return;
+                };
+                self.states.remove(&hash);
+                self.leaves.remove(&hash);
+                if !self.blocks.values().any(|e| e.parent == entry.parent) {
+                    self.parents.remove(&entry.parent);
+                    if self.blocks.contains_key(&entry.parent) {
+                        self.leaves.insert(entry.parent);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The reasons a record could not be read from the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadError {
+    /// The log ends part way through the record.
+    Incomplete,
+    /// The record's frame header does not match its own checksum, so not even the length of the
+    /// record can be trusted.
+    BadHeader,
+    /// The record is complete, but does not match its checksum or can not be decoded. It ends at
+    /// the given offset.
+    Corrupt { end: usize },
+}
+
+/// Try to read a complete, uncorrupted record from the log contents at the given offset.
+/// Returns the record and where the next record starts.
+fn read_record(contents: &[u8], offset: usize) -> Result<(Record, usize), ReadError> {
+    let header = contents
+        .get(offset..offset + FRAME_HEADER_LEN)
+        .ok_or(ReadError::Incomplete)?;
+    let (mut fields, mut header_checksum) = header.split_at(FRAME_HEADER_LEN - 4);
+    if u32::decode_from(&mut header_checksum) != Ok(checksum(fields) as u32) {
+        return Err(ReadError::BadHeader);
+    }
+    let len = u32::decode_from(&mut fields).map_err(|_| ReadError::BadHeader)? as usize;
+    let payload_checksum = u64::decode_from(&mut fields).map_err(|_| ReadError::BadHeader)?;
+
+    // The length is known to be intact, so a log that ends before the payload does can only
+    // mean that writing this record was cut short.
+    let payload_offset = offset + FRAME_HEADER_LEN;
+    let end = payload_offset + len;
+    let payload = contents
+        .get(payload_offset..end)
+        .ok_or(ReadError::Incomplete)?;
+    if checksum(payload) != payload_checksum {
+        return Err(ReadError::Corrupt { end });
+    }
+
+    let record = Record::decode(payload).map_err(|_| ReadError::Corrupt { end })?;
+    Ok((record, end))
+}
+
+/// A client whose blocks, states, and chain metadata are persisted to a database as they are
+/// imported. When the client is restarted, it can pick up where it left off.
+///
+/// Usually the wrapped client is our `FullClient`, but any client will do.
+pub struct PersistentClient<C: Consensus, SM: StateMachine, I> {
+    /// The in-memory client that does all of the actual work.
+    client: I,
+    /// The database that the client's data is written to.
+    database: BlockDatabase<C, SM>,
+}
+
+impl<C, SM, I> PersistentClient<C, SM, I>
+where
+    C: Consensus,
+    SM: StateMachine,
+    I: ClientApi<C, SM>,
+    C::Digest: Encode + Decode,
+    SM::Transition: Encode + Decode,
+    SM::State: Encode + Decode,
+    Block<C, SM>: Clone,
+{
+    /// Open the database in the given directory and restore the given client from it. The client
+    /// should be new, so that it knows nothing but its genesis block.
+    ///
+    /// If the database is empty, the client's genesis block is stored. Otherwise every stored
+    /// block is re-imported in order. Blocks are still checked against the consensus rules, but
+    /// a block whose state was stored is not executed again. The stored state only has to match
+    /// the header's state root. The finality marker is restored as well.
+    pub fn open(dir: impl AsRef<Path>, mut client: I) -> io::Result<Self> {
+        let mut database = BlockDatabase::open(dir)?;
+
+        let genesis_hash = client.genesis();
+        if !database.contains_block(genesis_hash) {
+            let (genesis, state) = client
+                .get_block(genesis_hash)
+                .zip(client.get_state(genesis_hash))
+                .expect("a new client always knows its genesis block and state");
+            database.insert_block(&genesis)?;
+            database.insert_state(genesis_hash, &state)?;
+            database.set_best(genesis_hash)?;
+        }
+
+        for block_hash in database.blocks_in_import_order() {
+            if client.get_block(block_hash).is_some() {
+                continue;
+            }
+            let block = database
+                .block(block_hash)?
+                .expect("hash came from the index");
+            let state = database.state(block_hash)?;
+            if !client.import_block_with_state(block, state) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("stored block {block_hash} failed to import"),
+                ));
+            }
+        }
+
+        if let Some(finalized) = database.finalized() {
+            client.manually_finalize_block(finalized);
+        }
+
+        Ok(Self { client, database })
+    }
+
+    /// Import a block into the client. If the import succeeds, the block, its post state, and
+    /// the resulting best block are written to the database before returning.
+    pub fn import_block(&mut self, block: Block<C, SM>) -> io::Result<bool> {
+        let block_hash = hash(&block.header);
+        if !self.client.import_block(block.clone()) {
+            return Ok(false);
+        }
+
+        self.database.insert_block(&block)?;
+        if let Some(state) = self.client.get_state(block_hash) {
+            self.database.insert_state(block_hash, &state)?;
+        }
+        self.database.set_best(self.client.best_block())?;
+        Ok(true)
+    }
+
+    /// Finalize a block in the client and record the finality in the database, along with the
+    /// blocks that the client pruned as a result.
+    pub fn finalize_block(&mut self, block_hash: u64) -> io::Result<bool> {
+        if !self.client.manually_finalize_block(block_hash) {
+            return Ok(false);
+        }
+        self.database.set_finalized(block_hash)?;
+        for stored in self.database.blocks_in_import_order() {
+            if self.client.get_block(stored).is_none() {
+                self.database.prune_block(stored)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// The in-memory client.
+    pub fn client(&self) -> &I {
+        &self.client
+    }
+
+    /// The underlying database.
+    pub fn database(&self) -> &BlockDatabase<C, SM> {
+        &self.database
+    }
+}
+
+#[cfg(test)]
+use crate::{
+    c1_state_machine::{
+        p4_accounted_currency::{AccountedCurrency, AccountingTransaction, Balances},
+        User,
+    },
+    c3_consensus::Header,
+};
+
+#[cfg(test)]
+type TestDatabase = BlockDatabase<(), AccountedCurrency>;
+
+/// A fresh, empty directory for a single test.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bfs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// A block at the given height with a single mint in its body. The parent and height are all
+/// the database cares about.
+#[cfg(test)]
+fn test_block(parent: Hash, height: u64) -> Block<(), AccountedCurrency> {
+    Block {
+        header: Header {
+            parent,
+            height,
+            state_root: 0,
+            extrinsics_root: 0,
+            timestamp: 0,
+            consensus_digest: (),
+        },
+        body: vec![AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: height,
+        }],
+    }
+}
+
+#[test]
+fn client_7_new_database_is_empty() {
+    let dir = test_dir("new-database-is-empty");
+    let db = TestDatabase::open(&dir).unwrap();
+
+    assert_eq!(db.block_count(), 0);
+    assert_eq!(db.leaves(), vec![]);
+    assert_eq!(db.best(), None);
+    assert_eq!(db.finalized(), None);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_blocks_and_states_survive_reopening() {
+    let dir = test_dir("blocks-survive-reopening");
+    let b0 = test_block(0, 0);
+    let b1 = test_block(hash(&b0.header), 1);
+    let state = Balances::from([(User::Alice, 1)]);
+    {
+        let mut db = TestDatabase::open(&dir).unwrap();
+        db.insert_block(&b0).unwrap();
+        let h1 = db.insert_block(&b1).unwrap();
+        db.insert_state(h1, &state).unwrap();
+        db.set_best(h1).unwrap();
+        db.set_finalized(hash(&b0.header)).unwrap();
+    }
+
+    let db = TestDatabase::open(&dir).unwrap();
+    let h1 = hash(&b1.header);
+    assert_eq!(db.block(h1).unwrap(), Some(b1));
+    assert_eq!(db.block(hash(&b0.header)).unwrap(), Some(b0.clone()));
+    assert_eq!(db.state(h1).unwrap(), Some(state));
+    assert_eq!(db.state(hash(&b0.header)).unwrap(), None);
+    assert_eq!(db.best(), Some(h1));
+    assert_eq!(db.finalized(), Some(hash(&b0.header)));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_leaves_are_tracked_across_forks() {
+    let dir = test_dir("leaves-tracked");
+    let b0 = test_block(0, 0);
+    let h0 = hash(&b0.header);
+    let b1 = test_block(h0, 1);
+    let mut b1_fork = test_block(h0, 1);
+    b1_fork.header.state_root = 1;
+    let b2 = test_block(hash(&b1.header), 2);
+
+    let mut db = TestDatabase::open(&dir).unwrap();
+    for block in [&b0, &b1, &b1_fork, &b2] {
+        db.insert_block(block).unwrap();
+    }
+    let mut expected = vec![hash(&b2.header), hash(&b1_fork.header)];
+    expected.sort();
+    assert_eq!(db.leaves(), expected);
+
+    drop(db);
+    let db = TestDatabase::open(&dir).unwrap();
+    assert_eq!(db.leaves(), expected);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_import_order_puts_parents_first() {
+    let dir = test_dir("import-order");
+    let b0 = test_block(0, 0);
+    let b1 = test_block(hash(&b0.header), 1);
+    let b2 = test_block(hash(&b1.header), 2);
+
+    let mut db = TestDatabase::open(&dir).unwrap();
+    for block in [&b2, &b0, &b1] {
+        db.insert_block(block).unwrap();
+    }
+
+    assert_eq!(
+        db.blocks_in_import_order(),
+        vec![hash(&b0.header), hash(&b1.header), hash(&b2.header)]
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_inserting_twice_does_not_grow_log() {
+    let dir = test_dir("insert-twice");
+    let b0 = test_block(0, 0);
+    let mut db = TestDatabase::open(&dir).unwrap();
+    db.insert_block(&b0).unwrap();
+    let len = fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+    db.insert_block(&b0).unwrap();
+
+    assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), len);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_torn_write_is_discarded() {
+    let dir = test_dir("torn-write");
+    let b0 = test_block(0, 0);
+    let b1 = test_block(hash(&b0.header), 1);
+    {
+        let mut db = TestDatabase::open(&dir).unwrap();
+        db.insert_block(&b0).unwrap();
+        db.insert_block(&b1).unwrap();
+    }
+
+    // Simulate a crash part way through writing the second block.
+    let log_path = dir.join(LOG_FILE);
+    let len = fs::metadata(&log_path).unwrap().len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+
+    let mut db = TestDatabase::open(&dir).unwrap();
+    assert!(db.contains_block(hash(&b0.header)));
+    assert!(!db.contains_block(hash(&b1.header)));
+
+    // The database is usable again after recovering.
+    db.insert_block(&b1).unwrap();
+    drop(db);
+    let db = TestDatabase::open(&dir).unwrap();
+    assert_eq!(db.block(hash(&b1.header)).unwrap(), Some(b1));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_corrupt_record_is_discarded() {
+    let dir = test_dir("corrupt-record");
+    let b0 = test_block(0, 0);
+    {
+        let mut db = TestDatabase::open(&dir).unwrap();
+        db.insert_block(&b0).unwrap();
+        db.set_best(hash(&b0.header)).unwrap();
+    }
+
+    // Flip a bit in the last byte of the log, which belongs to the best block record.
+    let log_path = dir.join(LOG_FILE);
+    let mut contents = fs::read(&log_path).unwrap();
+    *contents.last_mut().unwrap() ^= 1;
+    fs::write(&log_path, contents).unwrap();
+
+    let db = TestDatabase::open(&dir).unwrap();
+    assert!(db.contains_block(hash(&b0.header)));
+    assert_eq!(db.best(), None);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_corrupt_record_before_the_end_is_an_error() {
+    let dir = test_dir("corrupt-middle");
+    let b0 = test_block(0, 0);
+    {
+        let mut db = TestDatabase::open(&dir).unwrap();
+        db.insert_block(&b0).unwrap();
+        db.set_best(hash(&b0.header)).unwrap();
+    }
+
+    // Flip a bit in the last byte of the first record, which holds the genesis block.
+    let log_path = dir.join(LOG_FILE);
+    let mut contents = fs::read(&log_path).unwrap();
+    let first_end = read_record(&contents, 0).unwrap().1;
+    contents[first_end - 1] ^= 1;
+    fs::write(&log_path, &contents).unwrap();
+
+    let error = TestDatabase::open(&dir).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // Nothing was truncated.
+    assert_eq!(fs::read(&log_path).unwrap(), contents);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_damaged_length_before_the_end_is_an_error() {
+    let dir = test_dir("damaged-length");
+    let b0 = test_block(0, 0);
+    {
+        let mut db = TestDatabase::open(&dir).unwrap();
+        db.insert_block(&b0).unwrap();
+        db.set_best(hash(&b0.header)).unwrap();
+    }
+
+    // Make the length of the first record point past the end of the log, as if it were the
+    // last record and had been cut short.
+    let log_path = dir.join(LOG_FILE);
+    let mut contents = fs::read(&log_path).unwrap();
+    contents[3] ^= 0x80;
+    fs::write(&log_path, &contents).unwrap();
+
+    let error = TestDatabase::open(&dir).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // Nothing was truncated.
+    assert_eq!(fs::read(&log_path).unwrap(), contents);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_checksum_is_fnv_1a() {
+    // Test vectors from the FNV specification.
+    assert_eq!(checksum(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(checksum(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(checksum(b"foobar"), 0x8594_4171_f739_67e8);
+}
+
+#[cfg(test)]
+use super::p2_importing_blocks::ImportBlock;
+#[cfg(test)]
+use super::test_support::{test_chain, test_client, CountingConsensus, TestClient, TestCurrency};
+#[cfg(test)]
+use crate::c2_blockchain::p8_state_trie::StateTrie;
+
+#[cfg(test)]
+type TestPersistentClient = PersistentClient<CountingConsensus, TestCurrency, TestClient>;
+
+#[test]
+fn client_7_persistent_client_survives_restart() {
+    let dir = test_dir("persistent-client");
+    let chain = test_chain(&[], 4, 0);
+    let fork = test_chain(&chain[..2], 3, 1);
+    {
+        let mut persistent = TestPersistentClient::open(&dir, test_client()).unwrap();
+        for block in chain[1..].iter().chain(&fork[2..]) {
+            assert!(persistent.import_block(block.clone()).unwrap());
+        }
+        assert!(persistent.finalize_block(hash(&chain[1].header)).unwrap());
+        assert_eq!(persistent.database().block_count(), 7);
+    }
+
+    let restored = TestPersistentClient::open(&dir, test_client()).unwrap();
+    let client: &TestClient = restored.client();
+    let tip = hash(&chain[4].header);
+    assert_eq!(client.best_block(), tip);
+    assert_eq!(client.finalized_block(), hash(&chain[1].header));
+    assert!(client.get_block(hash(&fork[3].header)).is_some());
+    assert_eq!(
+        client.get_state(tip),
+        restored.database().state(tip).unwrap()
+    );
+    assert_eq!(restored.database().best(), Some(tip));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_pruned_blocks_stay_pruned_after_restart() {
+    let dir = test_dir("pruned-blocks");
+    let chain = test_chain(&[], 3, 0);
+    let fork = test_chain(&chain[..1], 2, 1);
+    {
+        let mut persistent = TestPersistentClient::open(&dir, test_client()).unwrap();
+        for block in chain[1..].iter().chain(&fork[1..]) {
+            assert!(persistent.import_block(block.clone()).unwrap());
+        }
+        assert_eq!(persistent.database().block_count(), 6);
+
+        // Finalizing block 1 of the main chain prunes the whole fork.
+        assert!(persistent.finalize_block(hash(&chain[1].header)).unwrap());
+        assert_eq!(persistent.database().block_count(), 4);
+        assert_eq!(persistent.database().leaves(), vec![hash(&chain[3].header)]);
+    }
+
+    let restored = TestPersistentClient::open(&dir, test_client()).unwrap();
+    let database = restored.database();
+    assert_eq!(database.block_count(), 4);
+    assert_eq!(database.leaves(), vec![hash(&chain[3].header)]);
+    for block in &fork[1..] {
+        let block_hash = hash(&block.header);
+        assert!(!database.contains_block(block_hash));
+        assert_eq!(database.state(block_hash).unwrap(), None);
+        assert!(restored.client().get_block(block_hash).is_none());
+    }
+    assert_eq!(restored.client().block_count(), 4);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn client_7_stored_states_must_match_their_headers() {
+    let dir = test_dir("stored-states");
+    let chain = test_chain(&[], 2, 0);
+    {
+        let mut persistent = TestPersistentClient::open(&dir, test_client()).unwrap();
+        for block in &chain[1..] {
+            assert!(persistent.import_block(block.clone()).unwrap());
+        }
+    }
+
+    // Replace the stored state of the tip. Restoring uses the stored state rather than executing
+    // the block, so it notices.
+    let tip = hash(&chain[2].header);
+    let mut db = BlockDatabase::<CountingConsensus, TestCurrency>::open(&dir).unwrap();
+    db.insert_state(tip, &StateTrie::new()).unwrap();
+    drop(db);
+
+    let error = TestPersistentClient::open(&dir, test_client())
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,462 @@
+//! It is often useful to hand somebody else an exact copy of a chain. Perhaps a colleague wants
+//! to reproduce a bug you found, or you want to save an interesting fork for a test.
+//!
+//! In this section we teach our client to export blocks to a file and to import them again.
+//! Two file formats are supported:
+//! * A compact binary format built on our codec. This is what you would use for big chains.
+//! * A JSON format that humans can read, and even edit by hand to craft interesting test cases.
+//!
+//! Exporting can include only the canonical chain (the ancestors of the best block), or every
+//! block the client knows about including abandoned forks. Either way it can be restricted to a
+//! range of heights.
+//!
+//! Importing does not trust the file at all. Every block is replayed through the regular
+//! `import_block` method, so it is fully verified exactly as if it had arrived from the network.
+
+use std::{collections::HashSet, fmt, fs, io, ops::RangeInclusive, path::Path};
+
+use super::{p2_importing_blocks::ImportBlock, Block, ClientApi, Consensus, StateMachine};
+use crate::codec::{Decode, Encode};
+use crate::hash;
+use crate::json::{object, FromJson, Json, ToJson};
+
+type Hash = u64;
+
+/// The bytes at the start of every binary chain file. They let us reject files that are
+/// obviously not chain exports before trying to decode them.
+const MAGIC: &[u8; 8] = b"BFSCHAIN";
+
+/// The file formats that chains can be exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The compact binary codec.
+    Binary,
+    /// Human-readable JSON.
+    Json,
+}
+
+/// Which blocks to include in an export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportScope {
+    /// Only the best block and its ancestors.
+    CanonicalChain,
+    /// Every block that the client knows about, including those on abandoned forks.
+    AllBlocks,
+}
+
+/// Progress of an import, reported after each block is processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// The number of blocks processed so far.
+    pub processed: usize,
+    /// The total number of blocks in the file.
+    pub total: usize,
+    /// The height of the block that was just processed.
+    pub height: u64,
+}
+
+/// The outcome of a successful import.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Blocks that were new to the client and imported successfully.
+    pub imported: usize,
+    /// Blocks that the client already knew about, such as the genesis block.
+    pub already_known: usize,
+}
+
+/// The reasons an import may fail.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file was read, but its contents are not a valid chain export.
+    Malformed(String),
+    /// A block in the file was rejected by the client. Blocks before it were imported.
+    InvalidBlock { hash: Hash, height: u64 },
+}
+
+impl From<io::Error> for ImportError {
+    fn from(error: io::Error) -> Self {
+        ImportError::Io(error)
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(error) => write!(f, "could not read chain file: {error}"),
+            ImportError::Malformed(reason) => write!(f, "malformed chain file: {reason}"),
+            ImportError::InvalidBlock { hash, height } => {
+                write!(f, "block {hash} at height {height} was rejected")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImportError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Collect blocks from a client for export, ordered by height so that every parent comes before
+/// its children.
+///
+/// The best block must be given because it determines the canonical chain.
+pub fn collect_blocks<C, SM, I>(
+    client: &I,
+    best_block: Hash,
+    scope: ExportScope,
+    heights: RangeInclusive<u64>,
+) -> Vec<Block<C, SM>>
+where
+    C: Consensus,
+    SM: StateMachine,
+    I: ImportBlock<C, SM>,
+{
+    let tips = match scope {
+        ExportScope::CanonicalChain => vec![best_block],
+        ExportScope::AllBlocks => client.all_leaves(),
+    };
+
+    // Walk back from each tip until we reach a block we have already seen or the genesis block.
+    let mut seen = HashSet::new();
+    let mut blocks = Vec::new();
+    for tip in tips {
+        let mut current = tip;
+        while seen.insert(current) {
+            let Some(block) = client.get_block(current) else {
+                break;
+            };
+            let (parent, height) = (block.header.parent, block.header.height);
+            if heights.contains(&height) {
+                blocks.push(block);
+            }
+            if height <= *heights.start() {
+                break;
+            }
+            current = parent;
+        }
+    }
+
+    blocks.sort_by_key(|block| (block.header.height, hash(&block.header)));
+    blocks
+}
+
+/// Write the given blocks to a file in the given format.
+pub fn write_blocks<C, SM>(
+    path: impl AsRef<Path>,
+    format: ExportFormat,
+    blocks: &[Block<C, SM>],
+) -> io::Result<()>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: Encode + ToJson,
+    SM::Transition: Encode + ToJson,
+{
+    let contents = match format {
+        ExportFormat::Binary => {
+            let mut bytes = MAGIC.to_vec();
+            blocks.encode_to(&mut bytes);
+            bytes
+        }
+        ExportFormat::Json => {
+            let json = object([
+                ("format", Json::String("bfs-chain".into())),
+                (
+                    "blocks",
+                    Json::Array(blocks.iter().map(ToJson::to_json).collect()),
+                ),
+            ]);
+            let mut text = json.to_pretty_string();
+            text.push('\n');
+            text.into_bytes()
+        }
+    };
+    fs::write(path, contents)
+}
+
+/// Read blocks from a file written by `write_blocks`.
+pub fn read_blocks<C, SM>(
+    path: impl AsRef<Path>,
+    format: ExportFormat,
+) -> Result<Vec<Block<C, SM>>, ImportError>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: Decode + FromJson,
+    SM::Transition: Decode + FromJson,
+{
+    let contents = fs::read(path)?;
+    match format {
+        ExportFormat::Binary => {
+            let Some(mut bytes) = contents.strip_prefix(MAGIC.as_slice()) else {
+                return Err(ImportError::Malformed("not a binary chain file".into()));
+            };
+            Vec::<Block<C, SM>>::decode_from(&mut bytes)
+                .ok()
+                .filter(|_| bytes.is_empty())
+                .ok_or_else(|| ImportError::Malformed("corrupt binary chain file".into()))
+        }
+        ExportFormat::Json => {
+            let text = String::from_utf8(contents)
+                .map_err(|_| ImportError::Malformed("chain file is not utf8".into()))?;
+            Json::parse(&text)
+                .and_then(|json| Vec::from_json(json.field("blocks")?))
+                .map_err(|error| ImportError::Malformed(error.0))
+        }
+    }
+}
+
+/// Import the given blocks into a client one at a time, reporting progress after each one.
+///
+/// Blocks that the client already knows are skipped. Importing stops at the first block that the
+/// client rejects.
+pub fn import_blocks<C, SM, I>(
+    client: &mut I,
+    blocks: Vec<Block<C, SM>>,
+    mut progress: impl FnMut(ImportProgress),
+) -> Result<ImportSummary, ImportError>
+where
+    C: Consensus,
+    SM: StateMachine,
+    I: ImportBlock<C, SM>,
+{
+    let total = blocks.len();
+    let mut summary = ImportSummary::default();
+    for (i, block) in blocks.into_iter().enumerate() {
+        let block_hash = hash(&block.header);
+        let height = block.header.height;
+        if client.get_block(block_hash).is_some() {
+            summary.already_known += 1;
+        } else if client.import_block(block) {
+            summary.imported += 1;
+        } else {
+            return Err(ImportError::InvalidBlock {
+                hash: block_hash,
+                height,
+            });
+        }
+        progress(ImportProgress {
+            processed: i + 1,
+            total,
+            height,
+        });
+    }
+    Ok(summary)
+}
+
+/// Export blocks from a client to a file. Returns the number of blocks written.
+pub fn export_chain<C, SM, I>(
+    client: &I,
+    path: impl AsRef<Path>,
+    format: ExportFormat,
+    scope: ExportScope,
+    heights: RangeInclusive<u64>,
+) -> io::Result<usize>
+where
+    C: Consensus,
+    SM: StateMachine,
+    I: ClientApi<C, SM>,
+    C::Digest: Encode + ToJson,
+    SM::Transition: Encode + ToJson,
+{
+    let blocks = collect_blocks(client, client.best_block(), scope, heights);
+    write_blocks(path, format, &blocks)?;
+    Ok(blocks.len())
+}
+
+/// Import every block from a file into a client, fully verifying each one.
+pub fn import_chain<C, SM, I>(
+    client: &mut I,
+    path: impl AsRef<Path>,
+    format: ExportFormat,
+    progress: impl FnMut(ImportProgress),
+) -> Result<ImportSummary, ImportError>
+where
+    C: Consensus,
+    SM: StateMachine,
+    I: ImportBlock<C, SM>,
+    C::Digest: Decode + FromJson,
+    SM::Transition: Decode + FromJson,
+{
+    let blocks = read_blocks(path, format)?;
+    import_blocks(client, blocks, progress)
+}
+
+#[cfg(test)]
+use super::test_support::{
+    test_chain, test_client, CountingConsensus, TestBlock, TestClient, TestCurrency,
+};
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::AccountingTransaction;
+
+/// Build a client with a main chain of five blocks after genesis, and a two block fork
+/// branching off after block 2. Returns the client along with the hashes of the two tips.
+#[cfg(test)]
+fn forked_test_chain() -> (TestClient, Hash, Hash) {
+    let main = test_chain(&[], 5, 0);
+    let fork = test_chain(&main[..=2], 4, 1);
+    let mut client = test_client();
+    for block in main[1..].iter().chain(&fork[3..]) {
+        assert!(client.import_block(block.clone()));
+    }
+    let tip = |chain: &[TestBlock]| hash(&chain.last().unwrap().header);
+    (client, tip(&main), tip(&fork))
+}
+
+/// Which fork of `forked_test_chain` the block belongs to, judging by what it mints.
+#[cfg(test)]
+fn fork_of(block: &TestBlock) -> u64 {
+    match block.body.as_slice() {
+        [AccountingTransaction::Mint { amount, .. }] => amount / 1000,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+fn test_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("bfs-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn client_8_collect_canonical_chain() {
+    let (chain, main_tip, _) = forked_test_chain();
+    let blocks = collect_blocks(&chain, main_tip, ExportScope::CanonicalChain, 0..=u64::MAX);
+
+    assert_eq!(blocks.len(), 6);
+    assert!(blocks.iter().all(|b| fork_of(b) == 0));
+    assert_eq!(hash(&blocks[5].header), main_tip);
+}
+
+#[test]
+fn client_8_collect_all_blocks() {
+    let (chain, main_tip, _) = forked_test_chain();
+    let blocks = collect_blocks(&chain, main_tip, ExportScope::AllBlocks, 0..=u64::MAX);
+
+    assert_eq!(blocks.len(), 8);
+    assert!(blocks
+        .windows(2)
+        .all(|w| w[0].header.height <= w[1].header.height));
+}
+
+#[test]
+fn client_8_collect_height_range() {
+    let (chain, _, fork_tip) = forked_test_chain();
+    let blocks = collect_blocks(&chain, fork_tip, ExportScope::CanonicalChain, 2..=3);
+    let heights: Vec<u64> = blocks.iter().map(|b| b.header.height).collect();
+
+    assert_eq!(heights, vec![2, 3]);
+    assert_eq!(fork_of(&blocks[1]), 1);
+}
+
+#[test]
+fn client_8_binary_round_trip() {
+    let (chain, main_tip, _) = forked_test_chain();
+    let blocks = collect_blocks(&chain, main_tip, ExportScope::AllBlocks, 0..=u64::MAX);
+    let path = test_file("binary-round-trip");
+    write_blocks(&path, ExportFormat::Binary, &blocks).unwrap();
+
+    let read: Vec<TestBlock> = read_blocks(&path, ExportFormat::Binary).unwrap();
+    assert_eq!(read, blocks);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn client_8_json_round_trip() {
+    let (chain, main_tip, _) = forked_test_chain();
+    let blocks = collect_blocks(&chain, main_tip, ExportScope::CanonicalChain, 0..=2);
+    let path = test_file("json-round-trip");
+    write_blocks(&path, ExportFormat::Json, &blocks).unwrap();
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text.contains("\"minter\": \"Alice\""));
+    let read: Vec<TestBlock> = read_blocks(&path, ExportFormat::Json).unwrap();
+    assert_eq!(read, blocks);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn client_8_wrong_format_is_malformed() {
+    let path = test_file("wrong-format");
+    write_blocks::<CountingConsensus, TestCurrency>(&path, ExportFormat::Json, &[]).unwrap();
+
+    let result = read_blocks::<CountingConsensus, TestCurrency>(&path, ExportFormat::Binary);
+    assert!(matches!(result, Err(ImportError::Malformed(_))));
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "malformed chain file: not a binary chain file"
+    );
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(
+        read_blocks::<CountingConsensus, TestCurrency>(&path, ExportFormat::Binary),
+        Err(ImportError::Io(error)) if error.kind() == io::ErrorKind::NotFound
+    ));
+}
+
+#[test]
+fn client_8_import_into_fresh_chain_with_progress() {
+    let (chain, _, _) = forked_test_chain();
+    let blocks = collect_blocks(&chain, 0, ExportScope::AllBlocks, 0..=u64::MAX);
+    let mut fresh = test_client();
+
+    let mut reports = Vec::new();
+    let summary = import_blocks(&mut fresh, blocks, |p| reports.push(p)).unwrap();
+
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 7,
+            already_known: 1
+        }
+    );
+    assert_eq!(reports.len(), 8);
+    assert_eq!(reports.last().unwrap().processed, 8);
+    assert_eq!(reports.last().unwrap().total, 8);
+    assert_eq!(fresh.block_count(), 8);
+}
+
+#[test]
+fn client_8_import_stops_at_invalid_block() {
+    let (chain, main_tip, _) = forked_test_chain();
+    let mut blocks = collect_blocks(&chain, main_tip, ExportScope::CanonicalChain, 0..=u64::MAX);
+    blocks[3].header.extrinsics_root = 666;
+    let bad_hash = hash(&blocks[3].header);
+
+    let mut fresh = test_client();
+    let result = import_blocks(&mut fresh, blocks, |_| ());
+
+    assert!(matches!(
+        result,
+        Err(ImportError::InvalidBlock { hash, height: 3 }) if hash == bad_hash
+    ));
+    assert_eq!(fresh.block_count(), 3);
+}
+
+#[test]
+fn client_8_clients_export_and_import_chains() {
+    let (chain, main_tip, fork_tip) = forked_test_chain();
+    for format in [ExportFormat::Binary, ExportFormat::Json] {
+        let path = test_file("client-export");
+        let written =
+            export_chain(&chain, &path, format, ExportScope::AllBlocks, 0..=u64::MAX).unwrap();
+        assert_eq!(written, 8);
+
+        let mut fresh = test_client();
+        let summary = import_chain(&mut fresh, &path, format, |_| ()).unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                imported: 7,
+                already_known: 1
+            }
+        );
+        assert_eq!(fresh.best_block(), main_tip);
+        assert!(fresh.get_block(fork_tip).is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1043 @@
+//! So far we have always worked with a single client at a time. But a blockchain is a network of
+//! many clients, and many of its most interesting behaviors only appear when those clients have
+//! to communicate over imperfect links. If two authors each create a block before hearing about
+//! the other's, the network forks. If a network is partitioned for a while, each side builds its
+//! own chain, and one side must re-org when the partition heals.
+//!
+//! In this section we build a simulator that hosts many clients in a single process and connects
+//! them with a simulated network. It is a discrete-event simulator: rather than sleeping in real
+//! time, it keeps a queue of future events (messages arriving, blocks being authored) and jumps
+//! straight from one event to the next. All randomness comes from a seeded random number
+//! generator, so every run with the same seed and configuration is exactly reproducible.
+//!
+//! Each link between two nodes has a latency, an optional bandwidth, and a probability of losing
+//! each message. The network may also be partitioned into groups that cannot talk to each other.
+//! Nodes gossip every new block and transaction they learn about to all of their peers.
+//!
+//! The simulator reports some statistics that are interesting to blockchain designers:
+//! * Fork rate - The fraction of authored blocks that did not end up in the canonical chain.
+//! * Re-org depth - How many blocks a node had to retract when it switched to a different fork.
+//! * Time to consensus - How long after the last block was authored it took all nodes to agree.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+use super::{p2_importing_blocks::ImportBlock, Block, Consensus, FullClient, StateMachine};
+use crate::codec::Encode;
+use crate::hash;
+
+type Hash = u64;
+
+/// How many blocks each node keeps while waiting for their parents. Beyond this the oldest are
+/// dropped, and will be requested again if one of their descendants arrives.
+const MAX_ORPHANS: usize = 1024;
+
+/// Simulated time, measured in milliseconds.
+pub type Time = u64;
+
+/// A small, seedable pseudo random number generator (SplitMix64).
+///
+/// It is not remotely suitable for cryptography, but it is fast, has no dependencies, and most
+/// importantly always produces the same sequence for the same seed.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// The next random u64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A random float uniformly distributed in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random index in [0, n).
+    pub fn next_below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// The properties of a one-directional link between two nodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// How long every message takes to cross the link, regardless of its size.
+    pub latency: Time,
+    /// How many bytes the link can transmit per millisecond. Messages queue up behind each other
+    /// while the link is busy. None means the link has unlimited bandwidth.
+    pub bandwidth: Option<u64>,
+    /// The probability, between 0 and 1, that any given message is lost.
+    pub loss: f64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: 100,
+            bandwidth: None,
+            loss: 0.0,
+        }
+    }
+}
+
+/// Everything the simulator needs from a node.
+///
+/// The simulator is written against this trait rather than directly against our `FullClient`,
+/// which lets other kinds of nodes take part too, such as the finality nodes of a later section.
+pub trait SimNode {
+    type Block: Clone;
+    type Transaction: Clone;
+    /// Messages of any finality protocol the node takes part in. Nodes without one use `()`.
+    type Vote: Clone;
+
+    /// The hash that identifies the given block.
+    fn hash_of(block: &Self::Block) -> Hash;
+
+    /// The hash of the given block's parent.
+    fn parent_of(block: &Self::Block) -> Hash;
+
+    /// The size of the block on the wire, in bytes.
+    fn block_size(block: &Self::Block) -> usize;
+
+    /// The size of the transaction on the wire, in bytes.
+    fn transaction_size(t: &Self::Transaction) -> usize;
+
+    /// Try to import a block. Returns whether the import succeeded.
+    fn import_block(&mut self, block: Self::Block) -> bool;
+
+    /// The full block with the given hash, or None if the block is not known.
+    fn get_block(&self, block_hash: Hash) -> Option<Self::Block>;
+
+    /// The height of the given block, or None if the block is not known.
+    fn block_height(&self, block_hash: Hash) -> Option<u64>;
+
+    /// The parent of the given block, or None if the block is not known.
+    fn block_parent(&self, block_hash: Hash) -> Option<Hash>;
+
+    /// The current best block according to the node's fork choice.
+    fn best_block(&self) -> Hash;
+
+    /// Add a transaction to the node's pool. Returns whether it was new to the node.
+    fn submit_transaction(&mut self, t: Self::Transaction) -> bool;
+
+    /// Author and import a new block on top of the node's best block. Nodes that put a
+    /// timestamp in their blocks should use the given time, so that simulations stay
+    /// reproducible.
+    fn author_block(&mut self, now: Time) -> Option<Self::Block>;
+
+    /// The size of the finality message on the wire, in bytes.
+    fn vote_size(_vote: &Self::Vote) -> usize {
+        64
+    }
+
+    /// Handle a finality message from a peer. Returns whether it was new to the node,
+    /// in which case it is gossiped onward.
+    fn import_vote(&mut self, _vote: Self::Vote) -> bool {
+        false
+    }
+
+    /// Any finality messages the node wants to send as a result of what it has seen so far.
+    /// The simulator calls this after every block and finality message the node handles.
+    fn poll_votes(&mut self) -> Vec<Self::Vote> {
+        Vec::new()
+    }
+
+    /// The most recent block the node considers final, if it runs a finality protocol.
+    fn finalized_block(&self) -> Option<Hash> {
+        None
+    }
+
+    /// Mark the given block as final, as decided by a finality protocol running next to the
+    /// node, and store the encoded justification that proves it with the block. Returns whether
+    /// the node accepted the block as final. Nodes that do not track finality ignore this.
+    fn finalize_block(&mut self, _block_hash: Hash, _justification: Vec<u8>) -> bool {
+        false
+    }
+
+    /// The encoded justification stored with the given block, if any.
+    fn justification(&self, _block_hash: Hash) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Our full client can participate in the simulation directly.
+impl<C, SM, FC, P> SimNode for FullClient<C, SM, FC, P>
+where
+    C: Consensus,
+    SM: StateMachine,
+    Block<C, SM>: Clone + Encode,
+    SM::Transition: Clone + Encode,
+{
+    type Block = Block<C, SM>;
+    type Transaction = SM::Transition;
+    type Vote = ();
+
+    fn hash_of(block: &Self::Block) -> Hash {
+        hash(&block.header)
+    }
+
+    fn parent_of(block: &Self::Block) -> Hash {
+        block.header.parent
+    }
+
+    fn block_size(block: &Self::Block) -> usize {
+        block.encoded_size()
+    }
+
+    fn transaction_size(t: &Self::Transaction) -> usize {
+        t.encoded_size()
+    }
+
+    fn import_block(&mut self, block: Self::Block) -> bool {
+        ImportBlock::import_block(self, block)
+    }
+
+    fn get_block(&self, block_hash: Hash) -> Option<Self::Block> {
+        ImportBlock::get_block(self, block_hash)
+    }
+
+    fn block_height(&self, block_hash: Hash) -> Option<u64> {
+        ImportBlock::get_block(self, block_hash).map(|b| b.header.height)
+    }
+
+    fn block_parent(&self, block_hash: Hash) -> Option<Hash> {
+        ImportBlock::get_block(self, block_hash).map(|b| b.header.parent)
+    }
+
+    fn best_block(&self) -> Hash {
+        FullClient::best_block(self)
+    }
+
+    fn submit_transaction(&mut self, t: Self::Transaction) -> bool {
+        if self.pool_contains(t.clone()) {
+            return false;
+        }
+        FullClient::submit_transaction(self, t);
+        true
+    }
+
+    fn author_block(&mut self, now: Time) -> Option<Self::Block> {
+        let block_hash = self.author_and_import_block_at(now)?;
+        ImportBlock::get_block(self, block_hash)
+    }
+
+    fn finalize_block(&mut self, block_hash: Hash, justification: Vec<u8>) -> bool {
+        self.finalize_block_with_justification(block_hash, justification)
+    }
+
+    fn justification(&self, block_hash: Hash) -> Option<Vec<u8>> {
+        FullClient::justification(self, block_hash)
+    }
+}
+
+/// A message sent over the simulated network.
+#[derive(Clone, Debug)]
+enum Message<B, T, V> {
+    Block(B),
+    Transaction(T),
+    Vote(V),
+    /// Ask a peer to send the block with the given hash. Nodes send this when they receive a
+    /// block whose parent they do not know, which lets them catch up after a partition.
+    BlockRequest(Hash),
+}
+
+/// The size of a block request on the wire, in bytes.
+const BLOCK_REQUEST_SIZE: usize = 8;
+
+/// Something that will happen at a particular time in the simulation.
+#[derive(Clone, Debug)]
+enum Event<B, T, V> {
+    /// A message arrives at a node.
+    Deliver {
+        from: usize,
+        to: usize,
+        message: Message<B, T, V>,
+    },
+    /// A specific node authors a block.
+    Author(usize),
+    /// A randomly chosen node authors a block, and the next random authoring is scheduled.
+    RandomAuthor { mean_interval: Time, until: Time },
+    /// A user submits a transaction to a node.
+    Submit(usize, T),
+    /// The network is split into the given groups. Each entry is the group of the node at that index.
+    Partition(Vec<usize>),
+    /// Any partition is removed.
+    Heal,
+}
+
+/// An event along with when it happens. Events at the same time happen in the order they
+/// were scheduled.
+struct Scheduled<B, T, V> {
+    time: Time,
+    sequence: u64,
+    event: Event<B, T, V>,
+}
+
+impl<B, T, V> PartialEq for Scheduled<B, T, V> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.sequence) == (other.time, other.sequence)
+    }
+}
+
+impl<B, T, V> Eq for Scheduled<B, T, V> {}
+
+impl<B, T, V> PartialOrd for Scheduled<B, T, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<B, T, V> Ord for Scheduled<B, T, V> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.time, self.sequence).cmp(&(other.time, other.sequence))
+    }
+}
+
+/// The events waiting to happen in a simulation of the given kind of node, earliest first.
+type EventQueue<N> = BinaryHeap<
+    Reverse<Scheduled<<N as SimNode>::Block, <N as SimNode>::Transaction, <N as SimNode>::Vote>>,
+>;
+
+/// The statistics gathered during a simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationReport {
+    /// The total number of blocks authored by all nodes.
+    pub blocks_authored: usize,
+    /// The number of authored blocks that are part of the canonical chain at the end.
+    pub canonical_blocks: usize,
+    /// The fraction of authored blocks that are not in the canonical chain.
+    pub fork_rate: f64,
+    /// The number of times any node switched its best block to a different fork.
+    pub reorgs: usize,
+    /// The largest number of blocks retracted in a single re-org.
+    pub max_reorg_depth: u64,
+    /// How long after the last block was authored all nodes agreed on the same best block.
+    /// None if the nodes do not agree at the end of the simulation.
+    pub time_to_consensus: Option<Time>,
+    /// The average time for an authored block to reach every node, for those blocks that did.
+    pub mean_propagation_time: Option<f64>,
+    /// The number of messages that were sent.
+    pub messages_sent: usize,
+    /// The number of messages that were lost or blocked by a partition.
+    pub messages_dropped: usize,
+    /// The lowest height any node has finalized. Zero for nodes without a finality protocol.
+    pub finalized_height: u64,
+}
+
+/// A simulated network of nodes.
+pub struct NetworkSimulator<N: SimNode> {
+    /// The nodes participating in the network.
+    nodes: Vec<N>,
+    /// The link used between nodes that do not have a specific link configured.
+    default_link: LinkConfig,
+    /// Links that have been specifically configured, keyed by (from, to).
+    links: HashMap<(usize, usize), LinkConfig>,
+    /// The time until which each link is busy transmitting earlier messages.
+    link_busy_until: HashMap<(usize, usize), Time>,
+    /// The partition group of each node, if the network is partitioned.
+    partition: Option<Vec<usize>>,
+    /// Blocks each node has received before their parents.
+    orphans: Vec<Vec<N::Block>>,
+    rng: Rng,
+    now: Time,
+    next_sequence: u64,
+    queue: EventQueue<N>,
+
+    /// When each block was authored.
+    authored: HashMap<Hash, Time>,
+    /// The time of the most recently authored block.
+    last_authored: Option<Time>,
+    /// When each node imported each block.
+    imported_at: HashMap<Hash, Vec<Option<Time>>>,
+    /// The best block of each node as of the last time we checked.
+    last_best: Vec<Hash>,
+    /// The time since which all nodes have agreed on the best block.
+    agreement_since: Option<Time>,
+    reorgs: usize,
+    max_reorg_depth: u64,
+    messages_sent: usize,
+    messages_dropped: usize,
+}
+
+impl<N: SimNode> NetworkSimulator<N> {
+    /// Create a new simulation with the given nodes, all connected with the default link.
+    pub fn new(nodes: Vec<N>, default_link: LinkConfig, seed: u64) -> Self {
+        let last_best: Vec<Hash> = nodes.iter().map(SimNode::best_block).collect();
+        let agreement_since = last_best.windows(2).all(|w| w[0] == w[1]).then_some(0);
+        Self {
+            orphans: vec![Vec::new(); nodes.len()],
+            nodes,
+            default_link,
+            links: HashMap::new(),
+            link_busy_until: HashMap::new(),
+            partition: None,
+            rng: Rng::new(seed),
+            now: 0,
+            next_sequence: 0,
+            queue: BinaryHeap::new(),
+            authored: HashMap::new(),
+            last_authored: None,
+            imported_at: HashMap::new(),
+            last_best,
+            agreement_since,
+            reorgs: 0,
+            max_reorg_depth: 0,
+            messages_sent: 0,
+            messages_dropped: 0,
+        }
+    }
+
+    /// The current simulated time.
+    pub fn now(&self) -> Time {
+        self.now
+    }
+
+    /// Access one of the nodes.
+    pub fn node(&self, index: usize) -> &N {
+        &self.nodes[index]
+    }
+
+    /// The number of nodes in the simulation.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Configure the link in one direction between two nodes.
+    pub fn set_link(&mut self, from: usize, to: usize, link: LinkConfig) {
+        self.links.insert((from, to), link);
+    }
+
+    /// Configure the links in both directions between two nodes.
+    pub fn set_link_symmetric(&mut self, a: usize, b: usize, link: LinkConfig) {
+        self.set_link(a, b, link);
+        self.set_link(b, a, link);
+    }
+
+    /// Split the network into the given groups at the given time. Nodes not listed in any group
+    /// form one additional group of their own.
+    pub fn partition_at(&mut self, time: Time, groups: &[Vec<usize>]) {
+        let mut assignment = vec![groups.len(); self.nodes.len()];
+        for (group, members) in groups.iter().enumerate() {
+            for member in members {
+                assignment[*member] = group;
+            }
+        }
+        self.schedule(time, Event::Partition(assignment));
+    }
+
+    /// Remove any partition at the given time.
+    pub fn heal_at(&mut self, time: Time) {
+        self.schedule(time, Event::Heal);
+    }
+
+    /// Have the given node author a block at the given time.
+    pub fn author_at(&mut self, time: Time, node: usize) {
+        self.schedule(time, Event::Author(node));
+    }
+
+    /// Have randomly chosen nodes author blocks from the given time until the given time.
+    /// The intervals between blocks are exponentially distributed with the given mean,
+    /// much like they are in Proof of Work.
+    pub fn author_randomly(&mut self, from: Time, until: Time, mean_interval: Time) {
+        self.schedule(
+            from,
+            Event::RandomAuthor {
+                mean_interval,
+                until,
+            },
+        );
+    }
+
+    /// Submit a transaction to the given node at the given time.
+    pub fn submit_at(&mut self, time: Time, node: usize, t: N::Transaction) {
+        self.schedule(time, Event::Submit(node, t));
+    }
+
+    /// Process events in order until there are none left before the given time.
+    pub fn run_until(&mut self, time: Time) {
+        while let Some(Reverse(next)) = self.queue.peek() {
+            if next.time > time {
+                break;
+            }
+            let Reverse(next) = self.queue.pop().expect("we just peeked it");
+            self.now = next.time;
+            self.handle(next.event);
+        }
+        self.now = self.now.max(time);
+    }
+
+    /// Summarize what happened during the simulation so far.
+    pub fn report(&self) -> SimulationReport {
+        // The canonical chain is the chain of whichever best block the most nodes agree on.
+        let mut votes: Vec<(Hash, usize)> = Vec::new();
+        for best in &self.last_best {
+            match votes.iter_mut().find(|(h, _)| h == best) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((*best, 1)),
+            }
+        }
+        let (canonical_tip, _) = votes
+            .iter()
+            .copied()
+            .max_by_key(|(_, count)| *count)
+            .expect("there is at least one node");
+        let voter = self
+            .last_best
+            .iter()
+            .position(|b| *b == canonical_tip)
+            .expect("the tip came from some node");
+
+        let mut canonical_blocks = 0;
+        let mut current = Some(canonical_tip);
+        while let Some(block) = current {
+            if self.authored.contains_key(&block) {
+                canonical_blocks += 1;
+            }
+            current = self.nodes[voter].block_parent(block);
+        }
+
+        let blocks_authored = self.authored.len();
+        let fork_rate = if blocks_authored == 0 {
+            0.0
+        } else {
+            (blocks_authored - canonical_blocks) as f64 / blocks_authored as f64
+        };
+
+        let propagation_times: Vec<Time> = self
+            .authored
+            .iter()
+            .filter_map(|(block, authored)| {
+                let imports = self.imported_at.get(block)?;
+                let last = imports.iter().copied().collect::<Option<Vec<_>>>()?;
+                Some(last.into_iter().max().unwrap_or(*authored) - authored)
+            })
+            .collect();
+        let mean_propagation_time = (!propagation_times.is_empty()).then(|| {
+            propagation_times.iter().sum::<Time>() as f64 / propagation_times.len() as f64
+        });
+
+        let time_to_consensus = self
+            .agreement_since
+            .map(|since| since.saturating_sub(self.last_authored.unwrap_or(0)));
+
+        let finalized_height = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.finalized_block()
+                    .and_then(|finalized| node.block_height(finalized))
+                    .unwrap_or(0)
+            })
+            .min()
+            .unwrap_or(0);
+
+        SimulationReport {
+            blocks_authored,
+            canonical_blocks,
+            fork_rate,
+            reorgs: self.reorgs,
+            max_reorg_depth: self.max_reorg_depth,
+            time_to_consensus,
+            mean_propagation_time,
+            messages_sent: self.messages_sent,
+            messages_dropped: self.messages_dropped,
+            finalized_height,
+        }
+    }
+
+    fn schedule(&mut self, time: Time, event: Event<N::Block, N::Transaction, N::Vote>) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.queue.push(Reverse(Scheduled {
+            time,
+            sequence,
+            event,
+        }));
+    }
+
+    fn handle(&mut self, event: Event<N::Block, N::Transaction, N::Vote>) {
+        match event {
+            Event::Deliver { from, to, message } => match message {
+                Message::Block(block) => self.receive_block(to, from, block),
+                Message::Transaction(t) => {
+                    if self.nodes[to].submit_transaction(t.clone()) {
+                        self.gossip(to, Some(from), Message::Transaction(t));
+                    }
+                }
+                Message::BlockRequest(block_hash) => {
+                    if let Some(block) = self.nodes[to].get_block(block_hash) {
+                        self.send(to, from, Message::Block(block));
+                    }
+                }
+                Message::Vote(vote) => {
+                    if self.nodes[to].import_vote(vote.clone()) {
+                        self.gossip(to, Some(from), Message::Vote(vote));
+                        self.flush_votes(to);
+                    }
+                }
+            },
+            Event::Author(node) => self.author(node),
+            Event::RandomAuthor {
+                mean_interval,
+                until,
+            } => {
+                let node = self.rng.next_below(self.nodes.len());
+                self.author(node);
+                // Sample an exponentially distributed delay until the next block.
+                let uniform = 1.0 - self.rng.next_f64();
+                let delay = (-(mean_interval as f64) * uniform.ln()).round() as Time;
+                let next = self.now + delay.max(1);
+                if next <= until {
+                    self.schedule(
+                        next,
+                        Event::RandomAuthor {
+                            mean_interval,
+                            until,
+                        },
+                    );
+                }
+            }
+            Event::Submit(node, t) => {
+                if self.nodes[node].submit_transaction(t.clone()) {
+                    self.gossip(node, None, Message::Transaction(t));
+                }
+            }
+            Event::Partition(assignment) => self.partition = Some(assignment),
+            Event::Heal => self.partition = None,
+        }
+    }
+
+    fn author(&mut self, node: usize) {
+        let Some(block) = self.nodes[node].author_block(self.now) else {
+            return;
+        };
+        let block_hash = N::hash_of(&block);
+        self.authored.insert(block_hash, self.now);
+        self.last_authored = Some(self.now);
+        self.record_import(node, block_hash);
+        self.gossip(node, None, Message::Block(block));
+        self.check_best(node);
+        self.flush_votes(node);
+    }
+
+    fn receive_block(&mut self, node: usize, from: usize, block: N::Block) {
+        let block_hash = N::hash_of(&block);
+        if self.nodes[node].block_height(block_hash).is_some() {
+            return;
+        }
+        let parent = N::parent_of(&block);
+        if self.nodes[node].block_height(parent).is_none() {
+            if !self.orphans[node]
+                .iter()
+                .any(|o| N::hash_of(o) == block_hash)
+            {
+                if self.orphans[node].len() == MAX_ORPHANS {
+                    self.orphans[node].remove(0);
+                }
+                self.orphans[node].push(block);
+                self.send(node, from, Message::BlockRequest(parent));
+            }
+            return;
+        }
+        if !self.nodes[node].import_block(block.clone()) {
+            return;
+        }
+        self.record_import(node, block_hash);
+        self.gossip(node, Some(from), Message::Block(block));
+
+        // Any orphans waiting for this block can now be imported too.
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.orphans[node])
+            .into_iter()
+            .partition(|orphan| N::parent_of(orphan) == block_hash);
+        self.orphans[node] = waiting;
+        for orphan in ready {
+            self.receive_block(node, from, orphan);
+        }
+
+        self.check_best(node);
+        self.flush_votes(node);
+    }
+
+    /// Gossip any finality messages the node has produced.
+    fn flush_votes(&mut self, node: usize) {
+        for vote in self.nodes[node].poll_votes() {
+            self.gossip(node, None, Message::Vote(vote));
+        }
+    }
+
+    fn record_import(&mut self, node: usize, block_hash: Hash) {
+        let node_count = self.nodes.len();
+        self.imported_at
+            .entry(block_hash)
+            .or_insert_with(|| vec![None; node_count])[node]
+            .get_or_insert(self.now);
+    }
+
+    /// Send a message from a node to all of its peers except the one it came from.
+    fn gossip(
+        &mut self,
+        from: usize,
+        except: Option<usize>,
+        message: Message<N::Block, N::Transaction, N::Vote>,
+    ) {
+        for to in 0..self.nodes.len() {
+            if to != from && Some(to) != except {
+                self.send(from, to, message.clone());
+            }
+        }
+    }
+
+    /// Send a message over the link from one node to another, subject to partitions, loss,
+    /// bandwidth and latency.
+    fn send(
+        &mut self,
+        from: usize,
+        to: usize,
+        message: Message<N::Block, N::Transaction, N::Vote>,
+    ) {
+        self.messages_sent += 1;
+
+        let partitioned = self
+            .partition
+            .as_ref()
+            .is_some_and(|groups| groups[from] != groups[to]);
+        let link = self
+            .links
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(self.default_link);
+        if partitioned || self.rng.next_f64() < link.loss {
+            self.messages_dropped += 1;
+            return;
+        }
+
+        let size = match &message {
+            Message::Block(block) => N::block_size(block),
+            Message::Transaction(t) => N::transaction_size(t),
+            Message::BlockRequest(_) => BLOCK_REQUEST_SIZE,
+            Message::Vote(vote) => N::vote_size(vote),
+        };
+        let busy_until = self.link_busy_until.entry((from, to)).or_insert(0);
+        let start = self.now.max(*busy_until);
+        let transmission = link
+            .bandwidth
+            .map(|bandwidth| (size as u64).div_ceil(bandwidth.max(1)))
+            .unwrap_or(0);
+        *busy_until = start + transmission;
+        self.schedule(
+            start + transmission + link.latency,
+            Event::Deliver { from, to, message },
+        );
+    }
+
+    /// Check whether the node's best block changed, and if so whether that was a re-org.
+    fn check_best(&mut self, node: usize) {
+        let old = self.last_best[node];
+        let new = self.nodes[node].best_block();
+        if old == new {
+            return;
+        }
+        self.last_best[node] = new;
+
+        if let Some(ancestor) = self.common_ancestor(node, old, new) {
+            if ancestor != old {
+                let old_height = self.nodes[node].block_height(old).unwrap_or(0);
+                let ancestor_height = self.nodes[node].block_height(ancestor).unwrap_or(0);
+                self.reorgs += 1;
+                self.max_reorg_depth = self.max_reorg_depth.max(old_height - ancestor_height);
+            }
+        }
+
+        let agreed = self.last_best.windows(2).all(|w| w[0] == w[1]);
+        self.agreement_since = match (agreed, self.agreement_since) {
+            (true, Some(since)) => Some(since),
+            (true, None) => Some(self.now),
+            (false, _) => None,
+        };
+    }
+
+    /// Find the most recent block that is an ancestor of both given blocks, as known by the node.
+    fn common_ancestor(&self, node: usize, mut a: Hash, mut b: Hash) -> Option<Hash> {
+        let node = &self.nodes[node];
+        let mut height_a = node.block_height(a)?;
+        let mut height_b = node.block_height(b)?;
+        while a != b {
+            if height_a >= height_b {
+                a = node.block_parent(a)?;
+                height_a = node.block_height(a)?;
+            } else {
+                b = node.block_parent(b)?;
+                height_b = node.block_height(b)?;
+            }
+        }
+        Some(a)
+    }
+}
+
+#[cfg(test)]
+use super::test_support::{test_chain, test_client, TestClient};
+#[cfg(test)]
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, User};
+
+#[cfg(test)]
+fn test_network(n: usize) -> Vec<TestClient> {
+    (0..n).map(|_| test_client()).collect()
+}
+
+#[cfg(test)]
+fn mint(amount: u64) -> AccountingTransaction {
+    AccountingTransaction::Mint {
+        minter: User::Alice,
+        amount,
+    }
+}
+
+#[test]
+fn client_9_rng_is_deterministic() {
+    let mut a = Rng::new(42);
+    let mut b = Rng::new(42);
+    let mut c = Rng::new(43);
+    let xs: Vec<u64> = (0..10).map(|_| a.next_u64()).collect();
+    let ys: Vec<u64> = (0..10).map(|_| b.next_u64()).collect();
+    let zs: Vec<u64> = (0..10).map(|_| c.next_u64()).collect();
+
+    assert_eq!(xs, ys);
+    assert_ne!(xs, zs);
+    assert!((0..1000).all(|_| (0.0..1.0).contains(&a.next_f64())));
+}
+
+#[test]
+fn client_9_block_propagates_to_all_nodes() {
+    let mut sim = NetworkSimulator::new(test_network(4), LinkConfig::default(), 0);
+    sim.author_at(10, 0);
+    sim.run_until(1_000);
+
+    let best = sim.node(0).best_block();
+    assert!((0..4).all(|i| sim.node(i).best_block() == best));
+
+    let report = sim.report();
+    assert_eq!(report.blocks_authored, 1);
+    assert_eq!(report.canonical_blocks, 1);
+    assert_eq!(report.fork_rate, 0.0);
+    assert_eq!(report.time_to_consensus, Some(100));
+    assert_eq!(report.mean_propagation_time, Some(100.0));
+}
+
+#[test]
+fn client_9_latency_delays_delivery() {
+    let mut sim = NetworkSimulator::new(test_network(2), LinkConfig::default(), 0);
+    sim.set_link(
+        0,
+        1,
+        LinkConfig {
+            latency: 500,
+            ..Default::default()
+        },
+    );
+    sim.author_at(0, 0);
+
+    sim.run_until(499);
+    assert_ne!(sim.node(1).best_block(), sim.node(0).best_block());
+    sim.run_until(500);
+    assert_eq!(sim.node(1).best_block(), sim.node(0).best_block());
+}
+
+#[test]
+fn client_9_bandwidth_queues_messages() {
+    let link = LinkConfig {
+        latency: 0,
+        bandwidth: Some(1),
+        loss: 0.0,
+    };
+    let mut sim = NetworkSimulator::new(test_network(2), link, 0);
+    let genesis = super::ClientApi::genesis(sim.node(0));
+    let size = TestClient::block_size(&SimNode::get_block(sim.node(0), genesis).unwrap()) as Time;
+    sim.author_at(0, 0);
+    sim.author_at(0, 0);
+
+    // Each empty block takes as many ms as it has bytes, and the second one waits for the first.
+    sim.run_until(2 * size - 1);
+    assert_eq!(sim.node(1).block_count(), 2);
+    sim.run_until(2 * size);
+    assert_eq!(sim.node(1).block_count(), 3);
+}
+
+#[test]
+fn client_9_lost_messages_are_counted() {
+    let link = LinkConfig {
+        loss: 1.0,
+        ..Default::default()
+    };
+    let mut sim = NetworkSimulator::new(test_network(3), link, 0);
+    sim.author_at(0, 0);
+    sim.run_until(1_000);
+
+    let report = sim.report();
+    assert_eq!(report.messages_sent, 2);
+    assert_eq!(report.messages_dropped, 2);
+    assert_eq!(report.time_to_consensus, None);
+}
+
+#[test]
+fn client_9_transactions_are_gossiped() {
+    let mut sim = NetworkSimulator::new(test_network(3), LinkConfig::default(), 0);
+    sim.submit_at(0, 2, mint(77));
+    sim.run_until(500);
+    assert!((0..3).all(|i| sim.node(i).pool_contains(mint(77))));
+
+    sim.author_at(600, 1);
+    sim.run_until(1_000);
+    assert!((0..3).all(|i| sim.node(i).pool_size() == 0));
+    let best = sim.node(0).best_block();
+    assert_eq!(
+        SimNode::get_block(sim.node(0), best).unwrap().body,
+        vec![mint(77)]
+    );
+}
+
+#[test]
+fn client_9_orphans_are_imported_when_parent_arrives() {
+    let mut sim = NetworkSimulator::new(test_network(3), LinkConfig::default(), 0);
+    // Node 0's blocks reach node 2 slowly, but node 1's blocks arrive quickly.
+    sim.set_link(
+        0,
+        2,
+        LinkConfig {
+            latency: 1_000,
+            ..Default::default()
+        },
+    );
+    sim.set_link(
+        1,
+        2,
+        LinkConfig {
+            latency: 10,
+            ..Default::default()
+        },
+    );
+    sim.set_link(
+        0,
+        1,
+        LinkConfig {
+            latency: 10,
+            ..Default::default()
+        },
+    );
+    sim.author_at(0, 0);
+    sim.author_at(20, 1);
+    sim.run_until(100);
+
+    // Node 1 relayed node 0's block to node 2, so everyone is in sync well before 1000ms.
+    let best = sim.node(1).best_block();
+    assert_eq!(sim.node(2).best_block(), best);
+    assert!(sim.orphans[2].is_empty());
+}
+
+#[test]
+fn client_9_partition_causes_fork_and_reorg() {
+    let mut sim = NetworkSimulator::new(test_network(4), LinkConfig::default(), 0);
+    sim.partition_at(0, &[vec![0, 1], vec![2, 3]]);
+    // The second side has a transaction the first side never sees, so the two sides build
+    // different blocks. The first side builds three blocks while the second side builds two.
+    sim.submit_at(0, 2, mint(1));
+    for (i, t) in [100, 300, 500].into_iter().enumerate() {
+        sim.author_at(t, i % 2);
+    }
+    for t in [200, 400] {
+        sim.author_at(t, 2);
+    }
+    sim.run_until(1_000);
+    assert_ne!(sim.node(0).best_block(), sim.node(2).best_block());
+
+    // After healing, a new block from the longer side brings everyone together.
+    sim.heal_at(1_000);
+    sim.author_at(1_100, 0);
+    sim.run_until(2_000);
+    let report = sim.report();
+
+    assert!((0..4).all(|i| sim.node(i).best_block() == sim.node(0).best_block()));
+    assert_eq!(report.blocks_authored, 6);
+    assert_eq!(report.canonical_blocks, 4);
+    assert!((report.fork_rate - 2.0 / 6.0).abs() < 1e-9);
+    assert_eq!(report.reorgs, 2);
+    assert_eq!(report.max_reorg_depth, 2);
+    // The new block takes 100ms to arrive, then the second side fetches its three unknown
+    // ancestors one round trip at a time.
+    assert_eq!(report.time_to_consensus, Some(700));
+}
+
+#[test]
+fn client_9_random_authoring_is_reproducible() {
+    let run = |seed| {
+        let link = LinkConfig {
+            latency: 400,
+            bandwidth: Some(10),
+            loss: 0.05,
+        };
+        let mut sim = NetworkSimulator::new(test_network(6), link, seed);
+        // Each node is given its own transactions, so that blocks authored on the same parent
+        // differ.
+        for (i, time) in (0..20_000).step_by(100).enumerate() {
+            sim.submit_at(time, i % 6, mint(i as u64));
+        }
+        sim.author_randomly(0, 20_000, 500);
+        sim.run_until(30_000);
+        sim.report()
+    };
+
+    let report = run(7);
+    assert_eq!(report, run(7));
+    assert!(report.blocks_authored > 10);
+    // Latency comparable to the block time causes some forks.
+    assert!(report.fork_rate > 0.0);
+    assert!(report.canonical_blocks < report.blocks_authored);
+}
+
+#[test]
+fn client_9_full_clients_author_from_their_pools() {
+    let mut sim = NetworkSimulator::new(test_network(3), LinkConfig::default(), 0);
+    let slow = LinkConfig {
+        latency: 300,
+        ..Default::default()
+    };
+    sim.set_link_symmetric(0, 2, slow);
+    sim.submit_at(0, 2, mint(10));
+    sim.author_at(500, 1);
+    sim.run_until(1_000);
+    assert_eq!(sim.now(), 1_000);
+
+    // The block authored by node 1 includes the gossiped transaction and the simulated time.
+    let best = sim.node(1).best_block();
+    let block = SimNode::get_block(sim.node(1), best).unwrap();
+    assert_eq!(block.body, vec![mint(10)]);
+    assert_eq!(block.header.timestamp, 500);
+    assert!((0..3).all(|i| sim.node(i).best_block() == best));
+    assert!((0..3).all(|i| sim.node(i).pool_size() == 0));
+}
+
+#[test]
+fn client_9_orphan_buffer_is_capped() {
+    let mut sim = NetworkSimulator::new(test_network(2), LinkConfig::default(), 0);
+    let block = test_chain(&[], 1, 0).pop().unwrap();
+    // A peer floods node 1 with blocks whose parents nobody has.
+    for parent in 0..MAX_ORPHANS as u64 + 10 {
+        let mut orphan = block.clone();
+        orphan.header.parent = parent;
+        sim.receive_block(1, 0, orphan);
+    }
+
+    assert_eq!(sim.orphans[1].len(), MAX_ORPHANS);
+    // The oldest orphans made way for the newest.
+    assert_eq!(sim.orphans[1][0].header.parent, 10);
+}
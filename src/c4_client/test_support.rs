@@ -0,0 +1,710 @@
+//! Fixtures shared by the tests throughout this chapter.
+//!
+//! Most tests need a client that really imports blocks. Our `FullClient` only does that once you
+//! have solved the exercises in the first few sections, so the tests of the later sections use the
+//! `FakeClient` below instead. They only talk to it through `ClientApi`, just like the code they
+//! test talks to whatever client it is given.
+
+use std::collections::HashMap;
+
+use super::p2_importing_blocks::ImportBlock;
+use super::p6_finality::{check_finality, descends_from, non_canonical_blocks};
+use super::p9_network_simulator::{SimNode, Time};
+use super::{Block, ClientApi, Consensus, ForkChoice, Header, StateMachine};
+use crate::c1_state_machine::p8_transaction_validity::{
+    InvalidTransaction, TransactionValidity, ValidTransaction, ValidateTransaction,
+};
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, User};
+use crate::c2_blockchain::{
+    p7_merkle_tree::merkle_root,
+    p8_state_trie::{balance_of, set_balance, StateTrie},
+};
+use crate::codec::{Decode, DecodeError, Encode};
+use crate::hash;
+
+type Hash = u64;
+
+/// A consensus engine for testing, whose digests simply count up from the genesis block.
+/// This way headers are only valid in the context of their ancestors.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CountingConsensus;
+
+impl Consensus for CountingConsensus {
+    type Digest = u64;
+
+    fn validate(&self, parent_digest: &u64, header: &Header<u64>) -> bool {
+        header.consensus_digest == parent_digest + 1
+    }
+
+    fn seal(&self, parent_digest: &u64, partial_header: Header<()>) -> Option<Header<u64>> {
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: parent_digest + 1,
+        })
+    }
+
+    fn verify_sub_chain(&self, parent_digest: &u64, chain: &[Header<u64>]) -> bool {
+        let mut parent_digest = *parent_digest;
+        for header in chain {
+            if !self.validate(&parent_digest, header) {
+                return false;
+            }
+            parent_digest = header.consensus_digest;
+        }
+        true
+    }
+}
+
+/// A stand-in for `TrieAccountedCurrency` that does not wait for your accounted currency from the
+/// State Machine chapter, so that this chapter's tests can run before those exercises are done. It
+/// stores balances in a state trie in the same way, so balance proofs work on its states too.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TestCurrency;
+
+impl StateMachine for TestCurrency {
+    type State = StateTrie;
+    type Transition = AccountingTransaction;
+
+    fn next_state(starting_state: &StateTrie, t: &AccountingTransaction) -> StateTrie {
+        let mut trie = starting_state.clone();
+        match *t {
+            AccountingTransaction::Mint { minter, amount } => {
+                let balance = balance_of(&trie, minter).saturating_add(amount);
+                set_balance(&mut trie, minter, balance);
+            }
+            AccountingTransaction::Burn { burner, amount } => {
+                let balance = balance_of(&trie, burner).saturating_sub(amount);
+                set_balance(&mut trie, burner, balance);
+            }
+            AccountingTransaction::Transfer {
+                sender,
+                receiver,
+                amount,
+            } => {
+                let sender_balance = balance_of(&trie, sender);
+                if sender != receiver && sender_balance >= amount {
+                    set_balance(&mut trie, sender, sender_balance - amount);
+                    let receiver_balance = balance_of(&trie, receiver).saturating_add(amount);
+                    set_balance(&mut trie, receiver, receiver_balance);
+                }
+            }
+        }
+        trie
+    }
+}
+
+impl ValidateTransaction for TestCurrency {
+    fn validate_transaction(state: &StateTrie, t: &AccountingTransaction) -> TransactionValidity {
+        match *t {
+            AccountingTransaction::Mint { amount: 0, .. }
+            | AccountingTransaction::Burn { amount: 0, .. }
+            | AccountingTransaction::Transfer { amount: 0, .. } => {
+                Err(InvalidTransaction::BadTransaction)
+            }
+            AccountingTransaction::Transfer {
+                sender, receiver, ..
+            } if sender == receiver => Err(InvalidTransaction::BadTransaction),
+            AccountingTransaction::Mint { .. } => Ok(ValidTransaction::default()),
+            AccountingTransaction::Burn { burner, .. } if balance_of(state, burner) > 0 => {
+                Ok(ValidTransaction::default())
+            }
+            AccountingTransaction::Transfer { sender, amount, .. }
+                if balance_of(state, sender) >= amount =>
+            {
+                Ok(ValidTransaction::default())
+            }
+            _ => Err(InvalidTransaction::InsufficientFunds),
+        }
+    }
+}
+
+/// A longest chain fork choice for testing, which returns the highest descendant of the given
+/// header, breaking ties by the lowest hash.
+pub(crate) struct TestLongestChain<D> {
+    headers: HashMap<Hash, Header<D>>,
+}
+
+impl<D> Default for TestLongestChain<D> {
+    fn default() -> Self {
+        Self {
+            headers: HashMap::new(),
+        }
+    }
+}
+
+impl<C: Consensus> ForkChoice<C> for TestLongestChain<C::Digest> {
+    fn best_block(&self, root: Header<C::Digest>) -> Option<u64> {
+        let root = hash(&root);
+        let descends = |mut h: Hash| loop {
+            if h == root {
+                return true;
+            }
+            match self.headers.get(&h) {
+                Some(header) if header.height > 0 => h = header.parent,
+                _ => return false,
+            }
+        };
+        self.headers
+            .iter()
+            .filter(|(h, _)| descends(**h))
+            .max_by_key(|(h, header)| (header.height, std::cmp::Reverse(**h)))
+            .map(|(h, _)| *h)
+    }
+
+    fn import_hook(&mut self, header: Header<C::Digest>) {
+        self.headers.insert(hash(&header), header);
+    }
+}
+
+/// A client for testing the sections that build on `FullClient`, before its exercises are done.
+///
+/// It is deliberately naive, and is not how `FullClient` should be written. Every block is kept
+/// next to its state in a single map, and anything else it needs is found by scanning that map.
+/// There is no fork choice rule: the best block is simply the highest block on the finalized
+/// chain. The transaction pool is a plain list.
+pub(crate) struct FakeClient<C: Consensus, SM: StateMachine> {
+    engine: C,
+    state_root: fn(&SM::State) -> Hash,
+    blocks: HashMap<Hash, (Block<C, SM>, SM::State)>,
+    /// The encoded justifications of blocks finalized by a finality gadget.
+    justifications: HashMap<Hash, Vec<u8>>,
+    genesis: Hash,
+    finalized: Hash,
+    pool: Vec<SM::Transition>,
+}
+
+impl<C: Consensus, SM: StateMachine> FakeClient<C, SM> {
+    /// A client whose genesis block has the given state and consensus digest, and no extrinsics.
+    pub(crate) fn new(
+        engine: C,
+        genesis_state: SM::State,
+        genesis_digest: C::Digest,
+        state_root: fn(&SM::State) -> Hash,
+    ) -> Self
+    where
+        SM::Transition: std::hash::Hash,
+    {
+        let genesis = Block {
+            header: Header {
+                parent: 0,
+                height: 0,
+                state_root: state_root(&genesis_state),
+                extrinsics_root: merkle_root::<SM::Transition>(&[]),
+                timestamp: 0,
+                consensus_digest: genesis_digest,
+            },
+            body: Vec::new(),
+        };
+        Self::starting_at(engine, genesis, genesis_state, state_root)
+    }
+
+    /// A client whose first block is the given block with the given state. It is final from the
+    /// start.
+    pub(crate) fn starting_at(
+        engine: C,
+        block: Block<C, SM>,
+        state: SM::State,
+        state_root: fn(&SM::State) -> Hash,
+    ) -> Self {
+        let block_hash = hash(&block.header);
+        Self {
+            engine,
+            state_root,
+            blocks: HashMap::from([(block_hash, (block, state))]),
+            justifications: HashMap::new(),
+            genesis: block_hash,
+            finalized: block_hash,
+            pool: Vec::new(),
+        }
+    }
+
+    /// The parent and height of a known block.
+    fn lookup(&self, block_hash: Hash) -> Option<(Hash, u64)> {
+        self.blocks
+            .get(&block_hash)
+            .map(|(b, _)| (b.header.parent, b.header.height))
+    }
+
+    /// Finalize a block, and drop the forks that branched off below it.
+    fn finalize(&mut self, block_hash: Hash) -> bool {
+        if check_finality(|h| self.lookup(h), self.finalized, block_hash).is_err() {
+            return false;
+        }
+        self.finalized = block_hash;
+        let dead = non_canonical_blocks(
+            self.blocks.keys().copied(),
+            |h| self.lookup(h),
+            self.finalized,
+        );
+        for block_hash in dead {
+            self.blocks.remove(&block_hash);
+            self.justifications.remove(&block_hash);
+        }
+        true
+    }
+
+    /// The number of blocks the client has.
+    pub(crate) fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub(crate) fn pool_size(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub(crate) fn pool_contains(&self, t: SM::Transition) -> bool
+    where
+        SM::Transition: PartialEq,
+    {
+        self.pool.contains(&t)
+    }
+}
+
+impl<C, SM> FakeClient<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    SM::State: Clone,
+    SM::Transition: std::hash::Hash + PartialEq,
+{
+    /// Import a block, executing its body unless the state is given.
+    fn import(&mut self, block: Block<C, SM>, state: Option<SM::State>) -> bool {
+        let block_hash = hash(&block.header);
+        if self.blocks.contains_key(&block_hash) {
+            return true;
+        }
+        if check_finality(|h| self.lookup(h), self.finalized, block.header.parent).is_err() {
+            return false;
+        }
+        let (parent, parent_state) = &self.blocks[&block.header.parent];
+        if block.header.height != parent.header.height + 1
+            || block.header.extrinsics_root != merkle_root(&block.body)
+            || !self
+                .engine
+                .validate(&parent.header.consensus_digest, &block.header)
+        {
+            return false;
+        }
+        let state = state.unwrap_or_else(|| {
+            block
+                .body
+                .iter()
+                .fold(parent_state.clone(), |state, t| SM::next_state(&state, t))
+        });
+        if (self.state_root)(&state) != block.header.state_root {
+            return false;
+        }
+        self.pool.retain(|t| !block.body.contains(t));
+        let is_final = self.engine.is_final(&block.header);
+        self.blocks.insert(block_hash, (block, state));
+        if is_final {
+            self.finalize(block_hash);
+        }
+        true
+    }
+
+    /// Author a block with every pooled transaction on top of the best block, and import it.
+    /// If that fails, the transactions stay in the pool.
+    fn author(&mut self, timestamp: u64) -> Option<Block<C, SM>>
+    where
+        Block<C, SM>: Clone,
+    {
+        let best = ClientApi::best_block(self);
+        let body = std::mem::take(&mut self.pool);
+        let (parent, parent_state) = &self.blocks[&best];
+        let state = body
+            .iter()
+            .fold(parent_state.clone(), |state, t| SM::next_state(&state, t));
+        let partial_header = Header {
+            parent: best,
+            height: parent.header.height + 1,
+            state_root: (self.state_root)(&state),
+            extrinsics_root: merkle_root(&body),
+            timestamp,
+            consensus_digest: (),
+        };
+        let Some(header) = self
+            .engine
+            .seal(&parent.header.consensus_digest, partial_header)
+        else {
+            self.pool = body;
+            return None;
+        };
+        let block = Block { header, body };
+        if !self.import(block.clone(), Some(state)) {
+            self.pool = block.body;
+            return None;
+        }
+        Some(block)
+    }
+}
+
+impl<C, SM> ImportBlock<C, SM> for FakeClient<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    SM::State: Clone,
+    SM::Transition: std::hash::Hash + PartialEq,
+    Block<C, SM>: Clone,
+{
+    fn import_block(&mut self, block: Block<C, SM>) -> bool {
+        self.import(block, None)
+    }
+
+    fn get_block(&self, block_hash: u64) -> Option<Block<C, SM>> {
+        self.blocks.get(&block_hash).map(|(b, _)| b.clone())
+    }
+
+    fn get_state(&self, block_hash: u64) -> Option<SM::State> {
+        self.blocks.get(&block_hash).map(|(_, s)| s.clone())
+    }
+
+    fn is_leaf(&self, block_hash: u64) -> Option<bool> {
+        self.blocks.get(&block_hash)?;
+        Some(
+            !self
+                .blocks
+                .values()
+                .any(|(b, _)| b.header.parent == block_hash),
+        )
+    }
+
+    fn all_leaves(&self) -> Vec<u64> {
+        let mut leaves: Vec<_> = self
+            .blocks
+            .keys()
+            .copied()
+            .filter(|h| self.is_leaf(*h) == Some(true))
+            .collect();
+        leaves.sort();
+        leaves
+    }
+}
+
+impl<C, SM> ClientApi<C, SM> for FakeClient<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    SM::State: Clone,
+    SM::Transition: std::hash::Hash + PartialEq,
+    Block<C, SM>: Clone,
+{
+    fn genesis(&self) -> Hash {
+        self.genesis
+    }
+
+    /// The highest block on the finalized chain, breaking ties by the lowest hash.
+    fn best_block(&self) -> Hash {
+        self.blocks
+            .iter()
+            .filter(|(h, _)| descends_from(|h| self.lookup(h), self.finalized, **h))
+            .max_by_key(|(h, (b, _))| (b.header.height, std::cmp::Reverse(**h)))
+            .map_or(self.finalized, |(h, _)| *h)
+    }
+
+    fn import_block_with_state(&mut self, block: Block<C, SM>, state: Option<SM::State>) -> bool {
+        self.import(block, state)
+    }
+
+    fn manually_finalize_block(&mut self, block_hash: Hash) -> bool {
+        self.finalize(block_hash)
+    }
+
+    fn finalized_block(&self) -> Hash {
+        self.finalized
+    }
+}
+
+/// The fake client can take part in network simulations.
+impl<C, SM> SimNode for FakeClient<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    SM::State: Clone,
+    SM::Transition: std::hash::Hash + Clone + PartialEq + Encode,
+    Block<C, SM>: Clone + Encode,
+{
+    type Block = Block<C, SM>;
+    type Transaction = SM::Transition;
+    type Vote = ();
+
+    fn hash_of(block: &Self::Block) -> Hash {
+        hash(&block.header)
+    }
+
+    fn parent_of(block: &Self::Block) -> Hash {
+        block.header.parent
+    }
+
+    fn block_size(block: &Self::Block) -> usize {
+        block.encoded_size()
+    }
+
+    fn transaction_size(t: &Self::Transaction) -> usize {
+        t.encoded_size()
+    }
+
+    fn import_block(&mut self, block: Self::Block) -> bool {
+        self.import(block, None)
+    }
+
+    fn get_block(&self, block_hash: Hash) -> Option<Self::Block> {
+        ImportBlock::get_block(self, block_hash)
+    }
+
+    fn block_height(&self, block_hash: Hash) -> Option<u64> {
+        self.lookup(block_hash).map(|(_, height)| height)
+    }
+
+    fn block_parent(&self, block_hash: Hash) -> Option<Hash> {
+        self.lookup(block_hash).map(|(parent, _)| parent)
+    }
+
+    fn best_block(&self) -> Hash {
+        ClientApi::best_block(self)
+    }
+
+    fn submit_transaction(&mut self, t: Self::Transaction) -> bool {
+        if self.pool.contains(&t) {
+            return false;
+        }
+        self.pool.push(t);
+        true
+    }
+
+    fn author_block(&mut self, now: Time) -> Option<Self::Block> {
+        self.author(now)
+    }
+
+    fn finalized_block(&self) -> Option<Hash> {
+        Some(self.finalized)
+    }
+
+    fn finalize_block(&mut self, block_hash: Hash, justification: Vec<u8>) -> bool {
+        if !ClientApi::manually_finalize_block(self, block_hash) {
+            return false;
+        }
+        self.justifications.insert(block_hash, justification);
+        true
+    }
+
+    fn justification(&self, block_hash: Hash) -> Option<Vec<u8>> {
+        self.justifications.get(&block_hash).cloned()
+    }
+}
+
+pub(crate) type TestBlock = Block<CountingConsensus, TestCurrency>;
+
+/// The client most tests in this chapter use.
+pub(crate) type TestClient = FakeClient<CountingConsensus, TestCurrency>;
+
+/// A client whose genesis block has an empty state, matching the chains from `test_chain`.
+pub(crate) fn test_client() -> TestClient {
+    FakeClient::new(
+        CountingConsensus,
+        StateTrie::new(),
+        0,
+        StateTrie::state_root,
+    )
+}
+
+/// Build a child of the given block with the given body, given the parent's state. Returns the
+/// child along with its state.
+pub(crate) fn test_block(
+    parent: &TestBlock,
+    parent_state: &StateTrie,
+    body: Vec<AccountingTransaction>,
+) -> (TestBlock, StateTrie) {
+    let state = body.iter().fold(parent_state.clone(), |state, t| {
+        TestCurrency::next_state(&state, t)
+    });
+    let block = Block {
+        header: Header {
+            parent: hash(&parent.header),
+            height: parent.header.height + 1,
+            state_root: state.state_root(),
+            extrinsics_root: merkle_root(&body),
+            timestamp: 0,
+            consensus_digest: parent.header.consensus_digest + 1,
+        },
+        body,
+    };
+    (block, state)
+}
+
+/// Extend the given chain (or start a new one with the genesis block) up to the given height.
+/// Every block mints to Alice, and different forks mint different amounts.
+pub(crate) fn test_chain(prefix: &[TestBlock], height: u64, fork: u64) -> Vec<TestBlock> {
+    let genesis = test_client();
+    let mut chain = prefix.to_vec();
+    if chain.is_empty() {
+        chain.extend(ImportBlock::get_block(&genesis, genesis.genesis()));
+    }
+    let mut state = chain[1..]
+        .iter()
+        .flat_map(|block| &block.body)
+        .fold(StateTrie::new(), |state, t| {
+            TestCurrency::next_state(&state, t)
+        });
+    while chain.last().unwrap().header.height < height {
+        let parent = chain.last().unwrap();
+        let body = vec![AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: fork * 1000 + parent.header.height + 1,
+        }];
+        let (block, next) = test_block(parent, &state, body);
+        chain.push(block);
+        state = next;
+    }
+    chain
+}
+
+/// A block in the mock chain used to test the simulator.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct MockBlock {
+    pub(crate) parent: Hash,
+    pub(crate) height: u64,
+    pub(crate) author: usize,
+    pub(crate) transactions: Vec<u64>,
+}
+
+impl Encode for MockBlock {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.parent.encode_to(out);
+        self.height.encode_to(out);
+        (self.author as u64).encode_to(out);
+        self.transactions.encode_to(out);
+    }
+}
+
+impl Decode for MockBlock {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(MockBlock {
+            parent: Decode::decode_from(input)?,
+            height: Decode::decode_from(input)?,
+            author: u64::decode_from(input)? as usize,
+            transactions: Decode::decode_from(input)?,
+        })
+    }
+}
+
+/// A minimal longest-chain node used to test the simulator.
+pub(crate) struct MockNode {
+    pub(crate) id: usize,
+    pub(crate) blocks: HashMap<Hash, MockBlock>,
+    pub(crate) best: Hash,
+    pub(crate) pool: Vec<u64>,
+    /// The encoded justifications of the blocks a finality gadget finalized.
+    pub(crate) justifications: HashMap<Hash, Vec<u8>>,
+}
+
+impl MockNode {
+    pub(crate) fn new(id: usize) -> Self {
+        let genesis = MockBlock {
+            parent: 0,
+            height: 0,
+            author: 0,
+            transactions: vec![],
+        };
+        let best = hash(&genesis);
+        Self {
+            id,
+            blocks: HashMap::from([(best, genesis)]),
+            best,
+            pool: vec![],
+            justifications: HashMap::new(),
+        }
+    }
+}
+
+impl SimNode for MockNode {
+    type Block = MockBlock;
+    type Transaction = u64;
+    type Vote = ();
+
+    fn hash_of(block: &MockBlock) -> Hash {
+        hash(block)
+    }
+
+    fn parent_of(block: &MockBlock) -> Hash {
+        block.parent
+    }
+
+    fn block_size(block: &MockBlock) -> usize {
+        100 + 8 * block.transactions.len()
+    }
+
+    fn transaction_size(_: &u64) -> usize {
+        8
+    }
+
+    fn import_block(&mut self, block: MockBlock) -> bool {
+        let Some(parent) = self.blocks.get(&block.parent) else {
+            return false;
+        };
+        if block.height != parent.height + 1 {
+            return false;
+        }
+        let block_hash = hash(&block);
+        // Longest chain, with ties broken by the lowest hash so that all nodes converge.
+        let best_height = self.blocks[&self.best].height;
+        if block.height > best_height || (block.height == best_height && block_hash < self.best) {
+            self.best = block_hash;
+        }
+        self.pool.retain(|t| !block.transactions.contains(t));
+        self.blocks.insert(block_hash, block);
+        true
+    }
+
+    fn get_block(&self, block_hash: Hash) -> Option<MockBlock> {
+        self.blocks.get(&block_hash).cloned()
+    }
+
+    fn block_height(&self, block_hash: Hash) -> Option<u64> {
+        self.blocks.get(&block_hash).map(|b| b.height)
+    }
+
+    fn block_parent(&self, block_hash: Hash) -> Option<Hash> {
+        self.blocks.get(&block_hash).map(|b| b.parent)
+    }
+
+    fn best_block(&self) -> Hash {
+        self.best
+    }
+
+    fn submit_transaction(&mut self, t: u64) -> bool {
+        if self.pool.contains(&t) {
+            return false;
+        }
+        self.pool.push(t);
+        true
+    }
+
+    fn author_block(&mut self, _now: Time) -> Option<MockBlock> {
+        let block = MockBlock {
+            parent: self.best,
+            height: self.blocks[&self.best].height + 1,
+            author: self.id,
+            transactions: std::mem::take(&mut self.pool),
+        };
+        assert!(self.import_block(block.clone()));
+        Some(block)
+    }
+
+    /// The mock node does not enforce finality, it only keeps the justifications.
+    fn finalize_block(&mut self, block_hash: Hash, justification: Vec<u8>) -> bool {
+        if !self.blocks.contains_key(&block_hash) {
+            return false;
+        }
+        self.justifications.insert(block_hash, justification);
+        true
+    }
+
+    fn justification(&self, block_hash: Hash) -> Option<Vec<u8>> {
+        self.justifications.get(&block_hash).cloned()
+    }
+}
@@ -0,0 +1,513 @@
+//! A minimal binary encoding for the data structures in this tutorial.
+//!
+//! Whenever blocks or states leave the memory of a single process, whether they are written to
+//! disk or sent to a peer, they must be turned into bytes. Real-world chains use encodings like
+//! SCALE or RLP. We write our own simple encoding here so that the tutorial stays free of
+//! external dependencies.
+//!
+//! Integers are little-endian and fixed width. Collections are prefixed with their length as
+//! a u32. Enums are prefixed with a single byte identifying the variant.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, StateMachine, User};
+use crate::c2_blockchain::p8_state_trie::StateTrie;
+use crate::c3_consensus::{Consensus, ConsensusAuthority, Header};
+use crate::c4_client::Block;
+
+/// The reason some bytes could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the value was complete.
+    UnexpectedEnd,
+    /// The input contained a value that is not valid for the type being decoded.
+    Invalid(&'static str),
+    /// The value was decoded successfully, but there were bytes left over.
+    TrailingBytes,
+}
+
+/// A type that can be turned into bytes.
+pub trait Encode {
+    /// Append the encoded form of this value to the given buffer.
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// Encode this value into a new buffer.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    /// The number of bytes in this value's encoded form.
+    fn encoded_size(&self) -> usize {
+        self.encode().len()
+    }
+}
+
+/// A type that can be recovered from the bytes produced by its `Encode` implementation.
+pub trait Decode: Sized {
+    /// Decode a value from the front of the input, advancing the input past it.
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError>;
+
+    /// Decode a value that occupies the entire input.
+    fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+        let value = Self::decode_from(&mut bytes)?;
+        if bytes.is_empty() {
+            Ok(value)
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+/// Take the given number of bytes from the front of the input.
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < n {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+macro_rules! impl_codec_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode_to(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Decode for $t {
+                fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let bytes = take(input, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().expect("took exactly enough bytes")))
+                }
+            }
+        )*
+    };
+}
+
+impl_codec_for_int!(u8, u16, u32, u64, u128, i64);
+
+impl Encode for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("bool")),
+        }
+    }
+}
+
+impl Encode for () {
+    fn encode_to(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode_from(_: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+/// Encode the length of a collection.
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    u32::try_from(len)
+        .expect("collections are never longer than u32::MAX")
+        .encode_to(out);
+}
+
+/// Decode the length of a collection.
+fn decode_len(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    Ok(u32::decode_from(input)? as usize)
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        for item in self {
+            item.encode_to(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_to(out);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_len(input)?;
+        // Don't trust the length prefix when allocating; it could be garbage.
+        let mut items = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            items.push(T::decode_from(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_len(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Invalid("utf8 string"))
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode_to(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode_from(input)?)),
+            _ => Err(DecodeError::Invalid("option")),
+        }
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+        self.1.encode_to(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((A::decode_from(input)?, B::decode_from(input)?))
+    }
+}
+
+impl<A: Encode, B: Encode, C: Encode> Encode for (A, B, C) {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+        self.1.encode_to(out);
+        self.2.encode_to(out);
+    }
+}
+
+impl<A: Decode, B: Decode, C: Decode> Decode for (A, B, C) {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((
+            A::decode_from(input)?,
+            B::decode_from(input)?,
+            C::decode_from(input)?,
+        ))
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for BTreeMap<K, V> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        encode_len(self.len(), out);
+        for (key, value) in self {
+            key.encode_to(out);
+            value.encode_to(out);
+        }
+    }
+}
+
+impl<K: Decode + Ord, V: Decode> Decode for BTreeMap<K, V> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Vec::<(K, V)>::decode_from(input)?.into_iter().collect())
+    }
+}
+
+/// Hash maps have no defined order, so the entries are sorted by their encoded keys. That way
+/// two equal maps always have the same encoding.
+impl<K: Encode, V: Encode> Encode for HashMap<K, V> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let mut entries: Vec<(Vec<u8>, &V)> = self.iter().map(|(k, v)| (k.encode(), v)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        encode_len(entries.len(), out);
+        for (key, value) in entries {
+            out.extend_from_slice(&key);
+            value.encode_to(out);
+        }
+    }
+}
+
+impl<K: Decode + Eq + std::hash::Hash, V: Decode> Decode for HashMap<K, V> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Vec::<(K, V)>::decode_from(input)?.into_iter().collect())
+    }
+}
+
+/// Like hash maps, hash sets are sorted by their encoded elements.
+impl<T: Encode> Encode for HashSet<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let mut items: Vec<Vec<u8>> = self.iter().map(Encode::encode).collect();
+        items.sort();
+        encode_len(items.len(), out);
+        for item in items {
+            out.extend_from_slice(&item);
+        }
+    }
+}
+
+impl<T: Decode + Eq + std::hash::Hash> Decode for HashSet<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Vec::<T>::decode_from(input)?.into_iter().collect())
+    }
+}
+
+impl Encode for User {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for User {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(User::Alice),
+            1 => Ok(User::Bob),
+            2 => Ok(User::Charlie),
+            _ => Err(DecodeError::Invalid("user")),
+        }
+    }
+}
+
+impl Encode for AccountingTransaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            AccountingTransaction::Mint { minter, amount } => {
+                out.push(0);
+                minter.encode_to(out);
+                amount.encode_to(out);
+            }
+            AccountingTransaction::Burn { burner, amount } => {
+                out.push(1);
+                burner.encode_to(out);
+                amount.encode_to(out);
+            }
+            AccountingTransaction::Transfer {
+                sender,
+                receiver,
+                amount,
+            } => {
+                out.push(2);
+                sender.encode_to(out);
+                receiver.encode_to(out);
+                amount.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for AccountingTransaction {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(AccountingTransaction::Mint {
+                minter: Decode::decode_from(input)?,
+                amount: Decode::decode_from(input)?,
+            }),
+            1 => Ok(AccountingTransaction::Burn {
+                burner: Decode::decode_from(input)?,
+                amount: Decode::decode_from(input)?,
+            }),
+            2 => Ok(AccountingTransaction::Transfer {
+                sender: Decode::decode_from(input)?,
+                receiver: Decode::decode_from(input)?,
+                amount: Decode::decode_from(input)?,
+            }),
+            _ => Err(DecodeError::Invalid("accounting transaction")),
+        }
+    }
+}
+
+/// A trie is encoded as its key-value pairs in key order. The inner nodes are rebuilt from them
+/// when it is decoded.
+impl Encode for StateTrie {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        let pairs: BTreeMap<Vec<u8>, Vec<u8>> = self
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.encode_to(out);
+    }
+}
+
+impl Decode for StateTrie {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let mut trie = StateTrie::new();
+        for (key, value) in BTreeMap::<Vec<u8>, Vec<u8>>::decode_from(input)? {
+            trie.insert(key, value);
+        }
+        Ok(trie)
+    }
+}
+
+impl Encode for ConsensusAuthority {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for ConsensusAuthority {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode_from(input)? {
+            0 => Ok(ConsensusAuthority::Alice),
+            1 => Ok(ConsensusAuthority::Bob),
+            2 => Ok(ConsensusAuthority::Charlie),
+            _ => Err(DecodeError::Invalid("consensus authority")),
+        }
+    }
+}
+
+impl<D: Encode> Encode for Header<D> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.parent.encode_to(out);
+        self.height.encode_to(out);
+        self.state_root.encode_to(out);
+        self.extrinsics_root.encode_to(out);
+        self.timestamp.encode_to(out);
+        self.consensus_digest.encode_to(out);
+    }
+}
+
+impl<D: Decode> Decode for Header<D> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Header {
+            parent: Decode::decode_from(input)?,
+            height: Decode::decode_from(input)?,
+            state_root: Decode::decode_from(input)?,
+            extrinsics_root: Decode::decode_from(input)?,
+            timestamp: Decode::decode_from(input)?,
+            consensus_digest: Decode::decode_from(input)?,
+        })
+    }
+}
+
+impl<C, SM> Encode for Block<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: Encode,
+    SM::Transition: Encode,
+{
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.header.encode_to(out);
+        self.body.encode_to(out);
+    }
+}
+
+impl<C, SM> Decode for Block<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: Decode,
+    SM::Transition: Decode,
+{
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Block {
+            header: Decode::decode_from(input)?,
+            body: Decode::decode_from(input)?,
+        })
+    }
+}
+
+#[test]
+fn codec_integers_round_trip() {
+    for n in [0u64, 1, 255, 256, u64::MAX] {
+        assert_eq!(u64::decode(&n.encode()), Ok(n));
+    }
+    assert_eq!(7u32.encode(), vec![7, 0, 0, 0]);
+}
+
+#[test]
+fn codec_collections_round_trip() {
+    let value = vec![(1u8, Some(2u64)), (3, None)];
+    assert_eq!(Vec::<(u8, Option<u64>)>::decode(&value.encode()), Ok(value));
+
+    let map = HashMap::from([(User::Alice, 5u64), (User::Charlie, 9)]);
+    assert_eq!(HashMap::<User, u64>::decode(&map.encode()), Ok(map));
+
+    let s = String::from("hello");
+    assert_eq!(String::decode(&s.encode()), Ok(s));
+}
+
+#[test]
+fn codec_hash_map_encoding_is_deterministic() {
+    let map_1: HashMap<u64, u64> = (0..100).map(|i| (i, i)).collect();
+    let map_2: HashMap<u64, u64> = (0..100).rev().map(|i| (i, i)).collect();
+
+    assert_eq!(map_1.encode(), map_2.encode());
+}
+
+#[test]
+fn codec_header_round_trip() {
+    let header = Header {
+        parent: 1,
+        height: 2,
+        state_root: 3,
+        extrinsics_root: 4,
+        timestamp: 5,
+        consensus_digest: ConsensusAuthority::Bob,
+    };
+
+    assert_eq!(Header::decode(&header.encode()), Ok(header));
+}
+
+#[test]
+fn codec_transaction_round_trip() {
+    let transaction = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 10,
+    };
+
+    assert_eq!(
+        AccountingTransaction::decode(&transaction.encode()),
+        Ok(transaction)
+    );
+}
+
+#[test]
+fn codec_state_trie_round_trip() {
+    let mut trie = StateTrie::new();
+    trie.insert(b"foo".to_vec(), b"bar".to_vec());
+    trie.insert(b"baz".to_vec(), vec![]);
+
+    let decoded = StateTrie::decode(&trie.encode()).unwrap();
+    assert_eq!(decoded.state_root(), trie.state_root());
+    assert_eq!(decoded.get(b"foo"), Some(&b"bar"[..]));
+}
+
+#[test]
+fn codec_rejects_bad_input() {
+    assert_eq!(u64::decode(&[1, 2, 3]), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(bool::decode(&[2]), Err(DecodeError::Invalid("bool")));
+    assert_eq!(u8::decode(&[1, 2]), Err(DecodeError::TrailingBytes));
+    assert_eq!(
+        Vec::<u64>::decode(&[255, 255, 255, 255]),
+        Err(DecodeError::UnexpectedEnd)
+    );
+}
@@ -0,0 +1,662 @@
+//! A minimal JSON representation for the data structures in this tutorial.
+//!
+//! The binary encoding in the `codec` module is compact, but not something a human can read.
+//! Sometimes we want to inspect a chain with our own eyes, or edit a block by hand to see how the
+//! client reacts. For that we provide this JSON format. As with the binary codec, we write it
+//! ourselves to keep the tutorial free of external dependencies.
+//!
+//! Only the parts of JSON that we actually need are supported. In particular, the only numbers
+//! are unsigned integers, which is all our blocks ever contain.
+
+use crate::c1_state_machine::{p4_accounted_currency::AccountingTransaction, StateMachine, User};
+use crate::c3_consensus::{Consensus, ConsensusAuthority, Header};
+use crate::c4_client::Block;
+
+/// How deeply arrays and objects may be nested. The parser calls itself for every level, so
+/// without a limit a document of a few thousand `[` would overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A JSON value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    /// The fields of an object, in the order they were written.
+    Object(Vec<(String, Json)>),
+}
+
+/// The reason some JSON could not be parsed or converted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonError(pub String);
+
+impl JsonError {
+    fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+/// A type that can be represented as JSON.
+pub trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+/// A type that can be recovered from the JSON produced by its `ToJson` implementation.
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Result<Self, JsonError>;
+}
+
+impl Json {
+    /// Look up a field of an object.
+    pub fn field(&self, name: &str) -> Result<&Json, JsonError> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| JsonError::new(format!("missing field `{name}`"))),
+            _ => Err(JsonError::new(format!(
+                "expected an object with field `{name}`"
+            ))),
+        }
+    }
+
+    /// Render this value as indented, human-friendly text.
+    pub fn to_pretty_string(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize) {
+        let pad = |out: &mut String, level: usize| out.push_str(&"  ".repeat(level));
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(s) => write_string(out, s),
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Array(items) => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    pad(out, indent + 1);
+                    item.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push(']');
+            }
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    pad(out, indent + 1);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push('}');
+            }
+        }
+    }
+
+    /// Parse a complete JSON document.
+    pub fn parse(text: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            text,
+            bytes: text.as_bytes(),
+            position: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A simple recursive descent parser.
+struct Parser<'a> {
+    text: &'a str,
+    bytes: &'a [u8],
+    position: usize,
+    /// How many arrays and objects enclose the current position.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError::new(format!("{message} at byte {}", self.position))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\n' | b'\r' | b'\t')) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", byte as char)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if self.bytes[self.position..].starts_with(word.as_bytes()) {
+            self.position += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected token"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'{') => self.nested(Self::object),
+            Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    /// Parse an array or object, one level deeper than the current position.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Json, JsonError>,
+    ) -> Result<Json, JsonError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.position;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.position])
+            .expect("digits are valid utf8")
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("number out of range"))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut s = String::new();
+        loop {
+            // The position only ever advances by whole characters, so it is always a char boundary.
+            let Some(c) = self.text[self.position..].chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated escape"))?;
+                    self.position += 1;
+                    match escape {
+                        b'"' => s.push('"'),
+                        b'\\' => s.push('\\'),
+                        b'/' => s.push('/'),
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'u' => {
+                            let hex = self
+                                .bytes
+                                .get(self.position..self.position + 4)
+                                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.position += 4;
+                            s.push(hex);
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+}
+
+/// Build an object from a list of fields.
+pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+impl ToJson for u64 {
+    fn to_json(&self) -> Json {
+        Json::Number(*self)
+    }
+}
+
+impl FromJson for u64 {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::Number(n) => Ok(*n),
+            _ => Err(JsonError::new("expected a number")),
+        }
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> Json {
+        Json::Bool(*self)
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::Bool(b) => Ok(*b),
+            _ => Err(JsonError::new("expected a boolean")),
+        }
+    }
+}
+
+impl ToJson for () {
+    fn to_json(&self) -> Json {
+        Json::Null
+    }
+}
+
+impl FromJson for () {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::Null => Ok(()),
+            _ => Err(JsonError::new("expected null")),
+        }
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> Json {
+        Json::String(self.clone())
+    }
+}
+
+impl FromJson for String {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::String(s) => Ok(s.clone()),
+            _ => Err(JsonError::new("expected a string")),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> Json {
+        Json::Array(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::Array(items) => items.iter().map(T::from_json).collect(),
+            _ => Err(JsonError::new("expected an array")),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> Json {
+        match self {
+            Some(value) => value.to_json(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Option<T> {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match json {
+            Json::Null => Ok(None),
+            json => Ok(Some(T::from_json(json)?)),
+        }
+    }
+}
+
+impl ToJson for User {
+    fn to_json(&self) -> Json {
+        Json::String(format!("{self:?}"))
+    }
+}
+
+impl FromJson for User {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match String::from_json(json)?.as_str() {
+            "Alice" => Ok(User::Alice),
+            "Bob" => Ok(User::Bob),
+            "Charlie" => Ok(User::Charlie),
+            other => Err(JsonError::new(format!("unknown user `{other}`"))),
+        }
+    }
+}
+
+impl ToJson for ConsensusAuthority {
+    fn to_json(&self) -> Json {
+        Json::String(format!("{self:?}"))
+    }
+}
+
+impl FromJson for ConsensusAuthority {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        match String::from_json(json)?.as_str() {
+            "Alice" => Ok(ConsensusAuthority::Alice),
+            "Bob" => Ok(ConsensusAuthority::Bob),
+            "Charlie" => Ok(ConsensusAuthority::Charlie),
+            other => Err(JsonError::new(format!("unknown authority `{other}`"))),
+        }
+    }
+}
+
+/// Transactions are written as an object with a single field named after the variant.
+impl ToJson for AccountingTransaction {
+    fn to_json(&self) -> Json {
+        match self {
+            AccountingTransaction::Mint { minter, amount } => object([(
+                "Mint",
+                object([("minter", minter.to_json()), ("amount", amount.to_json())]),
+            )]),
+            AccountingTransaction::Burn { burner, amount } => object([(
+                "Burn",
+                object([("burner", burner.to_json()), ("amount", amount.to_json())]),
+            )]),
+            AccountingTransaction::Transfer {
+                sender,
+                receiver,
+                amount,
+            } => object([(
+                "Transfer",
+                object([
+                    ("sender", sender.to_json()),
+                    ("receiver", receiver.to_json()),
+                    ("amount", amount.to_json()),
+                ]),
+            )]),
+        }
+    }
+}
+
+impl FromJson for AccountingTransaction {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        let Json::Object(fields) = json else {
+            return Err(JsonError::new("expected a transaction object"));
+        };
+        let [(variant, body)] = fields.as_slice() else {
+            return Err(JsonError::new("expected exactly one transaction variant"));
+        };
+        match variant.as_str() {
+            "Mint" => Ok(AccountingTransaction::Mint {
+                minter: FromJson::from_json(body.field("minter")?)?,
+                amount: FromJson::from_json(body.field("amount")?)?,
+            }),
+            "Burn" => Ok(AccountingTransaction::Burn {
+                burner: FromJson::from_json(body.field("burner")?)?,
+                amount: FromJson::from_json(body.field("amount")?)?,
+            }),
+            "Transfer" => Ok(AccountingTransaction::Transfer {
+                sender: FromJson::from_json(body.field("sender")?)?,
+                receiver: FromJson::from_json(body.field("receiver")?)?,
+                amount: FromJson::from_json(body.field("amount")?)?,
+            }),
+            other => Err(JsonError::new(format!("unknown transaction `{other}`"))),
+        }
+    }
+}
+
+impl<D: ToJson> ToJson for Header<D> {
+    fn to_json(&self) -> Json {
+        object([
+            ("parent", self.parent.to_json()),
+            ("height", self.height.to_json()),
+            ("state_root", self.state_root.to_json()),
+            ("extrinsics_root", self.extrinsics_root.to_json()),
+            ("timestamp", self.timestamp.to_json()),
+            ("consensus_digest", self.consensus_digest.to_json()),
+        ])
+    }
+}
+
+impl<D: FromJson> FromJson for Header<D> {
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        Ok(Header {
+            parent: FromJson::from_json(json.field("parent")?)?,
+            height: FromJson::from_json(json.field("height")?)?,
+            state_root: FromJson::from_json(json.field("state_root")?)?,
+            extrinsics_root: FromJson::from_json(json.field("extrinsics_root")?)?,
+            timestamp: FromJson::from_json(json.field("timestamp")?)?,
+            consensus_digest: FromJson::from_json(json.field("consensus_digest")?)?,
+        })
+    }
+}
+
+impl<C, SM> ToJson for Block<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: ToJson,
+    SM::Transition: ToJson,
+{
+    fn to_json(&self) -> Json {
+        object([
+            ("header", self.header.to_json()),
+            ("body", self.body.to_json()),
+        ])
+    }
+}
+
+impl<C, SM> FromJson for Block<C, SM>
+where
+    C: Consensus,
+    SM: StateMachine,
+    C::Digest: FromJson,
+    SM::Transition: FromJson,
+{
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        Ok(Block {
+            header: FromJson::from_json(json.field("header")?)?,
+            body: FromJson::from_json(json.field("body")?)?,
+        })
+    }
+}
+
+#[test]
+fn json_parse_and_print_round_trip() {
+    let json = object([
+        (
+            "a",
+            Json::Array(vec![Json::Number(1), Json::Null, Json::Bool(true)]),
+        ),
+        ("b", Json::String("quote \" and \\ and \n".into())),
+        ("c", Json::Object(vec![])),
+        ("d", Json::Array(vec![])),
+    ]);
+
+    assert_eq!(Json::parse(&json.to_pretty_string()), Ok(json));
+}
+
+#[test]
+fn json_parse_compact_text() {
+    let json = Json::parse(r#"{"x":[1,2,{"y":"A"}],"z":false}"#).unwrap();
+
+    assert_eq!(json.field("z"), Ok(&Json::Bool(false)));
+    assert_eq!(
+        json.field("x"),
+        Ok(&Json::Array(vec![
+            Json::Number(1),
+            Json::Number(2),
+            object([("y", Json::String("A".into()))]),
+        ]))
+    );
+}
+
+#[test]
+fn json_large_numbers_are_exact() {
+    let json = Json::parse(&u64::MAX.to_string()).unwrap();
+
+    assert_eq!(u64::from_json(&json), Ok(u64::MAX));
+}
+
+#[test]
+fn json_rejects_malformed_text() {
+    assert!(Json::parse("{").is_err());
+    assert!(Json::parse("[1,]").is_err());
+    assert!(Json::parse("-1").is_err());
+    assert!(Json::parse("1 2").is_err());
+    assert!(Json::parse("\"unterminated").is_err());
+}
+
+#[test]
+fn json_unicode_escapes_need_four_hex_digits() {
+    assert_eq!(Json::parse(r#""\u0041""#), Ok(Json::String("A".into())));
+    assert!(Json::parse(r#""\u+041""#).is_err());
+    assert!(Json::parse(r#""\u041""#).is_err());
+    assert!(Json::parse(r#""\u 041""#).is_err());
+}
+
+#[test]
+fn json_rejects_deep_nesting() {
+    let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+
+    assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+    assert_eq!(
+        Json::parse(&nested(MAX_DEPTH + 1)),
+        Err(JsonError::new(format!(
+            "nested too deeply at byte {MAX_DEPTH}"
+        )))
+    );
+    // Far too deep to parse recursively, but rejected rather than overflowing the stack.
+    assert!(Json::parse(&nested(1_000_000)).is_err());
+}
+
+#[test]
+fn json_transaction_round_trip() {
+    let transaction = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Charlie,
+        amount: 3,
+    };
+
+    assert_eq!(
+        AccountingTransaction::from_json(&transaction.to_json()),
+        Ok(transaction)
+    );
+}
+
+#[test]
+fn json_header_round_trip() {
+    let header = Header {
+        parent: 10,
+        height: 2,
+        state_root: 3,
+        extrinsics_root: 4,
+        timestamp: 5,
+        consensus_digest: ConsensusAuthority::Charlie,
+    };
+    let text = header.to_json().to_pretty_string();
+
+    assert!(text.contains("\"consensus_digest\": \"Charlie\""));
+    assert_eq!(Header::from_json(&Json::parse(&text).unwrap()), Ok(header));
+}
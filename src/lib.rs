@@ -5,6 +5,8 @@ mod c1_state_machine;
 mod c2_blockchain;
 mod c3_consensus;
 mod c4_client;
+mod codec;
+mod json;
 
 // Simple helper to do some hashing.
 fn hash<T: Hash>(t: &T) -> u64 {